@@ -1,16 +1,34 @@
 mod video;
 
 use std::{
+    collections::HashMap,
     env,
+    io::{
+        self,
+        Write,
+    },
+    os::fd::AsRawFd,
     path::{
+        Component,
         Path,
         PathBuf,
     },
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        mpsc,
+        Arc,
+    },
+    thread,
+    time::Duration,
 };
 use google_cloud_storage::{
     client::{
         Client,
         ClientConfig,
+        google_cloud_auth::credentials::CredentialsFile,
     },
     http::objects::{
         download::Range,
@@ -20,116 +38,2309 @@ use google_cloud_storage::{
             UploadType,
         },
         get::GetObjectRequest,
+        list::ListObjectsRequest,
+        Object,
     },
 };
 use tokio::{
-    fs::File,
+    fs::{
+        self,
+        File,
+    },
     io::AsyncWriteExt,
+    signal::unix::{
+        signal,
+        SignalKind,
+    },
 };
 use tokio_util::io::ReaderStream;
+use async_compression::tokio::write::GzipDecoder;
 use futures::stream::StreamExt;
-use env_logger;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use env_logger::{
+    self,
+    Target,
+};
+
+// errno for "No space left on device"
+const ENOSPC: i32 = 28;
+// next to the downloads it tracks
+const JOB_STATE_DIR: &str = "data";
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
+    let config = load_config(get_env_var("CONFIG_FILE"));
+    let log_file_path = get_log_file_path(config.log_file.clone(), env::args().nth(1).as_deref());
+    init_logger(&log_file_path, &get_log_filter(config.log_level.clone(), env::var("RUST_LOG").ok()));
 
-    let input_bucket = get_env_string("INPUT_BUCKET");
-    let output_bucket = get_env_string("OUTPUT_BUCKET");
-    let enough_vmaf = get_env_u8("ENOUGH_VMAF");
-    let min_crf = get_env_u8("MIN_CRF");
+    let input_bucket = get_required(config.input_bucket.clone(), "INPUT_BUCKET");
+    let output_buckets = match config.output_buckets.clone() {
+        Some(output_buckets) => parse_output_buckets(&output_buckets),
+        None => vec![get_required(config.output_bucket.clone(), "OUTPUT_BUCKET")],
+    };
+    // by default an output only fails when no bucket got it
+    let upload_fail_on_any = config.upload_fail_on_any.unwrap_or_default();
+    // bytes per read of a streamed upload, larger ones suit high latency links
+    let upload_chunk_size = config.upload_chunk_size;
+    if upload_chunk_size == Some(0) {
+        panic!("UPLOAD_CHUNK_SIZE must be positive");
+    }
+    let output_metadata = match config.output_metadata.clone() {
+        Some(output_metadata) => parse_output_metadata(&output_metadata),
+        None => HashMap::new(),
+    };
+    // fractional, e.g. 93.5, to tune near a threshold
+    let enough_vmaf = get_required(config.enough_vmaf, "ENOUGH_VMAF");
+    if !(0.0..=video::MAX_VMAF as f64).contains(&enough_vmaf) {
+        panic!("ENOUGH_VMAF must be in 0..={:}: {:}", video::MAX_VMAF, enough_vmaf);
+    }
+    let min_crf = get_required(config.min_crf, "MIN_CRF");
+    let measure_fallback_vmaf = config.measure_fallback_vmaf.unwrap_or_default();
+    let measure_signalstats = config.measure_signalstats.unwrap_or_default();
+    let strict_audio = config.strict_audio.unwrap_or_default();
+    // no timeout by default
+    let crf_search_timeout = config.crf_search_timeout_secs;
+    if crf_search_timeout.is_some_and(|crf_search_timeout| crf_search_timeout <= 0.0) {
+        panic!("CRF_SEARCH_TIMEOUT_SECS must be positive: {:?}", crf_search_timeout);
+    }
+    let prefer_smaller = config.prefer_smaller.unwrap_or_default();
+    let verify_prediction = config.verify_prediction.unwrap_or_default();
+    let verify_prediction_max_delta = config.verify_prediction_max_delta.unwrap_or(3.0);
+    if verify_prediction_max_delta < 0.0 {
+        panic!("VERIFY_PREDICTION_MAX_DELTA must not be negative: {:}", verify_prediction_max_delta);
+    }
+    let prefer_smaller_margin = config.prefer_smaller_margin.unwrap_or(1.0);
+    if prefer_smaller_margin < 0.0 {
+        panic!("PREFER_SMALLER_MARGIN must not be negative: {:}", prefer_smaller_margin);
+    }
+    let hard_floor_vmaf = config.hard_floor_vmaf;
+    if let Some(hard_floor_vmaf) = hard_floor_vmaf {
+        if video::MAX_VMAF < hard_floor_vmaf {
+            panic!("HARD_FLOOR_VMAF must be in 0..={:}: {:}", video::MAX_VMAF, hard_floor_vmaf);
+        }
+    }
+    let orientation_policy = match config.orientation_policy.as_deref() {
+        None | Some("pad") => video::OrientationPolicy::Pad,
+        Some("rotate-to-majority") => video::OrientationPolicy::RotateToMajority,
+        Some(v) => panic!("ORIENTATION_POLICY must be pad or rotate-to-majority: {:}", v),
+    };
+    // mostly silent clips read better as a video only output
+    let audio_policy = match config.audio_policy.as_deref() {
+        None | Some("silence-fill") => video::AudioPolicy::SilenceFill,
+        Some("drop-if-any-missing") => video::AudioPolicy::DropIfAnyMissing,
+        Some("drop-always") => video::AudioPolicy::DropAlways,
+        Some(v) => panic!("AUDIO_POLICY must be silence-fill, drop-if-any-missing or drop-always: {:}", v),
+    };
+    // for local runs, an existing output is kept or replaced by this
+    let overwrite_policy = match config.overwrite_policy.as_deref() {
+        None | Some("always") => video::OverwritePolicy::Always,
+        Some("never") => video::OverwritePolicy::Never,
+        Some("if-different-inputs") => video::OverwritePolicy::IfDifferentInputs,
+        Some(v) => panic!("OVERWRITE_POLICY must be always, never or if-different-inputs: {:}", v),
+    };
+    let deterministic = config.deterministic.unwrap_or_default();
+    let metadata_stamp = match (config.strip_metadata.unwrap_or_default(), config.set_creation_time.clone()) {
+        (true, Some(_)) => panic!("STRIP_METADATA and SET_CREATION_TIME can't be used together"),
+        (true, None) => video::MetadataStamp::Strip,
+        (false, Some(creation_time)) if creation_time.is_empty() => panic!("SET_CREATION_TIME must not be empty"),
+        (false, Some(creation_time)) => video::MetadataStamp::CreationTime(creation_time),
+        (false, None) => video::MetadataStamp::Keep,
+    };
+    let fail_below_vmaf = config.fail_below_vmaf.unwrap_or_default();
+    let strict_inputs = config.strict_inputs.unwrap_or_default();
+    let require_all_inputs = config.require_all_inputs.unwrap_or_default();
+    let keep_passlog = config.keep_passlog.unwrap_or_default();
+    let skip_output_validation = !config.validate_output.unwrap_or(true);
+    let timeline = config.timeline_offsets.clone().map(|timeline_offsets| video::TimelineSpec { offsets: parse_timeline_offsets(&timeline_offsets) });
+    let thumbnail_sheet = config.thumbnail_sheet.clone().map(|thumbnail_sheet| {
+        let (cols, rows) = match thumbnail_sheet.split_once('x').map(|(cols, rows)| (cols.parse::<u32>(), rows.parse::<u32>())) {
+            Some((Ok(cols), Ok(rows))) if 0 < cols && 0 < rows => (cols, rows),
+            _ => panic!("THUMBNAIL_SHEET must be COLSxROWS: {:}", thumbnail_sheet),
+        };
+        let width = config.thumbnail_width.unwrap_or(320);
+        if width == 0 || !width.is_multiple_of(2) {
+            panic!("THUMBNAIL_WIDTH must be a positive even number: {:}", width);
+        }
+        video::ThumbnailSheetSpec { rows, cols, width }
+    });
+    let preview_sprite = config.preview_sprite.clone().map(|preview_sprite| {
+        let (cols, rows) = match preview_sprite.split_once('x').map(|(cols, rows)| (cols.parse::<u32>(), rows.parse::<u32>())) {
+            Some((Ok(cols), Ok(rows))) if 0 < cols && 0 < rows => (cols, rows),
+            _ => panic!("PREVIEW_SPRITE must be COLSxROWS: {:}", preview_sprite),
+        };
+        let interval = config.preview_sprite_interval_secs.unwrap_or(2.0);
+        if interval <= 0.0 {
+            panic!("PREVIEW_SPRITE_INTERVAL_SECS must be positive: {:}", interval);
+        }
+        let width = config.preview_sprite_width.unwrap_or(160);
+        if width == 0 || !width.is_multiple_of(2) {
+            panic!("PREVIEW_SPRITE_WIDTH must be a positive even number: {:}", width);
+        }
+        video::SpriteSpec { interval, rows, cols, width }
+    });
+    let emit_audio_sidecar = config.emit_audio_sidecar.unwrap_or_default();
+    if emit_audio_sidecar && audio_policy == video::AudioPolicy::DropAlways {
+        panic!("EMIT_AUDIO_SIDECAR can't be used with AUDIO_POLICY=drop-always, there's no audio");
+    }
+    let fade_in = config.fade_in_secs;
+    let fade_out = config.fade_out_secs;
+    for (name, fade) in [("FADE_IN_SECS", fade_in), ("FADE_OUT_SECS", fade_out)] {
+        if let Some(fade) = fade {
+            if fade <= 0.0 {
+                panic!("{:} must be positive: {:}", name, fade);
+            }
+        }
+    }
+    // json lines for monitors, the writer thread ends when every sender is dropped
+    let (progress, progress_writer) = match config.progress_file.clone() {
+        Some(progress_file) => {
+            let file = open_progress_file(Path::new(&progress_file));
+            let (sender, receiver) = mpsc::channel();
+            (Some(sender), Some(thread::spawn(move || write_progress_lines(receiver, file))))
+        },
+        None => (None, None),
+    };
+    let speeds = config.speeds.clone().map(|speeds| parse_speeds(&speeds));
+    let audio_delays_ms = config.audio_delays_ms.clone().map(|audio_delays_ms| parse_audio_delays_ms(&audio_delays_ms));
+    let dedupe_consecutive = config.dedupe_consecutive.unwrap_or_default();
+    if dedupe_consecutive && timeline.is_some() {
+        panic!("DEDUPE_CONSECUTIVE can't be used with TIMELINE_OFFSETS, each offset is for one input");
+    }
+    let memory_limit_mb = config.memory_limit_mb;
+    let max_total_duration = config.max_total_duration_secs;
+    if let Some(max_total_duration) = max_total_duration {
+        if max_total_duration <= 0.0 {
+            panic!("MAX_TOTAL_DURATION_SECS must be positive: {:}", max_total_duration);
+        }
+    }
+    let allow_passthrough = config.allow_passthrough.unwrap_or_default();
+    let concat_unsafe = config.concat_unsafe.unwrap_or_default();
+    let tune = config.svtav1_tune;
+    // validated by the encode, e.g. 5.1 and main
+    let av1_level = config.svtav1_level.clone();
+    let av1_tier = config.svtav1_tier.clone();
+    let ab_av1_retries = config.ab_av1_retries.unwrap_or(2);
+    let genpts = config.genpts.unwrap_or_default();
+    let align_audio_to_video = config.align_audio_to_video.unwrap_or_default();
+    let chapters = config.chapters.unwrap_or_default();
+    let auto_encode = config.auto_encode.unwrap_or_default();
+    // edge trims are heuristics, off unless asked for
+    let trim_black = if config.trim_black.unwrap_or_default() {
+        let pixel_threshold = config.trim_black_pixel_threshold.unwrap_or(0.1);
+        if !(0.0..=1.0).contains(&pixel_threshold) {
+            panic!("TRIM_BLACK_PIXEL_THRESHOLD must be in 0.0..=1.0: {:}", pixel_threshold);
+        }
+        let min_duration = config.trim_black_min_duration_secs.unwrap_or(0.1);
+        if min_duration <= 0.0 {
+            panic!("TRIM_BLACK_MIN_DURATION_SECS must be positive: {:}", min_duration);
+        }
+        Some(video::BlackTrimSpec { pixel_threshold, min_duration })
+    } else {
+        None
+    };
+    let trim_silence = if config.trim_silence.unwrap_or_default() {
+        let noise_db = config.trim_silence_noise_db.unwrap_or(-50.0);
+        if 0.0 < noise_db {
+            panic!("TRIM_SILENCE_NOISE_DB must not be positive: {:}", noise_db);
+        }
+        let min_duration = config.trim_silence_min_duration_secs.unwrap_or(0.1);
+        if min_duration <= 0.0 {
+            panic!("TRIM_SILENCE_MIN_DURATION_SECS must be positive: {:}", min_duration);
+        }
+        Some(video::SilenceTrimSpec { noise_db, min_duration })
+    } else {
+        None
+    };
+    let output_range = config.output_range.clone().map(|output_range| parse_output_range(&output_range));
+    let fixed_crf = config.fixed_crf;
+    if let Some(fixed_crf) = fixed_crf {
+        if video::MAX_CRF < fixed_crf {
+            panic!("FIXED_CRF must be in 0..={:}: {:}", video::MAX_CRF, fixed_crf);
+        }
+    }
+    let renditions = match config.renditions.clone() {
+        Some(renditions) => parse_renditions(&renditions),
+        None => Vec::new(),
+    };
+    let target_resolution_policy = match config.target_resolution.as_deref() {
+        None | Some("max") => video::TargetResolutionPolicy::Max,
+        Some("min") => video::TargetResolutionPolicy::Min,
+        Some("median") => video::TargetResolutionPolicy::Median,
+        Some(v) => match v.split_once('x').map(|(width, height)| (width.parse::<i64>(), height.parse::<i64>())) {
+            Some((Ok(width), Ok(height))) if 0 < width && 0 < height && width % 2 == 0 && height % 2 == 0 => video::TargetResolutionPolicy::Fixed(width, height),
+            _ => panic!("TARGET_RESOLUTION must be max, min, median or even WIDTHxHEIGHT: {:}", v),
+        },
+    };
+    let aspect_ratio = config.aspect_ratio.clone().map(|aspect_ratio| match aspect_ratio.split_once(':').map(|(width, height)| (width.parse::<u32>(), height.parse::<u32>())) {
+        Some((Ok(width), Ok(height))) if 0 < width && 0 < height => (width, height),
+        _ => panic!("ASPECT_RATIO must be WIDTH:HEIGHT: {:}", aspect_ratio),
+    });
+    let opus_application = match config.opus_application.as_deref() {
+        None | Some("audio") => video::OpusApplication::Audio,
+        Some("voip") => video::OpusApplication::Voip,
+        Some("lowdelay") => video::OpusApplication::LowDelay,
+        Some(v) => panic!("OPUS_APPLICATION must be voip, audio or lowdelay: {:}", v),
+    };
+    let denoise = config.denoise.clone().map(|denoise| {
+        let (filter, default_strength) = match denoise.as_str() {
+            "hqdn3d" => (video::DenoiseFilter::Hqdn3d, 4.0),
+            "nlmeans" => (video::DenoiseFilter::Nlmeans, 1.0),
+            v => panic!("DENOISE must be hqdn3d or nlmeans: {:}", v),
+        };
+        let strength = config.denoise_strength.unwrap_or(default_strength);
+        if strength <= 0.0 {
+            panic!("DENOISE_STRENGTH must be positive: {:}", strength);
+        }
+        video::DenoiseSpec { filter, strength }
+    });
+    // faststart for stored outputs played from the start, fragmented for streamed ones
+    let mp4_mode = match config.mp4_mode.as_deref() {
+        None | Some("regular") => video::Mp4Mode::Regular,
+        Some("faststart") => video::Mp4Mode::Faststart,
+        Some("fragmented") => video::Mp4Mode::Fragmented,
+        Some(v) => panic!("MP4_MODE must be regular, faststart or fragmented: {:}", v),
+    };
+    // overrides the container the output object id extension implies
+    let output_container = match config.output_container.as_deref() {
+        None => None,
+        Some("mp4") => Some(video::OutputContainer::Mp4),
+        Some("mov") => Some(video::OutputContainer::Mov),
+        Some("mkv") => Some(video::OutputContainer::Mkv),
+        Some("webm") => Some(video::OutputContainer::Webm),
+        Some(v) => panic!("OUTPUT_CONTAINER must be mp4, mov, mkv or webm: {:}", v),
+    };
+    // the leading bytes of each bucket input are probed first, an input over the limits fails before its full download
+    let header_check = config.probe_header_bytes.map(|bytes| {
+        if bytes == 0 {
+            panic!("PROBE_HEADER_BYTES must be positive");
+        }
+        let max_pixels = config.max_input_pixels;
+        if max_pixels.is_some_and(|max_pixels| max_pixels <= 0) {
+            panic!("MAX_INPUT_PIXELS must be positive: {:?}", max_pixels);
+        }
+        HeaderCheck { bytes, max_duration: max_total_duration, max_pixels }
+    });
+    if header_check.is_none() && config.max_input_pixels.is_some() {
+        panic!("MAX_INPUT_PIXELS needs PROBE_HEADER_BYTES");
+    }
+    let pixel_format = config.pixel_format.clone().map(|v| parse_pixel_format("PIXEL_FORMAT", &v)).unwrap_or_default();
+    let search_pixel_format = config.search_pixel_format.clone().map(|v| parse_pixel_format("SEARCH_PIXEL_FORMAT", &v));
+    let best_input_metric = match config.best_input_metric.as_deref() {
+        None | Some("pixels") => video::BestInputMetric::Pixels,
+        Some("bitrate") => video::BestInputMetric::Bitrate,
+        Some("duration") => video::BestInputMetric::Duration,
+        Some(v) => panic!("BEST_INPUT_METRIC must be pixels, bitrate or duration: {:}", v),
+    };
+
+    // held until main returns, the os releases it on any exit
+    let _job_slot = config.max_concurrent_jobs.map(|max_concurrent_jobs| {
+        if max_concurrent_jobs == 0 {
+            panic!("MAX_CONCURRENT_JOBS must be positive");
+        }
+        acquire_job_slot(Path::new("data"), max_concurrent_jobs, config.job_slot_fail_fast.unwrap_or_default())
+    });
 
     let mut args = env::args().skip(1);
 
     let Some(output_object_id) = args.next() else {
         panic!("No output gcs object id given");
     };
-    let output_object_path = Path::new("output").join(&output_object_id);
+    let output_object_path = get_local_object_path("output", &output_object_id);
+
+    let args = args.collect::<Vec<_>>();
+    let client_config = create_client_config(config.gcs_key_path.clone()).await;
+    let client = Client::new(client_config);
+
+    // a prefix ending with "/" stands for all objects under it in lexical order
+    let mut object_ids = Vec::new();
+    if let Some(input_prefix) = config.input_prefix.clone() {
+        object_ids.extend(list_object_ids(&client, input_bucket.clone(), input_prefix).await);
+    }
+    for arg in args {
+        if is_http_url(&arg) {
+            object_ids.push(arg);
+        } else if arg.ends_with('/') {
+            object_ids.extend(list_object_ids(&client, input_bucket.clone(), arg).await);
+        } else {
+            object_ids.push(arg);
+        }
+    }
+    if let Some(exclude) = config.exclude.clone() {
+        let exclude_patterns = exclude.split(',').map(|pattern| pattern.trim()).filter(|pattern| !pattern.is_empty()).collect::<Vec<_>>();
+        object_ids = exclude_object_ids(object_ids, &exclude_patterns);
+    }
+    // a url signature is a credential, so only the part before the query goes in
+    let source_ids = if config.embed_source_ids.unwrap_or_default() {
+        object_ids.iter().map(|object_id| object_id.split(['?', '#']).next().unwrap_or_default().to_string()).collect()
+    } else {
+        Vec::new()
+    };
+
+    let reference_video_path = match config.reference_object.clone() {
+        Some(reference_object_id) => {
+            let reference_object_path = get_local_object_path("data", &reference_object_id);
+            download_object(&client, input_bucket.clone(), reference_object_id, &reference_object_path).await;
+            Some(reference_object_path)
+        },
+        None => None,
+    };
+    let watermark = match config.watermark_object.clone() {
+        Some(watermark_object_id) => {
+            let position = match config.watermark_position.as_deref() {
+                None | Some("bottom-right") => video::WatermarkPosition::BottomRight,
+                Some("bottom-left") => video::WatermarkPosition::BottomLeft,
+                Some("top-right") => video::WatermarkPosition::TopRight,
+                Some("top-left") => video::WatermarkPosition::TopLeft,
+                Some(v) => panic!("WATERMARK_POSITION must be top-left, top-right, bottom-left or bottom-right: {:}", v),
+            };
+            let opacity = config.watermark_opacity.unwrap_or(1.0);
+            if !(0.0..=1.0).contains(&opacity) {
+                panic!("WATERMARK_OPACITY must be in 0.0..=1.0: {:}", opacity);
+            }
+            let watermark_path = get_local_object_path("data", &watermark_object_id);
+            download_object(&client, input_bucket.clone(), watermark_object_id, &watermark_path).await;
+            Some(video::WatermarkSpec { path: watermark_path, position, opacity })
+        },
+        None => None,
+    };
+    let captions_path = match config.captions_object.clone() {
+        Some(captions_object_id) => {
+            let captions_path = get_local_object_path("data", &captions_object_id);
+            download_object(&client, input_bucket.clone(), captions_object_id, &captions_path).await;
+            Some(captions_path)
+        },
+        None => None,
+    };
+    let options = video::EncodeOptions { orientation_policy, deterministic, reference_video_path, best_input_metric, fixed_crf, renditions, fail_below_vmaf, strict_inputs, memory_limit_mb, allow_passthrough, output_range, watermark, require_all_inputs, keep_passlog, skip_output_validation, timeline, target_resolution_policy, opus_application, max_total_duration, pixel_format, search_pixel_format, concat_unsafe, genpts, tune, captions_path, ab_av1_retries, measure_fallback_vmaf, hard_floor_vmaf, align_audio_to_video, chapters, auto_encode, trim_black, trim_silence, aspect_ratio, cancel: None, dedupe_consecutive, thumbnail_sheet, preview_sprite, emit_audio_sidecar, fade_in, fade_out, prefer_smaller, prefer_smaller_margin, strict_audio, metadata_stamp, crf_search_timeout, mp4_mode, denoise, speeds, progress: progress.clone(), audio_policy, av1_level, av1_tier, output_container, measure_signalstats, source_ids, verify_prediction, verify_prediction_max_delta, audio_delays_ms, overwrite_policy };
+
+    // writing back into the input bucket must not replace a source, checked before the long encode
+    let allow_overwrite_input = config.allow_overwrite_input.unwrap_or_default();
+    let input_object_ids = object_ids.iter().filter(|object_id| !is_http_url(object_id)).cloned()
+        .chain([&config.reference_object, &config.watermark_object, &config.captions_object].into_iter().flatten().cloned())
+        .collect::<Vec<_>>();
+    if !allow_overwrite_input {
+        check_input_overwrite(&input_bucket, &input_object_ids, &output_buckets, &get_output_object_ids(&output_object_id, &options));
+    }
+
+    send_progress(&progress, video::ProgressEvent::new(video::ProgressPhase::Download, 0.0, None));
+    // a preempted job run again with the same inputs and output picks up where it stopped
+    let mut job_state = config.resume.unwrap_or_default().then(|| {
+        let job_key = get_job_key(&input_bucket, &object_ids, &output_object_id);
+        load_job_state(&get_job_state_path(Path::new(JOB_STATE_DIR), &job_key), &job_key)
+    });
+    let object_paths = download_objects(&client, input_bucket.clone(), object_ids, job_state.as_mut(), header_check.as_ref()).await;
+    send_progress(&progress, video::ProgressEvent::new(video::ProgressPhase::Download, 100.0, None));
+
+    // inspect the inputs before committing to an encode
+    if config.analyze_only.unwrap_or_default() {
+        let analysis = video::analyze_inputs(&object_paths);
+        println!("{:}", serde_json::to_string_pretty(&analysis).unwrap());
+        return;
+    }
+
+    let resumed_report = job_state.as_ref().and_then(|job_state| job_state.report.clone()).filter(|report| is_report_output_valid(&output_object_path, report));
+    let report = if let Some(report) = resumed_report {
+        log::info!("Encode already done, skip to upload: {:}", output_object_path.display());
+        report
+    } else {
+        // an output the overwrite policy keeps is no partial output, a signal must leave it
+        let partial_output_paths = if video::will_write_output(&object_paths, &output_object_path, options.overwrite_policy) {
+            std::iter::once(output_object_path.clone())
+                .chain(options.renditions.iter().map(|rendition| video::get_rendition_path(&output_object_path, rendition.height)))
+                .chain(options.thumbnail_sheet.iter().map(|_| video::get_thumbnail_sheet_path(&output_object_path)))
+                .chain(options.emit_audio_sidecar.then(|| video::get_audio_sidecar_path(&output_object_path)))
+                .chain(options.preview_sprite.iter().map(|_| video::get_preview_vtt_path(&output_object_path)))
+                .collect::<Vec<_>>()
+        } else {
+            vec![]
+        };
+        let is_encode_done = Arc::new(AtomicBool::new(false));
+        tokio::spawn(shutdown_on_signal(partial_output_paths, is_encode_done.clone()));
+
+        let report = match video::encode_best_effort_async(object_paths, output_object_path.clone(), enough_vmaf, min_crf, options).await {
+            Err(err) => panic!("Encode Failed: {:}", err),
+            Ok(report) => report,
+        };
+        // the outputs are complete now, a signal during the upload must keep them
+        is_encode_done.store(true, Ordering::SeqCst);
+        if let Some(job_state) = job_state.as_mut() {
+            job_state.report = Some(report.clone());
+            save_job_state(job_state);
+        }
+        report
+    };
+    log::info!("Encode report: {:?}", report);
+    send_progress(&progress, video::ProgressEvent { crf: report.crf, vmaf: report.predicted_vmaf, ..video::ProgressEvent::new(video::ProgressPhase::Upload, 0.0, Some(&output_object_path)) });
+
+    for rendition in &report.renditions {
+        let rendition_object_id = video::get_rendition_path(&output_object_id, rendition.height).to_string_lossy().to_string();
+        let results = upload_to_buckets(&output_buckets, |bucket| upload_object(&client, bucket, rendition_object_id.clone(), &rendition.path, &output_metadata, upload_chunk_size)).await;
+        check_upload_results(&rendition_object_id, &results, upload_fail_on_any);
+    }
+
+    if let Some(audio_sidecar_path) = &report.audio_sidecar_path {
+        let audio_sidecar_object_id = video::get_audio_sidecar_path(&output_object_id).to_string_lossy().to_string();
+        let results = upload_to_buckets(&output_buckets, |bucket| upload_object(&client, bucket, audio_sidecar_object_id.clone(), audio_sidecar_path, &output_metadata, upload_chunk_size)).await;
+        check_upload_results(&audio_sidecar_object_id, &results, upload_fail_on_any);
+    }
+
+    // the sprites before the vtt, so a player never sees cues for missing images
+    for (index, preview_sprite_path) in report.preview_sprite_paths.iter().enumerate() {
+        let preview_sprite_object_id = video::get_preview_sprite_path(&output_object_id, index).to_string_lossy().to_string();
+        // their count is only known after the encode
+        if !allow_overwrite_input {
+            check_input_overwrite(&input_bucket, &input_object_ids, &output_buckets, std::slice::from_ref(&preview_sprite_object_id));
+        }
+        let results = upload_to_buckets(&output_buckets, |bucket| upload_object(&client, bucket, preview_sprite_object_id.clone(), preview_sprite_path, &output_metadata, upload_chunk_size)).await;
+        check_upload_results(&preview_sprite_object_id, &results, upload_fail_on_any);
+    }
+    if let Some(preview_vtt_path) = &report.preview_vtt_path {
+        let preview_vtt_object_id = video::get_preview_vtt_path(&output_object_id).to_string_lossy().to_string();
+        let results = upload_to_buckets(&output_buckets, |bucket| upload_object(&client, bucket, preview_vtt_object_id.clone(), preview_vtt_path, &output_metadata, upload_chunk_size)).await;
+        check_upload_results(&preview_vtt_object_id, &results, upload_fail_on_any);
+    }
+
+    if let Some(thumbnail_sheet_path) = &report.thumbnail_sheet_path {
+        let thumbnail_sheet_object_id = video::get_thumbnail_sheet_path(&output_object_id).to_string_lossy().to_string();
+        let results = upload_to_buckets(&output_buckets, |bucket| upload_object(&client, bucket, thumbnail_sheet_object_id.clone(), thumbnail_sheet_path, &output_metadata, upload_chunk_size)).await;
+        check_upload_results(&thumbnail_sheet_object_id, &results, upload_fail_on_any);
+    }
+
+    let results = upload_to_buckets(&output_buckets, |bucket| upload_object(&client, bucket, output_object_id.clone(), &output_object_path, &output_metadata, upload_chunk_size)).await;
+    check_upload_results(&output_object_id, &results, upload_fail_on_any);
+
+    let uploaded_bucket_count = results.iter().filter(|(_, result)| result.is_ok()).count();
+    log::info!("{:} output={:} uploaded_buckets={:}", video::get_summary_line(&report), output_object_id, uploaded_bucket_count);
+    send_progress(&progress, video::ProgressEvent { crf: report.crf, vmaf: report.predicted_vmaf, ..video::ProgressEvent::new(video::ProgressPhase::Upload, 100.0, Some(&output_object_path)) });
+
+    // done, a later run of the same job starts over
+    if let Some(job_state) = &job_state {
+        if let Err(err) = std::fs::remove_file(&job_state.path) {
+            log::warn!("Couldn't remove the job state: {:} ({:})", job_state.path.display(), err);
+        }
+    }
+
+    // the last records are written before exiting
+    drop(progress);
+    if let Some(progress_writer) = progress_writer {
+        let _ = progress_writer.join();
+    }
+}
+
+fn send_progress(progress: &Option<mpsc::Sender<video::ProgressEvent>>, event: video::ProgressEvent) {
+    if let Some(progress) = progress {
+        let _ = progress.send(event);
+    }
+}
+
+// the encode runs on the blocking pool, so this still runs while ffmpeg or ab-av1 is running
+// the handler stays installed after the encode, so it still exits but only removes outputs that are partial
+async fn shutdown_on_signal(partial_output_paths: Vec<PathBuf>, is_encode_done: Arc<AtomicBool>) {
+    let (Ok(mut sigterm), Ok(mut sigint)) = (signal(SignalKind::terminate()), signal(SignalKind::interrupt())) else {
+        log::warn!("Couldn't install signal handlers, children may be orphaned on shutdown");
+        return;
+    };
+    let signal_name = tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigint.recv() => "SIGINT",
+    };
+    log::warn!("{:} received, shutting down", signal_name);
+
+    video::kill_running_commands();
+    let partial_output_paths = if is_encode_done.load(Ordering::SeqCst) { vec![] } else { partial_output_paths };
+    for path in partial_output_paths {
+        if path.exists() {
+            match std::fs::remove_file(&path) {
+                Ok(()) => log::info!("Partial output removed: {:}", path.display()),
+                Err(err) => log::warn!("Couldn't remove a partial output: {:} ({:})", path.display(), err),
+            }
+        }
+    }
+    // 128 + signal number, as the shell reports a killed process
+    let exit_code = if signal_name == "SIGTERM" { 143 } else { 130 };
+    std::process::exit(exit_code);
+}
+
+// one flock'd file per slot in the scratch dir, shared by the jobs on the host
+fn try_acquire_job_slot(dir: &Path, max_concurrent_jobs: u32) -> Option<std::fs::File> {
+    if let Err(err) = std::fs::create_dir_all(dir) {
+        panic!("Couldn't create the directory: {:} ({:})", dir.display(), err);
+    }
+    for index in 0..max_concurrent_jobs {
+        let path = dir.join(format!(".job-slot-{:}.lock", index));
+        let file = match std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(&path) {
+            Ok(file) => file,
+            Err(err) => panic!("Couldn't open the job slot file: {:} ({:})", path.display(), err),
+        };
+        // released when the file is closed, also by the os when the process dies
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            log::info!("Job slot acquired: {:}", path.display());
+            return Some(file);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::WouldBlock {
+            panic!("Couldn't lock the job slot file: {:} ({:})", path.display(), err);
+        }
+    }
+    None
+}
+
+fn acquire_job_slot(dir: &Path, max_concurrent_jobs: u32, fail_fast: bool) -> std::fs::File {
+    let mut logged = false;
+    loop {
+        if let Some(file) = try_acquire_job_slot(dir, max_concurrent_jobs) {
+            return file;
+        }
+        if fail_fast {
+            panic!("No job slot free: MAX_CONCURRENT_JOBS={:}", max_concurrent_jobs);
+        }
+        if !logged {
+            log::info!("No job slot free, wait: MAX_CONCURRENT_JOBS={:}", max_concurrent_jobs);
+            logged = true;
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
+
+#[cfg(test)]
+mod test_acquire_job_slot {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let dir = Path::new(&root_path).join("output/test_acquire_job_slot-it_works");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let first = try_acquire_job_slot(&dir, 2);
+        let second = try_acquire_job_slot(&dir, 2);
+        assert!(first.is_some() && second.is_some());
+        assert!(try_acquire_job_slot(&dir, 2).is_none());
+        assert!(std::panic::catch_unwind(|| acquire_job_slot(&dir, 2, true)).is_err());
+
+        drop(first);
+        assert!(try_acquire_job_slot(&dir, 2).is_some());
+    }
+
+    #[test]
+    fn it_serializes_processes() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let dir = Path::new(&root_path).join("output/test_acquire_job_slot-it_serializes_processes");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let events_path = dir.join("events.txt");
+
+        // this test binary again, running only the ignored child below
+        let children = (0..2).map(|_| {
+            std::process::Command::new(env::current_exe().unwrap())
+                .args(["--exact", "test_acquire_job_slot::job_slot_child", "--ignored", "--test-threads", "1"])
+                .env("JOB_SLOT_CHILD_DIR", &dir)
+                .env("JOB_SLOT_CHILD_EVENTS", &events_path)
+                .stdout(std::process::Stdio::null())
+                .spawn()
+                .unwrap()
+        }).collect::<Vec<_>>();
+        for mut child in children {
+            assert!(child.wait().unwrap().success());
+        }
+
+        let events = std::fs::read_to_string(&events_path).unwrap();
+        assert_eq!(events.lines().collect::<Vec<_>>(), vec!["start", "end", "start", "end"]);
+    }
+
+    #[test]
+    #[ignore]
+    fn job_slot_child() {
+        let (Ok(dir), Ok(events_path)) = (env::var("JOB_SLOT_CHILD_DIR"), env::var("JOB_SLOT_CHILD_EVENTS")) else {
+            return;
+        };
+        let write_event = |event: &str| {
+            let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&events_path).unwrap();
+            writeln!(file, "{:}", event).unwrap();
+        };
+        let _job_slot = acquire_job_slot(Path::new(&dir), 1, false);
+        write_event("start");
+        std::thread::sleep(Duration::from_millis(300));
+        write_event("end");
+    }
+}
+
+// all but the preview sprites, whose count depends on the output duration
+fn get_output_object_ids(output_object_id: &str, options: &video::EncodeOptions) -> Vec<String> {
+    std::iter::once(PathBuf::from(output_object_id))
+        .chain(options.renditions.iter().map(|rendition| video::get_rendition_path(output_object_id, rendition.height)))
+        .chain(options.thumbnail_sheet.iter().map(|_| video::get_thumbnail_sheet_path(output_object_id)))
+        .chain(options.emit_audio_sidecar.then(|| video::get_audio_sidecar_path(output_object_id)))
+        .chain(options.preview_sprite.iter().map(|_| video::get_preview_vtt_path(output_object_id)))
+        .map(|path| path.to_string_lossy().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod test_get_output_object_ids {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_output_object_ids("out/a.mp4", &video::EncodeOptions::default()), vec!["out/a.mp4"]);
+        let options = video::EncodeOptions {
+            renditions: vec![video::Rendition { height: 720, crf: None }],
+            thumbnail_sheet: Some(video::ThumbnailSheetSpec { rows: 2, cols: 2, width: 160 }),
+            emit_audio_sidecar: true,
+            preview_sprite: Some(video::SpriteSpec { interval: 2.0, rows: 2, cols: 2, width: 160 }),
+            ..Default::default()
+        };
+        assert_eq!(get_output_object_ids("out/a.mp4", &options), vec!["out/a.mp4", "out/a-720p.mp4", "out/a-thumbnails.jpg", "out/a-audio.opus", "out/a-sprite.vtt"]);
+    }
+}
+
+// only object ids in the same bucket collide, urls are never written back
+fn get_colliding_object_ids(input_bucket: &str, input_object_ids: &[String], output_buckets: &[String], output_object_ids: &[String]) -> Vec<String> {
+    if !output_buckets.iter().any(|output_bucket| output_bucket == input_bucket) {
+        return vec![];
+    }
+    output_object_ids.iter().filter(|output_object_id| input_object_ids.contains(output_object_id)).cloned().collect()
+}
+
+#[cfg(test)]
+mod test_get_colliding_object_ids {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let strings = |values: &[&str]| values.iter().map(|value| value.to_string()).collect::<Vec<_>>();
+        let input_object_ids = strings(&["in/a.mp4", "in/b.mp4"]);
+        assert_eq!(get_colliding_object_ids("b0", &input_object_ids, &strings(&["b0"]), &strings(&["out/a.mp4"])), Vec::<String>::new());
+        assert_eq!(get_colliding_object_ids("b0", &input_object_ids, &strings(&["b0"]), &strings(&["in/b.mp4", "in/b-720p.mp4"])), strings(&["in/b.mp4"]));
+        assert_eq!(get_colliding_object_ids("b0", &input_object_ids, &strings(&["b1", "b0"]), &strings(&["in/a.mp4"])), strings(&["in/a.mp4"]));
+        // another bucket, the same name is a different object
+        assert_eq!(get_colliding_object_ids("b0", &input_object_ids, &strings(&["b1"]), &strings(&["in/a.mp4"])), Vec::<String>::new());
+        // exact names only, as gcs compares them
+        assert_eq!(get_colliding_object_ids("b0", &input_object_ids, &strings(&["b0"]), &strings(&["in//a.mp4", "/in/a.mp4"])), Vec::<String>::new());
+    }
+}
+
+fn check_input_overwrite(input_bucket: &str, input_object_ids: &[String], output_buckets: &[String], output_object_ids: &[String]) {
+    let colliding_object_ids = get_colliding_object_ids(input_bucket, input_object_ids, output_buckets, output_object_ids);
+    if !colliding_object_ids.is_empty() {
+        panic!("Output would overwrite input objects in bucket {:}: {:?} (set ALLOW_OVERWRITE_INPUT to allow)", input_bucket, colliding_object_ids);
+    }
+}
+
+#[cfg(test)]
+mod test_check_input_overwrite {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let input_object_ids = vec!["in/a.mp4".to_string()];
+        check_input_overwrite("b0", &input_object_ids, &["b0".to_string()], &["out/a.mp4".to_string()]);
+        assert!(std::panic::catch_unwind(|| check_input_overwrite("b0", &input_object_ids, &["b0".to_string()], &["in/a.mp4".to_string()])).is_err());
+    }
+}
+
+// logs of concurrent jobs interleave on stderr, so each job also writes its own file
+// the output id isn't validated yet, so it goes through the same check as the local objects
+fn get_log_file_path(log_file: Option<String>, output_object_id: Option<&str>) -> PathBuf {
+    match log_file {
+        Some(log_file) => PathBuf::from(log_file),
+        None => get_local_object_path("logs", &format!("{:}.log", output_object_id.unwrap_or("unknown"))),
+    }
+}
+
+#[cfg(test)]
+mod test_get_log_file_path {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_log_file_path(Some("job.log".to_string()), Some("a/b.mp4")), PathBuf::from("job.log"));
+        assert_eq!(get_log_file_path(None, Some("a/b.mp4")), PathBuf::from("logs/a/b.mp4.log"));
+        assert_eq!(get_log_file_path(None, None), PathBuf::from("logs/unknown.log"));
+        assert_eq!(get_log_file_path(None, Some("/a/b.mp4")), PathBuf::from("logs/a/b.mp4.log"));
+
+        for invalid in ["../a.mp4", "a/../../b.mp4", "/../etc/passwd"] {
+            assert!(std::panic::catch_unwind(|| get_log_file_path(None, Some(invalid))).is_err(), "{:}", invalid);
+        }
+    }
+}
+
+// LOG_LEVEL is a plain level, RUST_LOG may have per module directives, info when neither is set
+fn get_log_filter(log_level: Option<String>, rust_log: Option<String>) -> String {
+    if let Some(log_level) = log_level {
+        if log_level.parse::<log::LevelFilter>().is_err() {
+            panic!("LOG_LEVEL must be off, error, warn, info, debug or trace: {:}", log_level);
+        }
+        return log_level;
+    }
+    rust_log.filter(|rust_log| !rust_log.is_empty()).unwrap_or_else(|| "info".to_string())
+}
+
+#[cfg(test)]
+mod test_get_log_filter {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_log_filter(None, None), "info");
+        assert_eq!(get_log_filter(None, Some("".to_string())), "info");
+        assert_eq!(get_log_filter(None, Some("concat_video=trace".to_string())), "concat_video=trace");
+        assert_eq!(get_log_filter(Some("trace".to_string()), Some("warn".to_string())), "trace");
+        assert_eq!(get_log_filter(Some("DEBUG".to_string()), None), "DEBUG");
+        assert!(std::panic::catch_unwind(|| get_log_filter(Some("verbose".to_string()), None)).is_err());
+    }
+}
+
+fn init_logger(log_file_path: &Path, log_filter: &str) {
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters(log_filter);
+    if let Ok(write_style) = env::var("RUST_LOG_STYLE") {
+        builder.parse_write_style(&write_style);
+    }
+    let log_file = match log_file_path.parent() {
+        Some(parent) => std::fs::create_dir_all(parent),
+        None => Ok(()),
+    }.and_then(|_| std::fs::File::create(log_file_path));
+
+    match log_file {
+        Ok(file) => builder.target(Target::Pipe(Box::new(TeeWriter { file }))).init(),
+        Err(err) => {
+            builder.init();
+            log::warn!("Couldn't open a log file, log to stderr only: {:} ({:})", log_file_path.display(), err);
+        },
+    }
+}
+
+// same lines to stderr and the log file
+struct TeeWriter {
+    file: std::fs::File,
+}
+
+impl Write for TeeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stderr().write_all(buf)?;
+        self.file.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()?;
+        self.file.flush()
+    }
+}
+
+// appended, so a monitor can tail one file across retries of the job
+fn open_progress_file(path: &Path) -> std::fs::File {
+    if let Some(parent) = path.parent() {
+        if let Err(err) = std::fs::create_dir_all(parent) {
+            panic!("Couldn't create the directory: {:} ({:})", parent.display(), err);
+        }
+    }
+    match std::fs::OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => file,
+        Err(err) => panic!("Couldn't open PROGRESS_FILE: {:} ({:})", path.display(), err),
+    }
+}
+
+// one write per line, so a reader never sees half a record, a failed write doesn't stop the job
+fn write_progress_lines(receiver: mpsc::Receiver<video::ProgressEvent>, mut file: impl Write) {
+    for event in receiver {
+        let line = format!("{:}\n", video::get_progress_json(&event));
+        if let Err(err) = file.write_all(line.as_bytes()).and_then(|_| file.flush()) {
+            log::warn!("Couldn't write progress: {:}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_write_progress_lines {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let path = Path::new(&root_path).join("output/test_write_progress_lines/progress.jsonl");
+        let _ = std::fs::remove_file(&path);
+
+        // a run, then another appended after it
+        for _ in 0..2 {
+            let (sender, receiver) = mpsc::channel();
+            let writer = {
+                let file = open_progress_file(&path);
+                thread::spawn(move || write_progress_lines(receiver, file))
+            };
+            sender.send(video::ProgressEvent::new(video::ProgressPhase::Download, 0.0, None)).unwrap();
+            sender.send(video::ProgressEvent { crf: Some(30), vmaf: Some(95.0), ..video::ProgressEvent::new(video::ProgressPhase::CrfSearch, 100.0, Some(Path::new("data/a.mp4"))) }).unwrap();
+            sender.send(video::ProgressEvent::new(video::ProgressPhase::Upload, 100.0, Some(Path::new("data/out.mp4")))).unwrap();
+            drop(sender);
+            writer.join().unwrap();
+        }
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.ends_with('\n'));
+        let records = content.lines().map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()).collect::<Vec<_>>();
+        assert_eq!(records.len(), 6);
+        assert_eq!(records.iter().map(|record| record["phase"].as_str().unwrap()).collect::<Vec<_>>(), vec!["download", "crf-search", "upload", "download", "crf-search", "upload"]);
+        assert_eq!(records[1], serde_json::json!({ "phase": "crf-search", "percent": 100.0, "current_file": "data/a.mp4", "crf": 30, "vmaf": 95.0 }));
+    }
+}
+
+// an explicit service account key picks the account per job, otherwise application default credentials
+async fn create_client_config(key_path: Option<String>) -> ClientConfig {
+    let Some(key_path) = key_path else {
+        return match ClientConfig::default().with_auth().await {
+            Ok(config) => config,
+            Err(err) => panic!("Couldn't auth with application default credentials: {:}", err),
+        };
+    };
+    let credentials = match CredentialsFile::new_from_file(key_path.clone()).await {
+        Ok(credentials) => credentials,
+        Err(err) => panic!("Couldn't read a service account key: {:} ({:})", key_path, err),
+    };
+    match ClientConfig::default().with_credentials(credentials).await {
+        Ok(config) => config,
+        Err(err) => panic!("Couldn't auth with a service account key: {:} ({:})", key_path, err),
+    }
+}
+
+// "0,5,12.5" -> start seconds of each input on the timeline
+fn parse_timeline_offsets(s: &str) -> Vec<f64> {
+    s.split(',').map(|offset| match offset.trim().parse::<f64>() {
+        Ok(offset) if 0.0 <= offset && offset.is_finite() => offset,
+        _ => panic!("TIMELINE_OFFSETS must be non-negative seconds: {:}", s),
+    }).collect()
+}
+
+#[cfg(test)]
+mod test_parse_timeline_offsets {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(parse_timeline_offsets("0,5, 12.5"), vec![0.0, 5.0, 12.5]);
+        assert_eq!(parse_timeline_offsets("3"), vec![3.0]);
+
+        for invalid in ["", "0,,5", "a", "-1", "0,inf"] {
+            assert!(std::panic::catch_unwind(|| parse_timeline_offsets(invalid)).is_err());
+        }
+    }
+}
+
+// "1,2,0.5" -> playback speed of each input
+fn parse_speeds(s: &str) -> Vec<f64> {
+    s.split(',').map(|speed| match speed.trim().parse::<f64>() {
+        Ok(speed) if 0.0 < speed && speed.is_finite() => speed,
+        _ => panic!("SPEEDS must be positive numbers: {:}", s),
+    }).collect()
+}
 
-    let object_ids = args.collect::<Vec<_>>();
-    let config = ClientConfig::default().with_auth().await.expect("Couldn't auth");
-    let client = Client::new(config);
+#[cfg(test)]
+mod test_parse_speeds {
+    use super::*;
 
-    let object_paths = download_objects(&client, input_bucket, object_ids).await;
+    #[test]
+    fn it_works() {
+        assert_eq!(parse_speeds("1,2, 0.5"), vec![1.0, 2.0, 0.5]);
+        assert_eq!(parse_speeds("4"), vec![4.0]);
 
-    match video::encode_best_effort(object_paths, &output_object_path, enough_vmaf, min_crf) {
-        Err(err) => panic!("Encode Failed: {:}", err),
-        _ => (),
+        for invalid in ["", "1,,2", "a", "0", "-1", "inf"] {
+            assert!(std::panic::catch_unwind(|| parse_speeds(invalid)).is_err());
+        }
+    }
+}
+
+// "0,-120,40" -> ms the audio of each input is late
+fn parse_audio_delays_ms(s: &str) -> Vec<i64> {
+    s.split(',').map(|audio_delay_ms| match audio_delay_ms.trim().parse::<i64>() {
+        Ok(audio_delay_ms) => audio_delay_ms,
+        Err(_) => panic!("AUDIO_DELAYS_MS must be integers: {:}", s),
+    }).collect()
+}
+
+#[cfg(test)]
+mod test_parse_audio_delays_ms {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(parse_audio_delays_ms("0,-120, 40"), vec![0, -120, 40]);
+        assert_eq!(parse_audio_delays_ms("250"), vec![250]);
+
+        for invalid in ["", "0,,40", "a", "1.5"] {
+            assert!(std::panic::catch_unwind(|| parse_audio_delays_ms(invalid)).is_err());
+        }
+    }
+}
+
+fn parse_pixel_format(name: &str, s: &str) -> video::OutputPixelFormat {
+    match s {
+        "yuv420p10le" => video::OutputPixelFormat::Yuv420p10le,
+        "yuv420p" => video::OutputPixelFormat::Yuv420p,
+        "yuv444p10le" => video::OutputPixelFormat::Yuv444p10le,
+        "yuv444p" => video::OutputPixelFormat::Yuv444p,
+        _ => panic!("{:} must be yuv420p10le, yuv420p, yuv444p10le or yuv444p: {:}", name, s),
+    }
+}
+
+// "600:720" -> start and end seconds
+fn parse_output_range(s: &str) -> (f64, f64) {
+    let Some((start, end)) = s.split_once(':') else {
+        panic!("OUTPUT_RANGE must be start:end in seconds: {:}", s);
+    };
+    let parse = |v: &str| match v.trim().parse::<f64>() {
+        Ok(v) if v.is_finite() => v,
+        _ => panic!("OUTPUT_RANGE couldn't parse as seconds: {:}", s),
     };
+    let (start, end) = (parse(start), parse(end));
+    if !(0.0 <= start && start < end) {
+        panic!("OUTPUT_RANGE start must be non-negative and before end: {:}", s);
+    }
+    (start, end)
+}
+
+#[cfg(test)]
+mod test_parse_output_range {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(parse_output_range("600:720"), (600.0, 720.0));
+        assert_eq!(parse_output_range(" 0.5 : 1.5 "), (0.5, 1.5));
+
+        for invalid in ["", "600", "a:720", "600:b", "720:600", "600:600", "-1:5", "0:inf"] {
+            assert!(std::panic::catch_unwind(|| parse_output_range(invalid)).is_err());
+        }
+    }
+}
+
+// "source-job-id=42,rendition=hd" -> custom object metadata
+fn parse_output_metadata(s: &str) -> HashMap<String, String> {
+    s.split(',').map(|entry| {
+        let Some((key, value)) = entry.split_once('=') else {
+            panic!("OUTPUT_METADATA entry must be key=value: {:}", entry);
+        };
+        let (key, value) = (key.trim(), value.trim());
+        if key.is_empty() || value.is_empty() {
+            panic!("OUTPUT_METADATA key and value must not be empty: {:}", entry);
+        }
+        (key.to_string(), value.to_string())
+    }).collect()
+}
+
+#[cfg(test)]
+mod test_parse_output_metadata {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let metadata = parse_output_metadata("source-job-id=42, rendition = hd");
+        assert_eq!(metadata.len(), 2);
+        assert_eq!(metadata.get("source-job-id"), Some(&"42".to_string()));
+        assert_eq!(metadata.get("rendition"), Some(&"hd".to_string()));
+
+        assert_eq!(parse_output_metadata("a=b=c").get("a"), Some(&"b=c".to_string()));
+
+        for invalid in ["", "a", "=b", "a=", "a=b,,c=d"] {
+            assert!(std::panic::catch_unwind(|| parse_output_metadata(invalid)).is_err());
+        }
+    }
+}
+
+// "1080:30,720,480" -> height with optional crf each
+fn parse_renditions(s: &str) -> Vec<video::Rendition> {
+    s.split(',').map(|rendition| {
+        let (height, crf) = match rendition.split_once(':') {
+            Some((height, crf)) => (height, Some(crf)),
+            None => (rendition, None),
+        };
+        let Ok(height) = height.trim().parse::<i64>() else {
+            panic!("RENDITIONS height couldn't parse as an integer: {:}", rendition);
+        };
+        let crf = crf.map(|crf| match crf.trim().parse::<u8>() {
+            Ok(crf) => crf,
+            Err(err) => panic!("RENDITIONS crf couldn't parse as an 8bit unsigned int: {:} ({:})", rendition, err),
+        });
+        video::Rendition { height, crf }
+    }).collect()
+}
+
+async fn list_object_ids(client: &Client, bucket: String, prefix: String) -> Vec<String> {
+    let mut object_ids = Vec::new();
+    let mut page_token = None;
+    loop {
+        let response = match client.list_objects(&ListObjectsRequest {
+            bucket: bucket.clone(), prefix: Some(prefix.clone()), page_token,
+            ..Default::default()
+        }).await {
+            Ok(response) => response,
+            Err(err) => panic!("Couldn't list objects: {:} ({:})", prefix, err),
+        };
+        // skip "folder" placeholder objects
+        object_ids.extend(response.items.unwrap_or_default().into_iter().map(|object| object.name).filter(|name| !name.ends_with('/')));
+        page_token = response.next_page_token;
+        if page_token.is_none() {
+            break;
+        }
+    }
+    object_ids.sort();
+    log::info!("Objects listed: {:} ({:} objects)", prefix, object_ids.len());
+    object_ids
+}
+
+// patterns are object ids or globs, "*" and "?" also match "/"
+fn exclude_object_ids(object_ids: Vec<String>, exclude_patterns: &[&str]) -> Vec<String> {
+    object_ids.into_iter().filter(|object_id| {
+        let Some(pattern) = exclude_patterns.iter().find(|pattern| glob_match(pattern, object_id)) else {
+            return true;
+        };
+        log::info!("Object excluded: {:} (pattern={:})", object_id, pattern);
+        false
+    }).collect()
+}
+
+#[cfg(test)]
+mod test_exclude_object_ids {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let object_ids = ["clips/0.mp4", "clips/thumb.jpg", "clips/.DS_Store", "clips/1.mp4", "clips/bad.mp4"].map(String::from).to_vec();
+        assert_eq!(exclude_object_ids(object_ids.clone(), &[]), object_ids);
+        assert_eq!(exclude_object_ids(object_ids.clone(), &["*.jpg", "*/.DS_Store", "clips/bad.mp4"]), vec!["clips/0.mp4".to_string(), "clips/1.mp4".to_string()]);
+        assert_eq!(exclude_object_ids(object_ids.clone(), &["clips/?.mp4"]), vec!["clips/thumb.jpg".to_string(), "clips/.DS_Store".to_string(), "clips/bad.mp4".to_string()]);
+        assert_eq!(exclude_object_ids(object_ids.clone(), &["bad.mp4"]), object_ids);
+    }
+}
 
-    upload_object(&client, output_bucket, output_object_id, output_object_path).await
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let s = s.chars().collect::<Vec<_>>();
+    let (mut p, mut i) = (0, 0);
+    // position of the last "*" and the char it's matched up to, for backtracking
+    let mut backtrack = None;
+    while i < s.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == s[i]) {
+            p += 1;
+            i += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, i));
+            p += 1;
+        } else if let Some((star_p, star_i)) = backtrack {
+            backtrack = Some((star_p, star_i + 1));
+            p = star_p + 1;
+            i = star_i + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
 }
 
-async fn download_objects(client: &Client, bucket: String, object_ids: Vec<String>) -> Vec<PathBuf> {
+#[cfg(test)]
+mod test_glob_match {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let test_cases = [
+            ("a.mp4", "a.mp4", true),
+            ("a.mp4", "b.mp4", false),
+            ("*", "", true),
+            ("*", "clips/a.mp4", true),
+            ("*.mp4", "clips/a.mp4", true),
+            ("*.mp4", "clips/a.mp4.jpg", false),
+            ("clips/*/a.mp4", "clips/x/y/a.mp4", true),
+            ("?.mp4", "a.mp4", true),
+            ("?.mp4", "ab.mp4", false),
+            ("a*b*c", "aXbYbZc", true),
+            ("a*b*c", "aXbYc d", false),
+            ("", "", true),
+            ("", "a", false),
+        ];
+        for (pattern, s, expected) in test_cases {
+            assert_eq!(glob_match(pattern, s), expected, "{:} {:}", pattern, s);
+        }
+    }
+}
+
+// object ids come from outside, a leading slash would replace the dir in the join and a ".." would climb out of it
+fn get_local_object_path(dir: &str, object_id: &str) -> PathBuf {
+    let relative_path = Path::new(object_id.trim_start_matches('/'));
+    let is_inside = relative_path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if relative_path.as_os_str().is_empty() || !is_inside {
+        panic!("Object id must be a path inside {:}/, without \"..\": {:}", dir, object_id);
+    }
+    Path::new(dir).join(relative_path)
+}
+
+#[cfg(test)]
+mod test_get_local_object_path {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_local_object_path("output", "a.mp4"), PathBuf::from("output/a.mp4"));
+        assert_eq!(get_local_object_path("output", "a/b.mp4"), PathBuf::from("output/a/b.mp4"));
+        assert_eq!(get_local_object_path("data", "/a/b.mp4"), PathBuf::from("data/a/b.mp4"));
+        assert_eq!(get_local_object_path("data", "//a/./b.mp4"), PathBuf::from("data/a/b.mp4"));
+        assert_eq!(get_local_object_path("data", "a..b.mp4"), PathBuf::from("data/a..b.mp4"));
+
+        for invalid in ["", "/", "..", "../a.mp4", "a/../../b.mp4", "/../etc/passwd", "a/.."] {
+            assert!(std::panic::catch_unwind(|| get_local_object_path("data", invalid)).is_err(), "{:}", invalid);
+        }
+    }
+}
+
+// signed urls from other systems can be mixed with object ids
+fn is_http_url(s: &str) -> bool {
+    s.starts_with("https://") || s.starts_with("http://")
+}
+
+#[cfg(test)]
+mod test_is_http_url {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert!(is_http_url("https://storage.googleapis.com/b/a.mp4?X-Goog-Signature=0"));
+        assert!(is_http_url("http://example.com/a.mp4"));
+        assert!(!is_http_url("a.mp4"));
+        assert!(!is_http_url("https/a.mp4"));
+        assert!(!is_http_url("ftp://example.com/a.mp4"));
+    }
+}
+
+// host and path without the query, which holds the signature and changes on every request
+fn get_url_local_object_id(url: &str) -> String {
+    let without_scheme = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let without_query = without_scheme.split(['?', '#']).next().unwrap_or_default();
+    without_query.to_string()
+}
+
+#[cfg(test)]
+mod test_get_url_local_object_id {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_url_local_object_id("https://storage.googleapis.com/b/a.mp4?X-Goog-Signature=0&X-Goog-Expires=900"), "storage.googleapis.com/b/a.mp4");
+        assert_eq!(get_url_local_object_id("http://example.com/a.mp4#t=1"), "example.com/a.mp4");
+        assert_eq!(get_url_local_object_id("https://example.com/a%20b.mp4"), "example.com/a%20b.mp4");
+        assert_eq!(get_local_object_path("data", &get_url_local_object_id("https://example.com/v/a.mp4?sig=1")), PathBuf::from("data/example.com/v/a.mp4"));
+    }
+}
+
+async fn download_objects(client: &Client, bucket: String, object_ids: Vec<String>, mut job_state: Option<&mut JobState>, header_check: Option<&HeaderCheck>) -> Vec<PathBuf> {
     let mut object_paths = Vec::new();
     for object_id in object_ids.into_iter() {
-        let object_path = Path::new("data").join(&object_id);
-        download_object(&client, bucket.clone(), object_id, &object_path).await;
-        object_paths.push(object_path);
+        let object_path = if is_http_url(&object_id) {
+            get_local_object_path("data", &get_url_local_object_id(&object_id))
+        } else {
+            get_local_object_path("data", &object_id)
+        };
+        let downloaded = job_state.as_deref().is_some_and(|job_state| is_downloaded(job_state, &object_id, &object_path));
+        if downloaded {
+            log::info!("Already downloaded, skip: {:}", object_id);
+        } else if is_http_url(&object_id) {
+            download_url(&object_id, &object_path).await;
+        } else {
+            if let Some(header_check) = header_check {
+                check_object_header(client, &bucket, &object_id, &object_path, header_check).await;
+            }
+            download_object(client, bucket.clone(), object_id.clone(), &object_path).await;
+        }
+        // a bundle stands for the videos in it, in place of itself
+        match get_archive_kind(&object_path) {
+            Some(archive_kind) => {
+                let extract_dir_path = PathBuf::from(format!("{:}.d", object_path.display()));
+                if !downloaded {
+                    extract_archive(archive_kind, &object_path, &extract_dir_path);
+                }
+                let video_paths = list_video_paths(&extract_dir_path);
+                log::info!("Extracted {:} videos: {:}", video_paths.len(), object_path.display());
+                object_paths.extend(video_paths);
+            },
+            None => object_paths.push(object_path),
+        }
+        // after the extraction, so a recorded archive is a complete one
+        if let (false, Some(job_state)) = (downloaded, job_state.as_deref_mut()) {
+            job_state.downloaded_object_ids.push(object_id);
+            save_job_state(job_state);
+        }
     }
     object_paths
 }
 
+// limits checked on the leading bytes of each input, before the full download
+#[derive(Debug, Clone, PartialEq)]
+struct HeaderCheck {
+    bytes: u64,
+    max_duration: Option<f64>,
+    max_pixels: Option<i64>,
+}
+
+fn check_header_probe(header_probe: &video::HeaderProbe, header_check: &HeaderCheck) -> Result<(), String> {
+    if let Some(max_pixels) = header_check.max_pixels {
+        if max_pixels < header_probe.width * header_probe.height {
+            return Err(format!("{:}x{:} exceeds MAX_INPUT_PIXELS={:}", header_probe.width, header_probe.height, max_pixels));
+        }
+    }
+    if let (Some(max_duration), Some(duration)) = (header_check.max_duration, header_probe.duration) {
+        if max_duration < duration {
+            return Err(format!("{:}s exceeds MAX_TOTAL_DURATION_SECS={:}", duration, max_duration));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_check_header_probe {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let header_probe = video::HeaderProbe { width: 1920, height: 1080, duration: Some(60.0) };
+        let header_check = HeaderCheck { bytes: 1024, max_duration: None, max_pixels: None };
+        assert_eq!(check_header_probe(&header_probe, &header_check), Ok(()));
+        assert_eq!(check_header_probe(&header_probe, &HeaderCheck { max_pixels: Some(1920 * 1080), max_duration: Some(60.0), ..header_check.clone() }), Ok(()));
+        assert!(check_header_probe(&header_probe, &HeaderCheck { max_pixels: Some(1280 * 720), ..header_check.clone() }).is_err());
+        assert!(check_header_probe(&header_probe, &HeaderCheck { max_duration: Some(30.0), ..header_check.clone() }).is_err());
+
+        // an unknown duration isn't rejected
+        let header_probe = video::HeaderProbe { duration: None, ..header_probe };
+        assert_eq!(check_header_probe(&header_probe, &HeaderCheck { max_duration: Some(30.0), ..header_check }), Ok(()));
+    }
+}
+
+// panics on an input over the limits, falls back to the full download when the header alone doesn't probe
+async fn check_object_header(client: &Client, bucket: &str, object_id: &str, object_path: &Path, header_check: &HeaderCheck) {
+    // the videos of a bundle aren't at its head
+    if get_archive_kind(object_path).is_some() {
+        return;
+    }
+    let Ok(object_stream) = client.download_streamed_object(&GetObjectRequest {
+        bucket: bucket.to_string(), object: object_id.to_string(),
+        ..Default::default()
+    }, &Range(Some(0), Some(header_check.bytes - 1))).await else {
+        log::warn!("Couldn't get the header, download in full: {:}", object_id);
+        return;
+    };
+    let header_path = PathBuf::from(format!("{:}.header", object_path.display()));
+    write_object_stream(object_stream, &header_path, false, object_id).await;
+    let header_probe = video::probe_header(&header_path);
+    let _ = fs::remove_file(&header_path).await;
+    let Some(header_probe) = header_probe else {
+        log::info!("Header alone didn't probe, download in full: {:}", object_id);
+        return;
+    };
+    log::info!("Header probed: {:} {:}x{:} {:?}s", object_id, header_probe.width, header_probe.height, header_probe.duration);
+    if let Err(message) = check_header_probe(&header_probe, header_check) {
+        panic!("Input rejected by its header: {:} ({:})", object_id, message);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct JobState {
+    #[serde(skip)]
+    path: PathBuf,
+    // the inputs and the output, a state for another job is ignored
+    job_key: String,
+    downloaded_object_ids: Vec<String>,
+    // set once the encode completed
+    report: Option<video::EncodeReport>,
+}
+
+fn get_job_key(input_bucket: &str, object_ids: &[String], output_object_id: &str) -> String {
+    serde_json::json!([input_bucket, object_ids, output_object_id]).to_string()
+}
+
+// one file per job, so concurrent jobs sharing the dir don't overwrite each other's state
+fn get_job_state_path(dir: &Path, job_key: &str) -> PathBuf {
+    dir.join(format!(".job-state-{:}.json", video::get_fnv1a_hash(job_key)))
+}
+
+#[cfg(test)]
+mod test_get_job_state_path {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let object_ids = vec!["a.mp4".to_string()];
+        let path = get_job_state_path(Path::new("data"), &get_job_key("input", &object_ids, "out.mp4"));
+        assert_eq!(path.parent(), Some(Path::new("data")));
+        assert_eq!(path, get_job_state_path(Path::new("data"), &get_job_key("input", &object_ids, "out.mp4")));
+        assert_ne!(path, get_job_state_path(Path::new("data"), &get_job_key("input", &object_ids, "other.mp4")));
+        assert_ne!(path, get_job_state_path(Path::new("data"), &get_job_key("input", &["b.mp4".to_string()], "out.mp4")));
+    }
+}
+
+// a missing, broken or another job's state starts over
+fn load_job_state(path: &Path, job_key: &str) -> JobState {
+    let new_job_state = JobState { path: path.to_path_buf(), job_key: job_key.to_string(), downloaded_object_ids: Vec::new(), report: None };
+    let s = match std::fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return new_job_state,
+        Err(err) => panic!("Couldn't read the job state: {:} ({:})", path.display(), err),
+    };
+    let Ok(mut job_state) = serde_json::from_str::<JobState>(&s) else {
+        log::warn!("Job state broken, start over: {:}", path.display());
+        return new_job_state;
+    };
+    job_state.path = path.to_path_buf();
+    if job_state.job_key != job_key {
+        log::info!("Job state is for another job, start over: {:}", path.display());
+        return new_job_state;
+    }
+    log::info!("Resume job: {:} downloaded, encoded={:}", job_state.downloaded_object_ids.len(), job_state.report.is_some());
+    job_state
+}
+
+// renamed into place, so a preemption mid-write leaves the previous state
+fn save_job_state(job_state: &JobState) {
+    if let Some(parent) = job_state.path.parent() {
+        if let Err(err) = std::fs::create_dir_all(parent) {
+            panic!("Couldn't create the directory: {:} ({:})", parent.display(), err);
+        }
+    }
+    let temp_path = PathBuf::from(format!("{:}.tmp", job_state.path.display()));
+    if let Err(err) = std::fs::write(&temp_path, serde_json::to_string(job_state).unwrap()).and_then(|_| std::fs::rename(&temp_path, &job_state.path)) {
+        panic!("Couldn't write the job state: {:} ({:})", job_state.path.display(), err);
+    }
+}
+
+// recorded and still on the disk, a partial download isn't recorded yet
+fn is_downloaded(job_state: &JobState, object_id: &str, object_path: &Path) -> bool {
+    job_state.downloaded_object_ids.iter().any(|downloaded_object_id| downloaded_object_id == object_id) && object_path.is_file()
+}
+
+#[cfg(test)]
+mod test_job_state {
+    use super::*;
+
+    #[test]
+    fn it_resumes_partial_download() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let dir = Path::new(&root_path).join("output/test_job_state");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(".job-state.json");
+        let object_ids = vec!["a.mp4".to_string(), "b.mp4".to_string()];
+        let job_key = get_job_key("input", &object_ids, "out.mp4");
+
+        // preempted while b.mp4 was downloading
+        let mut job_state = load_job_state(&path, &job_key);
+        assert_eq!(job_state.downloaded_object_ids, Vec::<String>::new());
+        std::fs::write(dir.join("a.mp4"), b"a").unwrap();
+        job_state.downloaded_object_ids.push("a.mp4".to_string());
+        save_job_state(&job_state);
+        std::fs::write(dir.join("b.mp4"), b"partial").unwrap();
+
+        let job_state = load_job_state(&path, &job_key);
+        assert_eq!(job_state.downloaded_object_ids, vec!["a.mp4".to_string()]);
+        assert!(is_downloaded(&job_state, "a.mp4", &dir.join("a.mp4")));
+        assert!(!is_downloaded(&job_state, "b.mp4", &dir.join("b.mp4")));
+        assert_eq!(job_state.report, None);
+
+        // recorded but gone from the disk
+        std::fs::remove_file(dir.join("a.mp4")).unwrap();
+        assert!(!is_downloaded(&job_state, "a.mp4", &dir.join("a.mp4")));
+
+        // another job
+        let job_state = load_job_state(&path, &get_job_key("input", &object_ids, "other.mp4"));
+        assert_eq!(job_state.downloaded_object_ids, Vec::<String>::new());
+
+        std::fs::write(&path, "{").unwrap();
+        assert_eq!(load_job_state(&path, &job_key).downloaded_object_ids, Vec::<String>::new());
+    }
+
+    #[test]
+    fn it_keeps_report() {
+        let report = video::EncodeReport {
+            crf: Some(30), concatenated: true, input_count: 2, predicted_duration: 3.5, predicted_vmaf: Some(95.5), output_bytes: Some(1234),
+            renditions: vec![video::RenditionReport { height: 720, crf: 32, path: PathBuf::from("output/a-720p.mp4") }],
+            preview_sprite_paths: vec![PathBuf::from("output/a-sprite-000.jpg")], preview_vtt_path: Some(PathBuf::from("output/a-sprite.vtt")),
+            ..Default::default()
+        };
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let dir = Path::new(&root_path).join("output/test_job_state_report");
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = get_job_state_path(&dir, "key");
+        let job_state = JobState { path: path.clone(), job_key: "key".to_string(), downloaded_object_ids: vec!["a.mp4".to_string()], report: Some(report) };
+        save_job_state(&job_state);
+        assert_eq!(load_job_state(&path, "key"), job_state);
+
+        assert!(serde_json::from_str::<JobState>(r#"{ "job_key": "key" }"#).is_err());
+    }
+}
+
+// every file to upload is still there, and the output plays to its length
+fn is_report_output_valid(output_path: &Path, report: &video::EncodeReport) -> bool {
+    let output_paths = report.renditions.iter().map(|rendition| &rendition.path)
+        .chain(report.thumbnail_sheet_path.iter())
+        .chain(report.preview_sprite_paths.iter())
+        .chain(report.preview_vtt_path.iter())
+        .chain(report.audio_sidecar_path.iter());
+    if let Some(missing_path) = output_paths.into_iter().find(|path| !path.is_file()) {
+        log::warn!("Encoded file missing, encode again: {:}", missing_path.display());
+        return false;
+    }
+    if let Err(err) = video::validate_output(output_path, report.predicted_duration) {
+        log::warn!("Encoded output invalid, encode again: {:}", err);
+        return false;
+    }
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArchiveKind {
+    TarGz,
+    Zip,
+}
+
+fn get_archive_kind(path: &Path) -> Option<ArchiveKind> {
+    let name = path.file_name()?.to_str()?.to_lowercase();
+    if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(ArchiveKind::TarGz)
+    } else if name.ends_with(".zip") {
+        Some(ArchiveKind::Zip)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test_get_archive_kind {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let test_cases = [
+            ("data/a.tar.gz", Some(ArchiveKind::TarGz)),
+            ("data/a.TGZ", Some(ArchiveKind::TarGz)),
+            ("data/a.zip", Some(ArchiveKind::Zip)),
+            ("data/a.mp4", None),
+            ("data/a.tar", None),
+            ("data/a.gz", None),
+            ("data/zip", None),
+        ];
+        for (path, expected) in test_cases {
+            assert_eq!(get_archive_kind(Path::new(path)), expected, "{:}", path);
+        }
+    }
+}
+
+// in process, an archive with an entry escaping the directory is rejected as a whole
+fn extract_archive(archive_kind: ArchiveKind, archive_path: &Path, extract_dir_path: &Path) {
+    if let Err(err) = std::fs::create_dir_all(extract_dir_path) {
+        panic!("Couldn't create the directory: {:} ({:})", extract_dir_path.display(), err);
+    }
+    let file = match std::fs::File::open(archive_path) {
+        Ok(file) => file,
+        Err(err) => panic!("Couldn't open the archive: {:} ({:})", archive_path.display(), err),
+    };
+    let result = match archive_kind {
+        ArchiveKind::TarGz => extract_tar_gz(file, archive_path, extract_dir_path),
+        ArchiveKind::Zip => extract_zip(file, archive_path, extract_dir_path),
+    };
+    if let Err(err) = result {
+        panic!("Couldn't extract the archive: {:} ({:})", archive_path.display(), err);
+    }
+}
+
+fn extract_tar_gz(file: std::fs::File, archive_path: &Path, extract_dir_path: &Path) -> io::Result<()> {
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(file));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = get_archive_entry_path(archive_path, extract_dir_path, &entry.path()?);
+        match entry.header().entry_type() {
+            tar::EntryType::Directory => std::fs::create_dir_all(&entry_path)?,
+            tar::EntryType::Regular => {
+                create_parent_dir(&entry_path)?;
+                io::copy(&mut entry, &mut std::fs::File::create(&entry_path)?)?;
+            },
+            // links could point outside the directory
+            entry_type => log::warn!("Archive entry skipped, not a file: {:} {:?}", entry_path.display(), entry_type),
+        }
+    }
+    Ok(())
+}
+
+fn extract_zip(file: std::fs::File, archive_path: &Path, extract_dir_path: &Path) -> io::Result<()> {
+    let mut archive = zip::ZipArchive::new(file)?;
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        let entry_path = get_archive_entry_path(archive_path, extract_dir_path, Path::new(entry.name()));
+        if entry.is_dir() {
+            std::fs::create_dir_all(&entry_path)?;
+        } else {
+            create_parent_dir(&entry_path)?;
+            io::copy(&mut entry, &mut std::fs::File::create(&entry_path)?)?;
+        }
+    }
+    Ok(())
+}
+
+fn create_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) => std::fs::create_dir_all(parent),
+        None => Ok(()),
+    }
+}
+
+// unlike an object id, an absolute entry path isn't made relative, it's as suspicious as a ".."
+fn get_archive_entry_path(archive_path: &Path, extract_dir_path: &Path, entry_path: &Path) -> PathBuf {
+    let is_inside = entry_path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !is_inside {
+        panic!("Archive entry must be a path inside the archive, without \"..\": {:} {:}", archive_path.display(), entry_path.display());
+    }
+    extract_dir_path.join(entry_path)
+}
+
+#[cfg(test)]
+mod test_extract_archive {
+    use super::*;
+
+    fn write_tar_gz(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(std::fs::File::create(path).unwrap(), flate2::Compression::default()));
+        for (name, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            // set_path refuses a "..", the raw name bytes don't
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(data.len() as u64);
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    fn write_zip(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(path).unwrap());
+        for (name, data) in entries {
+            writer.start_file(*name, zip::write::FileOptions::default()).unwrap();
+            io::Write::write_all(&mut writer, data).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn it_works() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let dir = Path::new(&root_path).join("output/test_extract_archive");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let entries: &[(&str, &[u8])] = &[("clips/a.mp4", b"a"), ("b.mov", b"b")];
+
+        let archive_path = dir.join("a.tar.gz");
+        write_tar_gz(&archive_path, entries);
+        extract_archive(ArchiveKind::TarGz, &archive_path, &dir.join("a.tar.gz.d"));
+        assert_eq!(std::fs::read(dir.join("a.tar.gz.d/clips/a.mp4")).unwrap(), b"a");
+        assert_eq!(std::fs::read(dir.join("a.tar.gz.d/b.mov")).unwrap(), b"b");
+
+        let archive_path = dir.join("a.zip");
+        write_zip(&archive_path, entries);
+        extract_archive(ArchiveKind::Zip, &archive_path, &dir.join("a.zip.d"));
+        assert_eq!(std::fs::read(dir.join("a.zip.d/clips/a.mp4")).unwrap(), b"a");
+        assert_eq!(std::fs::read(dir.join("a.zip.d/b.mov")).unwrap(), b"b");
+
+        // escaping entries
+        for (index, name) in ["../evil.mp4", "clips/../../evil.mp4", "/tmp/evil.mp4"].into_iter().enumerate() {
+            let archive_path = dir.join(format!("evil-{:}.tar.gz", index));
+            write_tar_gz(&archive_path, &[(name, b"evil")]);
+            assert!(std::panic::catch_unwind(|| extract_archive(ArchiveKind::TarGz, &archive_path, &dir.join("evil.d/tar"))).is_err(), "{:}", name);
+            let archive_path = dir.join(format!("evil-{:}.zip", index));
+            write_zip(&archive_path, &[(name, b"evil")]);
+            assert!(std::panic::catch_unwind(|| extract_archive(ArchiveKind::Zip, &archive_path, &dir.join("evil.d/zip"))).is_err(), "{:}", name);
+        }
+        assert!(!dir.join("evil.mp4").exists());
+        assert!(!dir.join("evil.d/evil.mp4").exists());
+
+        // broken archive
+        let archive_path = dir.join("broken.tar.gz");
+        std::fs::write(&archive_path, b"not an archive").unwrap();
+        assert!(std::panic::catch_unwind(|| extract_archive(ArchiveKind::TarGz, &archive_path, &dir.join("broken.tar.gz.d"))).is_err());
+        let archive_path = dir.join("broken.zip");
+        std::fs::write(&archive_path, b"not an archive").unwrap();
+        assert!(std::panic::catch_unwind(|| extract_archive(ArchiveKind::Zip, &archive_path, &dir.join("broken.zip.d"))).is_err());
+    }
+}
+
+const VIDEO_EXTENSIONS: [&str; 14] = ["mp4", "m4v", "mov", "mkv", "webm", "avi", "ts", "mts", "m2ts", "flv", "wmv", "3gp", "mpg", "mpeg"];
+
+// regular files with a video extension under the directory, sorted by path
+fn list_video_paths(dir_path: &Path) -> Vec<PathBuf> {
+    let mut video_paths = Vec::new();
+    let mut dir_paths = vec![dir_path.to_path_buf()];
+    while let Some(dir_path) = dir_paths.pop() {
+        let entries = match std::fs::read_dir(&dir_path) {
+            Ok(entries) => entries,
+            Err(err) => panic!("Couldn't read the directory: {:} ({:})", dir_path.display(), err),
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            // macos archivers add resource forks, named like the videos
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with("._") || name == "__MACOSX" {
+                continue;
+            }
+            // symlinks are not followed, they may point out of the directory
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_dir() {
+                dir_paths.push(path);
+            } else if file_type.is_file() && path.extension().is_some_and(|extension| VIDEO_EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str())) {
+                video_paths.push(path);
+            }
+        }
+    }
+    video_paths.sort();
+    video_paths
+}
+
+#[cfg(test)]
+mod test_list_video_paths {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let dir = Path::new(&root_path).join("output/test_list_video_paths");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("b")).unwrap();
+        std::fs::create_dir_all(dir.join("__MACOSX/b")).unwrap();
+        for name in ["b/2.mp4", "b/1.MOV", "a.webm", "c.txt", "._a.webm", "__MACOSX/b/._2.mp4", "b/mp4"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        std::os::unix::fs::symlink(dir.join("a.webm"), dir.join("d.mp4")).unwrap();
+
+        assert_eq!(list_video_paths(&dir), vec![dir.join("a.webm"), dir.join("b/1.MOV"), dir.join("b/2.mp4")]);
+    }
+}
+
+// redirects are followed by reqwest, up to 10
+async fn download_url(url: &str, path: impl AsRef<Path>) {
+    // the signature is a credential, keep it out of the logs
+    let url_for_log = url.split(['?', '#']).next().unwrap_or_default();
+    let mut response = match reqwest::get(url).await {
+        Ok(response) => response,
+        Err(err) if err.is_redirect() => panic!("Too many redirects: {:}", url_for_log),
+        Err(err) => panic!("Couldn't get url: {:} ({:})", url_for_log, err.without_url()),
+    };
+    if !response.status().is_success() {
+        panic!("Url responded with an error status: {:} ({:})", url_for_log, response.status());
+    }
+
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        if let Err(err) = fs::create_dir_all(parent).await {
+            panic!("Couldn't create the directory: {:} ({:})", parent.display(), err);
+        };
+    }
+    let Ok(mut file) = File::create(path).await else {
+        panic!("Couldn't create the path: {:}", path.display());
+    };
+
+    loop {
+        let bytes = match response.chunk().await {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => break,
+            Err(err) => panic!("Couldn't receive bytes from url: {:} ({:})", url_for_log, err.without_url()),
+        };
+        if let Err(err) = file.write_all(&bytes).await {
+            if err.raw_os_error() == Some(ENOSPC) {
+                panic!("Disk full, couldn't write bytes to file: {:} ({:})", path.display(), err);
+            }
+            panic!("Couldn't write bytes to file: {:} ({:})", path.display(), err);
+        };
+    }
+    if let Err(err) = file.flush().await {
+        panic!("Couldn't write bytes to file: {:} ({:})", path.display(), err);
+    }
+}
+
 async fn download_object(client: &Client, bucket: String, object_id: String, path: impl AsRef<Path>) {
-    let Ok(mut object_stream) = client.download_streamed_object(&GetObjectRequest {
+    let content_encoding = match client.get_object(&GetObjectRequest { bucket: bucket.clone(), object: object_id.clone(), ..Default::default() }).await {
+        Ok(object) => object.content_encoding,
+        Err(err) => panic!("Couldn't get object metadata: {:} ({:})", object_id, err),
+    };
+    let Ok(object_stream) = client.download_streamed_object(&GetObjectRequest {
         bucket, object: object_id.clone(),
         ..Default::default()
     }, &Range::default()).await else {
         panic!("Couldn't get object stream: {:}", object_id);
     };
+    let gunzip = content_encoding.is_some_and(|content_encoding| content_encoding.eq_ignore_ascii_case("gzip"));
+    write_object_stream(object_stream, path, gunzip, &object_id).await;
+}
 
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// gcs decompresses gzip encoded objects itself for some clients, so the bytes decide too
+async fn write_object_stream<S, B, E>(mut object_stream: S, path: impl AsRef<Path>, gunzip: bool, object_id: &str) where S: futures::Stream<Item = Result<B, E>> + Unpin, B: AsRef<[u8]> {
     let path = path.as_ref();
-    let Ok(mut file) = File::create(path.clone()).await else {
+    if let Some(parent) = path.parent() {
+        if let Err(err) = fs::create_dir_all(parent).await {
+            panic!("Couldn't create the directory: {:} ({:})", parent.display(), err);
+        };
+    }
+
+    // enough bytes to see the magic
+    let mut head = Vec::new();
+    let mut stream_ended = false;
+    while head.len() < GZIP_MAGIC.len() {
+        match object_stream.next().await {
+            Some(Ok(bytes)) => head.extend_from_slice(bytes.as_ref()),
+            Some(Err(_)) => panic!("Couldn't receive bytes in object: {:}", object_id),
+            None => {
+                stream_ended = true;
+                break;
+            },
+        }
+    }
+
+    let Ok(file) = File::create(path).await else {
         panic!("Couldn't create the path: {:}", path.display());
     };
+    let mut writer = if gunzip && head.starts_with(&GZIP_MAGIC) {
+        log::info!("Gunzip gzip encoded object: {:}", object_id);
+        ObjectWriter::Gunzip(GzipDecoder::new(file))
+    } else {
+        ObjectWriter::File(file)
+    };
 
-    while let Some(item) = object_stream.next().await {
-        let Ok(bytes) = item else {
-            panic!("Couldn't receive bytes in object: {:}", object_id);
+    writer.write_all(&head, path).await;
+    if !stream_ended {
+        while let Some(item) = object_stream.next().await {
+            let Ok(bytes) = item else {
+                panic!("Couldn't receive bytes in object: {:}", object_id);
+            };
+            writer.write_all(bytes.as_ref(), path).await;
+        }
+    }
+    writer.finish(path).await;
+}
+
+#[cfg(test)]
+mod test_write_object_stream {
+    use super::*;
+    use async_compression::tokio::write::GzipEncoder;
+
+    #[tokio::test]
+    async fn it_works() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let dir = root_path.join("output/test_write_object_stream");
+        let _ = std::fs::remove_dir_all(&dir);
+        let video_bytes = std::fs::read(root_path.join("tests/videos/va-300x400.mp4")).unwrap();
+        let mut encoder = GzipEncoder::new(Vec::new());
+        encoder.write_all(&video_bytes).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        let gzipped_bytes = encoder.into_inner();
+        assert!(gzipped_bytes.starts_with(&GZIP_MAGIC));
+        // small chunks, the first one shorter than the magic
+        let chunks = |bytes: &Vec<u8>| futures::stream::iter(std::iter::once(bytes[..1].to_vec()).chain(bytes[1..].chunks(4096).map(|chunk| chunk.to_vec())).map(Ok::<_, ()>).collect::<Vec<_>>());
+
+        let path = dir.join("gzipped/a.mp4");
+        write_object_stream(chunks(&gzipped_bytes), &path, true, "gzipped/a.mp4").await;
+        assert_eq!(std::fs::read(&path).unwrap(), video_bytes);
+
+        // not encoded, kept as it is
+        let path = dir.join("plain/a.mp4.gz");
+        write_object_stream(chunks(&gzipped_bytes), &path, false, "plain/a.mp4.gz").await;
+        assert_eq!(std::fs::read(&path).unwrap(), gzipped_bytes);
+
+        // encoded but already decompressed on the way
+        let path = dir.join("transcoded/a.mp4");
+        write_object_stream(chunks(&video_bytes), &path, true, "transcoded/a.mp4").await;
+        assert_eq!(std::fs::read(&path).unwrap(), video_bytes);
+
+        let path = dir.join("empty/a.mp4");
+        write_object_stream(futures::stream::iter(Vec::<Result<Vec<u8>, ()>>::new()), &path, true, "empty/a.mp4").await;
+        assert_eq!(std::fs::read(&path).unwrap(), Vec::<u8>::new());
+
+        let path = dir.join("truncated/a.mp4");
+        let truncated_bytes = gzipped_bytes[..gzipped_bytes.len() / 2].to_vec();
+        assert!(tokio::spawn(async move { write_object_stream(chunks(&truncated_bytes), &path, true, "truncated/a.mp4").await }).await.is_err());
+    }
+}
+
+// decompressed in process as the bytes arrive, so a gzipped object needs no room for both forms
+enum ObjectWriter {
+    File(File),
+    Gunzip(GzipDecoder<File>),
+}
+
+impl ObjectWriter {
+    async fn write_all(&mut self, bytes: &[u8], path: &Path) {
+        let result = match self {
+            ObjectWriter::File(file) => file.write_all(bytes).await,
+            ObjectWriter::Gunzip(decoder) => decoder.write_all(bytes).await,
         };
-        if let Err(err) = file.write_all(&bytes).await {
+        if let Err(err) = result {
+            if err.raw_os_error() == Some(ENOSPC) {
+                panic!("Disk full, couldn't write bytes to file: {:} ({:})", path.display(), err);
+            }
             panic!("Couldn't write bytes to file: {:} ({:})", path.display(), err);
+        }
+    }
+
+    // tokio writes the file in the background, it's only done when flushed
+    // the decoder also fails here on a truncated gzip stream
+    async fn finish(self, path: &Path) {
+        let result = match self {
+            ObjectWriter::File(mut file) => file.flush().await,
+            ObjectWriter::Gunzip(mut decoder) => decoder.shutdown().await,
         };
+        if let Err(err) = result {
+            if err.raw_os_error() == Some(ENOSPC) {
+                panic!("Disk full, couldn't write bytes to file: {:} ({:})", path.display(), err);
+            }
+            panic!("Couldn't write bytes to file: {:} ({:})", path.display(), err);
+        }
     }
 }
 
-async fn upload_object(client: &Client, bucket: String, object_id: String, path: impl AsRef<Path>) {
+// local file problems panic as they would for every bucket, only the upload itself is returned
+async fn upload_object(client: &Client, bucket: String, object_id: String, path: impl AsRef<Path>, metadata: &HashMap<String, String>, chunk_size: Option<usize>) -> Result<(), String> {
     let path = path.as_ref();
     
     let Ok(file) = File::open(path.clone()).await else {
         panic!("Couldn't open the path: {:}", path.display());
     };
 
-    let Ok(metadata) = file.metadata().await else {
+    let Ok(file_metadata) = file.metadata().await else {
         panic!("Couldn't get a file metadata: {:}", path.display());
     };
 
-    if !metadata.is_file() {
+    if !file_metadata.is_file() {
         panic!("Upload target not a file: {:}", path.display());
     };
 
-    let mut media = Media::new(object_id);
-    media.content_length = Some(metadata.len());
+    let stream = get_upload_stream(file, chunk_size);
+
+    if !metadata.is_empty() {
+        log::info!("Upload with metadata: {:?}", metadata);
+    }
+    let upload_type = get_upload_type(object_id, metadata, file_metadata.len());
+    match client.upload_streamed_object(&UploadObjectRequest { bucket, ..Default::default() }, stream, &upload_type).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Upload failed with error: {:} {:}", path.display(), err)),
+    }
+}
+
+// custom metadata only goes with a multipart upload
+// the size of a regular file is known and goes with either, without it the body goes chunked
+fn get_upload_type(object_id: String, metadata: &HashMap<String, String>, size: u64) -> UploadType {
+    if metadata.is_empty() {
+        let mut media = Media::new(object_id);
+        media.content_length = Some(size);
+        UploadType::Simple(media)
+    } else {
+        UploadType::Multipart(Box::new(Object {
+            name: object_id,
+            metadata: Some(metadata.clone()),
+            size: size as i64,
+            ..Default::default()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test_get_upload_type {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let UploadType::Simple(media) = get_upload_type("a.mp4".to_string(), &HashMap::new(), 1234) else {
+            panic!("Must be a simple upload");
+        };
+        assert_eq!((media.name.as_ref(), media.content_length), ("a.mp4", Some(1234)));
+
+        let metadata = HashMap::from([("job".to_string(), "1".to_string())]);
+        let UploadType::Multipart(object) = get_upload_type("a.mp4".to_string(), &metadata, 1234) else {
+            panic!("Must be a multipart upload");
+        };
+        assert_eq!((object.name.as_str(), object.metadata.as_ref(), object.size), ("a.mp4", Some(&metadata), 1234));
+    }
+}
+
+// none for the default capacity of ReaderStream
+fn get_upload_stream<R: tokio::io::AsyncRead>(reader: R, chunk_size: Option<usize>) -> ReaderStream<R> {
+    match chunk_size {
+        Some(chunk_size) => ReaderStream::with_capacity(reader, chunk_size),
+        None => ReaderStream::new(reader),
+    }
+}
+
+#[cfg(test)]
+mod test_get_upload_stream {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_works() {
+        let bytes = (0..10000).map(|i| (i % 256) as u8).collect::<Vec<_>>();
+
+        let mut sink = Vec::new();
+        let mut stream = get_upload_stream(&bytes[..], Some(6000));
+        while let Some(chunk) = stream.next().await {
+            sink.push(chunk.unwrap());
+        }
+        assert_eq!(sink.iter().map(|chunk| chunk.len()).collect::<Vec<_>>(), vec![6000, 4000]);
+        assert_eq!(sink.concat(), bytes);
+
+        // the default of ReaderStream
+        let mut sink = Vec::new();
+        let mut stream = get_upload_stream(&bytes[..], None);
+        while let Some(chunk) = stream.next().await {
+            sink.push(chunk.unwrap());
+        }
+        assert_eq!(sink.iter().map(|chunk| chunk.len()).collect::<Vec<_>>(), vec![4096, 4096, 1808]);
+        assert_eq!(sink.concat(), bytes);
+    }
+}
+
+// "bucket-a, bucket-b" -> bucket names, duplicates would upload twice
+fn parse_output_buckets(s: &str) -> Vec<String> {
+    let buckets = s.split(',').map(|bucket| bucket.trim()).filter(|bucket| !bucket.is_empty()).map(String::from).collect::<Vec<_>>();
+    if buckets.is_empty() {
+        panic!("OUTPUT_BUCKETS must list at least one bucket: {:}", s);
+    }
+    for (index, bucket) in buckets.iter().enumerate() {
+        if buckets[..index].contains(bucket) {
+            panic!("OUTPUT_BUCKETS has a duplicate bucket: {:}", bucket);
+        }
+    }
+    buckets
+}
+
+#[cfg(test)]
+mod test_parse_output_buckets {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(parse_output_buckets("a"), vec!["a".to_string()]);
+        assert_eq!(parse_output_buckets(" a , b ,"), vec!["a".to_string(), "b".to_string()]);
 
-    let stream = ReaderStream::new(file);
+        for invalid in ["", " , ", "a,b,a"] {
+            assert!(std::panic::catch_unwind(|| parse_output_buckets(invalid)).is_err(), "{:}", invalid);
+        }
+    }
+}
+
+// all buckets at once, the upload takes the bucket so tests can replace the storage
+async fn upload_to_buckets<F, Fut>(buckets: &[String], upload: F) -> Vec<(String, Result<(), String>)>
+where
+    F: Fn(String) -> Fut,
+    Fut: std::future::Future<Output = Result<(), String>>,
+{
+    let results = futures::future::join_all(buckets.iter().map(|bucket| upload(bucket.clone()))).await;
+    buckets.iter().cloned().zip(results).collect()
+}
+
+fn check_upload_results(object_id: &str, results: &[(String, Result<(), String>)], fail_on_any: bool) {
+    for (bucket, result) in results {
+        match result {
+            Ok(()) => log::info!("Uploaded: gs://{:}/{:}", bucket, object_id),
+            Err(err) => log::error!("Upload failed: gs://{:}/{:} ({:})", bucket, object_id, err),
+        }
+    }
+    let failed_count = results.iter().filter(|(_, result)| result.is_err()).count();
+    if (fail_on_any && 0 < failed_count) || failed_count == results.len() {
+        panic!("Upload failed for {:} of {:} buckets: {:}", failed_count, results.len(), object_id);
+    }
+}
+
+#[cfg(test)]
+mod test_upload_to_buckets {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn it_works() {
+        // mock storage, the bucket named "down" rejects every upload
+        let uploaded = Arc::new(Mutex::new(Vec::new()));
+        let upload = |bucket: String| {
+            let uploaded = uploaded.clone();
+            async move {
+                if bucket == "down" {
+                    return Err("unavailable".to_string());
+                }
+                uploaded.lock().unwrap().push(bucket);
+                Ok(())
+            }
+        };
+
+        let buckets = vec!["a".to_string(), "down".to_string(), "b".to_string()];
+        let results = upload_to_buckets(&buckets, upload).await;
+        assert_eq!(results, vec![
+            ("a".to_string(), Ok(())),
+            ("down".to_string(), Err("unavailable".to_string())),
+            ("b".to_string(), Ok(())),
+        ]);
+        let mut uploaded_buckets = uploaded.lock().unwrap().clone();
+        uploaded_buckets.sort();
+        assert_eq!(uploaded_buckets, vec!["a".to_string(), "b".to_string()]);
+
+        // fail on all by default, fail on any when asked
+        check_upload_results("a.mp4", &results, false);
+        assert!(std::panic::catch_unwind(|| check_upload_results("a.mp4", &results, true)).is_err());
+        let results = upload_to_buckets(&["down".to_string()], upload).await;
+        assert!(std::panic::catch_unwind(|| check_upload_results("a.mp4", &results, false)).is_err());
+    }
+}
+
+// every option, from CONFIG_FILE with the env var of the same name taking precedence
+macro_rules! define_config {
+    ($($name:ident: $type:ty,)*) => {
+        #[derive(Debug, Default, PartialEq, Deserialize)]
+        #[serde(rename_all = "SCREAMING_SNAKE_CASE", deny_unknown_fields)]
+        struct Config {
+            $($name: Option<$type>,)*
+        }
 
-    let upload_type = UploadType::Simple(media);
-    if let Err(err) = client.upload_streamed_object(&UploadObjectRequest { bucket, ..Default::default() }, stream, &upload_type).await {
-        panic!("Upload failed with error: {:} {:}", path.display(), err);
+        impl Config {
+            fn overlay(&mut self, get_var: impl Fn(&str) -> Option<String>) {
+                $(
+                    let name = stringify!($name).to_uppercase();
+                    if let Some(v) = get_var(&name) {
+                        self.$name = Some(EnvValue::parse_env_value(&name, &v));
+                    }
+                )*
+            }
+        }
     };
 }
 
-fn get_env_string(name: &str) -> String {
+define_config! {
+    log_file: String,
+    log_level: String,
+    input_bucket: String,
+    output_buckets: String,
+    output_bucket: String,
+    upload_fail_on_any: bool,
+    upload_chunk_size: usize,
+    output_metadata: String,
+    enough_vmaf: f64,
+    min_crf: u8,
+    measure_fallback_vmaf: bool,
+    measure_signalstats: bool,
+    strict_audio: bool,
+    crf_search_timeout_secs: f64,
+    prefer_smaller: bool,
+    verify_prediction: bool,
+    verify_prediction_max_delta: f64,
+    prefer_smaller_margin: f64,
+    hard_floor_vmaf: u8,
+    orientation_policy: String,
+    audio_policy: String,
+    overwrite_policy: String,
+    deterministic: bool,
+    strip_metadata: bool,
+    set_creation_time: String,
+    fail_below_vmaf: bool,
+    strict_inputs: bool,
+    require_all_inputs: bool,
+    keep_passlog: bool,
+    validate_output: bool,
+    timeline_offsets: String,
+    thumbnail_sheet: String,
+    thumbnail_width: u32,
+    preview_sprite: String,
+    preview_sprite_interval_secs: f64,
+    preview_sprite_width: u32,
+    emit_audio_sidecar: bool,
+    fade_in_secs: f64,
+    fade_out_secs: f64,
+    progress_file: String,
+    speeds: String,
+    audio_delays_ms: String,
+    dedupe_consecutive: bool,
+    memory_limit_mb: i64,
+    max_total_duration_secs: f64,
+    allow_passthrough: bool,
+    concat_unsafe: bool,
+    svtav1_tune: u8,
+    svtav1_level: String,
+    svtav1_tier: String,
+    ab_av1_retries: u8,
+    genpts: bool,
+    align_audio_to_video: bool,
+    chapters: bool,
+    auto_encode: bool,
+    trim_black: bool,
+    trim_black_pixel_threshold: f64,
+    trim_black_min_duration_secs: f64,
+    trim_silence: bool,
+    trim_silence_noise_db: f64,
+    trim_silence_min_duration_secs: f64,
+    output_range: String,
+    fixed_crf: u8,
+    renditions: String,
+    target_resolution: String,
+    aspect_ratio: String,
+    opus_application: String,
+    denoise: String,
+    denoise_strength: f64,
+    mp4_mode: String,
+    output_container: String,
+    probe_header_bytes: u64,
+    max_input_pixels: i64,
+    pixel_format: String,
+    search_pixel_format: String,
+    best_input_metric: String,
+    max_concurrent_jobs: u32,
+    job_slot_fail_fast: bool,
+    gcs_key_path: String,
+    input_prefix: String,
+    exclude: String,
+    embed_source_ids: bool,
+    reference_object: String,
+    watermark_object: String,
+    watermark_position: String,
+    watermark_opacity: f64,
+    captions_object: String,
+    allow_overwrite_input: bool,
+    resume: bool,
+    analyze_only: bool,
+}
+
+fn load_config(config_file: Option<String>) -> Config {
+    let mut config = match config_file {
+        Some(path) => match std::fs::read_to_string(&path) {
+            Ok(s) => parse_config(&s),
+            Err(err) => panic!("Couldn't read CONFIG_FILE: {:} ({:})", path, err),
+        },
+        None => Config::default(),
+    };
+    config.overlay(get_env_var);
+    config
+}
+
+// {"INPUT_BUCKET": "in", "ENOUGH_VMAF": 80, "DETERMINISTIC": true}, an unknown or mistyped option fails
+fn parse_config(s: &str) -> Config {
+    match serde_json::from_str::<Config>(s) {
+        Ok(config) => config,
+        Err(err) => panic!("CONFIG_FILE must be a json object of the options: {:}", err),
+    }
+}
+
+#[cfg(test)]
+mod test_config {
+    use super::*;
+
+    #[test]
+    fn it_parses() {
+        let config = parse_config(r#"{"INPUT_BUCKET": "in", "ENOUGH_VMAF": 80, "WATERMARK_OPACITY": 0.5, "DETERMINISTIC": true}"#);
+        assert_eq!(config.input_bucket.as_deref(), Some("in"));
+        assert_eq!(config.enough_vmaf, Some(80.0));
+        assert_eq!(config.watermark_opacity, Some(0.5));
+        assert_eq!(config.deterministic, Some(true));
+        assert_eq!(config.min_crf, None);
+        assert_eq!(parse_config("{}"), Config::default());
+
+        for invalid in ["", "[]", "{\"UNKNOWN\": 1}", "{\"MIN_CRF\": \"a\"}", "{\"MIN_CRF\": 256}", "{\"DETERMINISTIC\": 1}", "{\"INPUT_BUCKET\": 1}", "{"] {
+            assert!(std::panic::catch_unwind(|| parse_config(invalid)).is_err(), "{:}", invalid);
+        }
+    }
+
+    #[test]
+    fn it_overlays_env() {
+        let mut config = parse_config(r#"{"INPUT_BUCKET": "config", "OUTPUT_BUCKET": "config", "MIN_CRF": 20, "DETERMINISTIC": true}"#);
+        let vars = HashMap::from([("OUTPUT_BUCKET", "env"), ("MIN_CRF", "30"), ("DETERMINISTIC", "0"), ("STRICT_AUDIO", "1"), ("PATH", "/bin")]);
+        config.overlay(|name| vars.get(name).map(|v| v.to_string()));
+        assert_eq!(config.input_bucket.as_deref(), Some("config"));
+        assert_eq!(config.output_bucket.as_deref(), Some("env"));
+        assert_eq!(config.min_crf, Some(30));
+        assert_eq!(config.deterministic, Some(false));
+        assert_eq!(config.strict_audio, Some(true));
+
+        for (name, invalid) in [("MIN_CRF", "a"), ("DETERMINISTIC", "yes")] {
+            assert!(std::panic::catch_unwind(|| Config::default().overlay(|n| (n == name).then(|| invalid.to_string()))).is_err(), "{:}", name);
+        }
+    }
+}
+
+fn get_env_var(name: &str) -> Option<String> {
     match env::var(name) {
-        Ok(v) => v, 
-        Err(err) => panic!("{:} env var not set or invalid utf-8: {:}", name, err),
+        Ok(v) => Some(v),
+        Err(env::VarError::NotPresent) => None,
+        Err(err) => panic!("{:} env var invalid utf-8: {:}", name, err),
+    }
+}
+
+fn get_required<T>(value: Option<T>, name: &str) -> T {
+    match value {
+        Some(v) => v,
+        None => panic!("{:} env var not set", name),
+    }
+}
+
+// an env var is always a string, parsed into the type the option has in CONFIG_FILE
+trait EnvValue {
+    fn parse_env_value(name: &str, v: &str) -> Self;
+}
+
+impl EnvValue for String {
+    fn parse_env_value(_name: &str, v: &str) -> Self {
+        v.to_string()
+    }
+}
+
+impl EnvValue for bool {
+    fn parse_env_value(name: &str, v: &str) -> Self {
+        match v {
+            "" | "0" | "false" => false,
+            "1" | "true" => true,
+            v => panic!("{:} must be true or false: {:}", name, v),
+        }
     }
 }
 
-fn get_env_u8(name: &str) -> u8 {
-    match get_env_string(name).parse::<u8>() {
+fn parse_env_value_from_str<T: std::str::FromStr>(name: &str, v: &str) -> T where T::Err: std::fmt::Display {
+    match v.parse::<T>() {
         Ok(v) => v,
-        Err(err) => panic!("{:} couldn't parse as an 8bit unsigned int: {:}", name, err),
+        Err(err) => panic!("{:} couldn't parse: {:} ({:})", name, v, err),
+    }
+}
+
+impl EnvValue for f64 {
+    fn parse_env_value(name: &str, v: &str) -> Self {
+        parse_env_value_from_str(name, v)
+    }
+}
+
+impl EnvValue for i64 {
+    fn parse_env_value(name: &str, v: &str) -> Self {
+        parse_env_value_from_str(name, v)
+    }
+}
+
+impl EnvValue for u8 {
+    fn parse_env_value(name: &str, v: &str) -> Self {
+        parse_env_value_from_str(name, v)
+    }
+}
+
+impl EnvValue for u32 {
+    fn parse_env_value(name: &str, v: &str) -> Self {
+        parse_env_value_from_str(name, v)
+    }
+}
+
+impl EnvValue for u64 {
+    fn parse_env_value(name: &str, v: &str) -> Self {
+        parse_env_value_from_str(name, v)
+    }
+}
+
+impl EnvValue for usize {
+    fn parse_env_value(name: &str, v: &str) -> Self {
+        parse_env_value_from_str(name, v)
     }
 }
 
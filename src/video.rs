@@ -1,32 +1,91 @@
 use std::{
+    collections::HashMap,
+    io::{
+        self,
+        Read,
+    },
     path::{
         Path,
         PathBuf,
     },
     process::{
+        Child,
         Command,
         ExitStatus,
+        Output,
+        Stdio,
     },
     fmt,
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicU64,
+            Ordering,
+        },
+        mpsc::Sender,
+        Arc,
+        Mutex,
+    },
+    thread,
+    time::Duration,
 };
 use regex::Regex;
+use serde::{
+    Deserialize,
+    Serialize,
+};
 use log;
 use ffprobe;
 use lazy_static::lazy_static;
 
 const AB_AV1_CMD_STR: &str = "ab-av1";
 const FFMPEG_CMD_STR: &str = "ffmpeg";
-const MAX_CRF: u8 = 55;
+const FFPROBE_CMD_STR: &str = "ffprobe";
+pub(crate) const MAX_CRF: u8 = 55;
+pub(crate) const MAX_VMAF: u8 = 100;
+// rough svt-av1 peak usage at preset 8 with the lookahead below, about 2GB for 1080p
+const SVTAV1_ESTIMATED_BYTES_PER_PIXEL: i64 = 1000;
+const SVTAV1_MEMORY_LIMITED_LOOKAHEAD: u8 = 16;
+// 0 subjective, 1 psnr, 2 ssim
+const MAX_SVTAV1_TUNE: u8 = 2;
+// annex a of the av1 spec
+const AV1_LEVELS: [&str; 18] = ["2.0", "2.1", "3.0", "3.1", "4.0", "4.1", "5.0", "5.1", "5.2", "5.3", "6.0", "6.1", "6.2", "6.3", "7.0", "7.1", "7.2", "7.3"];
+const DEFAULT_OUTPUT_FORMAT: &str = "mp4";
+const KNOWN_OUTPUT_EXTENSIONS: [&str; 4] = ["mp4", "mkv", "webm", "mov"];
 
 const FFMPEG_STDOUT_RETRIEVE_VERSION_REGEX_SOURCE: &str = r"^ffmpeg\s+version\s+(\d+)\.(\d+)\b";
+// auto-encode takes the same search args as crf-search from this version
+const AB_AV1_AUTO_ENCODE_MIN_VERSION: (u8, u8) = (0, 7);
 const AB_AV1_STDOUT_RETRIEVE_VERSION_REGEX_SOURCE: &str = r"^ab-av1\s+(\d+)\.(\d+).\d\b";
 
 lazy_static! {
+    // children of the commands being waited for, by process id, so a shutdown can kill them
+    static ref RUNNING_CHILDREN: Mutex<HashMap<u32, Arc<Mutex<Child>>>> = Mutex::new(HashMap::new());
+
     static ref FFMPEG_STDOUT_RETRIEVE_VERSION_REGEX: Regex = Regex::new(FFMPEG_STDOUT_RETRIEVE_VERSION_REGEX_SOURCE).unwrap();
     static ref AB_AV1_STDOUT_RETRIEVE_VERSION_REGEX: Regex = Regex::new(AB_AV1_STDOUT_RETRIEVE_VERSION_REGEX_SOURCE).unwrap();
 
-    static ref AB_AV1_STDOUT_RETRIEVE_CRF_REGEX: Regex = Regex::new(r"^\s*crf\s+(\d+)\s+VMAF\s+(\d+(?:\.\d+)?)").unwrap();
+    // "crf 32 VMAF 95.12 predicted video stream size ..." and "crf 32 predicted VMAF 95.12 ..." across ab-av1 0.7 to 0.9
+    static ref AB_AV1_STDOUT_RETRIEVE_CRF_REGEXES: Vec<Regex> = vec![
+        Regex::new(r"^\s*crf\s+(\d+)\s+VMAF\s+(\d+(?:\.\d+)?)").unwrap(),
+        Regex::new(r"^\s*crf\s+(\d+)\s+predicted\s+VMAF\s+(\d+(?:\.\d+)?)").unwrap(),
+    ];
+    static ref AB_AV1_STDOUT_ANSI_ESCAPE_REGEX: Regex = Regex::new(r"\x1b\[[0-9;]*m").unwrap();
     static ref AB_AV1_STDERR_CHECK_GOOD_CRF_NOT_FOUND_REGEX: Regex = Regex::new(r"Failed to find a suitable crf\s*$").unwrap();
+    // a sample encode or vmaf run of ab-av1 dying, rather than the search itself failing
+    static ref AB_AV1_STDERR_CHECK_TRANSIENT_ERROR_REGEX: Regex = Regex::new(r"(?i)ffmpeg\b.*\b(?:exit code|signal)|broken pipe|resource temporarily unavailable").unwrap();
+    static ref FFMPEG_STDERR_RETRIEVE_FRAME_COUNT_REGEX: Regex = Regex::new(r"frame=\s*(\d+)").unwrap();
+    static ref FFMPEG_STDERR_RETRIEVE_BITRATE_REGEX: Regex = Regex::new(r"bitrate=\s*(\d+(?:\.\d+)?)kbits/s").unwrap();
+    static ref FFMPEG_STDERR_RETRIEVE_MUXING_OVERHEAD_REGEX: Regex = Regex::new(r"muxing overhead:\s*(-?\d+(?:\.\d+)?)%").unwrap();
+    static ref FFMPEG_STDERR_RETRIEVE_VMAF_REGEX: Regex = Regex::new(r"VMAF score:\s*(\d+(?:\.\d+)?)").unwrap();
+    static ref FFMPEG_STDERR_CHECK_VMAF_NOT_AVAILABLE_REGEX: Regex = Regex::new(r"No such filter: '?libvmaf'?").unwrap();
+    static ref FFMPEG_STDERR_RETRIEVE_INCOMPATIBLE_PIXEL_FORMAT_REGEX: Regex = Regex::new(r"Incompatible pixel format '([^']+)' for codec 'libsvtav1'").unwrap();
+    static ref FFMPEG_STDERR_RETRIEVE_BLACK_INTERVAL_REGEX: Regex = Regex::new(r"black_start:\s*(\d+(?:\.\d+)?)\s+black_end:\s*(\d+(?:\.\d+)?)").unwrap();
+    static ref FFMPEG_STDERR_RETRIEVE_SILENCE_START_REGEX: Regex = Regex::new(r"silence_start:\s*(-?\d+(?:\.\d+)?)").unwrap();
+    static ref FFMPEG_STDERR_RETRIEVE_SILENCE_END_REGEX: Regex = Regex::new(r"silence_end:\s*(\d+(?:\.\d+)?)").unwrap();
+    static ref FFMPEG_STDERR_RETRIEVE_YDIF_REGEX: Regex = Regex::new(r"lavfi\.signalstats\.YDIF=(\d+(?:\.\d+)?)").unwrap();
+    static ref FFMPEG_STDERR_CHECK_DISK_FULL_REGEX: Regex = Regex::new(r"No space left on device|ENOSPC").unwrap();
+    static ref FFPROBE_STDOUT_RETRIEVE_ROTATION_REGEX: Regex = Regex::new(r"(?m)^(?:TAG:rotate|rotation)=(-?\d+)").unwrap();
 }
 
 #[derive(Debug, PartialEq)]
@@ -52,6 +111,49 @@ pub enum ErrorKind {
     AbAv1CommandProcessFailed(PathBuf, String),
     InvalidAbAv1Output(PathBuf, String),
     UnknownAbAv1ErrorMessage(PathBuf, String),
+    VmafNotAvailable(String),
+    InvalidVmafOutput(PathBuf, String),
+    InvalidCrf(u8),
+    OutputDiskFull(String),
+    VmafTargetUnreachable(PathBuf, f64),
+    InputNotSupported(PathBuf, IgnoreReason),
+    ZeroDurationOutput,
+    ConcatListWriteFailed(PathBuf, String),
+    InvalidOutputRange(f64, f64),
+    WatermarkNotFound(PathBuf),
+    InvalidVmaf(f64),
+    EncodeTaskJoinFailed(String),
+    InputDropped(Vec<PathBuf>),
+    OutputValidationFailed(PathBuf, String),
+    InvalidTimeline(String),
+    TotalDurationExceeded(f64, f64),
+    PixelFormatNotSupported(String),
+    InvalidTune(u8),
+    InvalidAv1Level(String),
+    InvalidAv1Tier(String),
+    UnsupportedOutputContainer(OutputContainer),
+    CaptionsNotFound(PathBuf),
+    FallbackVmafBelowFloor(f64, u8),
+    ChaptersWriteFailed(PathBuf, String),
+    AllInputsAudioOnly(Vec<PathBuf>),
+    Cancelled,
+    IncompatibleAudioLayout(Vec<i64>),
+    CrfSearchTimedOut(PathBuf, f64),
+    PreviewVttWriteFailed(PathBuf, String),
+    InvalidSpeeds(String),
+    InvalidAudioDelays(String),
+    OutputExists(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IgnoreReason {
+    ProbeFailed(String),
+    NoVideoStream,
+    // audio but no video, likely the wrong file was passed
+    AudioOnly,
+    NoResolution,
+    InvalidResolution(i64, i64),
+    NoDuration,
 }
 
 #[cfg(test)]
@@ -70,297 +172,715 @@ mod test_error {
     }
 }
 
-#[derive(Debug)]
-struct InputFile {
-    path: PathBuf,
-    width: i64,
-    height: i64,
-    alternative_null_audio_duration: Option<f64>,
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum AudioPolicy {
+    // inputs without audio get silence, so the output always has an audio track
+    #[default]
+    SilenceFill,
+    // a video only output when any input has no audio
+    DropIfAnyMissing,
+    DropAlways,
 }
 
-#[cfg(test)]
-mod test_input_file {
-    use super::*;
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum OverwritePolicy {
+    // as ffmpeg -y
+    #[default]
+    Always,
+    Never,
+    // unless the inputs hash stored next to the output matches
+    IfDifferentInputs,
+}
 
-    #[test]
-    fn it_works() {
-        // just coverage for debug trait
-        assert!(0 < format!("{:?}", InputFile { path: PathBuf::from("."), width: 1, height: 2, alternative_null_audio_duration: None }).len());
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum OrientationPolicy {
+    // pillarbox/letterbox inputs whose orientation differs from the target
+    #[default]
+    Pad,
+    // transpose the inputs of the minority orientation to match the majority
+    RotateToMajority,
 }
 
-pub(crate) fn encode_best_effort(input_video_paths: Vec<PathBuf>, output_video_path: impl AsRef<Path>, enough_vmaf: u8, min_crf: u8) -> Result<(u8, Option<f64>), Error> {
-    encode_best_effort_impl(FFMPEG_CMD_STR, input_video_paths, output_video_path, enough_vmaf, min_crf)
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum TargetResolutionPolicy {
+    // width and height each taken over the inputs
+    #[default]
+    Max,
+    Min,
+    Median,
+    Fixed(i64, i64),
 }
 
-// separate impl for test
-fn encode_best_effort_impl(cmd_str: &str, input_video_paths: Vec<PathBuf>, output_video_path: impl AsRef<Path>, enough_vmaf: u8, min_crf: u8) -> Result<(u8, Option<f64>), Error> {
-    log::trace!("encode_best_effort(): {:?}", (&input_video_paths, output_video_path.as_ref(), enough_vmaf, min_crf));
-    let output_video_path = output_video_path.as_ref();
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum OpusApplication {
+    Voip,
+    #[default]
+    Audio,
+    LowDelay,
+}
 
-    check_command(6, 0, FFMPEG_CMD_STR, &["-version"], &FFMPEG_STDOUT_RETRIEVE_VERSION_REGEX)?;
-    check_command(0, 7, AB_AV1_CMD_STR, &["--version"], &AB_AV1_STDOUT_RETRIEVE_VERSION_REGEX)?;
+impl OpusApplication {
+    fn as_ffmpeg_value(&self) -> &'static str {
+        match self {
+            OpusApplication::Voip => "voip",
+            OpusApplication::Audio => "audio",
+            OpusApplication::LowDelay => "lowdelay",
+        }
+    }
+}
 
-    let input_files = input_video_paths.into_iter()
-        .filter_map(analyze_video_file)
-        .collect::<Vec<_>>();
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum OutputPixelFormat {
+    #[default]
+    Yuv420p10le,
+    Yuv420p,
+    // 4:4:4 keeps colored text edges of screen recordings, needs a libsvtav1 build supporting it
+    Yuv444p10le,
+    Yuv444p,
+}
 
-    let needs_concatenation = match input_files.len() {
-        0 => {
-            log::trace!("encode_best_effort() -> Error(NoAvailableVideoStream): {:?}", (&input_files));
-            return Err(Error { kind: ErrorKind::NoAvailableVideoStream });
-        },
-        1 => false,
-        _ => true,
-    };
+impl OutputPixelFormat {
+    fn as_ffmpeg_value(&self) -> &'static str {
+        match self {
+            OutputPixelFormat::Yuv420p10le => "yuv420p10le",
+            OutputPixelFormat::Yuv420p => "yuv420p",
+            OutputPixelFormat::Yuv444p10le => "yuv444p10le",
+            OutputPixelFormat::Yuv444p => "yuv444p",
+        }
+    }
+}
 
-    let mut ffmpeg_cmd = Command::new(cmd_str);
-    ffmpeg_cmd.arg("-y");
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum OutputContainer {
+    Mp4,
+    Mov,
+    Mkv,
+    Webm,
+}
 
-    for input_file in &input_files {
-        ffmpeg_cmd.arg("-i");
-        ffmpeg_cmd.arg(&input_file.path);
+impl OutputContainer {
+    fn as_ffmpeg_format(&self) -> &'static str {
+        match self {
+            OutputContainer::Mp4 => "mp4",
+            OutputContainer::Mov => "mov",
+            OutputContainer::Mkv => "matroska",
+            OutputContainer::Webm => "webm",
+        }
     }
 
-    if needs_concatenation {
-        let filter_code = get_avfilter_code(&input_files);
-        ffmpeg_cmd.args(["-filter_complex", &filter_code, "-map", "[vout]", "-map", "[aout]"]);
+    // the ffmpeg mov muxer has no opus tag
+    fn supports_av1_opus(&self) -> bool {
+        *self != OutputContainer::Mov
     }
+}
 
-    assert!(0 < input_files.len());
-    let best_input_file = input_files.iter().max_by_key(|input_file| input_file.width * input_file.height).expect("must not be none, because vec is not empty");
-    
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum Mp4Mode {
+    // index at the end, players need the whole file or range requests
+    #[default]
+    Regular,
+    // index moved to the front after the encode, one more pass over the file on disk
+    Faststart,
+    // fragments at keyframes, playable while streamed without the rewrite, some players dislike it
+    Fragmented,
+}
 
-    log::info!("Start search crf: {:} vmaf={:} crf={:}", best_input_file.path.display(), enough_vmaf, min_crf);
-    let (best_crf, predicted_vmaf) = get_best_crf(&best_input_file.path, enough_vmaf, min_crf)?;
-    if let Some(predicted_vmaf) = predicted_vmaf {
-        log::info!("Crf found: {:} (vmaf={:})", best_crf, predicted_vmaf);
-    } else {
-        log::info!("Suitable crf not found use min: {:}", best_crf);
-    };
+impl Mp4Mode {
+    fn as_movflags_value(&self) -> Option<&'static str> {
+        match self {
+            Mp4Mode::Regular => None,
+            Mp4Mode::Faststart => Some("+faststart"),
+            Mp4Mode::Fragmented => Some("+frag_keyframe+empty_moov"),
+        }
+    }
+}
 
-    let best_crf_str = best_crf.to_string();
-    ffmpeg_cmd.args([
-        "-c:v", "libsvtav1",
-        "-crf", &best_crf_str,
-        "-pix_fmt", "yuv420p10le",
-        "-preset", "8",
-    ]);
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum BestInputMetric {
+    #[default]
+    Pixels,
+    Bitrate,
+    Duration,
+}
 
-    ffmpeg_cmd.arg(&output_video_path);
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+}
 
-    log::info!("Start ffmpeg: {:?}", ffmpeg_cmd);
-    let output = match ffmpeg_cmd.output() {
-        Ok(output) => output,
-        Err(err) => {
-            log::trace!("encode_best_effort() -> Error(FfmpegCommandProcessFailed({:?})): {:?}", &err, (&ffmpeg_cmd));
-            return Err(Error { kind: ErrorKind::FfmpegCommandProcessFailed(err.to_string()) });
-        },
-    };
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct WatermarkSpec {
+    pub(crate) path: PathBuf,
+    pub(crate) position: WatermarkPosition,
+    // 0.0 is invisible, 1.0 keeps the image alpha as is
+    pub(crate) opacity: f64,
+}
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-        log::trace!("encode_best_effort() -> Error(FfmpegCommandExitAbnormally({:?}, {:?})): {:?}", &output.status, &stderr, (&ffmpeg_cmd));
-        return Err(Error { kind: ErrorKind::FfmpegCommandExitAbnormally(output.status, stderr) });
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(crate) enum MetadataStamp {
+    // whatever ffmpeg copies over from the first input, creation time included
+    #[default]
+    Keep,
+    Strip,
+    // ffmpeg date syntax, e.g. 2023-01-01T00:00:00Z
+    CreationTime(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TimelineSpec {
+    // start seconds on the output timeline, one for each input path in order, black and silence in between
+    pub(crate) offsets: Vec<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BlackTrimSpec {
+    // blackdetect pix_th, the ratio of the luma range below which a pixel counts as black
+    pub(crate) pixel_threshold: f64,
+    // shorter black runs at the edges are kept
+    pub(crate) min_duration: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SilenceTrimSpec {
+    // silencedetect noise, quieter audio counts as silence
+    pub(crate) noise_db: f64,
+    // shorter silent runs at the edges are kept
+    pub(crate) min_duration: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ThumbnailSheetSpec {
+    pub(crate) rows: u32,
+    pub(crate) cols: u32,
+    // of each cell, the height follows the output aspect ratio
+    pub(crate) width: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum DenoiseFilter {
+    // fast, may smear fine detail at higher strengths
+    Hqdn3d,
+    // keeps edges better, several times slower
+    Nlmeans,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DenoiseSpec {
+    pub(crate) filter: DenoiseFilter,
+    // hqdn3d luma_spatial or nlmeans s, the other parameters follow it
+    pub(crate) strength: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SpriteSpec {
+    // seconds of the output each tile stands for
+    pub(crate) interval: f64,
+    // tiles of each sprite image, more images when the output needs more
+    pub(crate) rows: u32,
+    pub(crate) cols: u32,
+    // of each tile, the height follows the output aspect ratio
+    pub(crate) width: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Rendition {
+    pub(crate) height: i64,
+    // none for the searched crf
+    pub(crate) crf: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ProgressPhase {
+    Download,
+    Analysis,
+    CrfSearch,
+    Encode,
+    Upload,
+}
+
+impl ProgressPhase {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ProgressPhase::Download => "download",
+            ProgressPhase::Analysis => "analysis",
+            ProgressPhase::CrfSearch => "crf-search",
+            ProgressPhase::Encode => "encode",
+            ProgressPhase::Upload => "upload",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ProgressEvent {
+    pub(crate) phase: ProgressPhase,
+    // of the phase, 0 when it starts and 100 when it's done
+    pub(crate) percent: f64,
+    pub(crate) current_file: Option<PathBuf>,
+    pub(crate) crf: Option<u8>,
+    pub(crate) vmaf: Option<f64>,
+}
+
+impl ProgressEvent {
+    pub(crate) fn new(phase: ProgressPhase, percent: f64, current_file: Option<&Path>) -> Self {
+        ProgressEvent { phase, percent, current_file: current_file.map(Path::to_path_buf), crf: None, vmaf: None }
     }
+}
 
-    log::trace!("encode_best_effort() -> Ok");
-    Ok((best_crf, predicted_vmaf))
+pub(crate) fn get_progress_json(event: &ProgressEvent) -> serde_json::Value {
+    serde_json::json!({
+        "phase": event.phase.as_str(),
+        "percent": event.percent,
+        "current_file": event.current_file.as_ref().map(|path| path.to_string_lossy()),
+        "crf": event.crf,
+        "vmaf": event.vmaf,
+    })
 }
 
 #[cfg(test)]
-mod test_encode_best_effort {
+mod test_get_progress_json {
     use super::*;
-    use std::env;
 
     #[test]
     fn it_works() {
-        let test_cases = vec![
-            (vec!["va-300x400.mp4"], "va.mp4", 0, MAX_CRF - 2, true, 1.0, MAX_CRF, true),
-            (vec!["va-300x400.mp4", "va-300x400.mp4"], "va-va.mp4", 0, MAX_CRF - 2, true, 2.0, MAX_CRF, true),
-            (vec!["v-300x400.mp4"], "v.mp4", 0, MAX_CRF - 2, true, 1.0, MAX_CRF, true),
-            (vec!["v-300x400.mp4", "v-300x400.mp4"], "v-v.mp4", 0, MAX_CRF - 2, true, 2.0, MAX_CRF, true),
-            (vec!["va-300x400.mp4", "v-300x400.mp4"], "va-v.mp4", 0, MAX_CRF - 2, true, 2.0, MAX_CRF, true),
-            (vec!["v-300x400.mp4", "va-300x400.mp4"], "v-va.mp4", 0, MAX_CRF - 2, true, 2.0, MAX_CRF, true),
-            (vec!["v-300x400.mp4", "va-300x400.mp4", "v-300x400.mp4"], "v-va-v.mp4", 0, MAX_CRF - 2, true, 3.0, MAX_CRF, true),
-            (vec!["va-300x400.mp4", "v-300x400.mp4", "va-300x400.mp4"], "va-v-va.mp4", 0, MAX_CRF - 2, true, 3.0, MAX_CRF, true),
-            (vec!["a.mp4"], "a.mp4", 0, MAX_CRF - 2, false, 0.0, 0, false),
-        ];
+        let event = ProgressEvent { crf: Some(30), vmaf: Some(95.5), ..ProgressEvent::new(ProgressPhase::CrfSearch, 100.0, Some(Path::new("a.mp4"))) };
+        assert_eq!(get_progress_json(&event), serde_json::json!({ "phase": "crf-search", "percent": 100.0, "current_file": "a.mp4", "crf": 30, "vmaf": 95.5 }));
 
-        evauate_test_cases(test_cases);
+        let event = ProgressEvent::new(ProgressPhase::Upload, 0.0, None);
+        assert_eq!(get_progress_json(&event), serde_json::json!({ "phase": "upload", "percent": 0.0, "current_file": null, "crf": null, "vmaf": null }));
     }
+}
 
-    #[test]
-    fn it_ignores_not_supported() {
-        let test_cases = vec![
-            (vec!["invalid.mp4", "va-300x400.mp4", "invalid.mp4", "va-300x400.mp4", "invalid.mp4"], "it_ignores_not_supported.mp4", 0, MAX_CRF - 2, true, 2.0, MAX_CRF, true),
-        ];
-        evauate_test_cases(test_cases);
+// the receiver going away doesn't stop the encode
+fn report_progress(options: &EncodeOptions, event: ProgressEvent) {
+    if let Some(progress) = &options.progress {
+        let _ = progress.send(event);
     }
+}
 
-    #[test]
-    fn it_can_use_min_crf() {
-        let test_cases = vec![
-            (vec!["invalid.mp4", "va-300x400.mp4", "invalid.mp4", "va-300x400.mp4", "invalid.mp4"], "it_can_use_min_crf-0.mp4", 0, MAX_CRF - 2, true, 2.0, MAX_CRF, true),
-            (vec!["invalid.mp4", "va-300x400.mp4", "invalid.mp4", "va-300x400.mp4", "invalid.mp4"], "it_can_use_min_crf-1.mp4", 100, MAX_CRF - 2, true, 2.0, MAX_CRF - 2, false),
-        ];
-        evauate_test_cases(test_cases);
-    }
+// ffprobe names, e.g. bt2020, smpte2084 and bt2020nc, none when unknown
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(crate) struct ColorProperties {
+    pub(crate) primaries: Option<String>,
+    pub(crate) transfer: Option<String>,
+    pub(crate) space: Option<String>,
+}
 
-    #[test]
-    fn it_fails_when_ffmpeg_command_failed() {
-        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
-        let root_path = Path::new(&root_path);
-        let video_dir_path = root_path.join("tests/videos");
-        let output_dir_path = root_path.join("output");
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EncodeOptions {
+    pub(crate) orientation_policy: OrientationPolicy,
+    // bit-identical output for the same inputs and settings, encoding runs single-threaded so it gets several times slower
+    pub(crate) deterministic: bool,
+    // measure the output against this mastered reference after encoding, the crf search still compares against the inputs
+    pub(crate) reference_video_path: Option<PathBuf>,
+    // how to pick the input the crf search runs on
+    pub(crate) best_input_metric: BestInputMetric,
+    // skip the crf search and ab-av1 entirely
+    pub(crate) fixed_crf: Option<u8>,
+    // extra outputs scaled from the same filtered source in the same ffmpeg pass, written next to the output with a height suffix
+    pub(crate) renditions: Vec<Rendition>,
+    // error instead of falling back to min crf when no crf reaches enough vmaf
+    pub(crate) fail_below_vmaf: bool,
+    // error on an input analysis can't use instead of ignoring it
+    pub(crate) strict_inputs: bool,
+    // shorten the svt-av1 lookahead and downscale the target resolution to stay within the budget
+    pub(crate) memory_limit_mb: Option<i64>,
+    // stream copy instead of re-encoding when every input is already av1 with matching parameters
+    pub(crate) allow_passthrough: bool,
+    // start and end seconds of the concatenated program to encode, cut after filtering
+    pub(crate) output_range: Option<(f64, f64)>,
+    // image overlaid on the concatenated video, renditions included
+    pub(crate) watermark: Option<WatermarkSpec>,
+    // error listing every ignored input after analysis, instead of encoding the rest
+    pub(crate) require_all_inputs: bool,
+    // leave the ffmpeg pass log files next to the output for debugging
+    pub(crate) keep_passlog: bool,
+    // don't probe the outputs after encoding, ffmpeg may exit successfully with a broken output
+    pub(crate) skip_output_validation: bool,
+    // place the inputs at offsets instead of back to back
+    pub(crate) timeline: Option<TimelineSpec>,
+    pub(crate) target_resolution_policy: TargetResolutionPolicy,
+    // libopus tuning for the output audio
+    pub(crate) opus_application: OpusApplication,
+    // seconds, error before encoding when the inputs add up to more
+    pub(crate) max_total_duration: Option<f64>,
+    pub(crate) pixel_format: OutputPixelFormat,
+    // for ab-av1, none is the same as the encode, a different one makes the predicted vmaf approximate
+    pub(crate) search_pixel_format: Option<OutputPixelFormat>,
+    // concat segments even when their formats differ, ffmpeg may then emit broken timestamps or garbled frames instead of failing
+    pub(crate) concat_unsafe: bool,
+    // regenerate missing input pts, this hides timebase problems rather than fixing them and may shift a/v sync
+    pub(crate) genpts: bool,
+    // svt-av1 tune for both the crf search and the encode, none keeps the svt-av1 default
+    pub(crate) tune: Option<u8>,
+    // srt or webvtt already timed to the concatenated program, muxed as a subtitle track into every output
+    pub(crate) captions_path: Option<PathBuf>,
+    // times to rerun ab-av1 when it crashed rather than failed the search
+    pub(crate) ab_av1_retries: u8,
+    // when no crf reaches enough vmaf, measure the vmaf at min crf, which costs one more sample encode
+    pub(crate) measure_fallback_vmaf: bool,
+    // error when the vmaf measured at min crf is below this, implies measuring it
+    pub(crate) hard_floor_vmaf: Option<u8>,
+    // pad or trim each segment's audio to its video duration, so small differences don't accumulate over many clips
+    pub(crate) align_audio_to_video: bool,
+    // a chapter per input named after its file stem, in every output
+    pub(crate) chapters: bool,
+    // let ab-av1 auto-encode search and encode in one step, it can't run our filter graph so it only applies to a single input without filtering options
+    pub(crate) auto_encode: bool,
+    // cut black frames off the head and tail of each input, detected by a blackdetect pass per input
+    pub(crate) trim_black: Option<BlackTrimSpec>,
+    // cut silence off the head and tail of each input, detected by a silencedetect pass per input, video is cut too to keep sync
+    pub(crate) trim_silence: Option<SilenceTrimSpec>,
+    // output width:height, the target resolution grows to it and inputs are padded to fit
+    pub(crate) aspect_ratio: Option<(u32, u32)>,
+    // set from another thread to stop the encode, the running ffmpeg or ab-av1 is killed
+    pub(crate) cancel: Option<Arc<AtomicBool>>,
+    // the same path listed twice in a row is used once, this shortens the output
+    pub(crate) dedupe_consecutive: bool,
+    // a jpeg grid of frames sampled evenly across the output, written next to it
+    pub(crate) thumbnail_sheet: Option<ThumbnailSheetSpec>,
+    // sprite images and a webvtt mapping the output time to their tiles, for hover-scrub previews
+    pub(crate) preview_sprite: Option<SpriteSpec>,
+    // an opus only copy of the concatenated audio, encoded in the same ffmpeg run
+    pub(crate) emit_audio_sidecar: bool,
+    // seconds of fade from and to black, with the audio, over the whole program rather than each input
+    pub(crate) fade_in: Option<f64>,
+    pub(crate) fade_out: Option<f64>,
+    // when the found crf beats enough vmaf by the margin, sample encode one crf higher and take it if it's still enough
+    pub(crate) prefer_smaller: bool,
+    pub(crate) prefer_smaller_margin: f64,
+    // inputs with different audio channel counts are an error instead of being downmixed to stereo
+    pub(crate) strict_audio: bool,
+    pub(crate) metadata_stamp: MetadataStamp,
+    // seconds, then ab-av1 is killed and min crf used as when no crf is good enough
+    pub(crate) crf_search_timeout: Option<f64>,
+    pub(crate) mp4_mode: Mp4Mode,
+    // on each input before it's scaled, the crf search runs on the raw best input so its predicted vmaf doesn't see it
+    pub(crate) denoise: Option<DenoiseSpec>,
+    // playback speed of each input path in order, 2.0 plays twice as fast in half the time
+    pub(crate) speeds: Option<Vec<f64>>,
+    // ms the audio of each input path in order is late, negative when it's early
+    pub(crate) audio_delays_ms: Option<Vec<i64>>,
+    pub(crate) overwrite_policy: OverwritePolicy,
+    // phases reported as they advance, for monitors outside the logs
+    pub(crate) progress: Option<Sender<ProgressEvent>>,
+    pub(crate) audio_policy: AudioPolicy,
+    // constraints for hardware decoders, "5.1" and "main" or "high", for both the crf search and the encode
+    pub(crate) av1_level: Option<String>,
+    pub(crate) av1_tier: Option<String>,
+    // the muxer regardless of the output extension, none infers it from the extension
+    pub(crate) output_container: Option<OutputContainer>,
+    // a decode pass per input for the report, the bits per pixel comes for free
+    pub(crate) measure_signalstats: bool,
+    // written as the comment tag for provenance, nothing when empty
+    pub(crate) source_ids: Vec<String>,
+    // measures a sample of the output against its source, as the search preset differs from the encode
+    pub(crate) verify_prediction: bool,
+    pub(crate) verify_prediction_max_delta: f64,
+}
 
-        assert!(match encode_best_effort_impl("__command_not_found__", vec![video_dir_path.join("va-300x400.mp4")], output_dir_path.join("it_fails_when_ffmpeg_command_failed.mp4"), 0, MAX_CRF - 2) {
-            Err(Error { kind: ErrorKind::FfmpegCommandProcessFailed(_) }) => true, _ => false,
-        });
-        assert!(match encode_best_effort_impl("false", vec![video_dir_path.join("va-300x400.mp4")], output_dir_path.join("it_fails_when_ffmpeg_command_failed.mp4"), 0, MAX_CRF - 2) {
-            Err(Error { kind: ErrorKind::FfmpegCommandExitAbnormally(_, _) }) => true, _ => false,
-        });
-    }
+// kept in the job state to resume a preempted job at the upload
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub(crate) struct EncodeReport {
+    // none for passthrough
+    pub(crate) crf: Option<u8>,
+    pub(crate) passthrough: bool,
+    pub(crate) concatenated: bool,
+    // joined by the concat demuxer instead of the concat filter
+    pub(crate) concat_demuxer: bool,
+    // usable inputs after ignoring the unsupported ones
+    pub(crate) input_count: usize,
+    // sum of the input durations with the output range applied, known before ffmpeg runs
+    pub(crate) predicted_duration: f64,
+    pub(crate) predicted_vmaf: Option<f64>,
+    // summary stats ffmpeg prints at the end, none when the line isn't found
+    pub(crate) frame_count: Option<u64>,
+    pub(crate) bitrate_kbps: Option<f64>,
+    pub(crate) muxing_overhead_percent: Option<f64>,
+    pub(crate) reference_vmaf: Option<f64>,
+    pub(crate) renditions: Vec<RenditionReport>,
+    // major and minor, ab-av1 is none when the crf search didn't run
+    pub(crate) ffmpeg_version: (u8, u8),
+    pub(crate) ab_av1_version: Option<(u8, u8)>,
+    // vmaf at min crf, only when the search fell back and it was asked for
+    pub(crate) fallback_vmaf: Option<f64>,
+    pub(crate) dropped_input_count: usize,
+    pub(crate) output_bytes: Option<u64>,
+    // final concat order, after dropping and deduping
+    pub(crate) input_paths: Vec<PathBuf>,
+    pub(crate) collapsed_input_count: usize,
+    pub(crate) thumbnail_sheet_path: Option<PathBuf>,
+    // in order, the vtt refers to them by file name
+    pub(crate) preview_sprite_paths: Vec<PathBuf>,
+    pub(crate) preview_vtt_path: Option<PathBuf>,
+    pub(crate) audio_sidecar_path: Option<PathBuf>,
+    // the crf is the min crf then
+    pub(crate) crf_search_timed_out: bool,
+    // in the concat order, for diagnosing unexpected crfs and sizes
+    pub(crate) input_complexities: Vec<InputComplexity>,
+    // none when not verified, or libvmaf wasn't there
+    pub(crate) measured_sample_vmaf: Option<f64>,
+    pub(crate) prediction_diverged: bool,
+    // the output was up to date with the inputs, this is the report kept from its encode
+    pub(crate) skipped: bool,
+}
 
-    fn evauate_test_cases(test_cases: Vec<(Vec<&str>, &str, u8, u8, bool, f64, u8, bool)>) {
-        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
-        let root_path = Path::new(&root_path);
-        let video_dir_path = root_path.join("tests/videos");
-        let output_dir_path = root_path.join("output");
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct InputComplexity {
+    pub(crate) path: PathBuf,
+    pub(crate) bits_per_pixel: Option<f64>,
+    // average luma difference between frames, only when signalstats is measured
+    pub(crate) temporal_difference: Option<f64>,
+}
 
-        for (input_filenames, output_filename, vmaf, crf, expected_result, expected_duration, expected_crf, expected_crf_found) in test_cases {
-            let input_paths = input_filenames.iter().map(|filename| { video_dir_path.join(filename) }).collect::<Vec<_>>();
-            let output_path = output_dir_path.join(&output_filename);
-            let (actual_result, actual_crf, actual_crf_found) = match encode_best_effort(input_paths, &output_path, vmaf, crf) {
-                Ok((crf, predicted_vmaf)) => {
-                    (true, crf, predicted_vmaf.is_some())
-                },
-                Err(err) => {
-                    log::trace!("test_encode_best_effort() case {:?} error {:?}", (input_filenames, output_filename, vmaf, crf, expected_result), err);
-                    (false, 0, false)
-                },
-            };
-            assert_eq!(actual_result, expected_result);
-            assert_eq!(actual_crf_found, expected_crf_found);
-            assert_eq!(actual_crf, expected_crf);
-            if actual_result {
-                let ffprobe::FfProbe { format, streams } = ffprobe::ffprobe(&output_path).unwrap();
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RenditionReport {
+    pub(crate) height: i64,
+    pub(crate) crf: u8,
+    pub(crate) path: PathBuf,
+}
 
-                let video_stream = get_first_video_stream(&streams).unwrap();
-                let actual_duration = get_stream_duration(&video_stream, &format).unwrap();
-                assert_eq!((actual_duration * 10.0).round(), expected_duration * 10.0);
+#[derive(Debug, Default)]
+struct InputFile {
+    path: PathBuf,
+    // display resolution, already swapped when rotated by 90 or 270 degrees
+    width: i64,
+    height: i64,
+    rotation: i64,
+    // n of v:n, cover art streams may come before the real video
+    video_stream_position: usize,
+    video_codec_name: Option<String>,
+    frame_rate: String,
+    pix_fmt: Option<String>,
+    duration: Option<f64>,
+    bit_rate: Option<i64>,
+    // first audio stream parameters, none for video only inputs
+    audio_sample_rate: Option<i64>,
+    audio_channels: Option<i64>,
+    audio_codec_name: Option<String>,
+    alternative_null_audio_duration: Option<f64>,
+    // timeline mode only
+    start_offset: Option<f64>,
+    // seconds of the source kept by the edge trim, duration is already the trimmed one
+    trim_range: Option<(f64, f64)>,
+    // of the frames entering the filter graph, none when unknown and taken as square
+    sample_aspect_ratio: Option<(i64, i64)>,
+    // none for the normal speed, duration is already the sped up one
+    speed: Option<f64>,
+    color: ColorProperties,
+    // none for in sync, positive delays the audio and negative advances it
+    audio_delay_ms: Option<i64>,
+}
 
-                if let Some(audio_stream) = get_first_audio_stream(&streams) {
-                    let actual_duration = get_stream_duration(&audio_stream, &format).unwrap();
-                    assert_eq!((actual_duration * 10.0).round(), expected_duration * 10.0);
-                };
+#[cfg(test)]
+mod test_input_file {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        // just coverage for debug trait
+        assert!(!format!("{:?}", InputFile { path: PathBuf::from("."), width: 1, height: 2, rotation: 0, video_stream_position: 0, video_codec_name: None, frame_rate: "30/1".to_string(), pix_fmt: None, duration: None, bit_rate: None, audio_sample_rate: None, audio_channels: None, audio_codec_name: None, alternative_null_audio_duration: None, start_offset: None, trim_range: None, sample_aspect_ratio: None, speed: None, color: ColorProperties::default(), audio_delay_ms: None }).is_empty());
+    }
+}
+
+pub(crate) fn encode_best_effort(input_video_paths: Vec<PathBuf>, output_video_path: impl AsRef<Path>, enough_vmaf: f64, min_crf: u8, options: &EncodeOptions) -> Result<EncodeReport, Error> {
+    let output_video_path = output_video_path.as_ref();
+    let inputs_hash = get_policy_inputs_hash(&input_video_paths, options.overwrite_policy);
+    let output_record = load_output_record(output_video_path);
+    let stored_inputs_hash = output_record.as_ref().map(|output_record| output_record.inputs_hash.as_str());
+    match get_overwrite_decision(options.overwrite_policy, output_video_path.exists(), stored_inputs_hash, &inputs_hash) {
+        OverwriteDecision::Write => {},
+        OverwriteDecision::Refuse => {
+            log::trace!("encode_best_effort() -> Error(OutputExists({:?}))", output_video_path);
+            return Err(Error { kind: ErrorKind::OutputExists(output_video_path.to_path_buf()) });
+        },
+        OverwriteDecision::UpToDate => {
+            log::info!("Output up to date with the inputs, skip the encode: {:}", output_video_path.display());
+            // up to date only with a record, so the report is there
+            let report = output_record.map(|output_record| output_record.report).unwrap_or_default();
+            return Ok(EncodeReport { skipped: true, ..report });
+        },
+    }
+
+    match encode_best_effort_impl(FFMPEG_CMD_STR, AB_AV1_CMD_STR, input_video_paths, output_video_path, enough_vmaf, min_crf, options) {
+        // whatever failed after the cancel, most likely a killed command
+        Err(err) if is_cancelled(options) => {
+            log::trace!("encode_best_effort() -> Error(Cancelled): {:?}", &err.kind);
+            Err(Error { kind: ErrorKind::Cancelled })
+        },
+        Ok(report) if options.overwrite_policy == OverwritePolicy::IfDifferentInputs => {
+            let output_record_path = get_output_record_path(output_video_path);
+            let output_record = OutputRecord { inputs_hash, report };
+            if let Err(err) = std::fs::write(&output_record_path, serde_json::to_string(&output_record).unwrap()) {
+                log::warn!("Couldn't write the output record, the next run encodes again: {:} ({:})", output_record_path.display(), err);
             }
-        }
+            Ok(output_record.report)
+        },
+        result => result,
     }
+}
 
+// whether the encode writes the output, for the caller to know what it may remove before the encode runs
+pub(crate) fn will_write_output(input_video_paths: &[PathBuf], output_video_path: &Path, overwrite_policy: OverwritePolicy) -> bool {
+    let inputs_hash = get_policy_inputs_hash(input_video_paths, overwrite_policy);
+    let stored_inputs_hash = load_output_record(output_video_path).map(|output_record| output_record.inputs_hash);
+    get_overwrite_decision(overwrite_policy, output_video_path.exists(), stored_inputs_hash.as_deref(), &inputs_hash) == OverwriteDecision::Write
 }
 
-fn check_command(expected_major_version: u8, min_minor_version: u8, cmd: &str, args: &[&str], re: &Regex) -> Result<(), Error> {
-    let mut cmd = Command::new(cmd);
-    cmd.args(args);
-    let output = match cmd.output() {
-        Ok(output) => output,
-        Err(err) => return Err(Error { kind: ErrorKind::VersionCheckCommandProcessFailed(err.to_string()) }),
-    };
+#[cfg(test)]
+mod test_will_write_output {
+    use super::*;
+    use std::env;
 
-    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-    let Some(caps) = re.captures(&stdout) else {
-        return Err(Error { kind: ErrorKind::VersionOutputNotMatched(stdout) });
-    };
-    assert!(caps.len() >= 2);
+    #[test]
+    fn it_works() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let dir = Path::new(&root_path).join("output/test_will_write_output");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let input_paths = [dir.join("a.mp4")];
+        std::fs::write(&input_paths[0], "a").unwrap();
+        let output_path = dir.join("out.mp4");
 
-    let major_version = parse_number::<u8, _>(&caps[1], Error { kind: ErrorKind::VersionNotValidInteger(caps[1].to_string()) })?;
-    let minor_version = parse_number::<u8, _>(&caps[2], Error { kind: ErrorKind::VersionNotValidInteger(caps[2].to_string()) })?;
+        assert!(will_write_output(&input_paths, &output_path, OverwritePolicy::Never));
+        std::fs::write(&output_path, "existing").unwrap();
+        assert!(will_write_output(&input_paths, &output_path, OverwritePolicy::Always));
+        assert!(!will_write_output(&input_paths, &output_path, OverwritePolicy::Never));
+        assert!(will_write_output(&input_paths, &output_path, OverwritePolicy::IfDifferentInputs));
 
-    if expected_major_version != major_version || minor_version < min_minor_version {
-        return Err(Error { kind: ErrorKind::NotSupportedCommandVersion(major_version, minor_version) });
-    };
+        let output_record = OutputRecord { inputs_hash: get_inputs_hash(&input_paths), report: EncodeReport::default() };
+        std::fs::write(get_output_record_path(&output_path), serde_json::to_string(&output_record).unwrap()).unwrap();
+        assert!(!will_write_output(&input_paths, &output_path, OverwritePolicy::IfDifferentInputs));
+        std::fs::write(&input_paths[0], "b").unwrap();
+        assert!(will_write_output(&input_paths, &output_path, OverwritePolicy::IfDifferentInputs));
+    }
+}
 
-    Ok(())
+#[derive(Debug, PartialEq)]
+enum OverwriteDecision {
+    Write,
+    Refuse,
+    UpToDate,
+}
+
+fn get_overwrite_decision(overwrite_policy: OverwritePolicy, output_exists: bool, stored_inputs_hash: Option<&str>, inputs_hash: &str) -> OverwriteDecision {
+    if !output_exists {
+        return OverwriteDecision::Write;
+    }
+    match overwrite_policy {
+        OverwritePolicy::Always => OverwriteDecision::Write,
+        OverwritePolicy::Never => OverwriteDecision::Refuse,
+        OverwritePolicy::IfDifferentInputs if stored_inputs_hash == Some(inputs_hash) => OverwriteDecision::UpToDate,
+        OverwritePolicy::IfDifferentInputs => OverwriteDecision::Write,
+    }
 }
 
 #[cfg(test)]
-mod test_check_command {
+mod test_get_overwrite_decision {
     use super::*;
 
     #[test]
     fn it_works() {
         let test_cases = [
-            (6, 0, "ffmpeg", "-version", FFMPEG_STDOUT_RETRIEVE_VERSION_REGEX_SOURCE, true),
-            (0, 7, "ab-av1", "--version", AB_AV1_STDOUT_RETRIEVE_VERSION_REGEX_SOURCE, true),
-            (0, 0, "__command_not_found__", "__unused__", r".", false),
-            (0, 0, "echo", "0.0", r"__not_matched__", false),
-            (0, 0, "echo", "0.0", r"^(\d+)\.(\d+)", true),
-            (5, 5, "echo", "5.5", r"^(\d+)\.(\d+)", true),
-            (5, 5, "echo", "4.5", r"^(\d+)\.(\d+)", false),
-            (5, 5, "echo", "6.5", r"^(\d+)\.(\d+)", false),
-            (5, 5, "echo", "5.6", r"^(\d+)\.(\d+)", true),
-            (5, 5, "echo", "5.4", r"^(\d+)\.(\d+)", false),
-            (255, 255, "echo", "255.256", r"^(\d+)\.(\d+)", false), // too big
-            (255, 255, "echo", "256.255", r"^(\d+)\.(\d+)", false), // too big
-            (255, 255, "echo", "255.255", r"^(\d+)\.(\d+)", true),
+            (OverwritePolicy::Always, false, None, OverwriteDecision::Write),
+            (OverwritePolicy::Always, true, Some("a"), OverwriteDecision::Write),
+            (OverwritePolicy::Never, false, None, OverwriteDecision::Write),
+            (OverwritePolicy::Never, true, None, OverwriteDecision::Refuse),
+            (OverwritePolicy::Never, true, Some("a"), OverwriteDecision::Refuse),
+            (OverwritePolicy::IfDifferentInputs, false, Some("a"), OverwriteDecision::Write),
+            (OverwritePolicy::IfDifferentInputs, true, Some("a"), OverwriteDecision::UpToDate),
+            (OverwritePolicy::IfDifferentInputs, true, Some("b"), OverwriteDecision::Write),
+            // made before the hash was kept, or by another policy
+            (OverwritePolicy::IfDifferentInputs, true, None, OverwriteDecision::Write),
         ];
-
-        for (expected_major_version, min_minor_version, cmd, arg, re, expected) in test_cases {
-            let re = Regex::new(re).unwrap();
-            let actual = check_command(expected_major_version, min_minor_version, cmd, &[arg], &re).is_ok();
-            assert_eq!(actual, expected);
+        for (overwrite_policy, output_exists, stored_inputs_hash, expected) in test_cases {
+            assert_eq!(get_overwrite_decision(overwrite_policy, output_exists, stored_inputs_hash, "a"), expected);
         }
     }
 }
 
-fn analyze_video_file(path: impl AsRef<Path>) -> Option<InputFile> {
-    let path = path.as_ref();
-    let ffprobe::FfProbe { format, streams } = match ffprobe::ffprobe(&path) {
-        Ok(ffprobe_info) => ffprobe_info,
-        Err(err) => {
-            log::warn!("Video file not support, ignored: {:} ({:})", path.display(), err);
-            return None;
-        },
-    };
+// -n makes ffmpeg fail rather than overwrite, on top of the check before the encode
+fn get_overwrite_flag(overwrite_policy: OverwritePolicy) -> &'static str {
+    match overwrite_policy {
+        OverwritePolicy::Never => "-n",
+        OverwritePolicy::Always | OverwritePolicy::IfDifferentInputs => "-y",
+    }
+}
 
-    analyze_video_file_impl(path, format, streams)
+#[cfg(test)]
+mod test_get_overwrite_flag {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_overwrite_flag(OverwritePolicy::Always), "-y");
+        assert_eq!(get_overwrite_flag(OverwritePolicy::Never), "-n");
+        assert_eq!(get_overwrite_flag(OverwritePolicy::IfDifferentInputs), "-y");
+    }
 }
 
-// separate impl for test
-fn analyze_video_file_impl(path: &Path, format: ffprobe::Format, streams: Vec<ffprobe::Stream>) -> Option<InputFile> {
-    let Some(video_stream) = get_first_video_stream(&streams) else {
-        log::warn!("No video stream in file, ignored: {:}", path.display());
-        return None;
-    };
+// kept next to the output by the if-different-inputs policy, the report is what an unchanged run returns
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct OutputRecord {
+    inputs_hash: String,
+    report: EncodeReport,
+}
 
-    let (Some(width), Some(height)) = (video_stream.width, video_stream.height) else {
-        log::warn!("Couldn't get video resolution, ignored: {:}", path.display());
-        return None;
-    };
+fn get_output_record_path(output_video_path: &Path) -> PathBuf {
+    PathBuf::from(format!("{:}.record.json", output_video_path.display()))
+}
 
-    if width < 0 || height < 0 {
-        log::warn!("Invalid resolution, ignored: {:} ({:}, {:})", path.display(), width, height);
-        return None;
-    };
+// none when missing or unreadable, the output is encoded again then
+fn load_output_record(output_video_path: &Path) -> Option<OutputRecord> {
+    let output_record_json = std::fs::read_to_string(get_output_record_path(output_video_path)).ok()?;
+    serde_json::from_str(&output_record_json).ok()
+}
 
-    
-    let alternative_null_audio_duration = match get_first_audio_stream(&streams) {
-        Some(_) => None,
-        None => {
-            let Some(video_duration) = get_stream_duration(&video_stream, &format) else {
-                log::warn!("Couldn't get video duration, ignored: {:}", path.display());
-                return None;
-            };
-            Some(video_duration)
-        },
-    };
+const FNV1A_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+
+fn update_fnv1a_hash(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// fnv-1a, stable across builds unlike DefaultHasher
+pub(crate) fn get_fnv1a_hash(s: &str) -> String {
+    format!("{:016x}", update_fnv1a_hash(FNV1A_OFFSET_BASIS, s.as_bytes()))
+}
+
+// the paths and contents in order, the inputs are downloaded again on each run so their modified times never match
+fn get_inputs_hash(input_video_paths: &[PathBuf]) -> String {
+    let mut hash = FNV1A_OFFSET_BASIS;
+    let mut buffer = vec![0u8; 1 << 16];
+    for input_video_path in input_video_paths {
+        hash = update_fnv1a_hash(hash, format!("{:}\0", input_video_path.display()).as_bytes());
+        // a missing input hashes as empty, the encode reports it anyway
+        if let Ok(mut file) = std::fs::File::open(input_video_path) {
+            while let Ok(read_size @ 1..) = file.read(&mut buffer) {
+                hash = update_fnv1a_hash(hash, &buffer[..read_size]);
+            }
+        }
+        hash = update_fnv1a_hash(hash, b"\n");
+    }
+    format!("{:016x}", hash)
+}
 
-    Some(InputFile { path: path.into(), width, height, alternative_null_audio_duration })
+// only the if-different-inputs policy compares it, and hashing reads every input through
+fn get_policy_inputs_hash(input_video_paths: &[PathBuf], overwrite_policy: OverwritePolicy) -> String {
+    match overwrite_policy {
+        OverwritePolicy::IfDifferentInputs => get_inputs_hash(input_video_paths),
+        OverwritePolicy::Always | OverwritePolicy::Never => String::new(),
+    }
 }
 
 #[cfg(test)]
-mod test_analyze_video_file {
+mod test_get_inputs_hash {
     use super::*;
     use std::env;
 
@@ -368,124 +888,5195 @@ mod test_analyze_video_file {
     fn it_works() {
         let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
         let root_path = Path::new(&root_path);
-        let video_dir_path = root_path.join("tests/videos");
+        let dir = root_path.join("output/test_get_inputs_hash");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let a_path = dir.join("a.mp4");
+        let b_path = dir.join("b.mp4");
+        std::fs::write(&a_path, "a").unwrap();
+        std::fs::write(&b_path, "b").unwrap();
 
-        let path = video_dir_path.join("va-300x400.mp4");
-        assert!(analyze_video_file(&path).is_some());
+        let hash = get_inputs_hash(&[a_path.clone(), b_path.clone()]);
+        assert_eq!(hash.len(), 16);
+        assert_eq!(get_inputs_hash(&[a_path.clone(), b_path.clone()]), hash);
+        assert_ne!(get_inputs_hash(&[b_path.clone(), a_path.clone()]), hash);
+        assert_ne!(get_inputs_hash(std::slice::from_ref(&a_path)), hash);
 
-        let ffprobe::FfProbe { mut format, streams } = ffprobe::ffprobe(&path).unwrap();
+        // downloaded again with the same contents
+        std::fs::File::options().write(true).open(&a_path).unwrap().set_modified(std::time::UNIX_EPOCH + Duration::from_secs(1)).unwrap();
+        assert_eq!(get_inputs_hash(&[a_path.clone(), b_path.clone()]), hash);
 
-        let mut video_stream = get_first_video_stream(&streams).unwrap().clone();
-        let audio_stream = get_first_audio_stream(&streams).unwrap().clone();
+        // a changed input
+        std::fs::write(&b_path, "bb").unwrap();
+        assert_ne!(get_inputs_hash(&[a_path, b_path]), hash);
+    }
+}
 
-        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()]).is_some());
-        assert!(analyze_video_file_impl(&path, format.clone(), vec![audio_stream.clone()]).is_none());
+// keeps the first of each run of the same path
+fn collapse_consecutive_duplicates(input_files: Vec<InputFile>) -> (Vec<InputFile>, usize) {
+    let mut collapsed_input_files: Vec<InputFile> = Vec::new();
+    let mut collapsed_input_count = 0;
+    for input_file in input_files {
+        if collapsed_input_files.last().is_some_and(|last_input_file| last_input_file.path == input_file.path && last_input_file.speed == input_file.speed && last_input_file.audio_delay_ms == input_file.audio_delay_ms) {
+            log::warn!("Consecutive duplicate input collapsed: {:}", input_file.path.display());
+            collapsed_input_count += 1;
+            continue;
+        }
+        collapsed_input_files.push(input_file);
+    }
+    (collapsed_input_files, collapsed_input_count)
+}
 
-        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()]).is_some());
-        video_stream.width = None;
-        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()]).is_none());
-        video_stream.width = Some(300);
+#[cfg(test)]
+mod test_collapse_consecutive_duplicates {
+    use super::*;
 
-        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()]).is_some());
-        video_stream.height = None;
-        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()]).is_none());
-        video_stream.height = Some(400);
+    #[test]
+    fn it_works() {
+        let input_files = |paths: &[&str]| paths.iter().map(|path| InputFile { path: PathBuf::from(path), ..Default::default() }).collect::<Vec<_>>();
+        let test_cases = [
+            (vec!["0.mp4", "0.mp4", "1.mp4"], vec!["0.mp4", "1.mp4"], 1),
+            (vec!["0.mp4", "0.mp4", "0.mp4"], vec!["0.mp4"], 2),
+            // only consecutive ones
+            (vec!["0.mp4", "1.mp4", "0.mp4"], vec!["0.mp4", "1.mp4", "0.mp4"], 0),
+            (vec!["0.mp4", "1.mp4", "1.mp4", "0.mp4", "0.mp4"], vec!["0.mp4", "1.mp4", "0.mp4"], 2),
+            (vec![], vec![], 0),
+        ];
+        for (paths, expected_paths, expected_count) in test_cases {
+            let (collapsed_input_files, collapsed_input_count) = collapse_consecutive_duplicates(input_files(&paths));
+            assert_eq!(collapsed_input_files.iter().map(|input_file| input_file.path.to_str().unwrap()).collect::<Vec<_>>(), expected_paths);
+            assert_eq!(collapsed_input_count, expected_count);
+        }
+    }
+}
 
-        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()]).is_some());
-        video_stream.width = Some(-1);
-        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()]).is_none());
-        video_stream.width = Some(400);
+fn is_cancelled(options: &EncodeOptions) -> bool {
+    options.cancel.as_ref().is_some_and(|cancel| cancel.load(Ordering::SeqCst))
+}
 
-        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()]).is_some());
-        video_stream.height = Some(-1);
-        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()]).is_none());
-        video_stream.height = Some(400);
+// between phases, so a cancel doesn't wait for the next command to start
+fn check_cancelled(options: &EncodeOptions) -> Result<(), Error> {
+    if is_cancelled(options) {
+        log::trace!("encode_best_effort() -> Error(Cancelled)");
+        return Err(Error { kind: ErrorKind::Cancelled });
+    }
+    Ok(())
+}
 
-        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()]).is_some());
-        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()]).unwrap().alternative_null_audio_duration.is_none());
-        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone()]).is_some());
-        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone()]).unwrap().alternative_null_audio_duration.is_some());
+#[cfg(test)]
+mod test_check_cancelled {
+    use super::*;
 
-        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone()]).is_some());
-        format.duration = None;
-        video_stream.duration = None;
-        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone()]).is_none());
+    #[test]
+    fn it_works() {
+        assert_eq!(check_cancelled(&EncodeOptions::default()), Ok(()));
+        let cancel = Arc::new(AtomicBool::new(false));
+        let options = EncodeOptions { cancel: Some(cancel.clone()), ..Default::default() };
+        assert_eq!(check_cancelled(&options), Ok(()));
+        cancel.store(true, Ordering::SeqCst);
+        assert_eq!(check_cancelled(&options), Err(Error { kind: ErrorKind::Cancelled }));
     }
 }
 
-fn get_avfilter_code(input_files: &Vec<InputFile>) -> String {
-    let mut filter_code = String::new();
-    let mut concat_input_part_filter_code = String::new();
+// runs on the blocking thread pool, so the runtime keeps driving other tasks during the encode
+pub(crate) async fn encode_best_effort_async(input_video_paths: Vec<PathBuf>, output_video_path: PathBuf, enough_vmaf: f64, min_crf: u8, options: EncodeOptions) -> Result<EncodeReport, Error> {
+    let task = tokio::task::spawn_blocking(move || {
+        encode_best_effort(input_video_paths, output_video_path, enough_vmaf, min_crf, &options)
+    });
+    match task.await {
+        Ok(result) => result,
+        Err(err) => {
+            log::trace!("encode_best_effort_async() -> Error(EncodeTaskJoinFailed({:?}))", &err);
+            Err(Error { kind: ErrorKind::EncodeTaskJoinFailed(err.to_string()) })
+        },
+    }
+}
 
-    assert!(0 < input_files.len());
+#[cfg(test)]
+mod test_encode_best_effort_async {
+    use super::*;
+    use std::env;
+
+    #[tokio::test]
+    async fn it_works() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
 
-    let target_width = input_files.iter().map(|input_file| { input_file.width }).max().expect("it must not be none, because input_files must not be 0");
-    let target_height = input_files.iter().map(|input_file| { input_file.height }).max().expect("it must not be none, because input_files must not be 0");
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), ..Default::default() };
+        let report = encode_best_effort_async(vec![video_dir_path.join("va-300x400.mp4")], output_dir_path.join("test_encode_best_effort_async.mp4"), 0.0, MAX_CRF - 2, options.clone()).await.unwrap();
+        assert_eq!(report.crf, Some(MAX_CRF));
 
-    for (index, input_file) in input_files.iter().enumerate() {
-        let part_video_filter_code = if input_file.width == target_width && input_file.height == target_height {
-            "null".to_string()
-        } else if input_file.width * target_height == input_file.height * target_width {
-            // same aspect ratio
-            format!("scale={:}:{:}", target_width, target_height)
+        assert!(matches!(encode_best_effort_async(vec![video_dir_path.join("a.mp4")], output_dir_path.join("test_encode_best_effort_async.mp4"), 0.0, MAX_CRF - 2, options).await, Err(Error { kind: ErrorKind::AllInputsAudioOnly(_) })));
+    }
+}
+
+// separate impl for test
+fn encode_best_effort_impl(cmd_str: &str, ab_av1_cmd_str: &str, input_video_paths: Vec<PathBuf>, output_video_path: impl AsRef<Path>, enough_vmaf: f64, min_crf: u8, options: &EncodeOptions) -> Result<EncodeReport, Error> {
+    log::trace!("encode_best_effort(): {:?}", (&input_video_paths, output_video_path.as_ref(), enough_vmaf, min_crf, options));
+    let output_video_path = output_video_path.as_ref();
+
+    let ffmpeg_version = check_command(6, 0, FFMPEG_CMD_STR, &["-version"], &FFMPEG_STDOUT_RETRIEVE_VERSION_REGEX)?;
+    if let Some(fixed_crf) = options.fixed_crf {
+        if MAX_CRF < fixed_crf {
+            log::trace!("encode_best_effort() -> Error(InvalidCrf({:?}))", fixed_crf);
+            return Err(Error { kind: ErrorKind::InvalidCrf(fixed_crf) });
+        }
+    }
+    if let Some(tune) = options.tune {
+        if MAX_SVTAV1_TUNE < tune {
+            log::trace!("encode_best_effort() -> Error(InvalidTune({:?}))", tune);
+            return Err(Error { kind: ErrorKind::InvalidTune(tune) });
+        }
+    }
+    if let Some(av1_level) = &options.av1_level {
+        if !AV1_LEVELS.contains(&av1_level.as_str()) {
+            log::trace!("encode_best_effort() -> Error(InvalidAv1Level({:?}))", av1_level);
+            return Err(Error { kind: ErrorKind::InvalidAv1Level(av1_level.clone()) });
+        }
+    }
+    if let Some(av1_tier) = &options.av1_tier {
+        if get_svtav1_tier_value(av1_tier).is_none() {
+            log::trace!("encode_best_effort() -> Error(InvalidAv1Tier({:?}))", av1_tier);
+            return Err(Error { kind: ErrorKind::InvalidAv1Tier(av1_tier.clone()) });
+        }
+    }
+    if let Some(output_container) = options.output_container {
+        if !output_container.supports_av1_opus() {
+            log::trace!("encode_best_effort() -> Error(UnsupportedOutputContainer({:?}))", output_container);
+            return Err(Error { kind: ErrorKind::UnsupportedOutputContainer(output_container) });
+        }
+    }
+    if let Some(watermark) = &options.watermark {
+        if !watermark.path.is_file() {
+            log::trace!("encode_best_effort() -> Error(WatermarkNotFound({:?}))", &watermark.path);
+            return Err(Error { kind: ErrorKind::WatermarkNotFound(watermark.path.clone()) });
+        }
+    }
+    if let Some(captions_path) = &options.captions_path {
+        if !captions_path.is_file() {
+            log::trace!("encode_best_effort() -> Error(CaptionsNotFound({:?}))", captions_path);
+            return Err(Error { kind: ErrorKind::CaptionsNotFound(captions_path.clone()) });
+        }
+    }
+    if let Some(timeline) = &options.timeline {
+        if timeline.offsets.len() != input_video_paths.len() {
+            let reason = format!("{:} offsets for {:} inputs", timeline.offsets.len(), input_video_paths.len());
+            log::trace!("encode_best_effort() -> Error(InvalidTimeline({:?}))", reason);
+            return Err(Error { kind: ErrorKind::InvalidTimeline(reason) });
+        }
+        if let Some(offset) = timeline.offsets.iter().find(|offset| !(0.0..f64::INFINITY).contains(*offset)) {
+            let reason = format!("offset must be non-negative: {:}", offset);
+            log::trace!("encode_best_effort() -> Error(InvalidTimeline({:?}))", reason);
+            return Err(Error { kind: ErrorKind::InvalidTimeline(reason) });
+        }
+    }
+    if let Some(speeds) = &options.speeds {
+        if speeds.len() != input_video_paths.len() {
+            let reason = format!("{:} speeds for {:} inputs", speeds.len(), input_video_paths.len());
+            log::trace!("encode_best_effort() -> Error(InvalidSpeeds({:?}))", reason);
+            return Err(Error { kind: ErrorKind::InvalidSpeeds(reason) });
+        }
+        if let Some(speed) = speeds.iter().find(|speed| !(speed.is_finite() && 0.0 < **speed)) {
+            let reason = format!("speed must be positive: {:}", speed);
+            log::trace!("encode_best_effort() -> Error(InvalidSpeeds({:?}))", reason);
+            return Err(Error { kind: ErrorKind::InvalidSpeeds(reason) });
+        }
+    }
+    if let Some(audio_delays_ms) = &options.audio_delays_ms {
+        if audio_delays_ms.len() != input_video_paths.len() {
+            let reason = format!("{:} audio delays for {:} inputs", audio_delays_ms.len(), input_video_paths.len());
+            log::trace!("encode_best_effort() -> Error(InvalidAudioDelays({:?}))", reason);
+            return Err(Error { kind: ErrorKind::InvalidAudioDelays(reason) });
+        }
+    }
+    if let Some((start, end)) = options.output_range {
+        if !(0.0 <= start && start < end) {
+            log::trace!("encode_best_effort() -> Error(InvalidOutputRange({:?}, {:?}))", start, end);
+            return Err(Error { kind: ErrorKind::InvalidOutputRange(start, end) });
+        }
+    }
+
+    let mut input_files = Vec::new();
+    let mut dropped_input_video_paths = Vec::new();
+    let mut audio_only_input_count = 0;
+    let input_video_path_count = input_video_paths.len();
+    for (index, input_video_path) in input_video_paths.into_iter().enumerate() {
+        report_progress(options, ProgressEvent::new(ProgressPhase::Analysis, 100.0 * index as f64 / input_video_path_count as f64, Some(&input_video_path)));
+        match analyze_video_file(&input_video_path) {
+            Ok(mut input_file) => {
+                input_file.start_offset = options.timeline.as_ref().map(|timeline| timeline.offsets[index]);
+                input_file.speed = options.speeds.as_ref().map(|speeds| speeds[index]).filter(|speed| *speed != 1.0);
+                input_file.audio_delay_ms = options.audio_delays_ms.as_ref().map(|audio_delays_ms| audio_delays_ms[index]).filter(|audio_delay_ms| *audio_delay_ms != 0);
+                input_files.push(input_file);
+            },
+            Err(reason) if options.strict_inputs => {
+                log::trace!("encode_best_effort() -> Error(InputNotSupported({:?}, {:?}))", &input_video_path, &reason);
+                return Err(Error { kind: ErrorKind::InputNotSupported(input_video_path, reason) });
+            },
+            Err(reason) => {
+                log::warn!("Video file not support, ignored: {:} ({:?})", input_video_path.display(), reason);
+                if reason == IgnoreReason::AudioOnly {
+                    audio_only_input_count += 1;
+                }
+                dropped_input_video_paths.push(input_video_path);
+            },
+        };
+    }
+    report_progress(options, ProgressEvent::new(ProgressPhase::Analysis, 100.0, None));
+    let dropped_input_count = dropped_input_video_paths.len();
+    if input_files.is_empty() && 0 < audio_only_input_count && audio_only_input_count == dropped_input_count {
+        log::trace!("encode_best_effort() -> Error(AllInputsAudioOnly({:?}))", &dropped_input_video_paths);
+        return Err(Error { kind: ErrorKind::AllInputsAudioOnly(dropped_input_video_paths) });
+    }
+    if options.require_all_inputs && !dropped_input_video_paths.is_empty() {
+        log::trace!("encode_best_effort() -> Error(InputDropped({:?}))", &dropped_input_video_paths);
+        return Err(Error { kind: ErrorKind::InputDropped(dropped_input_video_paths) });
+    }
+    let (input_files, collapsed_input_count) = if options.dedupe_consecutive {
+        collapse_consecutive_duplicates(input_files)
+    } else {
+        (input_files, 0)
+    };
+    let mut input_files = input_files;
+    let input_paths = input_files.iter().map(|input_file| input_file.path.clone()).collect::<Vec<_>>();
+    check_cancelled(options)?;
+    if options.trim_black.is_some() || options.trim_silence.is_some() {
+        for input_file in input_files.iter_mut() {
+            let (start, end) = match detect_edge_trim_range(cmd_str, input_file, options) {
+                Ok(Some(trim_range)) => trim_range,
+                Ok(None) => continue,
+                Err(err) => {
+                    log::trace!("encode_best_effort() -> Error({:?})", &err.kind);
+                    return Err(err);
+                },
+            };
+            log::info!("Trim edges: {:} {:}-{:}", input_file.path.display(), start, end);
+            input_file.trim_range = Some((start, end));
+            input_file.duration = Some(end - start);
+            if input_file.alternative_null_audio_duration.is_some() {
+                input_file.alternative_null_audio_duration = Some(end - start);
+            }
+        }
+    }
+    // after the trim, which is in the source time
+    for input_file in input_files.iter_mut() {
+        if let Some(speed) = input_file.speed {
+            input_file.duration = input_file.duration.map(|duration| get_sped_duration(duration, speed));
+            input_file.alternative_null_audio_duration = input_file.alternative_null_audio_duration.map(|duration| get_sped_duration(duration, speed));
+            log::info!("Change speed: {:} x{:} ({:?}s)", input_file.path.display(), speed, input_file.duration);
+        }
+    }
+    for input_file in &input_files {
+        log::info!("Input: {:} {:}x{:} audio={:?} {:?}Hz {:?}ch", input_file.path.display(), input_file.width, input_file.height, input_file.audio_codec_name, input_file.audio_sample_rate, input_file.audio_channels);
+    }
+    let drops_audio = get_drops_audio(&input_files, options.audio_policy);
+    // the audio sidecar has nothing to take
+    let sidecar_free_options;
+    let options = if drops_audio {
+        log::info!("Drop audio: {:?}", options.audio_policy);
+        if options.emit_audio_sidecar {
+            log::warn!("Audio dropped, no audio sidecar");
+        }
+        sidecar_free_options = EncodeOptions { emit_audio_sidecar: false, ..options.clone() };
+        &sidecar_free_options
+    } else {
+        options
+    };
+    // all hdr alike inputs tag the output with their color instead of the sdr default
+    let hdr_color = get_hdr_color(&input_files);
+    if let Some(hdr_color) = &hdr_color {
+        log::info!("All inputs are hdr, keep the color: {:?}", hdr_color);
+    } else if input_files.iter().any(|input_file| input_file.color.transfer.as_deref().is_some_and(is_hdr_transfer)) {
+        log::warn!("Hdr mixed with other inputs, encode as sdr");
+    }
+    // the layouts don't meet when the audio is dropped
+    if !drops_audio {
+        if let Err(err) = check_audio_layout(&input_files, options) {
+            log::trace!("encode_best_effort() -> Error({:?})", &err.kind);
+            return Err(err);
+        }
+    }
+
+    let mut input_complexities = Vec::new();
+    for input_file in &input_files {
+        let temporal_difference = if options.measure_signalstats {
+            measure_temporal_difference(cmd_str, input_file, options)?
         } else {
-            format!("scale={0:}:{1:}:force_original_aspect_ratio=decrease,pad={0:}:{1:}:(ow-iw)/2:(oh-ih)/2", target_width, target_height)
+            None
         };
-        let filter_code_statement = format!("[{0:}:v:0]{1:}[v{0:}];", index, part_video_filter_code);
-        filter_code.push_str(&filter_code_statement);
-        log::info!("Add filter: {:}", filter_code_statement);
+        input_complexities.push(InputComplexity { path: input_file.path.clone(), bits_per_pixel: get_bits_per_pixel(input_file), temporal_difference });
+    }
+    if let Some(input_complexity) = input_complexities.iter().filter(|input_complexity| input_complexity.bits_per_pixel.is_some()).max_by(|a, b| a.bits_per_pixel.partial_cmp(&b.bits_per_pixel).unwrap()) {
+        log::info!("Most complex input: {:} bpp={:?} ydif={:?}", input_complexity.path.display(), input_complexity.bits_per_pixel, input_complexity.temporal_difference);
+    }
+
+    let needs_concatenation = match input_files.len() {
+        0 => {
+            log::trace!("encode_best_effort() -> Error(NoAvailableVideoStream): {:?}", (&input_files));
+            return Err(Error { kind: ErrorKind::NoAvailableVideoStream });
+        },
+        1 => false,
+        _ => true,
+    };
+
+    let total_duration = get_total_duration(&input_files);
+    if total_duration <= 0.0 {
+        log::trace!("encode_best_effort() -> Error(ZeroDurationOutput): {:?}", (&input_files, total_duration));
+        return Err(Error { kind: ErrorKind::ZeroDurationOutput });
+    }
+    if let Some(max_total_duration) = options.max_total_duration {
+        if max_total_duration < total_duration {
+            log::trace!("encode_best_effort() -> Error(TotalDurationExceeded): {:?}", (total_duration, max_total_duration));
+            return Err(Error { kind: ErrorKind::TotalDurationExceeded(total_duration, max_total_duration) });
+        }
+    }
+    let predicted_duration = get_output_duration(total_duration, options.output_range);
+    if predicted_duration <= 0.0 {
+        log::trace!("encode_best_effort() -> Error(ZeroDurationOutput): {:?}", (total_duration, options.output_range));
+        return Err(Error { kind: ErrorKind::ZeroDurationOutput });
+    }
+
+    let passthrough = options.allow_passthrough && match get_passthrough_blocker(&input_files, options, drops_audio) {
+        Some(blocker) => {
+            log::info!("Can't passthrough, encode: {:}", blocker);
+            false
+        },
+        None => true,
+    };
+    if passthrough {
+        log::info!("All inputs are av1 with matching parameters, concat without re-encoding");
+        report_progress(options, ProgressEvent::new(ProgressPhase::Encode, 0.0, Some(output_video_path)));
+        let report = concat_passthrough(cmd_str, &input_files, output_video_path, predicted_duration, options)?;
+        report_progress(options, ProgressEvent::new(ProgressPhase::Encode, 100.0, Some(output_video_path)));
+        let thumbnail_sheet_path = make_output_thumbnail_sheet(cmd_str, output_video_path, predicted_duration, report.frame_count, options)?;
+        let (preview_sprite_paths, preview_vtt_path) = make_output_preview_sprite(cmd_str, output_video_path, predicted_duration, options)?;
+        let report = EncodeReport { ffmpeg_version, dropped_input_count, collapsed_input_count, thumbnail_sheet_path, preview_sprite_paths, preview_vtt_path, input_complexities, ..report };
+        log::info!("{:}", get_summary_line(&report));
+        return Ok(report);
+    }
+
+    let mut auto_encode_ab_av1_version = None;
+    if options.auto_encode {
+        if let Some(blocker) = get_auto_encode_blocker(&input_files, options) {
+            log::info!("Can't auto-encode, search and encode separately: {:}", blocker);
+        } else {
+            let ab_av1_version = check_command(0, 7, ab_av1_cmd_str, &["--version"], &AB_AV1_STDOUT_RETRIEVE_VERSION_REGEX)?;
+            if ab_av1_version < AB_AV1_AUTO_ENCODE_MIN_VERSION {
+                log::info!("Can't auto-encode, ab-av1 too old: {:?}", ab_av1_version);
+            } else {
+                log::info!("Start auto-encode: {:} vmaf={:} crf={:}", input_files[0].path.display(), enough_vmaf, min_crf);
+                report_progress(options, ProgressEvent::new(ProgressPhase::Encode, 0.0, Some(output_video_path)));
+                if let Some((crf, predicted_vmaf)) = auto_encode_impl(ab_av1_cmd_str, &input_files[0].path, output_video_path, enough_vmaf, min_crf, options)? {
+                    report_progress(options, ProgressEvent { crf: Some(crf), vmaf: Some(predicted_vmaf), ..ProgressEvent::new(ProgressPhase::Encode, 100.0, Some(output_video_path)) });
+                    if !options.skip_output_validation {
+                        validate_output(output_video_path, predicted_duration)?;
+                    }
+                    let thumbnail_sheet_path = make_output_thumbnail_sheet(cmd_str, output_video_path, predicted_duration, None, options)?;
+                    let (preview_sprite_paths, preview_vtt_path) = make_output_preview_sprite(cmd_str, output_video_path, predicted_duration, options)?;
+                    let report = EncodeReport { crf: Some(crf), input_count: 1, predicted_duration, predicted_vmaf: Some(predicted_vmaf), ffmpeg_version, ab_av1_version: Some(ab_av1_version), dropped_input_count, output_bytes: get_file_size(output_video_path), input_paths, collapsed_input_count, thumbnail_sheet_path, preview_sprite_paths, preview_vtt_path, input_complexities, ..Default::default() };
+                    log::info!("{:}", get_summary_line(&report));
+                    log::trace!("encode_best_effort() -> Ok({:?})", report);
+                    return Ok(report);
+                }
+                // none found, the normal encode runs next at min crf without searching again
+                auto_encode_ab_av1_version = Some(ab_av1_version);
+            }
+        }
+    }
+
+    // renditions split the filtered source, memory limit may downscale it, watermark overlays it and timeline offsets it, so they need the filter graph even for a single input
+    // captions need explicit maps, which the filter graph outputs give
+    let options_need_filter_graph = !options.renditions.is_empty() || options.memory_limit_mb.is_some() || options.aspect_ratio.is_some() || options.watermark.is_some() || options.timeline.is_some() || options.captions_path.is_some() || options.emit_audio_sidecar || options.fade_in.is_some() || options.fade_out.is_some() || options.denoise.is_some() || input_files.iter().any(|input_file| input_file.trim_range.is_some() || input_file.speed.is_some() || input_file.audio_delay_ms.is_some());
+    // inputs that decode to the same frames join before the encoder, without the per input chains
+    let uses_concat_demuxer = needs_concatenation && !options_need_filter_graph && can_concat_demuxer(&input_files, drops_audio);
+    let needs_filter_graph = (needs_concatenation && !uses_concat_demuxer) || options_need_filter_graph;
+
+    let mut ffmpeg_cmd = Command::new(cmd_str);
+    ffmpeg_cmd.arg(get_overwrite_flag(options.overwrite_policy));
+
+    // written right before ffmpeg runs, so an early error leaves nothing behind
+    let list_path = PathBuf::from(format!("{:}.concat.txt", output_video_path.display()));
+    let input_count = if uses_concat_demuxer {
+        log::info!("Inputs share the codec parameters, concat with the demuxer");
+        ffmpeg_cmd.args(["-fflags", "+genpts", "-f", "concat", "-safe", "0", "-i"]).arg(&list_path);
+        1
+    } else {
+        for input_file in &input_files {
+            ffmpeg_cmd.args(get_input_flag_args(options));
+            ffmpeg_cmd.arg("-i");
+            ffmpeg_cmd.arg(&input_file.path);
+        }
+        input_files.len()
+    };
+    // after the video inputs, so the filter graph input indexes stay the same
+    let captions_map = options.captions_path.as_ref().map(|captions_path| {
+        ffmpeg_cmd.arg("-i").arg(captions_path);
+        format!("{:}:s:0", input_count)
+    });
+    let chapters_path = PathBuf::from(format!("{:}.chapters.txt", output_video_path.display()));
+    let chapters_map = if options.chapters {
+        ffmpeg_cmd.args(["-f", "ffmetadata", "-i"]).arg(&chapters_path);
+        Some((input_count + captions_map.iter().count()).to_string())
+    } else {
+        None
+    };
+
+    for rendition in &options.renditions {
+        if let Some(crf) = rendition.crf {
+            if MAX_CRF < crf {
+                log::trace!("encode_best_effort() -> Error(InvalidCrf({:?}))", crf);
+                return Err(Error { kind: ErrorKind::InvalidCrf(crf) });
+            }
+        }
+    }
+
+    if needs_filter_graph {
+        let mut filter_code = get_avfilter_code(&input_files, options);
+        if !options.renditions.is_empty() || options.emit_audio_sidecar {
+            filter_code.push_str(&get_rendition_split_filter_code(&options.renditions, !drops_audio, options.emit_audio_sidecar));
+        }
+        ffmpeg_cmd.args(["-filter_complex", &filter_code]);
+    }
+
+    assert!(0 < input_files.len());
+    let best_input_file = select_best_input_file(&input_files, options.best_input_metric);
+
+    let mut crf_search_timed_out = false;
+    let (best_crf, predicted_vmaf, fallback_vmaf, ab_av1_version) = if let Some(fixed_crf) = options.fixed_crf {
+        log::info!("Skip search crf, use fixed: {:}", fixed_crf);
+        (fixed_crf, None, None, None)
+    } else if let Some(ab_av1_version) = auto_encode_ab_av1_version {
+        log::info!("Suitable crf not found by auto-encode use min: {:}", min_crf);
+        (min_crf, None, None, Some(ab_av1_version))
+    } else {
+        let ab_av1_version = check_command(0, 7, ab_av1_cmd_str, &["--version"], &AB_AV1_STDOUT_RETRIEVE_VERSION_REGEX)?;
+        check_cancelled(options)?;
+        log::info!("Start search crf: {:} vmaf={:} crf={:}", best_input_file.path.display(), enough_vmaf, min_crf);
+        report_progress(options, ProgressEvent::new(ProgressPhase::CrfSearch, 0.0, Some(&best_input_file.path)));
+        if let Some(search_pixel_format) = options.search_pixel_format.filter(|search_pixel_format| *search_pixel_format != options.pixel_format) {
+            log::warn!("Search pixel format differs from the encode, predicted vmaf is approximate: {:} != {:}", search_pixel_format.as_ffmpeg_value(), options.pixel_format.as_ffmpeg_value());
+        }
+        if options.denoise.is_some() {
+            log::warn!("Crf search runs without the denoise, predicted vmaf is approximate");
+        }
+        let (best_crf, predicted_vmaf, timed_out) = get_best_crf_or_fallback(ab_av1_cmd_str, &best_input_file.path, enough_vmaf, min_crf, options)?;
+        crf_search_timed_out = timed_out;
+        let (best_crf, predicted_vmaf) = match predicted_vmaf {
+            Some(predicted_vmaf) if options.prefer_smaller => {
+                let (best_crf, predicted_vmaf) = get_smaller_crf_impl(ab_av1_cmd_str, &best_input_file.path, enough_vmaf, best_crf, predicted_vmaf, options)?;
+                (best_crf, Some(predicted_vmaf))
+            },
+            _ => (best_crf, predicted_vmaf),
+        };
+        if let Some(predicted_vmaf) = predicted_vmaf {
+            log::info!("Crf found: {:} (vmaf={:})", best_crf, predicted_vmaf);
+        } else {
+            log::info!("Suitable crf not found use min: {:}", best_crf);
+        };
+        report_progress(options, ProgressEvent { crf: Some(best_crf), vmaf: predicted_vmaf, ..ProgressEvent::new(ProgressPhase::CrfSearch, 100.0, Some(&best_input_file.path)) });
+        let fallback_vmaf = if predicted_vmaf.is_none() && (options.measure_fallback_vmaf || options.hard_floor_vmaf.is_some()) {
+            let fallback_vmaf = get_sample_vmaf_impl(ab_av1_cmd_str, &best_input_file.path, best_crf, options)?;
+            log::info!("Vmaf at min crf: {:}", fallback_vmaf);
+            if let Some(hard_floor_vmaf) = options.hard_floor_vmaf {
+                if fallback_vmaf < hard_floor_vmaf as f64 {
+                    log::trace!("encode_best_effort() -> Error(FallbackVmafBelowFloor): {:?}", (fallback_vmaf, hard_floor_vmaf));
+                    return Err(Error { kind: ErrorKind::FallbackVmafBelowFloor(fallback_vmaf, hard_floor_vmaf) });
+                }
+            }
+            Some(fallback_vmaf)
+        } else {
+            None
+        };
+        (best_crf, predicted_vmaf, fallback_vmaf, Some(ab_av1_version))
+    };
+
+    if !options.renditions.is_empty() {
+        ffmpeg_cmd.args(["-map", "[vmain]"]);
+        if !drops_audio {
+            ffmpeg_cmd.args(["-map", "[amain]"]);
+        }
+    } else if options.emit_audio_sidecar {
+        ffmpeg_cmd.args(["-map", "[vout]", "-map", "[amain]"]);
+    } else if needs_filter_graph {
+        ffmpeg_cmd.args(["-map", "[vout]"]);
+        if !drops_audio {
+            ffmpeg_cmd.args(["-map", "[aout]"]);
+        }
+    } else if drops_audio {
+        ffmpeg_cmd.arg("-an");
+    }
+    if let Some(captions_map) = &captions_map {
+        ffmpeg_cmd.args(["-map", captions_map]);
+    }
+    if let Some(chapters_map) = &chapters_map {
+        ffmpeg_cmd.args(["-map_chapters", chapters_map]);
+    }
+    // ffmpeg writes pass logs into the cwd by default, where concurrent jobs collide
+    let passlog_prefix = get_passlog_prefix(output_video_path);
+    ffmpeg_cmd.args(get_output_args(best_crf, output_video_path, options, hdr_color.as_ref()));
+    ffmpeg_cmd.arg("-passlogfile").arg(&passlog_prefix);
+    ffmpeg_cmd.arg(output_video_path);
+
+    let mut rendition_reports = Vec::new();
+    for (index, rendition) in options.renditions.iter().enumerate() {
+        let crf = rendition.crf.unwrap_or(best_crf);
+        let path = get_rendition_path(output_video_path, rendition.height);
+        log::info!("Add rendition: {:} (height={:}, crf={:})", path.display(), rendition.height, crf);
+        ffmpeg_cmd.args(["-map", &format!("[vrendition{:}]", index)]);
+        if !drops_audio {
+            ffmpeg_cmd.args(["-map", &format!("[arendition{:}]", index)]);
+        }
+        if let Some(captions_map) = &captions_map {
+            ffmpeg_cmd.args(["-map", captions_map]);
+        }
+        if let Some(chapters_map) = &chapters_map {
+            ffmpeg_cmd.args(["-map_chapters", chapters_map]);
+        }
+        ffmpeg_cmd.args(get_output_args(crf, &path, options, hdr_color.as_ref()));
+        ffmpeg_cmd.arg("-passlogfile").arg(format!("{:}-{:}p", passlog_prefix.display(), rendition.height));
+        ffmpeg_cmd.arg(&path);
+        rendition_reports.push(RenditionReport { height: rendition.height, crf, path });
+    }
+
+    let audio_sidecar_path = if options.emit_audio_sidecar {
+        let path = get_audio_sidecar_path(output_video_path);
+        log::info!("Add audio sidecar: {:}", path.display());
+        ffmpeg_cmd.args(["-map", "[asidecar]"]);
+        ffmpeg_cmd.args(get_audio_sidecar_output_args(options));
+        ffmpeg_cmd.arg(&path);
+        Some(path)
+    } else {
+        None
+    };
+
+    check_cancelled(options)?;
+    if uses_concat_demuxer {
+        if let Err(err) = std::fs::write(&list_path, get_concat_list_code(&input_files)) {
+            log::trace!("encode_best_effort() -> Error(ConcatListWriteFailed({:?}, {:?}))", &list_path, &err);
+            return Err(Error { kind: ErrorKind::ConcatListWriteFailed(list_path, err.to_string()) });
+        }
+    }
+    if chapters_map.is_some() {
+        if let Err(err) = std::fs::write(&chapters_path, get_chapters_metadata_code(&input_files)) {
+            log::trace!("encode_best_effort() -> Error(ChaptersWriteFailed({:?}, {:?}))", &chapters_path, &err);
+            return Err(Error { kind: ErrorKind::ChaptersWriteFailed(chapters_path, err.to_string()) });
+        }
+    }
+
+    log::info!("Predicted output duration: {:.3}s", predicted_duration);
+    log::info!("Start ffmpeg: {:?}", ffmpeg_cmd);
+    report_progress(options, ProgressEvent { crf: Some(best_crf), ..ProgressEvent::new(ProgressPhase::Encode, 0.0, Some(output_video_path)) });
+    let output = run_command_with_cancel(&mut ffmpeg_cmd, options.cancel.as_deref());
+    if uses_concat_demuxer {
+        let _ = std::fs::remove_file(&list_path);
+    }
+    if chapters_map.is_some() {
+        let _ = std::fs::remove_file(&chapters_path);
+    }
+    if options.keep_passlog {
+        log::info!("Pass log files kept: {:}*", passlog_prefix.display());
+    } else {
+        remove_passlog_files(&passlog_prefix);
+    }
+    let output = match output {
+        Ok(output) => output,
+        Err(err) => {
+            log::trace!("encode_best_effort() -> Error(FfmpegCommandProcessFailed({:?})): {:?}", &err, (&ffmpeg_cmd));
+            return Err(Error { kind: ErrorKind::FfmpegCommandProcessFailed(err.to_string()) });
+        },
+    };
+
+    // ffmpeg falls back to a supported pixel format with just a warning, which would silently lose 4:4:4
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    if let Some(pixel_format) = get_rejected_pixel_format(&stderr) {
+        log::trace!("encode_best_effort() -> Error(PixelFormatNotSupported({:?})): {:?}", &pixel_format, (&ffmpeg_cmd));
+        return Err(Error { kind: ErrorKind::PixelFormatNotSupported(pixel_format) });
+    }
+
+    if !output.status.success() {
+        let err = classify_ffmpeg_failure(output.status, stderr);
+        log::trace!("encode_best_effort() -> Error({:?}): {:?}", &err.kind, (&ffmpeg_cmd));
+        return Err(err);
+    }
+
+    let FfmpegSummary { frame_count, bitrate_kbps, muxing_overhead_percent } = parse_ffmpeg_summary(&stderr);
+    report_progress(options, ProgressEvent { crf: Some(best_crf), vmaf: predicted_vmaf, ..ProgressEvent::new(ProgressPhase::Encode, 100.0, Some(output_video_path)) });
+
+    check_cancelled(options)?;
+    if !options.skip_output_validation {
+        validate_output(output_video_path, predicted_duration)?;
+        for rendition_report in &rendition_reports {
+            validate_output(&rendition_report.path, predicted_duration)?;
+        }
+    }
+
+    let reference_vmaf = match &options.reference_video_path {
+        Some(reference_video_path) => {
+            log::info!("Start measure vmaf against reference: {:}", reference_video_path.display());
+            let reference_vmaf = measure_vmaf(output_video_path, reference_video_path)?;
+            if reference_vmaf < enough_vmaf {
+                log::warn!("Vmaf against reference is lower than enough vmaf: {:} < {:}", reference_vmaf, enough_vmaf);
+            } else {
+                log::info!("Vmaf against reference: {:}", reference_vmaf);
+            }
+            Some(reference_vmaf)
+        },
+        None => None,
+    };
+
+    // a diagnostic, so a failed measurement doesn't fail the encode
+    let (measured_sample_vmaf, prediction_diverged) = match predicted_vmaf.filter(|_| options.verify_prediction) {
+        Some(predicted_vmaf) => match get_verify_prediction_blocker(&input_files, best_input_file, options) {
+            Some(blocker) => {
+                log::info!("Can't verify the predicted vmaf: {:}", blocker);
+                (None, false)
+            },
+            None => {
+                let (output_start, reference_start, duration) = get_verify_prediction_sample(&input_files, best_input_file);
+                log::info!("Start measure sample vmaf: {:} {:}s+{:}s", best_input_file.path.display(), reference_start, duration);
+                match measure_sample_vmaf_impl(cmd_str, output_video_path, output_start, &best_input_file.path, reference_start, duration) {
+                    Ok(measured_vmaf) => {
+                        let divergence = get_prediction_divergence(predicted_vmaf, measured_vmaf, options.verify_prediction_max_delta);
+                        if let Some(divergence) = divergence {
+                            log::warn!("Measured vmaf diverges from the predicted: {:} vs {:} ({:+})", measured_vmaf, predicted_vmaf, divergence);
+                        } else {
+                            log::info!("Measured vmaf: {:} (predicted {:})", measured_vmaf, predicted_vmaf);
+                        }
+                        (Some(measured_vmaf), divergence.is_some())
+                    },
+                    Err(Error { kind: ErrorKind::VmafNotAvailable(_) }) => {
+                        log::warn!("Libvmaf not available, predicted vmaf not verified");
+                        (None, false)
+                    },
+                    Err(err) => {
+                        log::warn!("Couldn't measure sample vmaf, predicted vmaf not verified: {:?}", err.kind);
+                        (None, false)
+                    },
+                }
+            },
+        },
+        None => (None, false),
+    };
+
+    let thumbnail_sheet_path = make_output_thumbnail_sheet(cmd_str, output_video_path, predicted_duration, frame_count, options)?;
+    let (preview_sprite_paths, preview_vtt_path) = make_output_preview_sprite(cmd_str, output_video_path, predicted_duration, options)?;
+
+    let report = EncodeReport { crf: Some(best_crf), passthrough: false, concatenated: needs_concatenation, concat_demuxer: uses_concat_demuxer, input_count: input_files.len(), predicted_duration, predicted_vmaf, frame_count, bitrate_kbps, muxing_overhead_percent, reference_vmaf, renditions: rendition_reports, ffmpeg_version, ab_av1_version, fallback_vmaf, dropped_input_count, output_bytes: get_file_size(output_video_path), input_paths, collapsed_input_count, thumbnail_sheet_path, preview_sprite_paths, preview_vtt_path, audio_sidecar_path, crf_search_timed_out, input_complexities, measured_sample_vmaf, prediction_diverged, skipped: false };
+    log::info!("{:}", get_summary_line(&report));
+    log::trace!("encode_best_effort() -> Ok({:?})", report);
+    Ok(report)
+}
+
+fn get_file_size(path: &Path) -> Option<u64> {
+    std::fs::metadata(path).ok().map(|metadata| metadata.len())
+}
+
+// one greppable line of key=value, the keys stay stable
+pub(crate) fn get_summary_line(report: &EncodeReport) -> String {
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "none".to_string());
+    format!(
+        "summary inputs={:} dropped={:} concatenated={:} passthrough={:} crf={:} predicted_vmaf={:} output_bytes={:} duration={:.3}",
+        report.input_count,
+        report.dropped_input_count,
+        report.concatenated,
+        report.passthrough,
+        optional(report.crf.map(|crf| crf.to_string())),
+        optional(report.predicted_vmaf.map(|predicted_vmaf| format!("{:.2}", predicted_vmaf))),
+        optional(report.output_bytes.map(|output_bytes| output_bytes.to_string())),
+        report.predicted_duration,
+    )
+}
+
+#[cfg(test)]
+mod test_get_summary_line {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let report = EncodeReport { crf: Some(30), concatenated: true, input_count: 2, dropped_input_count: 1, predicted_duration: 3.5, predicted_vmaf: Some(95.123), output_bytes: Some(12345), ..Default::default() };
+        assert_eq!(get_summary_line(&report), "summary inputs=2 dropped=1 concatenated=true passthrough=false crf=30 predicted_vmaf=95.12 output_bytes=12345 duration=3.500");
+
+        let summary_line = get_summary_line(&EncodeReport::default());
+        assert_eq!(summary_line, "summary inputs=0 dropped=0 concatenated=false passthrough=false crf=none predicted_vmaf=none output_bytes=none duration=0.000");
+        let keys = summary_line.split(' ').skip(1).map(|pair| pair.split_once('=').unwrap().0).collect::<Vec<_>>();
+        assert_eq!(keys, vec!["inputs", "dropped", "concatenated", "passthrough", "crf", "predicted_vmaf", "output_bytes", "duration"]);
+    }
+}
+
+#[cfg(test)]
+mod test_encode_best_effort {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn it_works() {
+        let test_cases = vec![
+            (vec!["va-300x400.mp4"], "va.mp4", 0.0, MAX_CRF - 2, true, 1.0, MAX_CRF, true),
+            (vec!["va-300x400.mp4", "va-300x400.mp4"], "va-va.mp4", 0.0, MAX_CRF - 2, true, 2.0, MAX_CRF, true),
+            (vec!["v-300x400.mp4"], "v.mp4", 0.0, MAX_CRF - 2, true, 1.0, MAX_CRF, true),
+            (vec!["v-300x400.mp4", "v-300x400.mp4"], "v-v.mp4", 0.0, MAX_CRF - 2, true, 2.0, MAX_CRF, true),
+            (vec!["va-300x400.mp4", "v-300x400.mp4"], "va-v.mp4", 0.0, MAX_CRF - 2, true, 2.0, MAX_CRF, true),
+            (vec!["v-300x400.mp4", "va-300x400.mp4"], "v-va.mp4", 0.0, MAX_CRF - 2, true, 2.0, MAX_CRF, true),
+            (vec!["v-300x400.mp4", "va-300x400.mp4", "v-300x400.mp4"], "v-va-v.mp4", 0.0, MAX_CRF - 2, true, 3.0, MAX_CRF, true),
+            (vec!["va-300x400.mp4", "v-300x400.mp4", "va-300x400.mp4"], "va-v-va.mp4", 0.0, MAX_CRF - 2, true, 3.0, MAX_CRF, true),
+            (vec!["a.mp4"], "a.mp4", 0.0, MAX_CRF - 2, false, 0.0, 0, false),
+            (vec!["va-300x400.mp4"], "va-no-extension", 0.0, MAX_CRF - 2, true, 1.0, MAX_CRF, true),
+        ];
+
+        evauate_test_cases(test_cases);
+    }
+
+    #[test]
+    fn it_ignores_not_supported() {
+        let test_cases = vec![
+            (vec!["invalid.mp4", "va-300x400.mp4", "invalid.mp4", "va-300x400.mp4", "invalid.mp4"], "it_ignores_not_supported.mp4", 0.0, MAX_CRF - 2, true, 2.0, MAX_CRF, true),
+        ];
+        evauate_test_cases(test_cases);
+    }
+
+    #[test]
+    fn it_can_be_cancelled() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        // long enough for the cancel to land in the middle of the ffmpeg encode
+        let input_paths = vec![video_dir_path.join("va-300x400.mp4"); 60];
+        let output_path = output_dir_path.join("it_can_be_cancelled.mp4");
+        let cancel = Arc::new(AtomicBool::new(false));
+        let canceller = {
+            let cancel = cancel.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_secs(2));
+                cancel.store(true, Ordering::SeqCst);
+            })
+        };
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), cancel: Some(cancel.clone()), ..Default::default() };
+        let started_at = std::time::Instant::now();
+        assert_eq!(encode_best_effort(input_paths, &output_path, 0.0, MAX_CRF - 2, &options), Err(Error { kind: ErrorKind::Cancelled }));
+        assert!(started_at.elapsed() < Duration::from_secs(10));
+        canceller.join().unwrap();
+
+        // already cancelled, nothing starts
+        assert_eq!(encode_best_effort(vec![video_dir_path.join("va-300x400.mp4")], &output_path, 0.0, MAX_CRF - 2, &options), Err(Error { kind: ErrorKind::Cancelled }));
+    }
+
+    #[test]
+    fn it_can_dedupe_consecutive_inputs() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let input_paths = vec![video_dir_path.join("va-300x400.mp4"), video_dir_path.join("va-300x400.mp4"), video_dir_path.join("v-300x400.mp4")];
+        let output_path = output_dir_path.join("it_can_dedupe_consecutive_inputs.mp4");
+
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), ..Default::default() };
+        let report = encode_best_effort(input_paths.clone(), &output_path, 0.0, MAX_CRF - 2, &options).unwrap();
+        assert_eq!((report.input_count, report.collapsed_input_count), (3, 0));
+        assert_eq!(report.input_paths, input_paths);
+        let predicted_duration = report.predicted_duration;
+
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), dedupe_consecutive: true, ..Default::default() };
+        let report = encode_best_effort(input_paths.clone(), &output_path, 0.0, MAX_CRF - 2, &options).unwrap();
+        assert_eq!((report.input_count, report.collapsed_input_count), (2, 1));
+        assert!(report.predicted_duration < predicted_duration);
+        assert_eq!(report.input_paths, vec![input_paths[0].clone(), input_paths[2].clone()]);
+    }
+
+    #[test]
+    fn it_can_make_thumbnail_sheet() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let output_path = output_dir_path.join("it_can_make_thumbnail_sheet.mp4");
+        let thumbnail_sheet_path = output_dir_path.join("it_can_make_thumbnail_sheet-thumbnails.jpg");
+        let _ = std::fs::remove_file(&thumbnail_sheet_path);
+
+        // 2s output, 3x2 cells of 100px
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), thumbnail_sheet: Some(ThumbnailSheetSpec { rows: 2, cols: 3, width: 100 }), ..Default::default() };
+        let report = encode_best_effort(vec![video_dir_path.join("va-300x400.mp4"); 2], &output_path, 0.0, MAX_CRF - 2, &options).unwrap();
+        assert_eq!(report.thumbnail_sheet_path, Some(thumbnail_sheet_path.clone()));
+        let ffprobe::FfProbe { streams, .. } = ffprobe::ffprobe(&thumbnail_sheet_path).unwrap();
+        let image_stream = get_first_video_stream(&streams).unwrap();
+        // 3 cells wide, 2 cells of about 133px high
+        assert_eq!(image_stream.width, Some(300));
+        assert!(image_stream.height.is_some_and(|height| (264..=268).contains(&height)));
+
+        // more cells than frames, the rest stays blank
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), thumbnail_sheet: Some(ThumbnailSheetSpec { rows: 20, cols: 20, width: 30 }), ..Default::default() };
+        let report = encode_best_effort(vec![video_dir_path.join("va-300x400.mp4")], &output_path, 0.0, MAX_CRF - 2, &options).unwrap();
+        assert_eq!(report.thumbnail_sheet_path, Some(thumbnail_sheet_path.clone()));
+        let ffprobe::FfProbe { streams, .. } = ffprobe::ffprobe(&thumbnail_sheet_path).unwrap();
+        assert_eq!(get_first_video_stream(&streams).unwrap().width, Some(600));
+
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), ..Default::default() };
+        assert_eq!(encode_best_effort(vec![video_dir_path.join("va-300x400.mp4")], &output_path, 0.0, MAX_CRF - 2, &options).unwrap().thumbnail_sheet_path, None);
+    }
+
+    #[test]
+    fn it_can_emit_audio_sidecar() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let output_path = output_dir_path.join("it_can_emit_audio_sidecar.mp4");
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), emit_audio_sidecar: true, ..Default::default() };
+        let input_paths = vec![video_dir_path.join("va-300x400.mp4"), video_dir_path.join("v-300x400.mp4")];
+        let report = encode_best_effort(input_paths, &output_path, 0.0, MAX_CRF - 2, &options).unwrap();
+        let audio_sidecar_path = output_dir_path.join("it_can_emit_audio_sidecar-audio.opus");
+        assert_eq!(report.audio_sidecar_path, Some(audio_sidecar_path.clone()));
+
+        let ffprobe::FfProbe { format, streams } = ffprobe::ffprobe(&output_path).unwrap();
+        let video_duration = get_stream_duration(get_first_video_stream(&streams).unwrap(), &format).unwrap();
+
+        let ffprobe::FfProbe { format, streams } = ffprobe::ffprobe(&audio_sidecar_path).unwrap();
+        assert!(get_first_video_stream(&streams).is_none());
+        let audio_stream = get_first_audio_stream(&streams).unwrap();
+        assert_eq!(audio_stream.codec_name.as_deref(), Some("opus"));
+        let audio_duration = get_stream_duration(audio_stream, &format).unwrap();
+        assert!((video_duration - audio_duration).abs() < 0.1, "{:} {:}", video_duration, audio_duration);
+    }
+
+    #[test]
+    fn it_rejects_all_audio_only_inputs() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), ..Default::default() };
+        let input_paths = vec![video_dir_path.join("a.mp4"), video_dir_path.join("a.mp4")];
+        assert_eq!(encode_best_effort(input_paths.clone(), output_dir_path.join("it_rejects_all_audio_only_inputs.mp4"), 0.0, MAX_CRF - 2, &options), Err(Error { kind: ErrorKind::AllInputsAudioOnly(input_paths) }));
+
+        // not a clear mistake when something else is dropped too
+        let input_paths = vec![video_dir_path.join("a.mp4"), video_dir_path.join("invalid.mp4")];
+        assert_eq!(encode_best_effort(input_paths, output_dir_path.join("it_rejects_all_audio_only_inputs.mp4"), 0.0, MAX_CRF - 2, &options), Err(Error { kind: ErrorKind::NoAvailableVideoStream }));
+    }
+
+    #[test]
+    fn it_can_be_strict_about_inputs() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let options = EncodeOptions { strict_inputs: true, ..Default::default() };
+        let input_paths = vec![video_dir_path.join("va-300x400.mp4"), video_dir_path.join("invalid.mp4")];
+        assert!(match encode_best_effort(input_paths, output_dir_path.join("it_can_be_strict_about_inputs.mp4"), 0.0, MAX_CRF - 2, &options) {
+            Err(Error { kind: ErrorKind::InputNotSupported(path, IgnoreReason::ProbeFailed(_)) }) => path == video_dir_path.join("invalid.mp4"), _ => false,
+        });
+        let input_paths = vec![video_dir_path.join("va-300x400.mp4"), video_dir_path.join("a.mp4")];
+        assert!(match encode_best_effort(input_paths, output_dir_path.join("it_can_be_strict_about_inputs.mp4"), 0.0, MAX_CRF - 2, &options) {
+            Err(Error { kind: ErrorKind::InputNotSupported(path, IgnoreReason::AudioOnly) }) => path == video_dir_path.join("a.mp4"), _ => false,
+        });
+    }
+
+    #[test]
+    fn it_can_require_all_inputs() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), require_all_inputs: true, ..Default::default() };
+        let input_paths = vec![video_dir_path.join("va-300x400.mp4"), video_dir_path.join("invalid.mp4"), video_dir_path.join("v-300x400.mp4")];
+        assert_eq!(encode_best_effort(input_paths, output_dir_path.join("it_can_require_all_inputs.mp4"), 0.0, MAX_CRF - 2, &options), Err(Error { kind: ErrorKind::InputDropped(vec![video_dir_path.join("invalid.mp4")]) }));
+
+        let input_paths = vec![video_dir_path.join("va-300x400.mp4"), video_dir_path.join("v-300x400.mp4")];
+        assert!(encode_best_effort(input_paths, output_dir_path.join("it_can_require_all_inputs.mp4"), 0.0, MAX_CRF - 2, &options).is_ok());
+    }
+
+    #[test]
+    fn it_can_use_min_crf() {
+        let test_cases = vec![
+            (vec!["invalid.mp4", "va-300x400.mp4", "invalid.mp4", "va-300x400.mp4", "invalid.mp4"], "it_can_use_min_crf-0.mp4", 0.0, MAX_CRF - 2, true, 2.0, MAX_CRF, true),
+            (vec!["invalid.mp4", "va-300x400.mp4", "invalid.mp4", "va-300x400.mp4", "invalid.mp4"], "it_can_use_min_crf-1.mp4", 100.0, MAX_CRF - 2, true, 2.0, MAX_CRF - 2, false),
+        ];
+        evauate_test_cases(test_cases);
+    }
+
+    #[test]
+    fn it_can_align_audio_to_video() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let input_paths = vec![video_dir_path.join("v-300x400.mp4"); 5];
+        let output_path = output_dir_path.join("it_can_align_audio_to_video.mp4");
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), align_audio_to_video: true, ..Default::default() };
+        encode_best_effort(input_paths, &output_path, 0.0, MAX_CRF - 2, &options).unwrap();
+
+        let ffprobe::FfProbe { format, streams } = ffprobe::ffprobe(&output_path).unwrap();
+        let video_duration = get_stream_duration(get_first_video_stream(&streams).unwrap(), &format).unwrap();
+        let audio_duration = get_stream_duration(get_first_audio_stream(&streams).unwrap(), &format).unwrap();
+        // within an opus frame
+        assert!((video_duration - audio_duration).abs() < 0.025, "{:} {:}", video_duration, audio_duration);
+    }
+
+    #[test]
+    fn it_can_add_chapters() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let input_paths = vec![video_dir_path.join("va-300x400.mp4"), video_dir_path.join("v-300x400.mp4")];
+        let output_path = output_dir_path.join("it_can_add_chapters.mp4");
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), chapters: true, ..Default::default() };
+        encode_best_effort(input_paths.clone(), &output_path, 0.0, MAX_CRF - 2, &options).unwrap();
+        assert!(!PathBuf::from(format!("{:}.chapters.txt", output_path.display())).exists());
+
+        let output = Command::new(FFPROBE_CMD_STR).args(["-v", "quiet", "-show_chapters", "-print_format", "json"]).arg(&output_path).output().unwrap();
+        let chapters = serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap()["chapters"].as_array().unwrap().clone();
+        assert_eq!(chapters.len(), 2);
+        let first_duration = ffprobe::ffprobe(&input_paths[0]).unwrap().format.duration.unwrap().parse::<f64>().unwrap();
+        let chapter_start = |chapter: &serde_json::Value| chapter["start_time"].as_str().unwrap().parse::<f64>().unwrap();
+        assert_eq!(chapter_start(&chapters[0]), 0.0);
+        assert!((chapter_start(&chapters[1]) - first_duration).abs() < 0.05);
+        assert_eq!(chapters[0]["tags"]["title"], "va-300x400");
+        assert_eq!(chapters[1]["tags"]["title"], "v-300x400");
+    }
+
+    #[test]
+    fn it_can_auto_encode() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        // version gated, older ab-av1 takes the separate steps
+        let ab_av1_version = check_command(0, 7, AB_AV1_CMD_STR, &["--version"], &AB_AV1_STDOUT_RETRIEVE_VERSION_REGEX).unwrap();
+        let options = EncodeOptions { auto_encode: true, ..Default::default() };
+        let output_path = output_dir_path.join("it_can_auto_encode.mp4");
+        let report = encode_best_effort(vec![video_dir_path.join("va-300x400.mp4")], &output_path, 0.0, MAX_CRF - 2, &options).unwrap();
+        assert_eq!(report.frame_count.is_none(), AB_AV1_AUTO_ENCODE_MIN_VERSION <= ab_av1_version);
+        assert!(output_path.exists());
+
+        // multiple inputs need the concat filter
+        let output_path = output_dir_path.join("it_can_auto_encode-concat.mp4");
+        let report = encode_best_effort(vec![video_dir_path.join("va-300x400.mp4"), video_dir_path.join("v-300x400.mp4")], &output_path, 0.0, MAX_CRF - 2, &options).unwrap();
+        assert!(report.concatenated);
+        assert!(report.frame_count.is_some());
+    }
+
+    #[test]
+    fn it_can_measure_fallback_vmaf() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let input_paths = vec![video_dir_path.join("va-300x400.mp4")];
+        let output_path = output_dir_path.join("it_can_measure_fallback_vmaf.mp4");
+
+        // vmaf 100 is never reached, so it falls back to min crf
+        let report = encode_best_effort(input_paths.clone(), &output_path, 100.0, MAX_CRF - 2, &EncodeOptions::default()).unwrap();
+        assert_eq!(report.fallback_vmaf, None);
+        let options = EncodeOptions { measure_fallback_vmaf: true, ..Default::default() };
+        let report = encode_best_effort(input_paths.clone(), &output_path, 100.0, MAX_CRF - 2, &options).unwrap();
+        assert_eq!(report.crf, Some(MAX_CRF - 2));
+        assert!(match report.fallback_vmaf { Some(vmaf) => vmaf < 100.0, None => false });
+
+        let options = EncodeOptions { hard_floor_vmaf: Some(100), ..Default::default() };
+        assert!(matches!(encode_best_effort(input_paths.clone(), &output_path, 100.0, MAX_CRF - 2, &options), Err(Error { kind: ErrorKind::FallbackVmafBelowFloor(_, 100) })));
+        let options = EncodeOptions { hard_floor_vmaf: Some(0), ..Default::default() };
+        assert!(encode_best_effort(input_paths, &output_path, 100.0, MAX_CRF - 2, &options).unwrap().fallback_vmaf.is_some());
+    }
+
+    #[test]
+    fn it_fails_when_ffmpeg_command_failed() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        assert!(matches!(encode_best_effort_impl("__command_not_found__", AB_AV1_CMD_STR, vec![video_dir_path.join("va-300x400.mp4")], output_dir_path.join("it_fails_when_ffmpeg_command_failed.mp4"), 0.0, MAX_CRF - 2, &EncodeOptions::default()), Err(Error { kind: ErrorKind::FfmpegCommandProcessFailed(_) })));
+        assert!(matches!(encode_best_effort_impl("false", AB_AV1_CMD_STR, vec![video_dir_path.join("va-300x400.mp4")], output_dir_path.join("it_fails_when_ffmpeg_command_failed.mp4"), 0.0, MAX_CRF - 2, &EncodeOptions::default()), Err(Error { kind: ErrorKind::FfmpegCommandExitAbnormally(_, _) })));
+    }
+
+    #[test]
+    fn it_can_use_fixed_crf() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        // ab-av1 never invoked, otherwise command process failed
+        let options = EncodeOptions { fixed_crf: Some(30), ..Default::default() };
+        assert!(matches!(encode_best_effort_impl(FFMPEG_CMD_STR, "__command_not_found__", vec![video_dir_path.join("va-300x400.mp4")], output_dir_path.join("it_can_use_fixed_crf.mp4"), 0.0, MAX_CRF - 2, &options), Ok(EncodeReport { crf: Some(30), predicted_vmaf: None, ffmpeg_version: (6, _), ab_av1_version: None, .. })));
+
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF + 1), ..Default::default() };
+        assert!(matches!(encode_best_effort_impl(FFMPEG_CMD_STR, "__command_not_found__", vec![video_dir_path.join("va-300x400.mp4")], output_dir_path.join("it_can_use_fixed_crf.mp4"), 0.0, MAX_CRF - 2, &options), Err(Error { kind: ErrorKind::InvalidCrf(_) })));
+    }
+
+    #[test]
+    fn it_can_override_output_container() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        // the extension says mp4
+        let output_path = output_dir_path.join("it_can_override_output_container.mp4");
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), output_container: Some(OutputContainer::Mkv), ..Default::default() };
+        assert!(encode_best_effort(vec![video_dir_path.join("va-300x400.mp4"), video_dir_path.join("v-300x400.mp4")], &output_path, 0.0, MAX_CRF - 2, &options).is_ok());
+        let format = ffprobe::ffprobe(&output_path).unwrap().format;
+        assert!(format.format_name.contains("matroska"), "{:}", format.format_name);
+
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), output_container: Some(OutputContainer::Mov), ..Default::default() };
+        assert!(matches!(encode_best_effort(vec![video_dir_path.join("va-300x400.mp4")], &output_path, 0.0, MAX_CRF - 2, &options), Err(Error { kind: ErrorKind::UnsupportedOutputContainer(OutputContainer::Mov) })));
+    }
+
+    #[test]
+    fn it_can_constrain_av1_level() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), av1_level: Some("5.1".to_string()), av1_tier: Some("main".to_string()), ..Default::default() };
+        assert!(encode_best_effort(vec![video_dir_path.join("va-300x400.mp4")], output_dir_path.join("it_can_constrain_av1_level.mp4"), 0.0, MAX_CRF - 2, &options).is_ok());
+
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), av1_level: Some("51".to_string()), ..Default::default() };
+        assert!(match encode_best_effort(vec![video_dir_path.join("va-300x400.mp4")], output_dir_path.join("it_can_constrain_av1_level.mp4"), 0.0, MAX_CRF - 2, &options) {
+            Err(Error { kind: ErrorKind::InvalidAv1Level(level) }) => level == "51", _ => false,
+        });
+
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), av1_tier: Some("Main".to_string()), ..Default::default() };
+        assert!(match encode_best_effort(vec![video_dir_path.join("va-300x400.mp4")], output_dir_path.join("it_can_constrain_av1_level.mp4"), 0.0, MAX_CRF - 2, &options) {
+            Err(Error { kind: ErrorKind::InvalidAv1Tier(tier) }) => tier == "Main", _ => false,
+        });
+    }
+
+    #[test]
+    fn it_can_encode_renditions() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let options = EncodeOptions { renditions: vec![Rendition { height: 200, crf: Some(MAX_CRF) }, Rendition { height: 100, crf: None }], ..Default::default() };
+        for input_filenames in [vec!["va-600x800.mp4"], vec!["va-600x800.mp4", "v-300x400.mp4"]] {
+            let input_paths = input_filenames.iter().map(|filename| video_dir_path.join(filename)).collect::<Vec<_>>();
+            let output_path = output_dir_path.join(format!("it_can_encode_renditions-{:}.mp4", input_filenames.len()));
+            let report = encode_best_effort(input_paths, &output_path, 0.0, MAX_CRF - 2, &options).unwrap();
+            assert_eq!(report.renditions.len(), 2);
+
+            for (rendition, (expected_width, expected_height)) in report.renditions.iter().zip([(150, 200), (76, 100)]) {
+                let ffprobe::FfProbe { streams, .. } = ffprobe::ffprobe(&rendition.path).unwrap();
+                let video_stream = get_first_video_stream(&streams).unwrap();
+                assert_eq!((video_stream.width, video_stream.height), (Some(expected_width), Some(expected_height)));
+                assert!(get_first_audio_stream(&streams).is_some());
+            }
+            assert_eq!(report.renditions[0].crf, MAX_CRF);
+            assert_eq!(Some(report.renditions[1].crf), report.crf);
+        }
+    }
+
+    #[test]
+    fn it_can_encode_deterministically() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let options = EncodeOptions { deterministic: true, ..Default::default() };
+        let input_paths = vec![video_dir_path.join("va-300x400.mp4"), video_dir_path.join("v-400x300.mp4")];
+        let output_paths = [output_dir_path.join("it_can_encode_deterministically-0.mp4"), output_dir_path.join("it_can_encode_deterministically-1.mp4")];
+        for output_path in &output_paths {
+            assert!(encode_best_effort(input_paths.clone(), output_path, 0.0, MAX_CRF - 2, &options).is_ok());
+        }
+        assert_eq!(std::fs::read(&output_paths[0]).unwrap(), std::fs::read(&output_paths[1]).unwrap());
+    }
+
+    #[test]
+    fn it_can_make_preview_sprite() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let output_path = output_dir_path.join("it_can_make_preview_sprite.mp4");
+        let input_paths = vec![video_dir_path.join("va-300x400.mp4"), video_dir_path.join("v-400x300.mp4")];
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), preview_sprite: Some(SpriteSpec { interval: 1.0, rows: 2, cols: 2, width: 60 }), ..Default::default() };
+        let report = encode_best_effort(input_paths, &output_path, 0.0, MAX_CRF - 2, &options).unwrap();
+
+        let tile_count = report.predicted_duration.ceil() as usize;
+        assert_eq!(report.preview_sprite_paths.len(), tile_count.div_ceil(4));
+        for (index, sprite_path) in report.preview_sprite_paths.iter().enumerate() {
+            assert_eq!(sprite_path, &output_dir_path.join(format!("it_can_make_preview_sprite-sprite-{:03}.jpg", index)));
+            assert!(sprite_path.is_file());
+        }
+        let vtt_path = output_dir_path.join("it_can_make_preview_sprite-sprite.vtt");
+        assert_eq!(report.preview_vtt_path, Some(vtt_path.clone()));
+        let vtt_code = std::fs::read_to_string(&vtt_path).unwrap();
+        assert!(vtt_code.starts_with("WEBVTT\n"));
+        assert_eq!(vtt_code.matches(" --> ").count(), tile_count);
+        assert!(vtt_code.contains("it_can_make_preview_sprite-sprite-000.jpg#xywh=0,0,60,"));
+    }
+
+    #[test]
+    fn it_can_keep_existing_output() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        // decided before anything runs, so the existing output stays as it is
+        let input_paths = vec![video_dir_path.join("va-300x400.mp4")];
+        let output_path = output_dir_path.join("it_can_keep_existing_output.mp4");
+        std::fs::write(&output_path, "existing").unwrap();
+        let options = EncodeOptions { overwrite_policy: OverwritePolicy::Never, ..Default::default() };
+        assert_eq!(encode_best_effort(input_paths.clone(), &output_path, 0.0, MAX_CRF - 2, &options).unwrap_err().kind, ErrorKind::OutputExists(output_path.clone()));
+        assert_eq!(std::fs::read_to_string(&output_path).unwrap(), "existing");
+
+        // the kept report comes back, for the caller to upload as usual
+        let report = EncodeReport { crf: Some(30), input_count: 1, output_bytes: Some(8), ..Default::default() };
+        let output_record = OutputRecord { inputs_hash: get_inputs_hash(&input_paths), report: report.clone() };
+        std::fs::write(get_output_record_path(&output_path), serde_json::to_string(&output_record).unwrap()).unwrap();
+        let options = EncodeOptions { overwrite_policy: OverwritePolicy::IfDifferentInputs, ..Default::default() };
+        assert_eq!(encode_best_effort(input_paths, &output_path, 0.0, MAX_CRF - 2, &options).unwrap(), EncodeReport { skipped: true, ..report });
+        assert_eq!(std::fs::read_to_string(&output_path).unwrap(), "existing");
+        let _ = std::fs::remove_file(get_output_record_path(&output_path));
+    }
+
+    #[test]
+    fn it_can_keep_hdr() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let output_dir_path = root_path.join("output");
+
+        // pq tagged, different sizes so they go through the filter graph
+        let mut input_paths = Vec::new();
+        for size in ["320x240", "240x320"] {
+            let input_path = output_dir_path.join(format!("it_can_keep_hdr-input-{:}.mkv", size));
+            let status = Command::new(FFMPEG_CMD_STR).args([
+                "-y", "-hide_banner", "-loglevel", "error",
+                "-f", "lavfi", "-i", &format!("testsrc=size={:}:rate=30:duration=1", size),
+                "-f", "lavfi", "-i", "sine=duration=1",
+                "-pix_fmt", "yuv420p10le", "-c:v", "ffv1", "-c:a", "flac",
+                "-color_primaries", "bt2020", "-color_trc", "smpte2084", "-colorspace", "bt2020nc",
+            ]).arg(&input_path).status().unwrap();
+            assert!(status.success());
+            input_paths.push(input_path);
+        }
+
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), ..Default::default() };
+        let output_path = output_dir_path.join("it_can_keep_hdr.mp4");
+        assert!(encode_best_effort(input_paths, &output_path, 0.0, MAX_CRF - 2, &options).is_ok());
+        let color = get_video_color(&output_path, 0);
+        assert_eq!(color, ColorProperties { primaries: Some("bt2020".to_string()), transfer: Some("smpte2084".to_string()), space: Some("bt2020nc".to_string()) });
+    }
+
+    #[test]
+    fn it_can_embed_source_ids() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let input_paths = vec![video_dir_path.join("va-300x400.mp4"), video_dir_path.join("v-400x300.mp4")];
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), source_ids: vec!["clips/va-300x400.mp4".to_string(), "clips/v-400x300.mp4".to_string()], ..Default::default() };
+        let output_path = output_dir_path.join("it_can_embed_source_ids.mp4");
+        assert!(encode_best_effort(input_paths, &output_path, 0.0, MAX_CRF - 2, &options).is_ok());
+        let output = Command::new(FFPROBE_CMD_STR).args(["-v", "error", "-show_entries", "format_tags=comment", "-of", "default=noprint_wrappers=1:nokey=1"]).arg(&output_path).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "sources: clips/va-300x400.mp4,clips/v-400x300.mp4");
+    }
+
+    #[test]
+    fn it_can_stamp_metadata() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let input_paths = vec![video_dir_path.join("va-300x400.mp4"), video_dir_path.join("v-400x300.mp4")];
+        let test_cases = [
+            (MetadataStamp::Strip, None),
+            (MetadataStamp::CreationTime("2023-01-01T00:00:00Z".to_string()), Some("2023-01-01T00:00:00.000000Z")),
+        ];
+        for (metadata_stamp, expected_creation_time) in test_cases {
+            let options = EncodeOptions { fixed_crf: Some(MAX_CRF), metadata_stamp, ..Default::default() };
+            let output_path = output_dir_path.join("it_can_stamp_metadata.mp4");
+            assert!(encode_best_effort(input_paths.clone(), &output_path, 0.0, MAX_CRF - 2, &options).is_ok());
+            let creation_time = ffprobe::ffprobe(&output_path).unwrap().format.tags.and_then(|tags| tags.creation_time);
+            assert_eq!(creation_time.as_deref(), expected_creation_time);
+        }
+    }
+
+    #[test]
+    fn it_can_drop_audio() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let test_cases = [
+            (AudioPolicy::SilenceFill, vec!["va-300x400.mp4", "v-300x400.mp4"], 1),
+            (AudioPolicy::DropIfAnyMissing, vec!["va-300x400.mp4", "v-300x400.mp4"], 0),
+            (AudioPolicy::DropIfAnyMissing, vec!["va-300x400.mp4", "va-300x400.mp4"], 1),
+            // without the filter graph
+            (AudioPolicy::DropAlways, vec!["va-300x400.mp4"], 0),
+        ];
+        for (audio_policy, input_filenames, expected_audio_stream_count) in test_cases {
+            let input_paths = input_filenames.iter().map(|filename| video_dir_path.join(filename)).collect::<Vec<_>>();
+            let output_path = output_dir_path.join("it_can_drop_audio.mp4");
+            let options = EncodeOptions { fixed_crf: Some(MAX_CRF), audio_policy, ..Default::default() };
+            assert!(encode_best_effort(input_paths, &output_path, 0.0, MAX_CRF - 2, &options).is_ok());
+            let streams = ffprobe::ffprobe(&output_path).unwrap().streams;
+            assert_eq!(streams.iter().filter(|stream| stream.codec_type.as_deref() == Some("audio")).count(), expected_audio_stream_count, "{:?}", audio_policy);
+        }
+    }
+
+    #[test]
+    fn it_reports_progress() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), progress: Some(sender), ..Default::default() };
+        let input_paths = vec![video_dir_path.join("va-300x400.mp4"), video_dir_path.join("v-400x300.mp4")];
+        let output_path = output_dir_path.join("it_reports_progress.mp4");
+        assert!(encode_best_effort(input_paths, &output_path, 0.0, MAX_CRF - 2, &options).is_ok());
+        drop(options);
+        let events = receiver.iter().collect::<Vec<_>>();
+        let phases = events.iter().map(|event| (event.phase, event.percent)).collect::<Vec<_>>();
+        assert_eq!(phases, vec![(ProgressPhase::Analysis, 0.0), (ProgressPhase::Analysis, 50.0), (ProgressPhase::Analysis, 100.0), (ProgressPhase::Encode, 0.0), (ProgressPhase::Encode, 100.0)]);
+        assert_eq!(events.last().unwrap().crf, Some(MAX_CRF));
+        assert_eq!(events.last().unwrap().current_file.as_deref(), Some(output_path.as_path()));
+    }
+
+    #[test]
+    fn it_can_concat_with_demuxer() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), ..Default::default() };
+        let input_path = video_dir_path.join("va-300x400.mp4");
+        let input_duration = analyze_video_file(&input_path).unwrap().duration.unwrap();
+        let output_path = output_dir_path.join("it_can_concat_with_demuxer.mp4");
+        let report = encode_best_effort(vec![input_path.clone(), input_path.clone()], &output_path, 0.0, MAX_CRF - 2, &options).unwrap();
+        assert!(report.concatenated);
+        assert!(report.concat_demuxer);
+        assert!(!PathBuf::from(format!("{:}.concat.txt", output_path.display())).exists());
+
+        let ffprobe::FfProbe { format, streams } = ffprobe::ffprobe(&output_path).unwrap();
+        let video_stream = get_first_video_stream(&streams).unwrap();
+        assert_eq!((get_stream_duration(video_stream, &format).unwrap() * 10.0).round(), (input_duration * 20.0).round());
+        assert!(get_first_audio_stream(&streams).is_some());
+
+        // the filter graph for differing inputs
+        let input_paths = vec![input_path, video_dir_path.join("va-400x300.mp4")];
+        let report = encode_best_effort(input_paths, &output_path, 0.0, MAX_CRF - 2, &options).unwrap();
+        assert!(report.concatenated);
+        assert!(!report.concat_demuxer);
+    }
+
+    #[test]
+    fn it_reports_concatenation() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), ..Default::default() };
+        let test_cases = [
+            (vec!["va-300x400.mp4"], false, 1),
+            (vec!["invalid.mp4", "va-300x400.mp4"], false, 1),
+            (vec!["va-300x400.mp4", "v-300x400.mp4"], true, 2),
+        ];
+        for (input_filenames, expected_concatenated, expected_input_count) in test_cases {
+            let input_paths = input_filenames.iter().map(|filename| video_dir_path.join(filename)).collect::<Vec<_>>();
+            let report = encode_best_effort(input_paths, output_dir_path.join("it_reports_concatenation.mp4"), 0.0, MAX_CRF - 2, &options).unwrap();
+            assert_eq!((report.concatenated, report.input_count), (expected_concatenated, expected_input_count));
+        }
+    }
+
+    #[test]
+    fn it_can_encode_output_range() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        // the window spans the boundary of the two inputs
+        let input_paths = vec![video_dir_path.join("va-300x400.mp4"), video_dir_path.join("v-300x400.mp4")];
+        let output_path = output_dir_path.join("it_can_encode_output_range.mp4");
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), output_range: Some((0.5, 1.5)), ..Default::default() };
+        let report = encode_best_effort(input_paths.clone(), &output_path, 0.0, MAX_CRF - 2, &options).unwrap();
+        assert_eq!(report.predicted_duration, 1.0);
+
+        let ffprobe::FfProbe { format, streams } = ffprobe::ffprobe(&output_path).unwrap();
+        let video_stream = get_first_video_stream(&streams).unwrap();
+        assert_eq!((get_stream_duration(video_stream, &format).unwrap() * 10.0).round(), 10.0);
+        let audio_stream = get_first_audio_stream(&streams).unwrap();
+        assert_eq!((get_stream_duration(audio_stream, &format).unwrap() * 10.0).round(), 10.0);
+
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), output_range: Some((1.5, 0.5)), ..Default::default() };
+        assert!(matches!(encode_best_effort(input_paths.clone(), &output_path, 0.0, MAX_CRF - 2, &options), Err(Error { kind: ErrorKind::InvalidOutputRange(_, _) })));
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), output_range: Some((5.0, 6.0)), ..Default::default() };
+        assert!(matches!(encode_best_effort(input_paths, &output_path, 0.0, MAX_CRF - 2, &options), Err(Error { kind: ErrorKind::ZeroDurationOutput })));
+    }
+
+    #[test]
+    fn it_can_encode_yuv444() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let output_path = output_dir_path.join("it_can_encode_yuv444.mp4");
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), pixel_format: OutputPixelFormat::Yuv444p10le, ..Default::default() };
+        match encode_best_effort(vec![video_dir_path.join("va-300x400.mp4")], &output_path, 0.0, MAX_CRF - 2, &options) {
+            Ok(_) => {
+                let ffprobe::FfProbe { streams, .. } = ffprobe::ffprobe(&output_path).unwrap();
+                assert_eq!(get_first_video_stream(&streams).unwrap().pix_fmt.as_deref(), Some("yuv444p10le"));
+            },
+            // libsvtav1 builds without 4:4:4 support
+            Err(Error { kind: ErrorKind::PixelFormatNotSupported(pixel_format) }) => assert_eq!(pixel_format, "yuv444p10le"),
+            Err(err) => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn it_can_mux_captions() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let input_paths = vec![video_dir_path.join("va-300x400.mp4"), video_dir_path.join("v-300x400.mp4")];
+        let output_path = output_dir_path.join("it_can_mux_captions.mp4");
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), captions_path: Some(video_dir_path.join("captions.srt")), ..Default::default() };
+        encode_best_effort(input_paths.clone(), &output_path, 0.0, MAX_CRF - 2, &options).unwrap();
+
+        let ffprobe::FfProbe { streams, .. } = ffprobe::ffprobe(&output_path).unwrap();
+        let subtitle_stream = get_first_stream_for_codec_type("subtitle", &streams).unwrap();
+        assert_eq!(subtitle_stream.codec_name.as_deref(), Some("mov_text"));
+
+        let captions_path = video_dir_path.join("__captions_not_found__.srt");
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), captions_path: Some(captions_path.clone()), ..Default::default() };
+        assert!(match encode_best_effort(input_paths, &output_path, 0.0, MAX_CRF - 2, &options) {
+            Err(Error { kind: ErrorKind::CaptionsNotFound(path) }) => path == captions_path, _ => false,
+        });
+    }
+
+    #[test]
+    fn it_fails_when_total_duration_exceeded() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let input_paths = vec![video_dir_path.join("va-300x400.mp4"), video_dir_path.join("v-300x400.mp4")];
+        let output_path = output_dir_path.join("it_fails_when_total_duration_exceeded.mp4");
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), max_total_duration: Some(0.5), ..Default::default() };
+        assert!(match encode_best_effort(input_paths, &output_path, 0.0, MAX_CRF - 2, &options) {
+            Err(Error { kind: ErrorKind::TotalDurationExceeded(total_duration, max_total_duration) }) => max_total_duration == 0.5 && 0.5 < total_duration, _ => false,
+        });
+        assert!(!output_path.exists());
+    }
+
+    #[test]
+    fn it_fails_when_watermark_not_found() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let watermark_path = video_dir_path.join("__watermark_not_found__.png");
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), watermark: Some(WatermarkSpec { path: watermark_path.clone(), position: WatermarkPosition::BottomRight, opacity: 0.5 }), ..Default::default() };
+        assert!(match encode_best_effort(vec![video_dir_path.join("va-300x400.mp4")], output_dir_path.join("it_fails_when_watermark_not_found.mp4"), 0.0, MAX_CRF - 2, &options) {
+            Err(Error { kind: ErrorKind::WatermarkNotFound(path) }) => path == watermark_path, _ => false,
+        });
+    }
+
+    #[test]
+    fn it_can_place_on_timeline() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        let input_paths = vec![video_dir_path.join("va-300x400.mp4"), video_dir_path.join("v-300x400.mp4")];
+        let output_path = output_dir_path.join("it_can_place_on_timeline.mp4");
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), timeline: Some(TimelineSpec { offsets: vec![0.5, 2.0] }), ..Default::default() };
+        let report = encode_best_effort(input_paths.clone(), &output_path, 0.0, MAX_CRF - 2, &options).unwrap();
+        assert_eq!(report.predicted_duration, 3.0);
+
+        let ffprobe::FfProbe { format, streams } = ffprobe::ffprobe(&output_path).unwrap();
+        let video_stream = get_first_video_stream(&streams).unwrap();
+        assert_eq!((get_stream_duration(video_stream, &format).unwrap() * 10.0).round(), 30.0);
+
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), timeline: Some(TimelineSpec { offsets: vec![0.5] }), ..Default::default() };
+        assert!(matches!(encode_best_effort(input_paths.clone(), &output_path, 0.0, MAX_CRF - 2, &options), Err(Error { kind: ErrorKind::InvalidTimeline(_) })));
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), timeline: Some(TimelineSpec { offsets: vec![0.5, -1.0] }), ..Default::default() };
+        assert!(matches!(encode_best_effort(input_paths, &output_path, 0.0, MAX_CRF - 2, &options), Err(Error { kind: ErrorKind::InvalidTimeline(_) })));
+    }
+
+    #[test]
+    fn it_can_passthrough_av1() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        // av1 inputs with matching parameters are what this tool outputs
+        let av1_path = output_dir_path.join("it_can_passthrough_av1-input.mp4");
+        let options = EncodeOptions { fixed_crf: Some(MAX_CRF), ..Default::default() };
+        assert!(encode_best_effort(vec![video_dir_path.join("va-300x400.mp4")], &av1_path, 0.0, MAX_CRF - 2, &options).is_ok());
+
+        // ab-av1 never invoked, otherwise command process failed
+        let output_path = output_dir_path.join("it_can_passthrough_av1.mp4");
+        let options = EncodeOptions { allow_passthrough: true, ..Default::default() };
+        let report = encode_best_effort_impl(FFMPEG_CMD_STR, "__command_not_found__", vec![av1_path.clone(), av1_path.clone()], &output_path, 0.0, MAX_CRF - 2, &options).unwrap();
+        assert_eq!((report.crf, report.passthrough), (None, true));
+
+        let ffprobe::FfProbe { format, streams } = ffprobe::ffprobe(&output_path).unwrap();
+        let video_stream = get_first_video_stream(&streams).unwrap();
+        assert_eq!(video_stream.codec_name, Some("av1".to_string()));
+        assert_eq!((get_stream_duration(video_stream, &format).unwrap() * 10.0).round(), 20.0);
+
+        // not av1, re-encoded
+        let options = EncodeOptions { allow_passthrough: true, fixed_crf: Some(MAX_CRF), ..Default::default() };
+        let report = encode_best_effort(vec![video_dir_path.join("va-300x400.mp4"), av1_path], &output_path, 0.0, MAX_CRF - 2, &options).unwrap();
+        assert_eq!((report.crf, report.passthrough), (Some(MAX_CRF), false));
+    }
+
+    fn evauate_test_cases(test_cases: Vec<(Vec<&str>, &str, f64, u8, bool, f64, u8, bool)>) {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        for (input_filenames, output_filename, vmaf, crf, expected_result, expected_duration, expected_crf, expected_crf_found) in test_cases {
+            let input_paths = input_filenames.iter().map(|filename| { video_dir_path.join(filename) }).collect::<Vec<_>>();
+            let output_path = output_dir_path.join(&output_filename);
+            let (actual_result, actual_crf, actual_crf_found, predicted_duration) = match encode_best_effort(input_paths, &output_path, vmaf, crf, &EncodeOptions::default()) {
+                Ok(report) => {
+                    assert!(report.frame_count.is_some());
+                    (true, report.crf.unwrap(), report.predicted_vmaf.is_some(), report.predicted_duration)
+                },
+                Err(err) => {
+                    log::trace!("test_encode_best_effort() case {:?} error {:?}", (input_filenames, output_filename, vmaf, crf, expected_result), err);
+                    (false, 0, false, 0.0)
+                },
+            };
+            assert_eq!(actual_result, expected_result);
+            assert_eq!(actual_crf_found, expected_crf_found);
+            assert_eq!(actual_crf, expected_crf);
+            if actual_result {
+                let ffprobe::FfProbe { format, streams } = ffprobe::ffprobe(&output_path).unwrap();
+
+                let video_stream = get_first_video_stream(&streams).unwrap();
+                let actual_duration = get_stream_duration(&video_stream, &format).unwrap();
+                assert_eq!((actual_duration * 10.0).round(), expected_duration * 10.0);
+                assert!((predicted_duration - actual_duration).abs() < 0.1, "{:} {:}", predicted_duration, actual_duration);
+
+                if let Some(audio_stream) = get_first_audio_stream(&streams) {
+                    let actual_duration = get_stream_duration(&audio_stream, &format).unwrap();
+                    assert_eq!((actual_duration * 10.0).round(), expected_duration * 10.0);
+                };
+            }
+        }
+    }
+
+}
+
+// unique per invocation even for the same output path, process id for concurrent jobs and a counter within the process
+fn get_passlog_prefix(output_video_path: &Path) -> PathBuf {
+    static PASSLOG_COUNTER: AtomicU64 = AtomicU64::new(0);
+    let count = PASSLOG_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let file_name = output_video_path.file_name().map(|file_name| file_name.to_string_lossy().to_string()).unwrap_or_default();
+    output_video_path.with_file_name(format!("{:}.{:}-{:}.passlog", file_name, std::process::id(), count))
+}
+
+#[cfg(test)]
+mod test_get_passlog_prefix {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let output_video_path = Path::new("output/a.mp4");
+        let prefixes = (0..3).map(|_| get_passlog_prefix(output_video_path)).collect::<Vec<_>>();
+        for (index, prefix) in prefixes.iter().enumerate() {
+            assert_eq!(prefix.parent(), Some(Path::new("output")));
+            assert!(prefix.file_name().unwrap().to_string_lossy().starts_with(&format!("a.mp4.{:}-", std::process::id())));
+            assert!(!prefixes[index + 1..].contains(prefix));
+        }
+    }
+}
+
+// the prefix plus whatever ffmpeg appends, e.g. "-0.log" and "-0.log.mbtree"
+fn remove_passlog_files(passlog_prefix: &Path) {
+    let (Some(dir_path), Some(prefix_file_name)) = (passlog_prefix.parent(), passlog_prefix.file_name()) else {
+        return;
+    };
+    let dir_path = if dir_path.as_os_str().is_empty() { Path::new(".") } else { dir_path };
+    let Ok(entries) = std::fs::read_dir(dir_path) else {
+        return;
+    };
+    let prefix_file_name = prefix_file_name.to_string_lossy();
+    for entry in entries.flatten() {
+        if entry.file_name().to_string_lossy().starts_with(prefix_file_name.as_ref()) {
+            if let Err(err) = std::fs::remove_file(entry.path()) {
+                log::warn!("Couldn't remove a pass log file: {:} ({:})", entry.path().display(), err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_remove_passlog_files {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn it_works() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let output_dir_path = Path::new(&root_path).join("output");
+        std::fs::create_dir_all(&output_dir_path).unwrap();
+
+        let passlog_prefix = get_passlog_prefix(&output_dir_path.join("test_remove_passlog_files.mp4"));
+        let passlog_path = PathBuf::from(format!("{:}-0.log", passlog_prefix.display()));
+        let other_path = output_dir_path.join("test_remove_passlog_files.txt");
+        std::fs::write(&passlog_path, "").unwrap();
+        std::fs::write(&other_path, "").unwrap();
+
+        remove_passlog_files(&passlog_prefix);
+        assert!(!passlog_path.exists());
+        assert!(other_path.exists());
+    }
+}
+
+// stream copy only works when the inputs share the codec parameters
+fn can_passthrough(input_files: &[InputFile]) -> bool {
+    let Some(first_input_file) = input_files.first() else {
+        return false;
+    };
+    input_files.iter().all(|input_file| {
+        input_file.video_codec_name.as_deref() == Some("av1")
+            && input_file.pix_fmt.is_some()
+            && (input_file.width, input_file.height, input_file.rotation) == (first_input_file.width, first_input_file.height, first_input_file.rotation)
+            && input_file.frame_rate == first_input_file.frame_rate
+            && input_file.pix_fmt == first_input_file.pix_fmt
+            && input_file.audio_codec_name == first_input_file.audio_codec_name
+            && input_file.audio_channels == first_input_file.audio_channels
+            && input_file.color == first_input_file.color
+            && get_display_resolution(input_file) == get_display_resolution(first_input_file)
+    })
+}
+
+#[cfg(test)]
+mod test_can_passthrough {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let av1_input_file = |path: &str| InputFile {
+            path: PathBuf::from(path), width: 300, height: 400, video_codec_name: Some("av1".to_string()), frame_rate: "30/1".to_string(), pix_fmt: Some("yuv420p10le".to_string()), audio_codec_name: Some("opus".to_string()), ..Default::default()
+        };
+        assert!(can_passthrough(&[av1_input_file("0.mp4")]));
+        assert!(can_passthrough(&[av1_input_file("0.mp4"), av1_input_file("1.mp4")]));
+        assert!(!can_passthrough(&[]));
+
+        let test_cases: Vec<fn(&mut InputFile)> = vec![
+            |input_file| input_file.video_codec_name = Some("h264".to_string()),
+            |input_file| input_file.video_codec_name = None,
+            |input_file| input_file.width = 400,
+            |input_file| input_file.rotation = 90,
+            |input_file| input_file.frame_rate = "25/1".to_string(),
+            |input_file| input_file.pix_fmt = Some("yuv420p".to_string()),
+            |input_file| input_file.audio_codec_name = Some("aac".to_string()),
+            |input_file| input_file.audio_codec_name = None,
+            |input_file| input_file.audio_channels = Some(6),
+            |input_file| input_file.sample_aspect_ratio = Some((4, 3)),
+            |input_file| input_file.color.transfer = Some("smpte2084".to_string()),
+        ];
+        for modify in test_cases {
+            let mut input_file = av1_input_file("1.mp4");
+            modify(&mut input_file);
+            assert!(!can_passthrough(&[av1_input_file("0.mp4"), input_file]));
+        }
+    }
+}
+
+// renditions and memory limit both need decoded frames, and stream copy can only cut on keyframes
+fn get_passthrough_blocker(input_files: &[InputFile], options: &EncodeOptions, drops_audio: bool) -> Option<&'static str> {
+    if !options.renditions.is_empty() || options.memory_limit_mb.is_some() || options.aspect_ratio.is_some() || options.watermark.is_some() || options.timeline.is_some() || options.pixel_format != OutputPixelFormat::default() || options.fade_in.is_some() || options.fade_out.is_some() || options.denoise.is_some() {
+        Some("the options need decoded frames")
+    } else if options.output_range.is_some() || input_files.iter().any(|input_file| input_file.trim_range.is_some()) {
+        Some("a cut may not be on a keyframe")
+    } else if input_files.iter().any(|input_file| input_file.speed.is_some() || input_file.audio_delay_ms.is_some()) {
+        Some("a speed or an audio delay needs the filter graph")
+    } else if options.captions_path.is_some() || options.chapters || options.emit_audio_sidecar {
+        Some("the options need extra ffmpeg inputs or outputs")
+    } else if drops_audio && has_any_audio(input_files) {
+        Some("dropping the audio needs an encode")
+    } else if !can_passthrough(input_files) {
+        Some("the inputs aren't all av1 with matching parameters")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test_get_passthrough_blocker {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let av1_input_file = || InputFile {
+            path: PathBuf::from("0.mp4"), width: 300, height: 400, video_codec_name: Some("av1".to_string()), frame_rate: "30/1".to_string(), pix_fmt: Some("yuv420p10le".to_string()), audio_codec_name: Some("opus".to_string()), ..Default::default()
+        };
+        assert_eq!(get_passthrough_blocker(&[av1_input_file()], &EncodeOptions::default(), false), None);
+        assert!(get_passthrough_blocker(&[av1_input_file()], &EncodeOptions { memory_limit_mb: Some(1024), ..Default::default() }, false).is_some());
+        assert!(get_passthrough_blocker(&[av1_input_file()], &EncodeOptions { output_range: Some((1.0, 2.0)), ..Default::default() }, false).is_some());
+        assert!(get_passthrough_blocker(&[av1_input_file()], &EncodeOptions { chapters: true, ..Default::default() }, false).is_some());
+        assert!(get_passthrough_blocker(&[InputFile { speed: Some(2.0), ..av1_input_file() }], &EncodeOptions::default(), false).is_some());
+        assert!(get_passthrough_blocker(&[InputFile { audio_delay_ms: Some(100), ..av1_input_file() }], &EncodeOptions::default(), false).is_some());
+        assert!(get_passthrough_blocker(&[av1_input_file()], &EncodeOptions::default(), true).is_some());
+        assert!(get_passthrough_blocker(&[InputFile { audio_codec_name: None, alternative_null_audio_duration: Some(1.0), ..av1_input_file() }], &EncodeOptions::default(), true).is_none());
+        assert!(get_passthrough_blocker(&[InputFile { video_codec_name: Some("h264".to_string()), ..av1_input_file() }], &EncodeOptions::default(), false).is_some());
+    }
+}
+
+// the demuxer takes the first streams of each file as they are, the audio too unless it's dropped
+fn can_concat_demuxer(input_files: &[InputFile], drops_audio: bool) -> bool {
+    let Some(first_input_file) = input_files.first() else {
+        return false;
+    };
+    input_files.iter().all(|input_file| {
+        input_file.video_stream_position == 0
+            && input_file.video_codec_name.is_some()
+            && input_file.pix_fmt.is_some()
+            && input_file.video_codec_name == first_input_file.video_codec_name
+            && (input_file.width, input_file.height, input_file.rotation) == (first_input_file.width, first_input_file.height, first_input_file.rotation)
+            && input_file.frame_rate == first_input_file.frame_rate
+            && input_file.pix_fmt == first_input_file.pix_fmt
+            && input_file.color == first_input_file.color
+            && get_display_resolution(input_file) == get_display_resolution(first_input_file)
+            && (drops_audio || (
+                input_file.audio_codec_name.is_some()
+                    && input_file.audio_codec_name == first_input_file.audio_codec_name
+                    && input_file.audio_sample_rate == first_input_file.audio_sample_rate
+                    && input_file.audio_channels == first_input_file.audio_channels
+            ))
+    })
+}
+
+#[cfg(test)]
+mod test_can_concat_demuxer {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let h264_input_file = |path: &str| InputFile {
+            path: PathBuf::from(path), width: 300, height: 400, video_codec_name: Some("h264".to_string()), frame_rate: "30/1".to_string(), pix_fmt: Some("yuv420p".to_string()), audio_codec_name: Some("aac".to_string()), audio_sample_rate: Some(48000), audio_channels: Some(2), ..Default::default()
+        };
+        assert!(can_concat_demuxer(&[h264_input_file("0.mp4"), h264_input_file("1.mp4")], false));
+        assert!(!can_concat_demuxer(&[], false));
+
+        let test_cases: Vec<fn(&mut InputFile)> = vec![
+            |input_file| input_file.video_codec_name = Some("hevc".to_string()),
+            |input_file| input_file.video_stream_position = 1,
+            |input_file| input_file.width = 400,
+            |input_file| input_file.rotation = 90,
+            |input_file| input_file.frame_rate = "25/1".to_string(),
+            |input_file| input_file.pix_fmt = Some("yuv420p10le".to_string()),
+            |input_file| input_file.sample_aspect_ratio = Some((4, 3)),
+            |input_file| input_file.color.transfer = Some("smpte2084".to_string()),
+        ];
+        for modify in test_cases {
+            let mut input_file = h264_input_file("1.mp4");
+            modify(&mut input_file);
+            assert!(!can_concat_demuxer(&[h264_input_file("0.mp4"), input_file], false));
+        }
+
+        // the audio only matters when it's kept
+        let test_cases: Vec<fn(&mut InputFile)> = vec![
+            |input_file| input_file.audio_codec_name = None,
+            |input_file| input_file.audio_codec_name = Some("opus".to_string()),
+            |input_file| input_file.audio_sample_rate = Some(44100),
+            |input_file| input_file.audio_channels = Some(6),
+        ];
+        for modify in test_cases {
+            let mut input_file = h264_input_file("1.mp4");
+            modify(&mut input_file);
+            assert!(!can_concat_demuxer(&[h264_input_file("0.mp4"), input_file], false));
+            let mut input_file = h264_input_file("1.mp4");
+            modify(&mut input_file);
+            assert!(can_concat_demuxer(&[h264_input_file("0.mp4"), input_file], true));
+        }
+    }
+}
+
+fn concat_passthrough(cmd_str: &str, input_files: &[InputFile], output_video_path: &Path, predicted_duration: f64, options: &EncodeOptions) -> Result<EncodeReport, Error> {
+    let list_path = PathBuf::from(format!("{:}.concat.txt", output_video_path.display()));
+    if let Err(err) = std::fs::write(&list_path, get_concat_list_code(input_files)) {
+        log::trace!("encode_best_effort() -> Error(ConcatListWriteFailed({:?}, {:?}))", &list_path, &err);
+        return Err(Error { kind: ErrorKind::ConcatListWriteFailed(list_path, err.to_string()) });
+    }
+
+    let mut ffmpeg_cmd = Command::new(cmd_str);
+    ffmpeg_cmd.arg(get_overwrite_flag(options.overwrite_policy));
+    ffmpeg_cmd.args(["-f", "concat", "-safe", "0", "-i"]);
+    ffmpeg_cmd.arg(&list_path);
+    ffmpeg_cmd.args(["-c", "copy"]);
+    ffmpeg_cmd.args(get_metadata_args(options));
+    ffmpeg_cmd.args(get_movflags_args(output_video_path, options.mp4_mode, options.output_container));
+    if let Some(format) = get_forced_output_format(output_video_path, options.output_container) {
+        ffmpeg_cmd.args(["-f", format]);
+    }
+    ffmpeg_cmd.arg(output_video_path);
+
+    log::info!("Predicted output duration: {:.3}s", predicted_duration);
+    log::info!("Start ffmpeg: {:?}", ffmpeg_cmd);
+    let output = run_command_with_cancel(&mut ffmpeg_cmd, options.cancel.as_deref());
+    let _ = std::fs::remove_file(&list_path);
+    let output = match output {
+        Ok(output) => output,
+        Err(err) => {
+            log::trace!("encode_best_effort() -> Error(FfmpegCommandProcessFailed({:?})): {:?}", &err, (&ffmpeg_cmd));
+            return Err(Error { kind: ErrorKind::FfmpegCommandProcessFailed(err.to_string()) });
+        },
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    if !output.status.success() {
+        let err = classify_ffmpeg_failure(output.status, stderr);
+        log::trace!("encode_best_effort() -> Error({:?}): {:?}", &err.kind, (&ffmpeg_cmd));
+        return Err(err);
+    }
+
+    let FfmpegSummary { frame_count, bitrate_kbps, muxing_overhead_percent } = parse_ffmpeg_summary(&stderr);
+    if !options.skip_output_validation {
+        validate_output(output_video_path, predicted_duration)?;
+    }
+    let report = EncodeReport { crf: None, passthrough: true, concatenated: 1 < input_files.len(), input_count: input_files.len(), predicted_duration, frame_count, bitrate_kbps, muxing_overhead_percent, output_bytes: get_file_size(output_video_path), input_paths: input_files.iter().map(|input_file| input_file.path.clone()).collect(), ..Default::default() };
+    log::trace!("encode_best_effort() -> Ok({:?})", report);
+    Ok(report)
+}
+
+// concat demuxer list, paths resolved against the list file so they must be absolute
+fn get_concat_list_code(input_files: &[InputFile]) -> String {
+    input_files.iter().map(|input_file| {
+        let path = std::env::current_dir().map(|current_dir| current_dir.join(&input_file.path)).unwrap_or(input_file.path.clone());
+        format!("file '{:}'\n", path.display().to_string().replace('\'', "'\\''"))
+    }).collect()
+}
+
+#[cfg(test)]
+mod test_get_concat_list_code {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let input_files = vec![
+            InputFile { path: PathBuf::from("/videos/0.mp4"), ..Default::default() },
+            InputFile { path: PathBuf::from("/videos/it's.mp4"), ..Default::default() },
+        ];
+        assert_eq!(get_concat_list_code(&input_files), "file '/videos/0.mp4'\nfile '/videos/it'\\''s.mp4'\n");
+
+        let input_files = vec![InputFile { path: PathBuf::from("0.mp4"), ..Default::default() }];
+        assert_eq!(get_concat_list_code(&input_files), format!("file '{:}'\n", std::env::current_dir().unwrap().join("0.mp4").display()));
+    }
+}
+
+// ffmetadata with a chapter per input, in milliseconds on the output timeline
+fn get_chapters_metadata_code(input_files: &Vec<InputFile>) -> String {
+    let mut code = ";FFMETADATA1\n".to_string();
+    let mut start = 0.0;
+    for input_file in input_files {
+        let start_ms = (input_file.start_offset.unwrap_or(start) * 1000.0).round() as i64;
+        let end_ms = start_ms + (input_file.duration.unwrap_or(0.0) * 1000.0).round() as i64;
+        let title = input_file.path.file_stem().map(|file_stem| file_stem.to_string_lossy().to_string()).unwrap_or_default();
+        code.push_str(&format!("[CHAPTER]\nTIMEBASE=1/1000\nSTART={:}\nEND={:}\ntitle={:}\n", start_ms, end_ms, escape_ffmetadata_value(&title)));
+        start += input_file.duration.unwrap_or(0.0);
+    }
+    code
+}
+
+fn escape_ffmetadata_value(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test_get_chapters_metadata_code {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let input_files = vec![
+            InputFile { path: PathBuf::from("data/intro.mp4"), duration: Some(1.5), ..Default::default() },
+            InputFile { path: PathBuf::from("data/a=b;c.mp4"), duration: Some(2.0), ..Default::default() },
+        ];
+        assert_eq!(get_chapters_metadata_code(&input_files), ";FFMETADATA1\n[CHAPTER]\nTIMEBASE=1/1000\nSTART=0\nEND=1500\ntitle=intro\n[CHAPTER]\nTIMEBASE=1/1000\nSTART=1500\nEND=3500\ntitle=a\\=b\\;c\n");
+
+        // placed on the timeline
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), duration: Some(1.0), start_offset: Some(0.5), ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), duration: Some(1.0), start_offset: Some(3.0), ..Default::default() },
+        ];
+        assert_eq!(get_chapters_metadata_code(&input_files), ";FFMETADATA1\n[CHAPTER]\nTIMEBASE=1/1000\nSTART=500\nEND=1500\ntitle=0\n[CHAPTER]\nTIMEBASE=1/1000\nSTART=3000\nEND=4000\ntitle=1\n");
+    }
+}
+
+pub(crate) fn validate_output(output_video_path: &Path, predicted_duration: f64) -> Result<(), Error> {
+    let ffprobe::FfProbe { format, streams } = match ffprobe::ffprobe(output_video_path) {
+        Ok(ffprobe_info) => ffprobe_info,
+        Err(err) => {
+            log::trace!("encode_best_effort() -> Error(OutputValidationFailed({:?}, {:?}))", output_video_path, &err);
+            return Err(Error { kind: ErrorKind::OutputValidationFailed(output_video_path.into(), err.to_string()) });
+        },
+    };
+    if let Err(reason) = validate_output_impl(&format, &streams, predicted_duration) {
+        log::trace!("encode_best_effort() -> Error(OutputValidationFailed({:?}, {:?}))", output_video_path, &reason);
+        return Err(Error { kind: ErrorKind::OutputValidationFailed(output_video_path.into(), reason) });
+    }
+    log::info!("Output validated: {:}", output_video_path.display());
+    Ok(())
+}
+
+// separate impl for test, error is the reason
+fn validate_output_impl(format: &ffprobe::Format, streams: &Vec<ffprobe::Stream>, predicted_duration: f64) -> Result<(), String> {
+    let Some(video_stream) = get_first_video_stream(streams) else {
+        return Err("no video stream".to_string());
+    };
+    let duration = match get_stream_duration(video_stream, format) {
+        Some(duration) if 0.0 < duration => duration,
+        duration => return Err(format!("no positive duration: {:?}", duration)),
+    };
+    // a frame or two of slack for rounding at the input boundaries
+    let duration_tolerance = (predicted_duration * 0.05).max(0.5);
+    if duration_tolerance < (duration - predicted_duration).abs() {
+        return Err(format!("duration {:} doesn't match predicted {:}", duration, predicted_duration));
+    }
+
+    let frame_count = video_stream.nb_frames.as_ref().and_then(|nb_frames| nb_frames.parse::<u64>().ok());
+    if frame_count == Some(0) {
+        return Err("no frames".to_string());
+    }
+    if let (Some(frame_count), Some(frame_rate)) = (frame_count, parse_frame_rate(&video_stream.r_frame_rate)) {
+        let predicted_frame_count = predicted_duration * frame_rate;
+        if duration_tolerance * frame_rate < (frame_count as f64 - predicted_frame_count).abs() {
+            return Err(format!("frame count {:} doesn't match predicted {:}", frame_count, predicted_frame_count));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_validate_output {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn it_works() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+
+        // fixtures are 1 second long
+        assert!(validate_output(&video_dir_path.join("va-300x400.mp4"), 1.0).is_ok());
+        assert!(matches!(validate_output(&video_dir_path.join("va-300x400.mp4"), 10.0), Err(Error { kind: ErrorKind::OutputValidationFailed(_, _) })));
+        assert!(matches!(validate_output(&video_dir_path.join("a.mp4"), 1.0), Err(Error { kind: ErrorKind::OutputValidationFailed(_, _) })));
+        assert!(matches!(validate_output(&video_dir_path.join("invalid.mp4"), 1.0), Err(Error { kind: ErrorKind::OutputValidationFailed(_, _) })));
+    }
+
+    #[test]
+    fn it_checks_duration_and_frames() {
+        let format = ffprobe::Format { duration: Some("10.0".to_string()), ..Default::default() };
+        let video_stream = ffprobe::Stream { codec_type: Some("video".to_string()), duration: Some("10.0".to_string()), nb_frames: Some("300".to_string()), r_frame_rate: "30/1".to_string(), ..Default::default() };
+        let audio_stream = ffprobe::Stream { codec_type: Some("audio".to_string()), ..Default::default() };
+
+        assert_eq!(validate_output_impl(&format, &vec![video_stream.clone(), audio_stream.clone()], 10.0), Ok(()));
+        assert_eq!(validate_output_impl(&format, &vec![video_stream.clone()], 10.2), Ok(()));
+        assert!(validate_output_impl(&format, &vec![audio_stream.clone()], 10.0).is_err());
+        assert!(validate_output_impl(&format, &vec![video_stream.clone()], 20.0).is_err());
+
+        let mut broken_stream = video_stream.clone();
+        broken_stream.nb_frames = Some("0".to_string());
+        assert!(validate_output_impl(&format, &vec![broken_stream], 10.0).is_err());
+
+        let mut broken_stream = video_stream.clone();
+        broken_stream.nb_frames = Some("30".to_string());
+        assert!(validate_output_impl(&format, &vec![broken_stream], 10.0).is_err());
+
+        let mut broken_stream = video_stream.clone();
+        broken_stream.duration = Some("0".to_string());
+        assert!(validate_output_impl(&format, &vec![broken_stream], 10.0).is_err());
+
+        // frame count isn't always in the container
+        let mut stream = video_stream.clone();
+        stream.nb_frames = None;
+        assert_eq!(validate_output_impl(&format, &vec![stream], 10.0), Ok(()));
+    }
+}
+
+// "30000/1001" -> 29.97, none for "0/0"
+fn parse_frame_rate(s: &str) -> Option<f64> {
+    let (numerator, denominator) = match s.split_once('/') {
+        Some((numerator, denominator)) => (numerator.parse::<f64>().ok()?, denominator.parse::<f64>().ok()?),
+        None => (s.parse::<f64>().ok()?, 1.0),
+    };
+    if numerator <= 0.0 || denominator <= 0.0 {
+        return None;
+    }
+    Some(numerator / denominator)
+}
+
+#[cfg(test)]
+mod test_parse_frame_rate {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(parse_frame_rate("30/1"), Some(30.0));
+        assert_eq!(parse_frame_rate("30000/1001"), Some(30000.0 / 1001.0));
+        assert_eq!(parse_frame_rate("25"), Some(25.0));
+        assert_eq!(parse_frame_rate("0/0"), None);
+        assert_eq!(parse_frame_rate(""), None);
+    }
+}
+
+// same as Command::output(), but the child stays killable by kill_running_commands() while waiting
+fn run_command(cmd: &mut Command) -> io::Result<Output> {
+    run_command_with_cancel(cmd, None)
+}
+
+fn run_command_with_cancel(cmd: &mut Command, cancel: Option<&AtomicBool>) -> io::Result<Output> {
+    run_command_with_timeout(cmd, cancel, None)
+}
+
+// the child is killed once cancel is set or the timeout passes, then this returns an interrupted or timed out error
+fn run_command_with_timeout(cmd: &mut Command, cancel: Option<&AtomicBool>, timeout: Option<Duration>) -> io::Result<Output> {
+    // the output regexes expect untranslated messages, whatever the host locale is
+    cmd.env("LANG", "C").env("LC_ALL", "C");
+    let mut child = cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+
+    // read both pipes concurrently, otherwise a full pipe blocks the child
+    let mut stdout_pipe = child.stdout.take().expect("stdout must be piped");
+    let mut stderr_pipe = child.stderr.take().expect("stderr must be piped");
+    let stdout_thread = thread::spawn(move || {
+        let mut stdout = Vec::new();
+        stdout_pipe.read_to_end(&mut stdout).map(|_| stdout)
+    });
+    let stderr_thread = thread::spawn(move || {
+        let mut stderr = Vec::new();
+        stderr_pipe.read_to_end(&mut stderr).map(|_| stderr)
+    });
+
+    let id = child.id();
+    let child = Arc::new(Mutex::new(child));
+    RUNNING_CHILDREN.lock().unwrap().insert(id, child.clone());
+    let started_at = std::time::Instant::now();
+    let mut killed_by = None;
+    let status = loop {
+        match child.lock().unwrap().try_wait() {
+            Ok(Some(status)) => break Ok(status),
+            Ok(None) => {},
+            Err(err) => break Err(err),
+        }
+        if killed_by.is_none() {
+            if cancel.is_some_and(|cancel| cancel.load(Ordering::SeqCst)) {
+                killed_by = Some(io::ErrorKind::Interrupted);
+            } else if timeout.is_some_and(|timeout| timeout <= started_at.elapsed()) {
+                killed_by = Some(io::ErrorKind::TimedOut);
+            }
+            if let Some(kind) = killed_by {
+                log::warn!("Kill command: {:} ({:?})", id, kind);
+                if let Err(err) = child.lock().unwrap().kill() {
+                    log::warn!("Couldn't kill a command: {:} ({:})", id, err);
+                }
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    RUNNING_CHILDREN.lock().unwrap().remove(&id);
+
+    let status = status?;
+    match killed_by {
+        Some(io::ErrorKind::Interrupted) => return Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled")),
+        Some(kind) => return Err(io::Error::new(kind, "timed out")),
+        None => {},
+    }
+    let stdout = stdout_thread.join().expect("stdout reader must not panic")?;
+    let stderr = stderr_thread.join().expect("stderr reader must not panic")?;
+    Ok(Output { status, stdout, stderr })
+}
+
+#[cfg(test)]
+mod test_run_command {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let output = run_command(Command::new("sh").args(["-c", "echo out; echo err >&2; exit 3"])).unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+
+        // larger than a pipe buffer on both pipes
+        let output = run_command(Command::new("sh").args(["-c", "head -c 200000 /dev/zero; head -c 200000 /dev/zero >&2"])).unwrap();
+        assert!(output.status.success());
+        assert_eq!((output.stdout.len(), output.stderr.len()), (200000, 200000));
+
+        assert!(run_command(&mut Command::new("__command_not_found__")).is_err());
+    }
+
+    #[test]
+    fn it_forces_c_locale() {
+        let output = run_command(Command::new("sh").args(["-c", "echo $LANG $LC_ALL"]).env("LANG", "ja_JP.UTF-8").env("LC_ALL", "ja_JP.UTF-8")).unwrap();
+        assert_eq!(output.stdout, b"C C\n");
+    }
+
+    #[test]
+    fn it_can_be_cancelled() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let canceller = {
+            let cancel = cancel.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(300));
+                cancel.store(true, Ordering::SeqCst);
+            })
+        };
+        let started_at = std::time::Instant::now();
+        let err = run_command_with_cancel(Command::new("sleep").arg("10"), Some(&cancel)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+        canceller.join().unwrap();
+
+        // not set, same as run_command()
+        let output = run_command_with_cancel(Command::new("sh").args(["-c", "exit 0"]), Some(&AtomicBool::new(false))).unwrap();
+        assert!(output.status.success());
+    }
+
+    #[test]
+    fn it_can_time_out() {
+        let started_at = std::time::Instant::now();
+        let err = run_command_with_timeout(Command::new("sleep").arg("10"), None, Some(Duration::from_millis(300))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+
+        let output = run_command_with_timeout(Command::new("sh").args(["-c", "exit 0"]), None, Some(Duration::from_secs(10))).unwrap();
+        assert!(output.status.success());
+    }
+}
+
+// for shutdown, the waiting callers see the killed children exit abnormally
+pub(crate) fn kill_running_commands() {
+    for (id, child) in RUNNING_CHILDREN.lock().unwrap().iter() {
+        log::warn!("Kill running command: {:}", id);
+        if let Err(err) = child.lock().unwrap().kill() {
+            log::warn!("Couldn't kill a running command: {:} ({:})", id, err);
+        }
+    }
+}
+
+// the override, otherwise by the extension, unknown extensions are forced to mp4
+fn get_output_container(path: &Path, output_container: Option<OutputContainer>) -> OutputContainer {
+    output_container.unwrap_or_else(|| match path.extension().and_then(|extension| extension.to_str()).map(|extension| extension.to_lowercase()).as_deref() {
+        Some("webm") => OutputContainer::Webm,
+        Some("mkv") => OutputContainer::Mkv,
+        Some("mov") => OutputContainer::Mov,
+        _ => OutputContainer::Mp4,
+    })
+}
+
+#[cfg(test)]
+mod test_get_output_container {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let test_cases = [
+            ("output/a.mp4", None, OutputContainer::Mp4),
+            ("output/a.MKV", None, OutputContainer::Mkv),
+            ("output/a.webm", None, OutputContainer::Webm),
+            ("output/a.mov", None, OutputContainer::Mov),
+            ("output/a", None, OutputContainer::Mp4),
+            ("output/a.mp4", Some(OutputContainer::Mkv), OutputContainer::Mkv),
+            ("output/a.webm", Some(OutputContainer::Mp4), OutputContainer::Mp4),
+        ];
+        for (path, output_container, expected) in test_cases {
+            assert_eq!(get_output_container(Path::new(path), output_container), expected, "{:} {:?}", path, output_container);
+        }
+    }
+}
+
+// the only text subtitle codec each container takes
+fn get_subtitle_codec(path: &Path, output_container: Option<OutputContainer>) -> &'static str {
+    match get_output_container(path, output_container) {
+        OutputContainer::Webm => "webvtt",
+        OutputContainer::Mkv => "srt",
+        OutputContainer::Mp4 | OutputContainer::Mov => "mov_text",
+    }
+}
+
+#[cfg(test)]
+mod test_get_subtitle_codec {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_subtitle_codec(Path::new("output/a.mp4"), None), "mov_text");
+        assert_eq!(get_subtitle_codec(Path::new("output/a.mov"), None), "mov_text");
+        assert_eq!(get_subtitle_codec(Path::new("output/a.WEBM"), None), "webvtt");
+        assert_eq!(get_subtitle_codec(Path::new("output/a.mkv"), None), "srt");
+        assert_eq!(get_subtitle_codec(Path::new("output/a"), None), "mov_text");
+        assert_eq!(get_subtitle_codec(Path::new("output/a.mp4"), Some(OutputContainer::Mkv)), "srt");
+    }
+}
+
+// ffmpeg infers the muxer from the extension, so it can't be left to infer without a known one
+// the container override wins over any extension
+fn get_forced_output_format(path: &Path, output_container: Option<OutputContainer>) -> Option<&'static str> {
+    if let Some(output_container) = output_container {
+        return Some(output_container.as_ffmpeg_format());
+    }
+    let is_known_extension = match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => KNOWN_OUTPUT_EXTENSIONS.contains(&extension.to_lowercase().as_str()),
+        None => false,
+    };
+    if is_known_extension {
+        None
+    } else {
+        Some(DEFAULT_OUTPUT_FORMAT)
+    }
+}
+
+// ffmpeg rejects movflags for the other muxers
+fn get_movflags_args(output_video_path: &Path, mp4_mode: Mp4Mode, output_container: Option<OutputContainer>) -> Vec<String> {
+    let is_mp4 = matches!(get_output_container(output_video_path, output_container), OutputContainer::Mp4 | OutputContainer::Mov);
+    match mp4_mode.as_movflags_value() {
+        Some(movflags) if is_mp4 => vec!["-movflags".to_string(), movflags.to_string()],
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod test_get_movflags_args {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let test_cases = [
+            ("a.mp4", Mp4Mode::Regular, None, ""),
+            ("a.mp4", Mp4Mode::Faststart, None, "-movflags +faststart"),
+            ("a.mp4", Mp4Mode::Fragmented, None, "-movflags +frag_keyframe+empty_moov"),
+            ("a.MOV", Mp4Mode::Faststart, None, "-movflags +faststart"),
+            ("a", Mp4Mode::Fragmented, None, "-movflags +frag_keyframe+empty_moov"),
+            ("a.mkv", Mp4Mode::Faststart, None, ""),
+            ("a.webm", Mp4Mode::Fragmented, None, ""),
+            // the container, not the extension
+            ("a.mp4", Mp4Mode::Faststart, Some(OutputContainer::Mkv), ""),
+            ("a.mkv", Mp4Mode::Faststart, Some(OutputContainer::Mp4), "-movflags +faststart"),
+        ];
+        for (path, mp4_mode, output_container, expected) in test_cases {
+            assert_eq!(get_movflags_args(Path::new(path), mp4_mode, output_container).join(" "), expected, "{:} {:?} {:?}", path, mp4_mode, output_container);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_get_forced_output_format {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let test_cases = [
+            ("output/a.mp4", None),
+            ("output/a.MP4", None),
+            ("output/a.mkv", None),
+            ("output/a.webm", None),
+            ("output/a.mov", None),
+            ("output/a", Some("mp4")),
+            ("output/a.", Some("mp4")),
+            ("output/a.unknown", Some("mp4")),
+            ("output/a.mp4.d/b", Some("mp4")),
+        ];
+
+        for (path, expected) in test_cases {
+            assert_eq!(get_forced_output_format(Path::new(path), None), expected);
+        }
+
+        // the override wins over any extension
+        assert_eq!(get_forced_output_format(Path::new("output/a.mp4"), Some(OutputContainer::Mkv)), Some("matroska"));
+        assert_eq!(get_forced_output_format(Path::new("output/a"), Some(OutputContainer::Webm)), Some("webm"));
+    }
+}
+
+// input options apply to the next -i only, so these go before each input
+fn get_input_flag_args(options: &EncodeOptions) -> Vec<String> {
+    let mut args = Vec::new();
+    if options.genpts {
+        args.extend(["-fflags".to_string(), "+genpts".to_string()]);
+    }
+    args
+}
+
+#[cfg(test)]
+mod test_get_input_flag_args {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_input_flag_args(&EncodeOptions::default()), Vec::<String>::new());
+        assert_eq!(get_input_flag_args(&EncodeOptions { genpts: true, ..Default::default() }).join(" "), "-fflags +genpts");
+    }
+}
+
+fn get_output_args(crf: u8, output_video_path: &Path, options: &EncodeOptions, hdr_color: Option<&ColorProperties>) -> Vec<String> {
+    let mut args = vec![
+        "-c:v".to_string(), "libsvtav1".to_string(),
+        "-crf".to_string(), crf.to_string(),
+        "-pix_fmt".to_string(), options.pixel_format.as_ffmpeg_value().to_string(),
+        "-preset".to_string(), "8".to_string(),
+        "-c:a".to_string(), "libopus".to_string(),
+        "-application".to_string(), options.opus_application.as_ffmpeg_value().to_string(),
+    ];
+
+    let svtav1_params = get_svtav1_params(options, hdr_color);
+    if !svtav1_params.is_empty() {
+        args.extend(["-svtav1-params".to_string(), svtav1_params.join(":")]);
+    }
+    if let Some(hdr_color) = hdr_color {
+        for (name, value) in [("-color_primaries", &hdr_color.primaries), ("-color_trc", &hdr_color.transfer), ("-colorspace", &hdr_color.space)] {
+            if let Some(value) = value {
+                args.extend([name.to_string(), value.clone()]);
+            }
+        }
+    }
+
+    if options.deterministic {
+        // no thread scheduling nondeterminism, no creation time, no encoder version tag
+        args.extend([
+            "-threads", "1",
+            "-map_metadata", "-1",
+            "-fflags", "+bitexact",
+            "-flags:v", "+bitexact",
+            "-flags:a", "+bitexact",
+        ].map(String::from));
+    }
+    args.extend(get_metadata_args(options));
+    args.extend(get_movflags_args(output_video_path, options.mp4_mode, options.output_container));
+
+    if options.captions_path.is_some() {
+        args.extend(["-c:s".to_string(), get_subtitle_codec(output_video_path, options.output_container).to_string()]);
+    }
+
+    // output side, so it cuts the filtered timeline of the whole program rather than each input
+    if let Some((start, end)) = options.output_range {
+        args.extend(["-ss".to_string(), start.to_string(), "-to".to_string(), end.to_string()]);
+    }
+
+    if let Some(output_format) = get_forced_output_format(output_video_path, options.output_container) {
+        log::info!("Force output format: {:} ({:})", output_format, output_video_path.display());
+        args.extend(["-f".to_string(), output_format.to_string()]);
+    }
+
+    args
+}
+
+#[cfg(test)]
+mod test_get_output_args {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_output_args(30, Path::new("a.mp4"), &EncodeOptions::default(), None).join(" "), "-c:v libsvtav1 -crf 30 -pix_fmt yuv420p10le -preset 8 -c:a libopus -application audio");
+        assert_eq!(get_output_args(30, Path::new("a.webm"), &EncodeOptions { captions_path: Some(PathBuf::from("data/a.srt")), ..Default::default() }, None).join(" "), "-c:v libsvtav1 -crf 30 -pix_fmt yuv420p10le -preset 8 -c:a libopus -application audio -c:s webvtt");
+        assert_eq!(get_output_args(30, Path::new("a"), &EncodeOptions::default(), None).join(" "), "-c:v libsvtav1 -crf 30 -pix_fmt yuv420p10le -preset 8 -c:a libopus -application audio -f mp4");
+        assert_eq!(get_output_args(30, Path::new("a.mp4"), &EncodeOptions { deterministic: true, ..Default::default() }, None).join(" "), "-c:v libsvtav1 -crf 30 -pix_fmt yuv420p10le -preset 8 -c:a libopus -application audio -svtav1-params lp=1 -threads 1 -map_metadata -1 -fflags +bitexact -flags:v +bitexact -flags:a +bitexact");
+        assert_eq!(get_output_args(30, Path::new("a"), &EncodeOptions { output_range: Some((600.0, 720.5)), ..Default::default() }, None).join(" "), "-c:v libsvtav1 -crf 30 -pix_fmt yuv420p10le -preset 8 -c:a libopus -application audio -ss 600 -to 720.5 -f mp4");
+        assert_eq!(get_output_args(30, Path::new("a.mp4"), &EncodeOptions { opus_application: OpusApplication::Voip, ..Default::default() }, None).join(" "), "-c:v libsvtav1 -crf 30 -pix_fmt yuv420p10le -preset 8 -c:a libopus -application voip");
+        assert_eq!(get_output_args(30, Path::new("a.mp4"), &EncodeOptions { opus_application: OpusApplication::LowDelay, ..Default::default() }, None).join(" "), "-c:v libsvtav1 -crf 30 -pix_fmt yuv420p10le -preset 8 -c:a libopus -application lowdelay");
+        assert_eq!(get_output_args(30, Path::new("a.mp4"), &EncodeOptions { mp4_mode: Mp4Mode::Faststart, ..Default::default() }, None).join(" "), "-c:v libsvtav1 -crf 30 -pix_fmt yuv420p10le -preset 8 -c:a libopus -application audio -movflags +faststart");
+        assert_eq!(get_output_args(30, Path::new("a"), &EncodeOptions { mp4_mode: Mp4Mode::Fragmented, ..Default::default() }, None).join(" "), "-c:v libsvtav1 -crf 30 -pix_fmt yuv420p10le -preset 8 -c:a libopus -application audio -movflags +frag_keyframe+empty_moov -f mp4");
+        assert_eq!(get_output_args(30, Path::new("a.mp4"), &EncodeOptions { pixel_format: OutputPixelFormat::Yuv444p10le, ..Default::default() }, None).join(" "), "-c:v libsvtav1 -crf 30 -pix_fmt yuv444p10le -preset 8 -c:a libopus -application audio");
+        let hdr_color = ColorProperties { primaries: Some("bt2020".to_string()), transfer: Some("smpte2084".to_string()), space: Some("bt2020nc".to_string()) };
+        assert_eq!(get_output_args(30, Path::new("a.mp4"), &EncodeOptions::default(), Some(&hdr_color)).join(" "), "-c:v libsvtav1 -crf 30 -pix_fmt yuv420p10le -preset 8 -c:a libopus -application audio -svtav1-params enable-hdr=1:color-primaries=9:transfer-characteristics=16:matrix-coefficients=9 -color_primaries bt2020 -color_trc smpte2084 -colorspace bt2020nc");
+    }
+}
+
+// splits [vout][aout] into [vmain][amain] and [vrendition{n}][arendition{n}]
+fn get_rendition_split_filter_code(renditions: &[Rendition], audio: bool, audio_sidecar: bool) -> String {
+    let mut filter_code = String::new();
+
+    if !renditions.is_empty() {
+        filter_code.push_str(&format!(";[vout]split={:}[vmain]", renditions.len() + 1));
+        for index in 0..renditions.len() {
+            filter_code.push_str(&format!("[vsplit{:}]", index));
+        }
+    }
+    if audio {
+        filter_code.push_str(&format!(";[aout]asplit={:}[amain]", renditions.len() + 1 + audio_sidecar as usize));
+        for index in 0..renditions.len() {
+            filter_code.push_str(&format!("[arendition{:}]", index));
+        }
+        if audio_sidecar {
+            filter_code.push_str("[asidecar]");
+        }
+    }
+    for (index, rendition) in renditions.iter().enumerate() {
+        // -2 keeps the aspect ratio with an even width
+        filter_code.push_str(&format!(";[vsplit{0:}]scale=-2:{1:}[vrendition{0:}]", index, rendition.height));
+    }
+
+    log::info!("Add filter: {:}", filter_code);
+    filter_code
+}
+
+#[cfg(test)]
+mod test_get_rendition_split_filter_code {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let renditions = vec![Rendition { height: 720, crf: None }, Rendition { height: 480, crf: Some(40) }];
+        assert_eq!(get_rendition_split_filter_code(&renditions, true, false), ";[vout]split=3[vmain][vsplit0][vsplit1];[aout]asplit=3[amain][arendition0][arendition1];[vsplit0]scale=-2:720[vrendition0];[vsplit1]scale=-2:480[vrendition1]");
+        assert_eq!(get_rendition_split_filter_code(&renditions, true, true), ";[vout]split=3[vmain][vsplit0][vsplit1];[aout]asplit=4[amain][arendition0][arendition1][asidecar];[vsplit0]scale=-2:720[vrendition0];[vsplit1]scale=-2:480[vrendition1]");
+
+        // the video isn't split for the audio sidecar alone
+        assert_eq!(get_rendition_split_filter_code(&[], true, true), ";[aout]asplit=2[amain][asidecar]");
+
+        // no audio to split
+        assert_eq!(get_rendition_split_filter_code(&renditions, false, false), ";[vout]split=3[vmain][vsplit0][vsplit1];[vsplit0]scale=-2:720[vrendition0];[vsplit1]scale=-2:480[vrendition1]");
+    }
+}
+
+// a.mp4 -> a-720p.mp4
+pub(crate) fn get_rendition_path(path: impl AsRef<Path>, height: i64) -> PathBuf {
+    let path = path.as_ref();
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    let file_name = match path.extension() {
+        Some(extension) => format!("{:}-{:}p.{:}", stem, height, extension.to_string_lossy()),
+        None => format!("{:}-{:}p", stem, height),
+    };
+    path.with_file_name(file_name)
+}
+
+// a.mp4 -> a-audio.opus
+pub(crate) fn get_audio_sidecar_path(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    path.with_file_name(format!("{:}-audio.opus", stem))
+}
+
+#[cfg(test)]
+mod test_get_audio_sidecar_path {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let test_cases = [
+            ("output/a.mp4", "output/a-audio.opus"),
+            ("output/a", "output/a-audio.opus"),
+            ("a.b.mkv", "a.b-audio.opus"),
+        ];
+
+        for (path, expected) in test_cases {
+            assert_eq!(get_audio_sidecar_path(path), PathBuf::from(expected));
+        }
+    }
+}
+
+// deterministic mode strips the metadata already
+fn get_metadata_args(options: &EncodeOptions) -> Vec<String> {
+    let mut args = match &options.metadata_stamp {
+        MetadataStamp::Keep => vec![],
+        MetadataStamp::Strip if options.deterministic => vec![],
+        MetadataStamp::Strip => vec!["-map_metadata".to_string(), "-1".to_string()],
+        MetadataStamp::CreationTime(creation_time) => vec!["-metadata".to_string(), format!("creation_time={:}", creation_time)],
+    };
+    // set after the strip, so it stays
+    if !options.source_ids.is_empty() {
+        args.extend(["-metadata".to_string(), format!("comment={:}", get_sources_comment(&options.source_ids))]);
+    }
+    args
+}
+
+#[cfg(test)]
+mod test_get_metadata_args {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert!(get_metadata_args(&EncodeOptions::default()).is_empty());
+        assert_eq!(get_metadata_args(&EncodeOptions { metadata_stamp: MetadataStamp::Strip, ..Default::default() }).join(" "), "-map_metadata -1");
+        assert!(get_metadata_args(&EncodeOptions { metadata_stamp: MetadataStamp::Strip, deterministic: true, ..Default::default() }).is_empty());
+        let metadata_stamp = MetadataStamp::CreationTime("2023-01-01T00:00:00Z".to_string());
+        assert_eq!(get_metadata_args(&EncodeOptions { metadata_stamp: metadata_stamp.clone(), ..Default::default() }).join(" "), "-metadata creation_time=2023-01-01T00:00:00Z");
+        assert_eq!(get_metadata_args(&EncodeOptions { metadata_stamp, deterministic: true, ..Default::default() }).join(" "), "-metadata creation_time=2023-01-01T00:00:00Z");
+
+        let source_ids = vec!["a.mp4".to_string(), "b.mp4".to_string()];
+        assert_eq!(get_metadata_args(&EncodeOptions { source_ids: source_ids.clone(), ..Default::default() }).join(" "), "-metadata comment=sources: a.mp4,b.mp4");
+        assert_eq!(get_metadata_args(&EncodeOptions { source_ids, metadata_stamp: MetadataStamp::Strip, ..Default::default() }).join(" "), "-map_metadata -1 -metadata comment=sources: a.mp4,b.mp4");
+    }
+}
+
+// in chars, muxers and players commonly cut a text tag at 255
+const MAX_SOURCES_COMMENT_LENGTH: usize = 255;
+
+// "sources: id1,id2,...", with an ellipsis at the end when too long
+fn get_sources_comment(source_ids: &[String]) -> String {
+    let comment = format!("sources: {:}", source_ids.join(","));
+    if comment.chars().count() <= MAX_SOURCES_COMMENT_LENGTH {
+        return comment;
+    }
+    let mut truncated = comment.chars().take(MAX_SOURCES_COMMENT_LENGTH - 1).collect::<String>();
+    truncated.push('…');
+    truncated
+}
+
+#[cfg(test)]
+mod test_get_sources_comment {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_sources_comment(&["a.mp4".to_string()]), "sources: a.mp4");
+        assert_eq!(get_sources_comment(&["clips/a.mp4".to_string(), "clips/b.mp4".to_string()]), "sources: clips/a.mp4,clips/b.mp4");
+
+        let source_ids = (0..100).map(|i| format!("clips/{:}.mp4", i)).collect::<Vec<_>>();
+        let comment = get_sources_comment(&source_ids);
+        assert_eq!(comment.chars().count(), MAX_SOURCES_COMMENT_LENGTH);
+        assert!(comment.starts_with("sources: clips/0.mp4,clips/1.mp4,"));
+        assert!(comment.ends_with('…'));
+
+        // cut on a char, not a byte
+        let source_ids = vec!["あ".repeat(300)];
+        let comment = get_sources_comment(&source_ids);
+        assert_eq!(comment.chars().count(), MAX_SOURCES_COMMENT_LENGTH);
+        assert!(comment.ends_with("あ…"));
+    }
+}
+
+// the audio part of get_output_args(), without video
+fn get_audio_sidecar_output_args(options: &EncodeOptions) -> Vec<String> {
+    let mut args = vec![
+        "-vn".to_string(),
+        "-c:a".to_string(), "libopus".to_string(),
+        "-application".to_string(), options.opus_application.as_ffmpeg_value().to_string(),
+    ];
+    if options.deterministic {
+        args.extend(["-map_metadata", "-1", "-fflags", "+bitexact", "-flags:a", "+bitexact"].map(String::from));
+    }
+    args.extend(get_metadata_args(options));
+    if let Some((start, end)) = options.output_range {
+        args.extend(["-ss".to_string(), start.to_string(), "-to".to_string(), end.to_string()]);
+    }
+    args
+}
+
+#[cfg(test)]
+mod test_get_audio_sidecar_output_args {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_audio_sidecar_output_args(&EncodeOptions::default()).join(" "), "-vn -c:a libopus -application audio");
+        assert_eq!(get_audio_sidecar_output_args(&EncodeOptions { opus_application: OpusApplication::Voip, deterministic: true, output_range: Some((1.0, 2.5)), ..Default::default() }).join(" "), "-vn -c:a libopus -application voip -map_metadata -1 -fflags +bitexact -flags:a +bitexact -ss 1 -to 2.5");
+    }
+}
+
+// a.mp4 -> a-thumbnails.jpg
+pub(crate) fn get_thumbnail_sheet_path(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    path.with_file_name(format!("{:}-thumbnails.jpg", stem))
+}
+
+#[cfg(test)]
+mod test_get_thumbnail_sheet_path {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let test_cases = [
+            ("output/a.mp4", "output/a-thumbnails.jpg"),
+            ("output/a", "output/a-thumbnails.jpg"),
+            ("a.b.mkv", "a.b-thumbnails.jpg"),
+        ];
+
+        for (path, expected) in test_cases {
+            assert_eq!(get_thumbnail_sheet_path(path), PathBuf::from(expected));
+        }
+    }
+}
+
+fn get_thumbnail_sheet_filter_code(spec: &ThumbnailSheetSpec, duration: f64, frame_count: Option<u64>) -> String {
+    let cell_count = spec.rows * spec.cols;
+    let scale_and_tile = format!("scale={:}:-2,tile={:}x{:}", spec.width, spec.cols, spec.rows);
+    // too few frames to sample, take them all and tile leaves the rest of the cells blank
+    if frame_count.is_some_and(|frame_count| frame_count <= cell_count as u64) {
+        return scale_and_tile;
+    }
+    format!("fps={:.6},{:}", cell_count as f64 / duration, scale_and_tile)
+}
+
+#[cfg(test)]
+mod test_get_thumbnail_sheet_filter_code {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let spec = ThumbnailSheetSpec { rows: 3, cols: 4, width: 160 };
+        assert_eq!(get_thumbnail_sheet_filter_code(&spec, 6.0, Some(180)), "fps=2.000000,scale=160:-2,tile=4x3");
+        assert_eq!(get_thumbnail_sheet_filter_code(&spec, 6.0, None), "fps=2.000000,scale=160:-2,tile=4x3");
+        assert_eq!(get_thumbnail_sheet_filter_code(&spec, 0.2, Some(6)), "scale=160:-2,tile=4x3");
+        assert_eq!(get_thumbnail_sheet_filter_code(&spec, 0.4, Some(12)), "scale=160:-2,tile=4x3");
+    }
+}
+
+fn make_output_thumbnail_sheet(cmd_str: &str, output_video_path: &Path, duration: f64, frame_count: Option<u64>, options: &EncodeOptions) -> Result<Option<PathBuf>, Error> {
+    let Some(spec) = &options.thumbnail_sheet else {
+        return Ok(None);
+    };
+    let thumbnail_sheet_path = get_thumbnail_sheet_path(output_video_path);
+
+    let mut ffmpeg_cmd = Command::new(cmd_str);
+    ffmpeg_cmd.arg("-y").arg("-i").arg(output_video_path);
+    ffmpeg_cmd.args(["-vf", &get_thumbnail_sheet_filter_code(spec, duration, frame_count), "-frames:v", "1", "-update", "1", "-q:v", "3"]);
+    ffmpeg_cmd.arg(&thumbnail_sheet_path);
+
+    log::info!("Start thumbnail sheet: {:?}", ffmpeg_cmd);
+    let output = match run_command_with_cancel(&mut ffmpeg_cmd, options.cancel.as_deref()) {
+        Ok(output) => output,
+        Err(err) => {
+            log::trace!("encode_best_effort() -> Error(FfmpegCommandProcessFailed({:?})): {:?}", &err, (&ffmpeg_cmd));
+            return Err(Error { kind: ErrorKind::FfmpegCommandProcessFailed(err.to_string()) });
+        },
+    };
+    if !output.status.success() {
+        let err = classify_ffmpeg_failure(output.status, String::from_utf8_lossy(&output.stderr).to_string());
+        log::trace!("encode_best_effort() -> Error({:?}): {:?}", &err.kind, (&ffmpeg_cmd));
+        return Err(err);
+    }
+    Ok(Some(thumbnail_sheet_path))
+}
+
+// a.mp4 -> a-sprite-000.jpg, numbered from zero
+pub(crate) fn get_preview_sprite_path(path: impl AsRef<Path>, index: usize) -> PathBuf {
+    let path = path.as_ref();
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    path.with_file_name(format!("{:}-sprite-{:03}.jpg", stem, index))
+}
+
+// a.mp4 -> a-sprite.vtt
+pub(crate) fn get_preview_vtt_path(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    path.with_file_name(format!("{:}-sprite.vtt", stem))
+}
+
+#[cfg(test)]
+mod test_get_preview_sprite_path {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_preview_sprite_path("output/a.mp4", 0), PathBuf::from("output/a-sprite-000.jpg"));
+        assert_eq!(get_preview_sprite_path("output/a", 12), PathBuf::from("output/a-sprite-012.jpg"));
+        assert_eq!(get_preview_sprite_path("a.b.mkv", 1000), PathBuf::from("a.b-sprite-1000.jpg"));
+        assert_eq!(get_preview_vtt_path("output/a.mp4"), PathBuf::from("output/a-sprite.vtt"));
+        assert_eq!(get_preview_vtt_path("a.b.mkv"), PathBuf::from("a.b-sprite.vtt"));
+    }
+}
+
+// one tile for each started interval
+fn get_preview_tile_count(duration: f64, interval: f64) -> usize {
+    ((duration / interval).ceil() as usize).max(1)
+}
+
+#[cfg(test)]
+mod test_get_preview_tile_count {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_preview_tile_count(10.0, 2.0), 5);
+        assert_eq!(get_preview_tile_count(10.5, 2.0), 6);
+        assert_eq!(get_preview_tile_count(0.5, 2.0), 1);
+    }
+}
+
+// HH:MM:SS.mmm
+fn format_vtt_timestamp(secs: f64) -> String {
+    let millis = (secs * 1000.0).round() as u64;
+    format!("{:02}:{:02}:{:02}.{:03}", millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60, millis % 1000)
+}
+
+#[cfg(test)]
+mod test_format_vtt_timestamp {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(format_vtt_timestamp(0.0), "00:00:00.000");
+        assert_eq!(format_vtt_timestamp(1.5), "00:00:01.500");
+        assert_eq!(format_vtt_timestamp(3723.0004), "01:02:03.000");
+        assert_eq!(format_vtt_timestamp(59.9996), "00:01:00.000");
+    }
+}
+
+// one cue for each tile, the last one ends with the output
+fn get_preview_vtt_code(spec: &SpriteSpec, duration: f64, sprite_file_names: &[String], tile_width: u32, tile_height: u32) -> String {
+    let tiles_per_sprite = (spec.rows * spec.cols) as usize;
+    let mut code = "WEBVTT\n".to_string();
+    for index in 0..get_preview_tile_count(duration, spec.interval) {
+        let start = index as f64 * spec.interval;
+        let end = (start + spec.interval).min(duration);
+        let cell = index % tiles_per_sprite;
+        let (x, y) = ((cell as u32 % spec.cols) * tile_width, (cell as u32 / spec.cols) * tile_height);
+        code.push_str(&format!("\n{:} --> {:}\n{:}#xywh={:},{:},{:},{:}\n", format_vtt_timestamp(start), format_vtt_timestamp(end), sprite_file_names[index / tiles_per_sprite], x, y, tile_width, tile_height));
+    }
+    code
+}
+
+#[cfg(test)]
+mod test_get_preview_vtt_code {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let spec = SpriteSpec { interval: 2.0, rows: 2, cols: 2, width: 160 };
+        let sprite_file_names = vec!["a-sprite-000.jpg".to_string(), "a-sprite-001.jpg".to_string()];
+        let code = get_preview_vtt_code(&spec, 9.5, &sprite_file_names, 160, 90);
+        assert_eq!(code, "WEBVTT
+
+00:00:00.000 --> 00:00:02.000
+a-sprite-000.jpg#xywh=0,0,160,90
+
+00:00:02.000 --> 00:00:04.000
+a-sprite-000.jpg#xywh=160,0,160,90
+
+00:00:04.000 --> 00:00:06.000
+a-sprite-000.jpg#xywh=0,90,160,90
+
+00:00:06.000 --> 00:00:08.000
+a-sprite-000.jpg#xywh=160,90,160,90
+
+00:00:08.000 --> 00:00:09.500
+a-sprite-001.jpg#xywh=0,0,160,90
+");
+        assert_eq!(code.matches(" --> ").count(), 5);
+    }
+}
+
+// after the output is written, from the output itself so the tiles show what's played
+fn make_output_preview_sprite(cmd_str: &str, output_video_path: &Path, duration: f64, options: &EncodeOptions) -> Result<(Vec<PathBuf>, Option<PathBuf>), Error> {
+    let Some(spec) = &options.preview_sprite else {
+        return Ok((vec![], None));
+    };
+    let tile_count = get_preview_tile_count(duration, spec.interval);
+    let tiles_per_sprite = (spec.rows * spec.cols) as usize;
+    let sprite_count = tile_count.div_ceil(tiles_per_sprite);
+    let sprite_paths = (0..sprite_count).map(|index| get_preview_sprite_path(output_video_path, index)).collect::<Vec<_>>();
+
+    let mut ffmpeg_cmd = Command::new(cmd_str);
+    ffmpeg_cmd.arg("-y").arg("-i").arg(output_video_path);
+    // tile flushes the last sprite with its empty tiles left black
+    let filter_code = format!("fps={:.6},scale={:}:-2,tile={:}x{:}", 1.0 / spec.interval, spec.width, spec.cols, spec.rows);
+    ffmpeg_cmd.args(["-vf", &filter_code, "-frames:v", &sprite_count.to_string(), "-start_number", "0", "-q:v", "3"]);
+    let stem = output_video_path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    ffmpeg_cmd.arg(output_video_path.with_file_name(format!("{:}-sprite-%03d.jpg", stem)));
+
+    log::info!("Start preview sprite: {:?}", ffmpeg_cmd);
+    let output = match run_command_with_cancel(&mut ffmpeg_cmd, options.cancel.as_deref()) {
+        Ok(output) => output,
+        Err(err) => {
+            log::trace!("encode_best_effort() -> Error(FfmpegCommandProcessFailed({:?})): {:?}", &err, (&ffmpeg_cmd));
+            return Err(Error { kind: ErrorKind::FfmpegCommandProcessFailed(err.to_string()) });
+        },
+    };
+    if !output.status.success() {
+        let err = classify_ffmpeg_failure(output.status, String::from_utf8_lossy(&output.stderr).to_string());
+        log::trace!("encode_best_effort() -> Error({:?}): {:?}", &err.kind, (&ffmpeg_cmd));
+        return Err(err);
+    }
+
+    // the tile height comes from the scaled output, so read it back
+    let (sprite_width, sprite_height) = match ffprobe::ffprobe(&sprite_paths[0]).map(|ffprobe_info| get_first_video_stream(&ffprobe_info.streams).and_then(|stream| stream.width.zip(stream.height))) {
+        Ok(Some(resolution)) => resolution,
+        Ok(None) => {
+            log::trace!("encode_best_effort() -> Error(OutputValidationFailed({:?}))", &sprite_paths[0]);
+            return Err(Error { kind: ErrorKind::OutputValidationFailed(sprite_paths[0].clone(), "no resolution".to_string()) });
+        },
+        Err(err) => {
+            log::trace!("encode_best_effort() -> Error(OutputValidationFailed({:?}, {:?}))", &sprite_paths[0], &err);
+            return Err(Error { kind: ErrorKind::OutputValidationFailed(sprite_paths[0].clone(), err.to_string()) });
+        },
+    };
+    let (tile_width, tile_height) = (sprite_width as u32 / spec.cols, sprite_height as u32 / spec.rows);
+
+    let sprite_file_names = sprite_paths.iter().map(|sprite_path| sprite_path.file_name().unwrap_or_default().to_string_lossy().to_string()).collect::<Vec<_>>();
+    let vtt_path = get_preview_vtt_path(output_video_path);
+    if let Err(err) = std::fs::write(&vtt_path, get_preview_vtt_code(spec, duration, &sprite_file_names, tile_width, tile_height)) {
+        log::trace!("encode_best_effort() -> Error(PreviewVttWriteFailed({:?}, {:?}))", &vtt_path, &err);
+        return Err(Error { kind: ErrorKind::PreviewVttWriteFailed(vtt_path, err.to_string()) });
+    }
+    log::info!("Preview sprite: {:} images, {:} tiles of {:}x{:}", sprite_paths.len(), tile_count, tile_width, tile_height);
+    Ok((sprite_paths, Some(vtt_path)))
+}
+
+#[cfg(test)]
+mod test_get_rendition_path {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let test_cases = [
+            ("output/a.mp4", 720, "output/a-720p.mp4"),
+            ("output/a", 480, "output/a-480p"),
+            ("a.b.mkv", 1080, "a.b-1080p.mkv"),
+        ];
+
+        for (path, height, expected) in test_cases {
+            assert_eq!(get_rendition_path(path, height), PathBuf::from(expected));
+        }
+    }
+}
+
+fn get_svtav1_params(options: &EncodeOptions, hdr_color: Option<&ColorProperties>) -> Vec<String> {
+    let mut params = Vec::new();
+    if options.deterministic {
+        params.push("lp=1".to_string());
+    }
+    if options.memory_limit_mb.is_some() {
+        params.push(format!("lookahead={:}", SVTAV1_MEMORY_LIMITED_LOOKAHEAD));
+    }
+    params.extend(get_search_svtav1_params(options));
+    if let Some(hdr_color) = hdr_color {
+        params.extend(get_svtav1_color_params(hdr_color));
+    }
+    params
+}
+
+// svt-av1 takes the numbers of the av1 spec, the names it doesn't know are left to ffmpeg's tags
+fn get_svtav1_color_params(color: &ColorProperties) -> Vec<String> {
+    let mut params = vec!["enable-hdr=1".to_string()];
+    let color_primaries = color.primaries.as_deref().and_then(|primaries| match primaries {
+        "bt709" => Some(1),
+        "bt2020" => Some(9),
+        "smpte432" => Some(12),
+        _ => None,
+    });
+    if let Some(color_primaries) = color_primaries {
+        params.push(format!("color-primaries={:}", color_primaries));
+    }
+    let transfer_characteristics = color.transfer.as_deref().and_then(|transfer| match transfer {
+        "bt709" => Some(1),
+        "smpte2084" => Some(16),
+        "arib-std-b67" => Some(18),
+        _ => None,
+    });
+    if let Some(transfer_characteristics) = transfer_characteristics {
+        params.push(format!("transfer-characteristics={:}", transfer_characteristics));
+    }
+    let matrix_coefficients = color.space.as_deref().and_then(|space| match space {
+        "bt709" => Some(1),
+        "bt2020nc" => Some(9),
+        "bt2020c" => Some(10),
+        _ => None,
+    });
+    if let Some(matrix_coefficients) = matrix_coefficients {
+        params.push(format!("matrix-coefficients={:}", matrix_coefficients));
+    }
+    params
+}
+
+// the params the crf search needs too, otherwise the searched crf doesn't match the encode
+fn get_search_svtav1_params(options: &EncodeOptions) -> Vec<String> {
+    let mut params = Vec::new();
+    if let Some(tune) = options.tune {
+        params.push(format!("tune={:}", tune));
+    }
+    if let Some(av1_level) = &options.av1_level {
+        params.push(format!("level={:}", av1_level));
+    }
+    if let Some(tier) = options.av1_tier.as_deref().and_then(get_svtav1_tier_value) {
+        params.push(format!("tier={:}", tier));
+    }
+    params
+}
+
+fn get_svtav1_tier_value(av1_tier: &str) -> Option<u8> {
+    match av1_tier {
+        "main" => Some(0),
+        "high" => Some(1),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test_get_svtav1_params {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_svtav1_params(&EncodeOptions::default(), None), Vec::<String>::new());
+        assert_eq!(get_svtav1_params(&EncodeOptions { deterministic: true, ..Default::default() }, None), vec!["lp=1".to_string()]);
+        assert_eq!(get_svtav1_params(&EncodeOptions { deterministic: true, memory_limit_mb: Some(1024), ..Default::default() }, None), vec!["lp=1".to_string(), "lookahead=16".to_string()]);
+        assert_eq!(get_svtav1_params(&EncodeOptions { tune: Some(0), ..Default::default() }, None), vec!["tune=0".to_string()]);
+        assert_eq!(get_svtav1_params(&EncodeOptions { av1_level: Some("5.1".to_string()), av1_tier: Some("high".to_string()), ..Default::default() }, None), vec!["level=5.1".to_string(), "tier=1".to_string()]);
+        assert_eq!(get_svtav1_params(&EncodeOptions { av1_tier: Some("main".to_string()), ..Default::default() }, None), vec!["tier=0".to_string()]);
+        let hdr_color = ColorProperties { primaries: Some("bt2020".to_string()), transfer: Some("arib-std-b67".to_string()), space: Some("unknown-space".to_string()) };
+        assert_eq!(get_svtav1_params(&EncodeOptions::default(), Some(&hdr_color)), vec!["enable-hdr=1".to_string(), "color-primaries=9".to_string(), "transfer-characteristics=18".to_string()]);
+    }
+}
+
+fn get_rejected_pixel_format(stderr: &str) -> Option<String> {
+    FFMPEG_STDERR_RETRIEVE_INCOMPATIBLE_PIXEL_FORMAT_REGEX.captures(stderr).map(|caps| caps[1].to_string())
+}
+
+#[cfg(test)]
+mod test_get_rejected_pixel_format {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_rejected_pixel_format("[libsvtav1 @ 0x55d0] Incompatible pixel format 'yuv444p10le' for codec 'libsvtav1', auto-selecting format 'yuv420p10le'\n"), Some("yuv444p10le".to_string()));
+        assert_eq!(get_rejected_pixel_format("Incompatible pixel format 'yuv444p' for codec 'libx264', auto-selecting format 'yuv420p'\n"), None);
+        assert_eq!(get_rejected_pixel_format(""), None);
+    }
+}
+
+fn classify_ffmpeg_failure(status: ExitStatus, stderr: String) -> Error {
+    if FFMPEG_STDERR_CHECK_DISK_FULL_REGEX.is_match(&stderr) {
+        Error { kind: ErrorKind::OutputDiskFull(stderr) }
+    } else {
+        Error { kind: ErrorKind::FfmpegCommandExitAbnormally(status, stderr) }
+    }
+}
+
+#[cfg(test)]
+mod test_classify_ffmpeg_failure {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn it_works() {
+        let test_cases = [
+            ("[out#0/mp4 @ 0x55d0] Error writing trailer: No space left on device\n", true),
+            ("av_interleaved_write_frame(): No space left on device\nError writing trailer of output/a.mp4: No space left on device\n", true),
+            ("Error: ENOSPC\n", true),
+            ("output/a.mp4: Permission denied\n", false),
+            ("", false),
+        ];
+
+        for (stderr, expected_disk_full) in test_cases {
+            let actual_disk_full = match classify_ffmpeg_failure(ExitStatus::from_raw(256), stderr.to_string()) {
+                Error { kind: ErrorKind::OutputDiskFull(_) } => true,
+                Error { kind: ErrorKind::FfmpegCommandExitAbnormally(_, _) } => false,
+                err => panic!("unexpected error: {:?}", err),
+            };
+            assert_eq!(actual_disk_full, expected_disk_full);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Default)]
+struct FfmpegSummary {
+    frame_count: Option<u64>,
+    bitrate_kbps: Option<f64>,
+    muxing_overhead_percent: Option<f64>,
+}
+
+// the format varies by ffmpeg version, so every field is optional, the last progress line wins
+fn parse_ffmpeg_summary(stderr: &str) -> FfmpegSummary {
+    fn last_capture<T: std::str::FromStr>(re: &Regex, stderr: &str) -> Option<T> {
+        re.captures_iter(stderr).last().and_then(|caps| caps[1].parse::<T>().ok())
+    }
+
+    FfmpegSummary {
+        frame_count: last_capture(&FFMPEG_STDERR_RETRIEVE_FRAME_COUNT_REGEX, stderr),
+        bitrate_kbps: last_capture(&FFMPEG_STDERR_RETRIEVE_BITRATE_REGEX, stderr),
+        muxing_overhead_percent: last_capture(&FFMPEG_STDERR_RETRIEVE_MUXING_OVERHEAD_REGEX, stderr),
+    }
+}
+
+#[cfg(test)]
+mod test_parse_ffmpeg_summary {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        // ffmpeg 6
+        let stderr = "frame=   30 fps=0.0 q=0.0 size=       0kB time=00:00:00.50 bitrate=   0.0kbits/s speed=   1x    \rframe=   60 fps= 45 q=-0.0 Lsize=      48kB time=00:00:02.00 bitrate= 196.1kbits/s speed=1.51x    \nvideo:40kB audio:6kB subtitle:0kB other streams:0kB global headers:0kB muxing overhead: 3.456210%\n";
+        assert_eq!(parse_ffmpeg_summary(stderr), FfmpegSummary { frame_count: Some(60), bitrate_kbps: Some(196.1), muxing_overhead_percent: Some(3.45621) });
+
+        // ffmpeg 7
+        let stderr = "[out#0/mp4 @ 0x5581] video:40KiB audio:6KiB subtitle:0KiB other streams:0KiB global headers:0KiB muxing overhead: 3.4%\nframe=   60 fps= 45 q=-0.0 Lsize=      48KiB time=00:00:02.00 bitrate= 196.1kbits/s speed=1.51x\n";
+        assert_eq!(parse_ffmpeg_summary(stderr), FfmpegSummary { frame_count: Some(60), bitrate_kbps: Some(196.1), muxing_overhead_percent: Some(3.4) });
+
+        // audio only progress has no frame count
+        let stderr = "size=      48kB time=00:00:02.00 bitrate=N/A speed=1.51x\n";
+        assert_eq!(parse_ffmpeg_summary(stderr), FfmpegSummary::default());
+
+        assert_eq!(parse_ffmpeg_summary(""), FfmpegSummary::default());
+    }
+}
+
+fn measure_vmaf(distorted_video_path: impl AsRef<Path>, reference_video_path: impl AsRef<Path>) -> Result<f64, Error> {
+    measure_vmaf_impl(FFMPEG_CMD_STR, distorted_video_path, reference_video_path)
+}
+
+// separate impl for test
+fn measure_vmaf_impl(cmd_str: &str, distorted_video_path: impl AsRef<Path>, reference_video_path: impl AsRef<Path>) -> Result<f64, Error> {
+    let distorted_video_path = distorted_video_path.as_ref();
+    let reference_video_path = reference_video_path.as_ref();
+
+    let mut ffmpeg_cmd = Command::new(cmd_str);
+    ffmpeg_cmd.arg("-i").arg(distorted_video_path);
+    ffmpeg_cmd.arg("-i").arg(reference_video_path);
+    // libvmaf needs the same resolution on both sides
+    ffmpeg_cmd.args([
+        "-lavfi", "[0:v:0][1:v:0]scale2ref=flags=bicubic[distorted][reference];[distorted][reference]libvmaf",
+        "-f", "null", "-",
+    ]);
+
+    let output = match run_command(&mut ffmpeg_cmd) {
+        Ok(output) => output,
+        Err(err) => return Err(Error { kind: ErrorKind::FfmpegCommandProcessFailed(err.to_string()) }),
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    if FFMPEG_STDERR_CHECK_VMAF_NOT_AVAILABLE_REGEX.is_match(&stderr) {
+        return Err(Error { kind: ErrorKind::VmafNotAvailable(stderr) });
+    }
+    if !output.status.success() {
+        return Err(Error { kind: ErrorKind::FfmpegCommandExitAbnormally(output.status, stderr) });
+    }
+
+    let Some(caps) = FFMPEG_STDERR_RETRIEVE_VMAF_REGEX.captures(&stderr) else {
+        return Err(Error { kind: ErrorKind::InvalidVmafOutput(distorted_video_path.into(), stderr) });
+    };
+    parse_number::<f64, _>(&caps[1], Error { kind: ErrorKind::InvalidVmafOutput(distorted_video_path.into(), stderr.clone()) })
+}
+
+// seconds measured of the best input, the middle of it
+const VERIFY_PREDICTION_SAMPLE_SECS: f64 = 5.0;
+
+// the sample has to be the same frames on both sides
+fn get_verify_prediction_blocker(input_files: &[InputFile], best_input_file: &InputFile, options: &EncodeOptions) -> Option<&'static str> {
+    if options.output_range.is_some() || options.timeline.is_some() {
+        Some("the output timeline doesn't follow the inputs")
+    } else if options.watermark.is_some() || options.fade_in.is_some() || options.fade_out.is_some() || options.denoise.is_some() || options.aspect_ratio.is_some() || options.memory_limit_mb.is_some() {
+        Some("the options change the picture")
+    } else if best_input_file.trim_range.is_some() || best_input_file.speed.is_some() {
+        Some("the best input doesn't start at its source time")
+    } else if best_input_file.duration.is_none() {
+        Some("the best input duration is unknown")
+    } else if input_files.iter().any(|input_file| get_display_resolution(input_file) != get_display_resolution(best_input_file)) {
+        Some("the best input is scaled or padded in the output")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test_get_verify_prediction_blocker {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let input_file = || InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 400, duration: Some(10.0), ..Default::default() };
+        let input_files = vec![input_file(), input_file()];
+        assert_eq!(get_verify_prediction_blocker(&input_files, &input_files[1], &EncodeOptions::default()), None);
+        assert!(get_verify_prediction_blocker(&input_files, &input_files[0], &EncodeOptions { output_range: Some((1.0, 2.0)), ..Default::default() }).is_some());
+        assert!(get_verify_prediction_blocker(&input_files, &input_files[0], &EncodeOptions { fade_in: Some(1.0), ..Default::default() }).is_some());
+
+        let input_files = vec![input_file(), InputFile { width: 400, height: 300, ..input_file() }];
+        assert!(get_verify_prediction_blocker(&input_files, &input_files[0], &EncodeOptions::default()).is_some());
+        let input_files = vec![InputFile { speed: Some(2.0), ..input_file() }];
+        assert!(get_verify_prediction_blocker(&input_files, &input_files[0], &EncodeOptions::default()).is_some());
+        let input_files = vec![InputFile { duration: None, ..input_file() }];
+        assert!(get_verify_prediction_blocker(&input_files, &input_files[0], &EncodeOptions::default()).is_some());
+    }
+}
+
+// (output start, reference start, duration), the best input sits after the inputs before it
+fn get_verify_prediction_sample(input_files: &[InputFile], best_input_file: &InputFile) -> (f64, f64, f64) {
+    let offset = input_files.iter().take_while(|input_file| !std::ptr::eq(*input_file, best_input_file)).map(|input_file| input_file.duration.unwrap_or(0.0)).sum::<f64>();
+    let duration = best_input_file.duration.unwrap_or(0.0);
+    let sample_duration = duration.min(VERIFY_PREDICTION_SAMPLE_SECS);
+    let reference_start = (duration - sample_duration) / 2.0;
+    (offset + reference_start, reference_start, sample_duration)
+}
+
+#[cfg(test)]
+mod test_get_verify_prediction_sample {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let input_file = |duration: f64| InputFile { duration: Some(duration), ..Default::default() };
+        let input_files = vec![input_file(3.0), input_file(20.0), input_file(4.0)];
+        assert_eq!(get_verify_prediction_sample(&input_files, &input_files[1]), (10.5, 7.5, 5.0));
+        assert_eq!(get_verify_prediction_sample(&input_files, &input_files[0]), (0.0, 0.0, 3.0));
+        assert_eq!(get_verify_prediction_sample(&input_files, &input_files[2]), (23.0, 0.0, 4.0));
+    }
+}
+
+// the measured minus the predicted, only when it's off by more than the max delta
+fn get_prediction_divergence(predicted_vmaf: f64, measured_vmaf: f64, max_delta: f64) -> Option<f64> {
+    let divergence = measured_vmaf - predicted_vmaf;
+    (max_delta < divergence.abs()).then_some(divergence)
+}
+
+#[cfg(test)]
+mod test_get_prediction_divergence {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_prediction_divergence(95.0, 94.0, 3.0), None);
+        assert_eq!(get_prediction_divergence(95.0, 98.0, 3.0), None);
+        assert_eq!(get_prediction_divergence(95.0, 90.5, 3.0), Some(-4.5));
+        assert_eq!(get_prediction_divergence(90.0, 94.5, 3.0), Some(4.5));
+        assert_eq!(get_prediction_divergence(95.0, 95.0, 0.0), None);
+    }
+}
+
+// measure_vmaf() over a window of each side
+fn measure_sample_vmaf_impl(cmd_str: &str, distorted_video_path: &Path, distorted_start: f64, reference_video_path: &Path, reference_start: f64, duration: f64) -> Result<f64, Error> {
+    let mut ffmpeg_cmd = Command::new(cmd_str);
+    ffmpeg_cmd.args(["-ss".to_string(), distorted_start.to_string(), "-t".to_string(), duration.to_string(), "-i".to_string()]).arg(distorted_video_path);
+    ffmpeg_cmd.args(["-ss".to_string(), reference_start.to_string(), "-t".to_string(), duration.to_string(), "-i".to_string()]).arg(reference_video_path);
+    ffmpeg_cmd.args([
+        "-lavfi", "[0:v:0][1:v:0]scale2ref=flags=bicubic[distorted][reference];[distorted][reference]libvmaf",
+        "-f", "null", "-",
+    ]);
+
+    let output = match run_command(&mut ffmpeg_cmd) {
+        Ok(output) => output,
+        Err(err) => return Err(Error { kind: ErrorKind::FfmpegCommandProcessFailed(err.to_string()) }),
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    if FFMPEG_STDERR_CHECK_VMAF_NOT_AVAILABLE_REGEX.is_match(&stderr) {
+        return Err(Error { kind: ErrorKind::VmafNotAvailable(stderr) });
+    }
+    if !output.status.success() {
+        return Err(Error { kind: ErrorKind::FfmpegCommandExitAbnormally(output.status, stderr) });
+    }
+
+    let Some(caps) = FFMPEG_STDERR_RETRIEVE_VMAF_REGEX.captures(&stderr) else {
+        return Err(Error { kind: ErrorKind::InvalidVmafOutput(distorted_video_path.into(), stderr) });
+    };
+    parse_number::<f64, _>(&caps[1], Error { kind: ErrorKind::InvalidVmafOutput(distorted_video_path.into(), stderr.clone()) })
+}
+
+#[cfg(test)]
+mod test_measure_vmaf {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn it_works() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let path = video_dir_path.join("va-300x400.mp4");
+
+        assert!(matches!(measure_vmaf_impl("__command_not_found__", &path, &path), Err(Error { kind: ErrorKind::FfmpegCommandProcessFailed(_) })));
+        assert!(matches!(measure_vmaf_impl("false", &path, &path), Err(Error { kind: ErrorKind::FfmpegCommandExitAbnormally(_, _) })));
+        assert!(matches!(measure_vmaf_impl("true", &path, &path), Err(Error { kind: ErrorKind::InvalidVmafOutput(_, _) })));
+        assert!(match measure_vmaf(&path, &path) {
+            Ok(vmaf) => 90.0 < vmaf, _ => false,
+        });
+        assert!(match measure_vmaf(&path, video_dir_path.join("va-600x800.mp4")) {
+            Ok(vmaf) => 0.0 < vmaf, _ => false,
+        });
+    }
+}
+
+// returns the detected major and minor version
+fn check_command(expected_major_version: u8, min_minor_version: u8, cmd: &str, args: &[&str], re: &Regex) -> Result<(u8, u8), Error> {
+    let mut cmd = Command::new(cmd);
+    cmd.args(args);
+    let output = match run_command(&mut cmd) {
+        Ok(output) => output,
+        Err(err) => return Err(Error { kind: ErrorKind::VersionCheckCommandProcessFailed(err.to_string()) }),
+    };
+
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let Some(caps) = re.captures(&stdout) else {
+        return Err(Error { kind: ErrorKind::VersionOutputNotMatched(stdout) });
+    };
+    assert!(caps.len() >= 2);
+
+    let major_version = parse_number::<u8, _>(&caps[1], Error { kind: ErrorKind::VersionNotValidInteger(caps[1].to_string()) })?;
+    let minor_version = parse_number::<u8, _>(&caps[2], Error { kind: ErrorKind::VersionNotValidInteger(caps[2].to_string()) })?;
+
+    if expected_major_version != major_version || minor_version < min_minor_version {
+        return Err(Error { kind: ErrorKind::NotSupportedCommandVersion(major_version, minor_version) });
+    };
+
+    Ok((major_version, minor_version))
+}
+
+#[cfg(test)]
+mod test_check_command {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn it_works() {
+        let test_cases = [
+            (6, 0, "ffmpeg", "-version", FFMPEG_STDOUT_RETRIEVE_VERSION_REGEX_SOURCE, Ok(None)),
+            (0, 7, "ab-av1", "--version", AB_AV1_STDOUT_RETRIEVE_VERSION_REGEX_SOURCE, Ok(None)),
+            (0, 0, "__command_not_found__", "__unused__", r".", Err(())),
+            (0, 0, "echo", "0.0", r"__not_matched__", Err(())),
+            (0, 0, "echo", "0.0", r"^(\d+)\.(\d+)", Ok(Some((0, 0)))),
+            (5, 5, "echo", "5.5", r"^(\d+)\.(\d+)", Ok(Some((5, 5)))),
+            (5, 5, "echo", "4.5", r"^(\d+)\.(\d+)", Err(())),
+            (5, 5, "echo", "6.5", r"^(\d+)\.(\d+)", Err(())),
+            (5, 5, "echo", "5.6", r"^(\d+)\.(\d+)", Ok(Some((5, 6)))),
+            (5, 5, "echo", "5.4", r"^(\d+)\.(\d+)", Err(())),
+            (255, 255, "echo", "255.256", r"^(\d+)\.(\d+)", Err(())), // too big
+            (255, 255, "echo", "256.255", r"^(\d+)\.(\d+)", Err(())), // too big
+            (255, 255, "echo", "255.255", r"^(\d+)\.(\d+)", Ok(Some((255, 255)))),
+        ];
+
+        for (expected_major_version, min_minor_version, cmd, arg, re, expected) in test_cases {
+            let re = Regex::new(re).unwrap();
+            let actual = check_command(expected_major_version, min_minor_version, cmd, &[arg], &re);
+            match expected {
+                // installed commands, any supported version
+                Ok(None) => assert_eq!(actual.unwrap().0, expected_major_version),
+                Ok(Some(expected_version)) => assert_eq!(actual.unwrap(), expected_version),
+                Err(()) => assert!(actual.is_err()),
+            }
+        }
+    }
+
+    #[test]
+    fn it_ignores_host_locale() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let output_dir_path = root_path.join("output");
+
+        // a localized build only prints the english version line in the c locale
+        let script_path = output_dir_path.join("test_check_command-localized.sh");
+        std::fs::write(&script_path, r#"#!/bin/sh
+if [ "$LC_ALL" = "C" ]; then
+    echo "ffmpeg version 6.0 Copyright (c) 2000-2023 the FFmpeg developers"
+else
+    echo "ffmpeg バージョン 6.0 Copyright (c) 2000-2023 the FFmpeg developers"
+fi
+"#).unwrap();
+        std::fs::set_permissions(&script_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let re = Regex::new(FFMPEG_STDOUT_RETRIEVE_VERSION_REGEX_SOURCE).unwrap();
+        assert_eq!(check_command(6, 0, script_path.to_str().unwrap(), &["-version"], &re), Ok((6, 0)));
+    }
+}
+
+// what the encode would see for each input, the ignored ones with the reason, nothing is encoded
+pub(crate) fn analyze_inputs(input_video_paths: &[PathBuf]) -> serde_json::Value {
+    serde_json::Value::Array(input_video_paths.iter().map(|input_video_path| get_input_analysis_json(input_video_path, &analyze_video_file(input_video_path))).collect())
+}
+
+fn get_input_analysis_json(path: &Path, analysis: &Result<InputFile, IgnoreReason>) -> serde_json::Value {
+    match analysis {
+        Ok(input_file) => serde_json::json!({
+            "path": path.display().to_string(),
+            "usable": true,
+            "width": input_file.width,
+            "height": input_file.height,
+            "rotation": input_file.rotation,
+            "video_codec": input_file.video_codec_name,
+            "frame_rate": input_file.frame_rate,
+            "pix_fmt": input_file.pix_fmt,
+            "duration": input_file.duration,
+            "bit_rate": input_file.bit_rate,
+            "audio": input_file.audio_codec_name.as_ref().map(|audio_codec_name| serde_json::json!({
+                "codec": audio_codec_name,
+                "sample_rate": input_file.audio_sample_rate,
+                "channels": input_file.audio_channels,
+            })),
+        }),
+        Err(reason) => serde_json::json!({
+            "path": path.display().to_string(),
+            "usable": false,
+            "ignore_reason": format!("{:?}", reason),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test_get_input_analysis_json {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let input_file = InputFile {
+            path: PathBuf::from("a.mp4"), width: 300, height: 400, rotation: 90, video_codec_name: Some("h264".to_string()), frame_rate: "30/1".to_string(), pix_fmt: Some("yuv420p".to_string()), duration: Some(1.5), bit_rate: Some(1000),
+            audio_sample_rate: Some(48000), audio_channels: Some(2), audio_codec_name: Some("aac".to_string()), ..Default::default()
+        };
+        assert_eq!(get_input_analysis_json(Path::new("a.mp4"), &Ok(input_file)), serde_json::json!({
+            "path": "a.mp4", "usable": true, "width": 300, "height": 400, "rotation": 90, "video_codec": "h264", "frame_rate": "30/1", "pix_fmt": "yuv420p", "duration": 1.5, "bit_rate": 1000,
+            "audio": { "codec": "aac", "sample_rate": 48000, "channels": 2 },
+        }));
+
+        let input_file = InputFile { path: PathBuf::from("v.mp4"), width: 300, height: 400, frame_rate: "30/1".to_string(), ..Default::default() };
+        assert_eq!(get_input_analysis_json(Path::new("v.mp4"), &Ok(input_file))["audio"], serde_json::Value::Null);
+
+        assert_eq!(get_input_analysis_json(Path::new("i.mp4"), &Err(IgnoreReason::NoVideoStream)), serde_json::json!({
+            "path": "i.mp4", "usable": false, "ignore_reason": "NoVideoStream",
+        }));
+    }
+}
+
+#[cfg(test)]
+mod test_analyze_inputs {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn it_works() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+
+        let analysis = analyze_inputs(&[video_dir_path.join("va-300x400.mp4"), video_dir_path.join("invalid.mp4"), video_dir_path.join("v-400x300.mp4")]);
+        let analysis = analysis.as_array().unwrap();
+        assert_eq!(analysis.len(), 3);
+        assert_eq!((&analysis[0]["usable"], &analysis[0]["width"], &analysis[0]["height"]), (&serde_json::json!(true), &serde_json::json!(300), &serde_json::json!(400)));
+        assert!(analysis[0]["audio"].is_object());
+        assert_eq!(analysis[1]["usable"], serde_json::json!(false));
+        assert!(analysis[1]["ignore_reason"].is_string());
+        assert_eq!(analysis[2]["audio"], serde_json::Value::Null);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HeaderProbe {
+    pub(crate) width: i64,
+    pub(crate) height: i64,
+    pub(crate) duration: Option<f64>,
+}
+
+// from the leading bytes of an input, none when the header isn't in them, a moov at the end for example
+pub(crate) fn probe_header(path: impl AsRef<Path>) -> Option<HeaderProbe> {
+    let path = path.as_ref();
+    match analyze_video_file(path) {
+        Ok(input_file) => Some(HeaderProbe { width: input_file.width, height: input_file.height, duration: input_file.duration }),
+        Err(reason) => {
+            log::debug!("Header not probed: {:} ({:?})", path.display(), reason);
+            None
+        },
+    }
+}
+
+#[cfg(test)]
+mod test_probe_header {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn it_works() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+        let head = |path: &Path, header_path: &Path| {
+            let bytes = std::fs::read(path).unwrap();
+            std::fs::write(header_path, &bytes[..65536]).unwrap();
+        };
+
+        // moov first, the head alone is enough
+        let faststart_path = output_dir_path.join("test_probe_header-faststart.mp4");
+        let status = Command::new(FFMPEG_CMD_STR).args(["-y", "-hide_banner", "-loglevel", "error", "-i"]).arg(video_dir_path.join("va-300x400.mp4")).args(["-c", "copy", "-movflags", "+faststart"]).arg(&faststart_path).status().unwrap();
+        assert!(status.success());
+        let header_path = output_dir_path.join("test_probe_header-faststart-header.mp4");
+        head(&faststart_path, &header_path);
+        let header_probe = probe_header(&header_path).unwrap();
+        assert_eq!((header_probe.width, header_probe.height), (300, 400));
+        let duration = analyze_video_file(&faststart_path).unwrap().duration.unwrap();
+        assert!((header_probe.duration.unwrap() - duration).abs() < 0.1);
+
+        // moov last, nothing to probe in the head
+        let header_path = output_dir_path.join("test_probe_header-header.mp4");
+        head(&video_dir_path.join("va-300x400.mp4"), &header_path);
+        assert_eq!(probe_header(&header_path), None);
+    }
+}
+
+fn analyze_video_file(path: impl AsRef<Path>) -> Result<InputFile, IgnoreReason> {
+    let path = path.as_ref();
+    let ffprobe::FfProbe { format, streams } = match ffprobe::ffprobe(&path) {
+        Ok(ffprobe_info) => ffprobe_info,
+        Err(err) => return Err(IgnoreReason::ProbeFailed(err.to_string())),
+    };
+
+    let video_stream_position = select_video_stream(&streams).map(|(position, _)| position).unwrap_or(0);
+    let rotation = get_video_rotation(path, video_stream_position);
+    let color = get_video_color(path, video_stream_position);
+
+    let input_file = analyze_video_file_impl(path, format, streams, rotation)?;
+    Ok(InputFile { color, ..input_file })
+}
+
+// separate impl for test
+fn analyze_video_file_impl(path: &Path, format: ffprobe::Format, streams: Vec<ffprobe::Stream>, rotation: i64) -> Result<InputFile, IgnoreReason> {
+    let Some((video_stream_position, video_stream)) = select_video_stream(&streams) else {
+        if get_first_audio_stream(&streams).is_some() {
+            return Err(IgnoreReason::AudioOnly);
+        }
+        return Err(IgnoreReason::NoVideoStream);
+    };
+
+    let (Some(width), Some(height)) = (video_stream.width, video_stream.height) else {
+        return Err(IgnoreReason::NoResolution);
+    };
+
+    if width < 0 || height < 0 {
+        return Err(IgnoreReason::InvalidResolution(width, height));
+    };
+
+    // ffmpeg autorotates on decode, so the frames entering the filter graph have the display resolution
+    let (width, height) = if rotation == 90 || rotation == 270 { (height, width) } else { (width, height) };
+    // and the transpose inverts the sample aspect ratio too
+    let sample_aspect_ratio = video_stream.sample_aspect_ratio.as_deref().and_then(parse_sample_aspect_ratio);
+    let sample_aspect_ratio = if rotation == 90 || rotation == 270 { sample_aspect_ratio.map(|(num, den)| (den, num)) } else { sample_aspect_ratio };
+
+    
+    let video_codec_name = video_stream.codec_name.clone();
+    let frame_rate = video_stream.r_frame_rate.clone();
+    let pix_fmt = video_stream.pix_fmt.clone();
+    // the printed duration is rounded to microseconds, which adds up over many clips
+    let duration = get_exact_stream_duration(video_stream).or_else(|| get_stream_duration(video_stream, &format));
+    let bit_rate = get_video_bit_rate(video_stream, &format, duration);
+
+    let audio_stream = get_first_audio_stream(&streams);
+    let audio_sample_rate = audio_stream.and_then(|audio_stream| audio_stream.sample_rate.as_ref()).and_then(|sample_rate| sample_rate.parse::<i64>().ok());
+    let audio_channels = audio_stream.and_then(|audio_stream| audio_stream.channels);
+    let audio_codec_name = audio_stream.and_then(|audio_stream| audio_stream.codec_name.clone());
+
+    let alternative_null_audio_duration = match audio_stream {
+        Some(_) => None,
+        None => {
+            let Some(video_duration) = duration else {
+                return Err(IgnoreReason::NoDuration);
+            };
+            Some(video_duration)
+        },
+    };
+
+    Ok(InputFile { path: path.into(), width, height, rotation, video_stream_position, video_codec_name, frame_rate, pix_fmt, duration, bit_rate, audio_sample_rate, audio_channels, audio_codec_name, alternative_null_audio_duration, start_offset: None, trim_range: None, sample_aspect_ratio, speed: None, color: ColorProperties::default(), audio_delay_ms: None })
+}
+
+#[cfg(test)]
+mod test_analyze_video_file {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn it_works() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+
+        let path = video_dir_path.join("va-300x400.mp4");
+        assert!(analyze_video_file(&path).is_ok());
+
+        let input_file = analyze_video_file(&path).unwrap();
+        assert!(input_file.audio_sample_rate.is_some_and(|sample_rate| 0 < sample_rate));
+        assert!(input_file.audio_channels.is_some_and(|channels| 0 < channels));
+        assert!(input_file.audio_codec_name.is_some());
+        assert!(input_file.bit_rate.is_some_and(|bit_rate| 0 < bit_rate));
+        let input_file = analyze_video_file(video_dir_path.join("v-300x400.mp4")).unwrap();
+        assert_eq!((input_file.audio_sample_rate, input_file.audio_channels, input_file.audio_codec_name), (None, None, None));
+        assert!(input_file.bit_rate.is_some_and(|bit_rate| 0 < bit_rate));
+
+        let ffprobe::FfProbe { mut format, streams } = ffprobe::ffprobe(&path).unwrap();
+
+        let mut video_stream = get_first_video_stream(&streams).unwrap().clone();
+        let audio_stream = get_first_audio_stream(&streams).unwrap().clone();
+
+        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()], 0).is_ok());
+        assert_eq!(analyze_video_file_impl(&path, format.clone(), vec![audio_stream.clone()], 0).err(), Some(IgnoreReason::AudioOnly));
+        assert_eq!(analyze_video_file_impl(&path, format.clone(), vec![], 0).err(), Some(IgnoreReason::NoVideoStream));
+
+        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()], 0).is_ok());
+        video_stream.width = None;
+        assert_eq!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()], 0).err(), Some(IgnoreReason::NoResolution));
+        video_stream.width = Some(300);
+
+        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()], 0).is_ok());
+        video_stream.height = None;
+        assert_eq!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()], 0).err(), Some(IgnoreReason::NoResolution));
+        video_stream.height = Some(400);
+
+        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()], 0).is_ok());
+        video_stream.width = Some(-1);
+        assert_eq!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()], 0).err(), Some(IgnoreReason::InvalidResolution(-1, 400)));
+        video_stream.width = Some(400);
+
+        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()], 0).is_ok());
+        video_stream.height = Some(-1);
+        assert_eq!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()], 0).err(), Some(IgnoreReason::InvalidResolution(400, -1)));
+        video_stream.height = Some(400);
+
+        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()], 0).is_ok());
+        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()], 0).unwrap().alternative_null_audio_duration.is_none());
+        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone()], 0).is_ok());
+        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone()], 0).unwrap().alternative_null_audio_duration.is_some());
+
+        assert!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone()], 0).is_ok());
+        format.duration = None;
+        video_stream.duration = None;
+        video_stream.duration_ts = None;
+        assert_eq!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone()], 0).err(), Some(IgnoreReason::NoDuration));
+        video_stream.duration = Some("1.0".to_string());
+
+        let input_file = analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()], 0).unwrap();
+        assert_eq!((input_file.width, input_file.height, input_file.rotation), (400, 400, 0));
+        assert_eq!(input_file.duration, Some(1.0));
+        video_stream.width = Some(300);
+        let input_file = analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()], 90).unwrap();
+        assert_eq!((input_file.width, input_file.height, input_file.rotation), (400, 300, 90));
+        let input_file = analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()], 180).unwrap();
+        assert_eq!((input_file.width, input_file.height, input_file.rotation), (300, 400, 180));
+        let input_file = analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()], 270).unwrap();
+        assert_eq!((input_file.width, input_file.height, input_file.rotation), (400, 300, 270));
+
+        video_stream.sample_aspect_ratio = Some("4:3".to_string());
+        assert_eq!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()], 0).unwrap().sample_aspect_ratio, Some((4, 3)));
+        assert_eq!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()], 90).unwrap().sample_aspect_ratio, Some((3, 4)));
+        video_stream.sample_aspect_ratio = Some("0:1".to_string());
+        assert_eq!(analyze_video_file_impl(&path, format.clone(), vec![video_stream.clone(), audio_stream.clone()], 0).unwrap().sample_aspect_ratio, None);
+    }
+}
+
+// 0:1 is ffprobe's unknown
+fn parse_sample_aspect_ratio(sample_aspect_ratio: &str) -> Option<(i64, i64)> {
+    let (num, den) = sample_aspect_ratio.split_once(':')?;
+    let (num, den) = (num.parse::<i64>().ok()?, den.parse::<i64>().ok()?);
+    if num <= 0 || den <= 0 {
+        return None;
+    }
+    Some((num, den))
+}
+
+#[cfg(test)]
+mod test_parse_sample_aspect_ratio {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let test_cases = [
+            ("1:1", Some((1, 1))),
+            ("4:3", Some((4, 3))),
+            ("32:27", Some((32, 27))),
+            ("0:1", None),
+            ("1:0", None),
+            ("-1:1", None),
+            ("N/A", None),
+            ("", None),
+        ];
+        for (sample_aspect_ratio, expected) in test_cases {
+            assert_eq!(parse_sample_aspect_ratio(sample_aspect_ratio), expected, "{:}", sample_aspect_ratio);
+        }
+    }
+}
+
+// square pixels of the same display aspect ratio, the width stretched or squeezed as players do, rounded to even
+fn get_display_resolution(input_file: &InputFile) -> (i64, i64) {
+    match input_file.sample_aspect_ratio {
+        Some((num, den)) if num != den => {
+            let width = ((input_file.width * num) as f64 / den as f64 / 2.0).round() as i64 * 2;
+            (width.max(2), input_file.height)
+        },
+        _ => (input_file.width, input_file.height),
+    }
+}
+
+#[cfg(test)]
+mod test_get_display_resolution {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let input_file = |width: i64, height: i64, sample_aspect_ratio: Option<(i64, i64)>| InputFile { width, height, sample_aspect_ratio, ..Default::default() };
+        let test_cases = [
+            (input_file(720, 480, None), (720, 480)),
+            (input_file(720, 480, Some((1, 1))), (720, 480)),
+            // ntsc dv widescreen
+            (input_file(720, 480, Some((32, 27))), (854, 480)),
+            // hdv
+            (input_file(1440, 1080, Some((4, 3))), (1920, 1080)),
+            (input_file(400, 300, Some((3, 4))), (300, 300)),
+        ];
+        for (input_file, expected) in test_cases {
+            assert_eq!(get_display_resolution(&input_file), expected);
+        }
+    }
+}
+
+// some containers (e.g. mkv, webm) have no stream bit rate, then the whole file's, which includes audio and overhead
+fn get_video_bit_rate(video_stream: &ffprobe::Stream, format: &ffprobe::Format, duration: Option<f64>) -> Option<i64> {
+    let parse_positive = |bit_rate: &String| bit_rate.parse::<i64>().ok().filter(|bit_rate| 0 < *bit_rate);
+    if let Some(bit_rate) = video_stream.bit_rate.as_ref().and_then(parse_positive) {
+        return Some(bit_rate);
+    }
+    if let Some(bit_rate) = format.bit_rate.as_ref().and_then(parse_positive) {
+        return Some(bit_rate);
+    }
+    let size = format.size.parse::<i64>().ok().filter(|size| 0 < *size)?;
+    let duration = duration.filter(|duration| 0.0 < *duration)?;
+    Some((size as f64 * 8.0 / duration) as i64)
+}
+
+#[cfg(test)]
+mod test_get_video_bit_rate {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let video_stream = ffprobe::Stream { bit_rate: Some("1000000".to_string()), ..Default::default() };
+        let format = ffprobe::Format { bit_rate: Some("1200000".to_string()), size: "300000".to_string(), ..Default::default() };
+        assert_eq!(get_video_bit_rate(&video_stream, &format, Some(2.0)), Some(1_000_000));
+
+        let video_stream = ffprobe::Stream { bit_rate: None, ..Default::default() };
+        assert_eq!(get_video_bit_rate(&video_stream, &format, Some(2.0)), Some(1_200_000));
+
+        let format = ffprobe::Format { bit_rate: None, size: "300000".to_string(), ..Default::default() };
+        assert_eq!(get_video_bit_rate(&video_stream, &format, Some(2.0)), Some(1_200_000));
+        assert_eq!(get_video_bit_rate(&video_stream, &format, None), None);
+        assert_eq!(get_video_bit_rate(&video_stream, &format, Some(0.0)), None);
+
+        let video_stream = ffprobe::Stream { bit_rate: Some("N/A".to_string()), ..Default::default() };
+        let format = ffprobe::Format { bit_rate: Some("0".to_string()), ..Default::default() };
+        assert_eq!(get_video_bit_rate(&video_stream, &format, Some(2.0)), None);
+    }
+}
+
+fn select_best_input_file(input_files: &[InputFile], metric: BestInputMetric) -> &InputFile {
+    assert!(!input_files.is_empty());
+    let best_input_file = match metric {
+        BestInputMetric::Pixels => input_files.iter().max_by_key(|input_file| input_file.width * input_file.height),
+        BestInputMetric::Bitrate => input_files.iter().max_by_key(|input_file| input_file.bit_rate),
+        BestInputMetric::Duration => input_files.iter().max_by(|a, b| a.duration.partial_cmp(&b.duration).unwrap_or(std::cmp::Ordering::Equal)),
+    };
+    best_input_file.expect("must not be none, because vec is not empty")
+}
+
+#[cfg(test)]
+mod test_select_best_input_file {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 1920, height: 1080, duration: Some(10.0), bit_rate: Some(1_000_000), ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 640, height: 480, duration: Some(5.0), bit_rate: Some(8_000_000), ..Default::default() },
+            InputFile { path: PathBuf::from("2.mp4"), width: 1280, height: 720, duration: Some(60.0), bit_rate: None, ..Default::default() },
+            InputFile { path: PathBuf::from("3.mp4"), width: 320, height: 240, duration: None, bit_rate: None, ..Default::default() },
+        ];
+
+        let test_cases = [
+            (BestInputMetric::Pixels, "0.mp4"),
+            (BestInputMetric::Bitrate, "1.mp4"),
+            (BestInputMetric::Duration, "2.mp4"),
+        ];
+
+        for (metric, expected) in test_cases {
+            assert_eq!(select_best_input_file(&input_files, metric).path, PathBuf::from(expected));
+        }
+    }
+}
+
+// start and end seconds, none end runs to the end of the input
+fn parse_black_intervals(stderr: &str) -> Vec<(f64, Option<f64>)> {
+    FFMPEG_STDERR_RETRIEVE_BLACK_INTERVAL_REGEX.captures_iter(stderr).filter_map(|caps| {
+        Some((caps[1].parse::<f64>().ok()?, Some(caps[2].parse::<f64>().ok()?)))
+    }).collect()
+}
+
+#[cfg(test)]
+mod test_parse_black_intervals {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let stderr = "[blackdetect @ 0x55d0] black_start:0 black_end:1.001 black_duration:1.001\nframe=   90 fps=0.0 q=-0.0 size=N/A\n[blackdetect @ 0x55d0] black_start:2.5 black_end:3 black_duration:0.5\n";
+        assert_eq!(parse_black_intervals(stderr), vec![(0.0, Some(1.001)), (2.5, Some(3.0))]);
+        assert_eq!(parse_black_intervals(""), vec![]);
+    }
+}
+
+// start and end seconds, silencedetect prints no end when the silence runs to the end
+fn parse_silence_intervals(stderr: &str) -> Vec<(f64, Option<f64>)> {
+    let mut intervals = Vec::new();
+    let mut start = None;
+    for line in stderr.lines() {
+        if let Some(caps) = FFMPEG_STDERR_RETRIEVE_SILENCE_START_REGEX.captures(line) {
+            if let Some(start) = start.take() {
+                intervals.push((start, None));
+            }
+            // the start is shifted back by the detect duration, so it may be negative
+            start = caps[1].parse::<f64>().ok().map(|start| start.max(0.0));
+        } else if let Some(caps) = FFMPEG_STDERR_RETRIEVE_SILENCE_END_REGEX.captures(line) {
+            if let Some(start) = start.take() {
+                intervals.push((start, caps[1].parse::<f64>().ok()));
+            }
+        }
+    }
+    if let Some(start) = start {
+        intervals.push((start, None));
+    }
+    intervals
+}
+
+#[cfg(test)]
+mod test_parse_silence_intervals {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let stderr = "[silencedetect @ 0x55d0] silence_start: -0.01\n[silencedetect @ 0x55d0] silence_end: 0.52 | silence_duration: 0.53\nsize=N/A time=00:00:02.00\n[silencedetect @ 0x55d0] silence_start: 2.4\n";
+        assert_eq!(parse_silence_intervals(stderr), vec![(0.0, Some(0.52)), (2.4, None)]);
+        assert_eq!(parse_silence_intervals("[silencedetect @ 0x55d0] silence_start: 1\n[silencedetect @ 0x55d0] silence_end: 1.5 | silence_duration: 0.5\n"), vec![(1.0, Some(1.5))]);
+        assert_eq!(parse_silence_intervals(""), vec![]);
+    }
+}
+
+// the part between the intervals touching the head and the tail, none when nothing or everything would be cut
+fn get_edge_trim_range(intervals: &Vec<(f64, Option<f64>)>, duration: f64) -> Option<(f64, f64)> {
+    // detected timestamps are frame or sample aligned, not exactly the duration
+    const EDGE_TOLERANCE_SECS: f64 = 0.05;
+    let mut start: f64 = 0.0;
+    let mut end = duration;
+    for (interval_start, interval_end) in intervals {
+        let interval_end = interval_end.unwrap_or(duration);
+        if *interval_start <= EDGE_TOLERANCE_SECS {
+            start = start.max(interval_end);
+        }
+        if duration - EDGE_TOLERANCE_SECS <= interval_end {
+            end = end.min(*interval_start);
+        }
+    }
+    if end <= start {
+        log::warn!("Edge trim would cut the whole input, keep it as is: {:?}", intervals);
+        return None;
+    }
+    if start == 0.0 && end == duration {
+        return None;
+    }
+    Some((start, end))
+}
+
+#[cfg(test)]
+mod test_get_edge_trim_range {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_edge_trim_range(&vec![], 3.0), None);
+        assert_eq!(get_edge_trim_range(&vec![(0.0, Some(1.0))], 3.0), Some((1.0, 3.0)));
+        assert_eq!(get_edge_trim_range(&vec![(2.5, Some(2.99))], 3.0), Some((0.0, 2.5)));
+        assert_eq!(get_edge_trim_range(&vec![(2.5, None)], 3.0), Some((0.0, 2.5)));
+        assert_eq!(get_edge_trim_range(&vec![(0.0, Some(1.0)), (2.5, None)], 3.0), Some((1.0, 2.5)));
+
+        // the longer of black and silence wins at each edge
+        assert_eq!(get_edge_trim_range(&vec![(0.0, Some(1.0)), (0.0, Some(0.4)), (2.0, None), (2.5, None)], 3.0), Some((1.0, 2.0)));
+
+        // the middle is not an edge
+        assert_eq!(get_edge_trim_range(&vec![(1.0, Some(2.0))], 3.0), None);
+
+        // entirely black or silent
+        assert_eq!(get_edge_trim_range(&vec![(0.0, None)], 3.0), None);
+    }
+}
+
+fn detect_edge_trim_range(cmd_str: &str, input_file: &InputFile, options: &EncodeOptions) -> Result<Option<(f64, f64)>, Error> {
+    let Some(duration) = input_file.duration else {
+        return Ok(None);
+    };
+
+    let run_detect_filter = |args: Vec<String>| -> Result<String, Error> {
+        let mut ffmpeg_cmd = Command::new(cmd_str);
+        ffmpeg_cmd.args(["-hide_banner", "-nostats", "-i"]).arg(&input_file.path).args(args).args(["-f", "null", "-"]);
+        let output = match run_command_with_cancel(&mut ffmpeg_cmd, options.cancel.as_deref()) {
+            Ok(output) => output,
+            Err(err) => return Err(Error { kind: ErrorKind::FfmpegCommandProcessFailed(err.to_string()) }),
+        };
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        if !output.status.success() {
+            return Err(classify_ffmpeg_failure(output.status, stderr));
+        }
+        Ok(stderr)
+    };
+
+    let mut intervals = Vec::new();
+    if let Some(spec) = &options.trim_black {
+        let stderr = run_detect_filter(vec!["-map".to_string(), format!("0:v:{:}", input_file.video_stream_position), "-vf".to_string(), format!("blackdetect=d={:}:pix_th={:}", spec.min_duration, spec.pixel_threshold)])?;
+        intervals.extend(parse_black_intervals(&stderr));
+    }
+    // the silence of a video only input is added by us, nothing to detect
+    if let (Some(spec), None) = (&options.trim_silence, input_file.alternative_null_audio_duration) {
+        let stderr = run_detect_filter(vec!["-map".to_string(), "0:a:0".to_string(), "-af".to_string(), format!("silencedetect=n={:}dB:d={:}", spec.noise_db, spec.min_duration)])?;
+        intervals.extend(parse_silence_intervals(&stderr));
+    }
+    Ok(get_edge_trim_range(&intervals, duration))
+}
+
+// bitrate over the pixel rate, none when the probe didn't give the bitrate or the frame rate
+fn get_bits_per_pixel(input_file: &InputFile) -> Option<f64> {
+    let bit_rate = input_file.bit_rate?;
+    let frame_rate = parse_frame_rate(&input_file.frame_rate)?;
+    let pixel_rate = (input_file.width * input_file.height) as f64 * frame_rate;
+    if bit_rate <= 0 || pixel_rate <= 0.0 {
+        return None;
+    }
+    Some(bit_rate as f64 / pixel_rate)
+}
+
+#[cfg(test)]
+mod test_get_bits_per_pixel {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let input_file = InputFile { width: 100, height: 100, frame_rate: "25/1".to_string(), bit_rate: Some(500000), ..Default::default() };
+        assert_eq!(get_bits_per_pixel(&input_file), Some(2.0));
+
+        // the resolution counts
+        let input_file = InputFile { width: 200, height: 100, frame_rate: "25/1".to_string(), bit_rate: Some(500000), ..Default::default() };
+        assert_eq!(get_bits_per_pixel(&input_file), Some(1.0));
+
+        let input_file = InputFile { width: 100, height: 100, frame_rate: "25/1".to_string(), bit_rate: None, ..Default::default() };
+        assert_eq!(get_bits_per_pixel(&input_file), None);
+        let input_file = InputFile { width: 100, height: 100, frame_rate: "0/0".to_string(), bit_rate: Some(500000), ..Default::default() };
+        assert_eq!(get_bits_per_pixel(&input_file), None);
+        let input_file = InputFile { width: 0, height: 0, frame_rate: "25/1".to_string(), bit_rate: Some(500000), ..Default::default() };
+        assert_eq!(get_bits_per_pixel(&input_file), None);
+    }
+}
+
+// average of the per frame values, none when no frame printed it
+fn parse_ydif_average(stderr: &str) -> Option<f64> {
+    let values = FFMPEG_STDERR_RETRIEVE_YDIF_REGEX.captures_iter(stderr).filter_map(|caps| caps[1].parse::<f64>().ok()).collect::<Vec<_>>();
+    if values.is_empty() {
+        return None;
+    }
+    Some(values.iter().sum::<f64>() / values.len() as f64)
+}
+
+#[cfg(test)]
+mod test_parse_ydif_average {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let stderr = "frame:0    pts:0       pts_time:0\nlavfi.signalstats.YDIF=0\nframe:1    pts:1       pts_time:0.04\nlavfi.signalstats.YDIF=3.5\nlavfi.signalstats.YDIF=5.5\n";
+        assert_eq!(parse_ydif_average(stderr), Some(3.0));
+        assert_eq!(parse_ydif_average("lavfi.signalstats.YAVG=16\n"), None);
+        assert_eq!(parse_ydif_average(""), None);
+    }
+}
+
+fn measure_temporal_difference(cmd_str: &str, input_file: &InputFile, options: &EncodeOptions) -> Result<Option<f64>, Error> {
+    let mut ffmpeg_cmd = Command::new(cmd_str);
+    ffmpeg_cmd.args(["-hide_banner", "-nostats", "-i"]).arg(&input_file.path);
+    ffmpeg_cmd.args(["-map".to_string(), format!("0:v:{:}", input_file.video_stream_position)]);
+    ffmpeg_cmd.args(["-vf", "signalstats,metadata=mode=print:key=lavfi.signalstats.YDIF", "-f", "null", "-"]);
+    let output = match run_command_with_cancel(&mut ffmpeg_cmd, options.cancel.as_deref()) {
+        Ok(output) => output,
+        Err(err) => return Err(Error { kind: ErrorKind::FfmpegCommandProcessFailed(err.to_string()) }),
+    };
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    if !output.status.success() {
+        return Err(classify_ffmpeg_failure(output.status, stderr));
+    }
+    Ok(parse_ydif_average(&stderr))
+}
+
+#[cfg(test)]
+mod test_measure_temporal_difference {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn it_works() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+
+        let input_file = analyze_video_file(video_dir_path.join("va-300x400.mp4")).unwrap();
+        let temporal_difference = measure_temporal_difference(FFMPEG_CMD_STR, &input_file, &EncodeOptions::default()).unwrap();
+        assert!(temporal_difference.is_some());
+        assert!(measure_temporal_difference("__command_not_found__", &input_file, &EncodeOptions::default()).is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_detect_edge_trim_range {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn it_works() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let output_dir_path = root_path.join("output");
+
+        // 1s black, 2s test pattern, audio silent for the first 0.5s and the last 1s
+        let input_path = output_dir_path.join("test_detect_edge_trim_range-input.mp4");
+        let status = Command::new(FFMPEG_CMD_STR).args([
+            "-y", "-filter_complex", "color=c=black:s=320x240:r=30:d=1[black];testsrc=s=320x240:r=30:d=2[pattern];[black][pattern]concat=n=2:v=1:a=0[v];sine=d=1.5,adelay=500:all=1,apad=whole_dur=3[a]",
+            "-map", "[v]", "-map", "[a]", "-c:v", "libx264", "-c:a", "aac",
+        ]).arg(&input_path).status().unwrap();
+        assert!(status.success());
+        let input_file = analyze_video_file(&input_path).unwrap();
+
+        let black_spec = BlackTrimSpec { pixel_threshold: 0.1, min_duration: 0.1 };
+        let silence_spec = SilenceTrimSpec { noise_db: -50.0, min_duration: 0.1 };
+        assert_eq!(detect_edge_trim_range(FFMPEG_CMD_STR, &input_file, &EncodeOptions::default()).unwrap(), None);
+
+        let (start, end) = detect_edge_trim_range(FFMPEG_CMD_STR, &input_file, &EncodeOptions { trim_black: Some(black_spec.clone()), ..Default::default() }).unwrap().unwrap();
+        assert!((start - 1.0).abs() < 0.1 && (end - input_file.duration.unwrap()).abs() < 0.1, "{:} {:}", start, end);
+
+        let (start, end) = detect_edge_trim_range(FFMPEG_CMD_STR, &input_file, &EncodeOptions { trim_silence: Some(silence_spec.clone()), ..Default::default() }).unwrap().unwrap();
+        assert!((start - 0.5).abs() < 0.1 && (end - 2.0).abs() < 0.1, "{:} {:}", start, end);
+
+        let (start, end) = detect_edge_trim_range(FFMPEG_CMD_STR, &input_file, &EncodeOptions { trim_black: Some(black_spec), trim_silence: Some(silence_spec), ..Default::default() }).unwrap().unwrap();
+        assert!((start - 1.0).abs() < 0.1 && (end - 2.0).abs() < 0.1, "{:} {:}", start, end);
+
+        assert!(detect_edge_trim_range("__command_not_found__", &input_file, &EncodeOptions { trim_black: Some(BlackTrimSpec { pixel_threshold: 0.1, min_duration: 0.1 }), ..Default::default() }).is_err());
+    }
+}
+
+fn get_avfilter_code(input_files: &[InputFile], options: &EncodeOptions) -> String {
+    let mut filter_code = String::new();
+    let mut concat_input_part_filter_code = String::new();
+
+    assert!(0 < input_files.len());
+
+    let majority_is_portrait = match options.orientation_policy {
+        OrientationPolicy::Pad => None,
+        OrientationPolicy::RotateToMajority => Some(get_majority_is_portrait(input_files)),
+    };
+    let resolutions = input_files.iter().map(|input_file| get_oriented_resolution(input_file, majority_is_portrait)).collect::<Vec<_>>();
+
+    let (target_width, target_height) = get_target_resolution(&resolutions.iter().map(|(width, height, _)| (*width, *height)).collect::<Vec<_>>(), options.target_resolution_policy);
+    let (target_width, target_height) = match options.aspect_ratio {
+        Some(aspect_ratio) => fit_resolution_to_aspect_ratio(target_width, target_height, aspect_ratio),
+        None => (target_width, target_height),
+    };
+    // yuv420 needs even dimensions, odd inputs get scaled and padded into the even target
+    let (target_width, target_height) = ((target_width / 2 * 2).max(2), (target_height / 2 * 2).max(2));
+
+    let (target_width, target_height) = match options.memory_limit_mb {
+        Some(memory_limit_mb) => {
+            let max_pixels = memory_limit_mb * 1024 * 1024 / SVTAV1_ESTIMATED_BYTES_PER_PIXEL;
+            let (width, height) = fit_resolution_to_max_pixels(target_width, target_height, max_pixels);
+            if (width, height) != (target_width, target_height) {
+                log::warn!("Target resolution exceeds memory limit {:}MB, downscale: {:}x{:} -> {:}x{:}", memory_limit_mb, target_width, target_height, width, height);
+            }
+            (width, height)
+        },
+        None => (target_width, target_height),
+    };
+
+    let drops_audio = get_drops_audio(input_files, options.audio_policy);
+
+    // the concat output layout is ambiguous over different channel counts, so everything goes to stereo
+    let downmix_to_stereo = 1 < get_audio_channel_counts(input_files).len();
+
+    // concat rejects audio segments with different parameters, so silence follows the real audio
+    let null_audio_params = input_files.iter().find_map(|input_file| match (input_file.audio_sample_rate, input_file.audio_channels) {
+        (Some(sample_rate), Some(_)) if downmix_to_stereo => Some(format!(":sample_rate={:}:channel_layout=stereo", sample_rate)),
+        (Some(sample_rate), Some(channels)) => Some(format!(":sample_rate={:}:channel_layout={:}", sample_rate, get_channel_layout(channels))),
+        _ => None,
+    }).unwrap_or_default();
+
+    for (index, (input_file, (width, height, needs_transpose))) in input_files.iter().zip(resolutions).enumerate() {
+        let mut part_video_filters = Vec::new();
+        if let Some((start, end)) = input_file.trim_range {
+            part_video_filters.push(format!("trim=start={:}:end={:},setpts=PTS-STARTPTS", start, end));
+        }
+        if let Some(speed) = input_file.speed {
+            part_video_filters.push(format!("setpts=PTS/{:}", speed));
+        }
+        // before any scale, the grain is finer then and upscaled noise costs bits
+        if let Some(denoise) = &options.denoise {
+            part_video_filters.push(get_denoise_filter_code(denoise));
+        }
+        // the scale and pad below work on pixels, so they need to be square first
+        if let Some((num, den)) = input_file.sample_aspect_ratio.filter(|(num, den)| num != den) {
+            let (display_width, display_height) = get_display_resolution(input_file);
+            log::info!("Square non-square pixels: {:} (sar={:}:{:}, {:}x{:} -> {:}x{:})", input_file.path.display(), num, den, input_file.width, input_file.height, display_width, display_height);
+            part_video_filters.push(format!("scale={:}:{:},setsar=1", display_width, display_height));
+        }
+        if needs_transpose {
+            log::info!("Rotate to majority orientation: {:} (rotation={:})", input_file.path.display(), input_file.rotation);
+            part_video_filters.push("transpose=1".to_string());
+        }
+        if width == target_width && height == target_height {
+            // no scale needed
+        } else if width * target_height == height * target_width {
+            // same aspect ratio
+            part_video_filters.push(format!("scale={:}:{:}", target_width, target_height));
+        } else {
+            part_video_filters.push(format!("scale={0:}:{1:}:force_original_aspect_ratio=decrease,pad={0:}:{1:}:(ow-iw)/2:(oh-ih)/2", target_width, target_height));
+        };
+        let part_video_filter_code = if part_video_filters.is_empty() { "null".to_string() } else { part_video_filters.join(",") };
+        let filter_code_statement = format!("[{0:}:v:{2:}]{1:}[v{0:}];", index, part_video_filter_code, input_file.video_stream_position);
+        filter_code.push_str(&filter_code_statement);
+        log::info!("Add filter: {:}", filter_code_statement);
+
+        concat_input_part_filter_code.push_str(&format!("[v{0:}]", index));
+        if drops_audio {
+            continue;
+        }
+
+        let filter_code_statement = match (input_file.alternative_null_audio_duration, input_file.duration) {
+            // anullsrc ends on a whole frame of samples, so it may overshoot
+            (Some(alternative_null_audio_duration), _) if options.align_audio_to_video => format!("anullsrc=d={0:}{1:},atrim=duration={0:}[a{2:}];", alternative_null_audio_duration, null_audio_params, index),
+            (Some(alternative_null_audio_duration), _) => format!("anullsrc=d={:}{:}[a{:}];", alternative_null_audio_duration, null_audio_params, index),
+            (None, duration) => {
+                let mut part_audio_filters = Vec::new();
+                // in the source time, before the trim cuts the same range as the video
+                if let Some(audio_delay_ms) = input_file.audio_delay_ms {
+                    part_audio_filters.push(get_audio_delay_filter_code(audio_delay_ms));
+                }
+                if let Some((start, end)) = input_file.trim_range {
+                    part_audio_filters.push(format!("atrim=start={:}:end={:},asetpts=PTS-STARTPTS", start, end));
+                }
+                // before the pad, whose duration is the sped up one
+                if let Some(speed) = input_file.speed {
+                    part_audio_filters.push(get_atempo_filter_code(speed));
+                }
+                // a shifted audio no longer ends with the video, which concat needs
+                if let (true, Some(duration)) = (options.align_audio_to_video || input_file.audio_delay_ms.is_some(), duration) {
+                    part_audio_filters.push(format!("apad=whole_dur={0:},atrim=duration={0:}", duration));
+                }
+                if downmix_to_stereo {
+                    part_audio_filters.push("aformat=channel_layouts=stereo".to_string());
+                }
+                let part_audio_filter_code = if part_audio_filters.is_empty() { "anull".to_string() } else { part_audio_filters.join(",") };
+                format!("[{0:}:a:0]{1:}[a{0:}];", index, part_audio_filter_code)
+            },
+        };
+        filter_code.push_str(&filter_code_statement);
+        log::info!("Add filter: {:}", filter_code_statement);
+
+        concat_input_part_filter_code.push_str(&format!("[a{0:}]", index));
+    }
+
+    // the fades go last, over the watermark too
+    let has_fade = options.fade_in.is_some() || options.fade_out.is_some();
+    let (program_video_label, program_audio_label) = if has_fade { ("vprogram", "aprogram") } else { ("vout", "aout") };
+    let concat_video_output_label = if options.watermark.is_some() { "vconcat" } else { program_video_label };
+    let program_audio_label = (!drops_audio).then_some(program_audio_label);
+    let filter_code_statement = if options.timeline.is_some() {
+        get_timeline_filter_code(input_files, target_width, target_height, &null_audio_params, concat_video_output_label, program_audio_label)
+    } else {
+        let unsafe_option = if options.concat_unsafe { ":unsafe=1" } else { "" };
+        let audio_output_code = program_audio_label.map(|label| format!("[{:}]", label)).unwrap_or_default();
+        format!("{:}concat=n={:}:v=1:a={:}{:}[{:}]{:}", concat_input_part_filter_code, input_files.len(), !drops_audio as u8, unsafe_option, concat_video_output_label, audio_output_code)
+    };
+
+    log::info!("Add filter: {:}", filter_code_statement);
+    filter_code.push_str(&filter_code_statement);
+
+    if let Some(watermark) = &options.watermark {
+        let filter_code_statement = get_watermark_filter_code(watermark, program_video_label);
+        log::info!("Add filter: {:}", filter_code_statement);
+        filter_code.push_str(&filter_code_statement);
+    }
+
+    if has_fade {
+        // the output range cuts after the filter graph, so the fades go at its edges
+        let total_duration = get_total_duration(input_files);
+        let (program_start, program_end) = match options.output_range {
+            Some((start, end)) => (start, end.min(total_duration)),
+            None => (0.0, total_duration),
+        };
+        let filter_code_statement = get_fade_filter_code(program_start, program_end, options.fade_in, options.fade_out, !drops_audio);
+        log::info!("Add filter: {:}", filter_code_statement);
+        filter_code.push_str(&filter_code_statement);
+    }
+    filter_code
+}
+
+// [vprogram] and [aprogram] to [vout] and [aout], fades longer than the program are shortened to meet in the middle
+fn get_fade_filter_code(start: f64, end: f64, fade_in: Option<f64>, fade_out: Option<f64>, audio: bool) -> String {
+    let duration = (end - start).max(0.0);
+    let requested_duration = fade_in.unwrap_or(0.0) + fade_out.unwrap_or(0.0);
+    let scale = if duration < requested_duration {
+        log::warn!("Fades longer than the output, shortened: {:}s > {:}s", requested_duration, duration);
+        duration / requested_duration
+    } else {
+        1.0
+    };
+
+    let mut video_filters = Vec::new();
+    let mut audio_filters = Vec::new();
+    if let Some(fade_in) = fade_in {
+        let fade_in = fade_in * scale;
+        video_filters.push(format!("fade=t=in:st={:}:d={:}", start, fade_in));
+        audio_filters.push(format!("afade=t=in:st={:}:d={:}", start, fade_in));
+    }
+    if let Some(fade_out) = fade_out {
+        let fade_out = fade_out * scale;
+        video_filters.push(format!("fade=t=out:st={:}:d={:}", end - fade_out, fade_out));
+        audio_filters.push(format!("afade=t=out:st={:}:d={:}", end - fade_out, fade_out));
+    }
+    let mut filter_code = format!(";[vprogram]{:}[vout]", video_filters.join(","));
+    if audio {
+        filter_code.push_str(&format!(";[aprogram]{:}[aout]", audio_filters.join(",")));
+    }
+    filter_code
+}
+
+#[cfg(test)]
+mod test_get_fade_filter_code {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_fade_filter_code(0.0, 10.0, Some(1.0), Some(2.0), true), ";[vprogram]fade=t=in:st=0:d=1,fade=t=out:st=8:d=2[vout];[aprogram]afade=t=in:st=0:d=1,afade=t=out:st=8:d=2[aout]");
+        assert_eq!(get_fade_filter_code(0.0, 10.0, Some(1.5), None, true), ";[vprogram]fade=t=in:st=0:d=1.5[vout];[aprogram]afade=t=in:st=0:d=1.5[aout]");
+        assert_eq!(get_fade_filter_code(0.0, 10.0, None, Some(0.5), true), ";[vprogram]fade=t=out:st=9.5:d=0.5[vout];[aprogram]afade=t=out:st=9.5:d=0.5[aout]");
+
+        // at the output range edges
+        assert_eq!(get_fade_filter_code(2.0, 6.0, Some(1.0), Some(1.0), true), ";[vprogram]fade=t=in:st=2:d=1,fade=t=out:st=5:d=1[vout];[aprogram]afade=t=in:st=2:d=1,afade=t=out:st=5:d=1[aout]");
+
+        // shorter than the fades, they meet
+        assert_eq!(get_fade_filter_code(0.0, 2.0, Some(3.0), Some(1.0), true), ";[vprogram]fade=t=in:st=0:d=1.5,fade=t=out:st=1.5:d=0.5[vout];[aprogram]afade=t=in:st=0:d=1.5,afade=t=out:st=1.5:d=0.5[aout]");
+        assert_eq!(get_fade_filter_code(0.0, 1.0, None, Some(4.0), true), ";[vprogram]fade=t=out:st=0:d=1[vout];[aprogram]afade=t=out:st=0:d=1[aout]");
+
+        // the audio dropped
+        assert_eq!(get_fade_filter_code(0.0, 10.0, Some(1.0), Some(2.0), false), ";[vprogram]fade=t=in:st=0:d=1,fade=t=out:st=8:d=2[vout]");
+    }
+}
+
+// overlays [v{n}] onto a black base and mixes delayed [a{n}] onto silence, both as long as the whole timeline
+fn get_timeline_filter_code(input_files: &[InputFile], width: i64, height: i64, null_audio_params: &str, video_output_label: &str, audio_output_label: Option<&str>) -> String {
+    let total_duration = get_total_duration(input_files);
+    let frame_rate = input_files.iter().map(|input_file| input_file.frame_rate.as_str()).find(|frame_rate| parse_frame_rate(frame_rate).is_some()).unwrap_or("25");
+
+    let mut filter_code = format!("color=c=black:s={:}x{:}:r={:}:d={:}[vbase0];", width, height, frame_rate, total_duration);
+    for (index, input_file) in input_files.iter().enumerate() {
+        let offset = input_file.start_offset.unwrap_or(0.0);
+        let output_label = if index + 1 == input_files.len() { video_output_label.to_string() } else { format!("vbase{:}", index + 1) };
+        filter_code.push_str(&format!("[v{0:}]setpts=PTS-STARTPTS+{1:}/TB[vt{0:}];[vbase{0:}][vt{0:}]overlay=eof_action=pass[{2:}];", index, offset, output_label));
+    }
+    let Some(audio_output_label) = audio_output_label else {
+        // no statement follows
+        filter_code.pop();
+        return filter_code;
+    };
+
+    filter_code.push_str(&format!("anullsrc=d={:}{:}[abase];", total_duration, null_audio_params));
+    let mut mix_input_labels = "[abase]".to_string();
+    for (index, input_file) in input_files.iter().enumerate() {
+        let offset_ms = (input_file.start_offset.unwrap_or(0.0) * 1000.0).round() as i64;
+        filter_code.push_str(&format!("[a{0:}]asetpts=PTS-STARTPTS,adelay={1:}:all=1[at{0:}];", index, offset_ms));
+        mix_input_labels.push_str(&format!("[at{:}]", index));
+    }
+    // the silent base comes first and decides the length, no normalize to keep the levels
+    filter_code.push_str(&format!("{:}amix=inputs={:}:duration=first:normalize=0[{:}]", mix_input_labels, input_files.len() + 1, audio_output_label));
+    filter_code
+}
+
+// adelay pads the start with silence, an early audio is cut at the start instead
+fn get_audio_delay_filter_code(audio_delay_ms: i64) -> String {
+    if 0 <= audio_delay_ms {
+        format!("adelay=delays={:}:all=1", audio_delay_ms)
+    } else {
+        format!("atrim=start={:},asetpts=PTS-STARTPTS", -audio_delay_ms as f64 / 1000.0)
+    }
+}
+
+#[cfg(test)]
+mod test_get_audio_delay_filter_code {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_audio_delay_filter_code(120), "adelay=delays=120:all=1");
+        assert_eq!(get_audio_delay_filter_code(-250), "atrim=start=0.25,asetpts=PTS-STARTPTS");
+    }
+}
+
+fn get_sped_duration(duration: f64, speed: f64) -> f64 {
+    duration / speed
+}
+
+#[cfg(test)]
+mod test_get_sped_duration {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_sped_duration(10.0, 2.0), 5.0);
+        assert_eq!(get_sped_duration(10.0, 0.5), 20.0);
+        assert_eq!(get_sped_duration(3.0, 1.5), 2.0);
+    }
+}
+
+// atempo takes 0.5 to 2.0 in older ffmpeg, so the rest is chained
+fn get_atempo_filter_code(speed: f64) -> String {
+    let mut tempos = Vec::new();
+    let mut rest = speed;
+    while 2.0 < rest {
+        tempos.push(2.0);
+        rest /= 2.0;
+    }
+    while rest < 0.5 {
+        tempos.push(0.5);
+        rest /= 0.5;
+    }
+    tempos.push(rest);
+    tempos.iter().map(|tempo| format!("atempo={:}", tempo)).collect::<Vec<_>>().join(",")
+}
+
+#[cfg(test)]
+mod test_get_atempo_filter_code {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let test_cases = [
+            (2.0, "atempo=2"),
+            (0.5, "atempo=0.5"),
+            (1.5, "atempo=1.5"),
+            (3.0, "atempo=2,atempo=1.5"),
+            (8.0, "atempo=2,atempo=2,atempo=2"),
+            (0.25, "atempo=0.5,atempo=0.5"),
+            (0.3, "atempo=0.5,atempo=0.6"),
+        ];
+        for (speed, expected) in test_cases {
+            assert_eq!(get_atempo_filter_code(speed), expected, "{:}", speed);
+        }
+    }
+}
+
+fn get_denoise_filter_code(denoise: &DenoiseSpec) -> String {
+    match denoise.filter {
+        DenoiseFilter::Hqdn3d => format!("hqdn3d={:}", denoise.strength),
+        DenoiseFilter::Nlmeans => format!("nlmeans=s={:}", denoise.strength),
+    }
+}
+
+#[cfg(test)]
+mod test_get_denoise_filter_code {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_denoise_filter_code(&DenoiseSpec { filter: DenoiseFilter::Hqdn3d, strength: 4.0 }), "hqdn3d=4");
+        assert_eq!(get_denoise_filter_code(&DenoiseSpec { filter: DenoiseFilter::Hqdn3d, strength: 1.5 }), "hqdn3d=1.5");
+        assert_eq!(get_denoise_filter_code(&DenoiseSpec { filter: DenoiseFilter::Nlmeans, strength: 3.0 }), "nlmeans=s=3");
+    }
+}
+
+// inputs without audio are the ones filled with null audio
+fn has_any_audio(input_files: &[InputFile]) -> bool {
+    input_files.iter().any(|input_file| input_file.alternative_null_audio_duration.is_none())
+}
+
+#[cfg(test)]
+mod test_has_any_audio {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let with_audio = || InputFile { path: PathBuf::from("0.mp4"), duration: Some(1.0), ..Default::default() };
+        let without_audio = || InputFile { path: PathBuf::from("1.mp4"), duration: Some(1.0), alternative_null_audio_duration: Some(1.0), ..Default::default() };
+        assert!(has_any_audio(&[with_audio()]));
+        assert!(has_any_audio(&[without_audio(), with_audio()]));
+        assert!(!has_any_audio(&[without_audio(), without_audio()]));
+        assert!(!has_any_audio(&[]));
+    }
+}
+
+fn get_drops_audio(input_files: &[InputFile], audio_policy: AudioPolicy) -> bool {
+    match audio_policy {
+        AudioPolicy::SilenceFill => false,
+        AudioPolicy::DropIfAnyMissing => input_files.iter().any(|input_file| input_file.alternative_null_audio_duration.is_some()),
+        AudioPolicy::DropAlways => true,
+    }
+}
+
+#[cfg(test)]
+mod test_get_drops_audio {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let with_audio = || InputFile { path: PathBuf::from("0.mp4"), duration: Some(1.0), ..Default::default() };
+        let without_audio = || InputFile { path: PathBuf::from("1.mp4"), duration: Some(1.0), alternative_null_audio_duration: Some(1.0), ..Default::default() };
+        let test_cases = [
+            (AudioPolicy::SilenceFill, vec![with_audio(), without_audio()], false),
+            (AudioPolicy::SilenceFill, vec![without_audio()], false),
+            (AudioPolicy::DropIfAnyMissing, vec![with_audio(), without_audio()], true),
+            (AudioPolicy::DropIfAnyMissing, vec![with_audio(), with_audio()], false),
+            (AudioPolicy::DropAlways, vec![with_audio()], true),
+        ];
+        for (audio_policy, input_files, expected) in test_cases {
+            assert_eq!(get_drops_audio(&input_files, audio_policy), expected, "{:?}", audio_policy);
+        }
+    }
+}
+
+// distinct and sorted, inputs without audio don't count
+fn get_audio_channel_counts(input_files: &[InputFile]) -> Vec<i64> {
+    let mut audio_channel_counts = input_files.iter().filter_map(|input_file| input_file.audio_channels).collect::<Vec<_>>();
+    audio_channel_counts.sort();
+    audio_channel_counts.dedup();
+    audio_channel_counts
+}
+
+#[cfg(test)]
+mod test_get_audio_channel_counts {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let input_file = |audio_channels: Option<i64>| InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, audio_channels, ..Default::default() };
+        assert_eq!(get_audio_channel_counts(&[]), Vec::<i64>::new());
+        assert_eq!(get_audio_channel_counts(&[input_file(None)]), Vec::<i64>::new());
+        assert_eq!(get_audio_channel_counts(&[input_file(Some(2)), input_file(None), input_file(Some(2))]), vec![2]);
+        assert_eq!(get_audio_channel_counts(&[input_file(Some(6)), input_file(Some(2)), input_file(Some(6))]), vec![2, 6]);
+    }
+}
+
+// the filter graph downmixes different channel counts unless strict
+fn check_audio_layout(input_files: &[InputFile], options: &EncodeOptions) -> Result<(), Error> {
+    let audio_channel_counts = get_audio_channel_counts(input_files);
+    if audio_channel_counts.len() <= 1 {
+        return Ok(());
+    }
+    if options.strict_audio {
+        return Err(Error { kind: ErrorKind::IncompatibleAudioLayout(audio_channel_counts) });
+    }
+    log::warn!("Inputs have different audio channel counts, downmix all to stereo: {:?}", audio_channel_counts);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_check_audio_layout {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let input_file = |audio_channels: Option<i64>| InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, audio_channels, ..Default::default() };
+        let strict_options = EncodeOptions { strict_audio: true, ..Default::default() };
+        let same_input_files = vec![input_file(Some(2)), input_file(None), input_file(Some(2))];
+        assert_eq!(check_audio_layout(&same_input_files, &EncodeOptions::default()), Ok(()));
+        assert_eq!(check_audio_layout(&same_input_files, &strict_options), Ok(()));
+
+        // downmixed by default
+        let mixed_input_files = vec![input_file(Some(6)), input_file(Some(2))];
+        assert_eq!(check_audio_layout(&mixed_input_files, &EncodeOptions::default()), Ok(()));
+        assert_eq!(check_audio_layout(&mixed_input_files, &strict_options), Err(Error { kind: ErrorKind::IncompatibleAudioLayout(vec![2, 6]) }));
+    }
+}
+
+// ffmpeg's default layout for the channel count
+fn get_channel_layout(channels: i64) -> String {
+    match channels {
+        1 => "mono".to_string(),
+        2 => "stereo".to_string(),
+        3 => "2.1".to_string(),
+        4 => "quad".to_string(),
+        5 => "5.0".to_string(),
+        6 => "5.1".to_string(),
+        8 => "7.1".to_string(),
+        channels => format!("{:}c", channels),
+    }
+}
+
+#[cfg(test)]
+mod test_get_channel_layout {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_channel_layout(1), "mono");
+        assert_eq!(get_channel_layout(2), "stereo");
+        assert_eq!(get_channel_layout(6), "5.1");
+        assert_eq!(get_channel_layout(7), "7c");
+    }
+}
+
+// overlays [vconcat] into [vout]
+fn get_watermark_filter_code(watermark: &WatermarkSpec, output_label: &str) -> String {
+    const MARGIN: i64 = 10;
+    let (x, y) = match watermark.position {
+        WatermarkPosition::TopLeft => (format!("{:}", MARGIN), format!("{:}", MARGIN)),
+        WatermarkPosition::TopRight => (format!("W-w-{:}", MARGIN), format!("{:}", MARGIN)),
+        WatermarkPosition::BottomLeft => (format!("{:}", MARGIN), format!("H-h-{:}", MARGIN)),
+        WatermarkPosition::BottomRight => (format!("W-w-{:}", MARGIN), format!("H-h-{:}", MARGIN)),
+    };
+    format!(";movie={:},format=rgba,colorchannelmixer=aa={:}[watermark];[vconcat][watermark]overlay={:}:{:}[{:}]", escape_filter_option_value(&watermark.path.to_string_lossy()), watermark.opacity, x, y, output_label)
+}
+
+// option value escaping, then filter graph escaping, as a path may contain ':' or ','
+fn escape_filter_option_value(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        if matches!(c, '\\' | '\'' | ':') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    let mut graph_escaped = String::new();
+    for c in escaped.chars() {
+        if matches!(c, '\\' | '\'' | '[' | ']' | ',' | ';') {
+            graph_escaped.push('\\');
+        }
+        graph_escaped.push(c);
+    }
+    graph_escaped
+}
+
+#[cfg(test)]
+mod test_escape_filter_option_value {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(escape_filter_option_value("data/logo.png"), "data/logo.png");
+        assert_eq!(escape_filter_option_value("data/a:b.png"), "data/a\\\\:b.png");
+        assert_eq!(escape_filter_option_value("data/a,b.png"), "data/a\\,b.png");
+        assert_eq!(escape_filter_option_value("data/it's.png"), "data/it\\\\\\'s.png");
+    }
+}
+
+#[cfg(test)]
+mod test_get_avfilter_code {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let test_cases = [
+            ("[0:v:0]null[v0];[0:a:0]anull[a0];[1:v:0]null[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]", vec![
+                InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, alternative_null_audio_duration: None, ..Default::default() },
+                InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, alternative_null_audio_duration: None, ..Default::default() },
+            ]),
+            ("[0:v:0]null[v0];[0:a:0]anull[a0];[1:v:0]scale=300:100[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]", vec![
+                InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, alternative_null_audio_duration: None, ..Default::default() },
+                InputFile { path: PathBuf::from("1.mp4"), width: 150, height: 50, alternative_null_audio_duration: None, ..Default::default() },
+            ]),
+            ("[0:v:0]scale=300:150:force_original_aspect_ratio=decrease,pad=300:150:(ow-iw)/2:(oh-ih)/2[v0];[0:a:0]anull[a0];[1:v:0]scale=300:150:force_original_aspect_ratio=decrease,pad=300:150:(ow-iw)/2:(oh-ih)/2[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]", vec![
+                InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, alternative_null_audio_duration: None, ..Default::default() },
+                InputFile { path: PathBuf::from("1.mp4"), width: 50, height: 150, alternative_null_audio_duration: None, ..Default::default() },
+            ]),
+            ("[0:v:0]null[v0];anullsrc=d=3.5[a0];[1:v:0]null[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]", vec![
+                InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, alternative_null_audio_duration: Some(3.5), ..Default::default() },
+                InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, alternative_null_audio_duration: None, ..Default::default() },
+            ]),
+            ("[0:v:0]null[v0];[0:a:0]anull[a0];[1:v:0]null[v1];anullsrc=d=10.5[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]", vec![
+                InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, alternative_null_audio_duration: None, ..Default::default() },
+                InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, alternative_null_audio_duration: Some(10.5), ..Default::default() },
+            ]),
+        ];
+
+        for (filter, input_files) in test_cases {
+            assert_eq!(get_avfilter_code(&input_files, &EncodeOptions::default()), filter.to_string());
+        }
+    }
+
+    #[test]
+    fn it_matches_null_audio_to_real_audio() {
+        let test_cases = [
+            ("[0:v:0]null[v0];anullsrc=d=3.5:sample_rate=48000:channel_layout=stereo[a0];[1:v:0]null[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]", vec![
+                InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, alternative_null_audio_duration: Some(3.5), ..Default::default() },
+                InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, audio_sample_rate: Some(48000), audio_channels: Some(2), ..Default::default() },
+            ]),
+            ("[0:v:0]null[v0];[0:a:0]anull[a0];[1:v:0]null[v1];anullsrc=d=10.5:sample_rate=44100:channel_layout=mono[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]", vec![
+                InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, audio_sample_rate: Some(44100), audio_channels: Some(1), ..Default::default() },
+                InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, alternative_null_audio_duration: Some(10.5), ..Default::default() },
+            ]),
+            ("[0:v:0]null[v0];anullsrc=d=1:sample_rate=48000:channel_layout=5.1[a0];[v0][a0]concat=n=1:v=1:a=1[vout][aout]", vec![
+                InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, audio_sample_rate: Some(48000), audio_channels: Some(6), alternative_null_audio_duration: Some(1.0), ..Default::default() },
+            ]),
+        ];
+
+        for (filter, input_files) in test_cases {
+            assert_eq!(get_avfilter_code(&input_files, &EncodeOptions::default()), filter.to_string());
+        }
+    }
+
+    #[test]
+    fn it_can_drop_audio() {
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, duration: Some(1.0), audio_sample_rate: Some(48000), audio_channels: Some(2), ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, duration: Some(2.0), alternative_null_audio_duration: Some(2.0), ..Default::default() },
+        ];
+        let test_cases = [
+            (AudioPolicy::SilenceFill, "[0:v:0]null[v0];[0:a:0]anull[a0];[1:v:0]null[v1];anullsrc=d=2:sample_rate=48000:channel_layout=stereo[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]"),
+            (AudioPolicy::DropIfAnyMissing, "[0:v:0]null[v0];[1:v:0]null[v1];[v0][v1]concat=n=2:v=1:a=0[vout]"),
+            (AudioPolicy::DropAlways, "[0:v:0]null[v0];[1:v:0]null[v1];[v0][v1]concat=n=2:v=1:a=0[vout]"),
+        ];
+        for (audio_policy, expected) in test_cases {
+            let options = EncodeOptions { audio_policy, ..Default::default() };
+            assert_eq!(get_avfilter_code(&input_files, &options), expected, "{:?}", audio_policy);
+        }
+
+        // every input has audio
+        let options = EncodeOptions { audio_policy: AudioPolicy::DropIfAnyMissing, ..Default::default() };
+        let input_files_with_audio = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, duration: Some(1.0), audio_sample_rate: Some(48000), audio_channels: Some(2), ..Default::default() },
+        ];
+        assert_eq!(get_avfilter_code(&input_files_with_audio, &options), "[0:v:0]null[v0];[0:a:0]anull[a0];[v0][a0]concat=n=1:v=1:a=1[vout][aout]");
+
+        // the timeline and the fades go video only too
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, duration: Some(1.0), audio_sample_rate: Some(48000), audio_channels: Some(2), start_offset: Some(0.0), ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, duration: Some(2.0), alternative_null_audio_duration: Some(2.0), start_offset: Some(1.0), ..Default::default() },
+        ];
+        let options = EncodeOptions { audio_policy: AudioPolicy::DropAlways, timeline: Some(TimelineSpec { offsets: vec![0.0, 1.0] }), fade_in: Some(0.5), ..Default::default() };
+        assert_eq!(get_avfilter_code(&input_files, &options), [
+            "[0:v:0]null[v0];[1:v:0]null[v1];",
+            "color=c=black:s=300x100:r=25:d=3[vbase0];",
+            "[v0]setpts=PTS-STARTPTS+0/TB[vt0];[vbase0][vt0]overlay=eof_action=pass[vbase1];",
+            "[v1]setpts=PTS-STARTPTS+1/TB[vt1];[vbase1][vt1]overlay=eof_action=pass[vprogram]",
+            ";[vprogram]fade=t=in:st=0:d=0.5[vout]",
+        ].concat());
+    }
+
+    #[test]
+    fn it_can_delay_audio() {
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, duration: Some(2.0), audio_delay_ms: Some(120), ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, duration: Some(2.0), ..Default::default() },
+            InputFile { path: PathBuf::from("2.mp4"), width: 300, height: 100, duration: Some(1.5), audio_delay_ms: Some(-500), ..Default::default() },
+        ];
+        assert_eq!(get_avfilter_code(&input_files, &EncodeOptions::default()), "[0:v:0]null[v0];[0:a:0]adelay=delays=120:all=1,apad=whole_dur=2,atrim=duration=2[a0];[1:v:0]null[v1];[1:a:0]anull[a1];[2:v:0]null[v2];[2:a:0]atrim=start=0.5,asetpts=PTS-STARTPTS,apad=whole_dur=1.5,atrim=duration=1.5[a2];[v0][a0][v1][a1][v2][a2]concat=n=3:v=1:a=1[vout][aout]");
+
+        // nothing to shift in the silence of a video only input
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, duration: Some(2.0), alternative_null_audio_duration: Some(2.0), audio_delay_ms: Some(120), ..Default::default() },
+        ];
+        assert_eq!(get_avfilter_code(&input_files, &EncodeOptions::default()), "[0:v:0]null[v0];anullsrc=d=2[a0];[v0][a0]concat=n=1:v=1:a=1[vout][aout]");
+    }
+
+    #[test]
+    fn it_can_change_speed() {
+        // durations are the sped up ones, as encode_best_effort() sets them
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, duration: Some(2.0), speed: Some(2.0), ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, duration: Some(2.0), ..Default::default() },
+            InputFile { path: PathBuf::from("2.mp4"), width: 300, height: 100, duration: Some(1.5), alternative_null_audio_duration: Some(1.5), speed: Some(4.0), ..Default::default() },
+        ];
+        assert_eq!(get_avfilter_code(&input_files, &EncodeOptions::default()), "[0:v:0]setpts=PTS/2[v0];[0:a:0]atempo=2[a0];[1:v:0]null[v1];[1:a:0]anull[a1];[2:v:0]setpts=PTS/4[v2];anullsrc=d=1.5[a2];[v0][a0][v1][a1][v2][a2]concat=n=3:v=1:a=1[vout][aout]");
+
+        // after the trim, before the pad to the sped up duration
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, duration: Some(1.0), trim_range: Some((1.0, 4.0)), speed: Some(3.0), ..Default::default() },
+        ];
+        let options = EncodeOptions { align_audio_to_video: true, ..Default::default() };
+        assert_eq!(get_avfilter_code(&input_files, &options), "[0:v:0]trim=start=1:end=4,setpts=PTS-STARTPTS,setpts=PTS/3[v0];[0:a:0]atrim=start=1:end=4,asetpts=PTS-STARTPTS,atempo=2,atempo=1.5,apad=whole_dur=1,atrim=duration=1[a0];[v0][a0]concat=n=1:v=1:a=1[vout][aout]");
+    }
+
+    #[test]
+    fn it_can_denoise_inputs() {
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 640, height: 360, ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 1280, height: 720, ..Default::default() },
+        ];
+        let options = EncodeOptions { denoise: Some(DenoiseSpec { filter: DenoiseFilter::Hqdn3d, strength: 4.0 }), ..Default::default() };
+        assert_eq!(get_avfilter_code(&input_files, &options), "[0:v:0]hqdn3d=4,scale=1280:720[v0];[0:a:0]anull[a0];[1:v:0]hqdn3d=4[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]");
+
+        // after the trim, so the filter isn't fed frames that are dropped anyway
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 640, height: 360, trim_range: Some((1.0, 3.0)), ..Default::default() },
+        ];
+        let options = EncodeOptions { denoise: Some(DenoiseSpec { filter: DenoiseFilter::Nlmeans, strength: 2.0 }), ..Default::default() };
+        assert_eq!(get_avfilter_code(&input_files, &options), "[0:v:0]trim=start=1:end=3,setpts=PTS-STARTPTS,nlmeans=s=2[v0];[0:a:0]atrim=start=1:end=3,asetpts=PTS-STARTPTS[a0];[v0][a0]concat=n=1:v=1:a=1[vout][aout]");
+    }
+
+    #[test]
+    fn it_squares_anamorphic_inputs() {
+        let test_cases = [
+            // hdv next to full hd, the same display size
+            (TargetResolutionPolicy::Max, "[0:v:0]scale=1920:1080,setsar=1[v0];[0:a:0]anull[a0];[1:v:0]null[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]", vec![
+                InputFile { path: PathBuf::from("0.mp4"), width: 1440, height: 1080, sample_aspect_ratio: Some((4, 3)), ..Default::default() },
+                InputFile { path: PathBuf::from("1.mp4"), width: 1920, height: 1080, sample_aspect_ratio: Some((1, 1)), ..Default::default() },
+            ]),
+            // ntsc widescreen next to 4:3, padded by the display aspect ratio rather than the storage one
+            (TargetResolutionPolicy::Max, "[0:v:0]scale=854:480,setsar=1[v0];[0:a:0]anull[a0];[1:v:0]scale=854:480:force_original_aspect_ratio=decrease,pad=854:480:(ow-iw)/2:(oh-ih)/2[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]", vec![
+                InputFile { path: PathBuf::from("0.mp4"), width: 720, height: 480, sample_aspect_ratio: Some((32, 27)), ..Default::default() },
+                InputFile { path: PathBuf::from("1.mp4"), width: 640, height: 480, ..Default::default() },
+            ]),
+            // squared then scaled down to the target
+            (TargetResolutionPolicy::Min, "[0:v:0]scale=600:300,setsar=1,scale=400:200[v0];[0:a:0]anull[a0];[1:v:0]null[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]", vec![
+                InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 300, sample_aspect_ratio: Some((2, 1)), ..Default::default() },
+                InputFile { path: PathBuf::from("1.mp4"), width: 400, height: 200, ..Default::default() },
+            ]),
+        ];
+
+        for (target_resolution_policy, filter, input_files) in test_cases {
+            let options = EncodeOptions { target_resolution_policy, ..Default::default() };
+            assert_eq!(get_avfilter_code(&input_files, &options), filter.to_string());
+        }
+    }
+
+    #[test]
+    fn it_downmixes_different_channel_counts_to_stereo() {
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, audio_sample_rate: Some(48000), audio_channels: Some(6), ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, audio_sample_rate: Some(48000), audio_channels: Some(2), ..Default::default() },
+            InputFile { path: PathBuf::from("2.mp4"), width: 300, height: 100, alternative_null_audio_duration: Some(1.0), ..Default::default() },
+        ];
+        assert_eq!(get_avfilter_code(&input_files, &EncodeOptions::default()), "[0:v:0]null[v0];[0:a:0]aformat=channel_layouts=stereo[a0];[1:v:0]null[v1];[1:a:0]aformat=channel_layouts=stereo[a1];[2:v:0]null[v2];anullsrc=d=1:sample_rate=48000:channel_layout=stereo[a2];[v0][a0][v1][a1][v2][a2]concat=n=3:v=1:a=1[vout][aout]");
+    }
+
+    #[test]
+    fn it_can_target_min_resolution() {
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 150, height: 50, ..Default::default() },
+            InputFile { path: PathBuf::from("2.mp4"), width: 600, height: 200, ..Default::default() },
+        ];
+        let test_cases = [
+            (TargetResolutionPolicy::Min, "[0:v:0]scale=150:50[v0];[0:a:0]anull[a0];[1:v:0]null[v1];[1:a:0]anull[a1];[2:v:0]scale=150:50[v2];[2:a:0]anull[a2];[v0][a0][v1][a1][v2][a2]concat=n=3:v=1:a=1[vout][aout]"),
+            (TargetResolutionPolicy::Median, "[0:v:0]null[v0];[0:a:0]anull[a0];[1:v:0]scale=300:100[v1];[1:a:0]anull[a1];[2:v:0]scale=300:100[v2];[2:a:0]anull[a2];[v0][a0][v1][a1][v2][a2]concat=n=3:v=1:a=1[vout][aout]"),
+            (TargetResolutionPolicy::Fixed(90, 30), "[0:v:0]scale=90:30[v0];[0:a:0]anull[a0];[1:v:0]scale=90:30[v1];[1:a:0]anull[a1];[2:v:0]scale=90:30[v2];[2:a:0]anull[a2];[v0][a0][v1][a1][v2][a2]concat=n=3:v=1:a=1[vout][aout]"),
+        ];
+        for (target_resolution_policy, filter) in test_cases {
+            let options = EncodeOptions { target_resolution_policy, ..Default::default() };
+            assert_eq!(get_avfilter_code(&input_files, &options), filter.to_string());
+        }
+
+        // a different aspect ratio is padded into the min box
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 200, height: 200, ..Default::default() },
+        ];
+        let options = EncodeOptions { target_resolution_policy: TargetResolutionPolicy::Min, ..Default::default() };
+        assert_eq!(get_avfilter_code(&input_files, &options), "[0:v:0]scale=200:100:force_original_aspect_ratio=decrease,pad=200:100:(ow-iw)/2:(oh-ih)/2[v0];[0:a:0]anull[a0];[1:v:0]scale=200:100:force_original_aspect_ratio=decrease,pad=200:100:(ow-iw)/2:(oh-ih)/2[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]".to_string());
+    }
+
+    #[test]
+    fn it_can_place_on_timeline() {
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, frame_rate: "30/1".to_string(), duration: Some(2.0), audio_sample_rate: Some(48000), audio_channels: Some(2), start_offset: Some(1.0), ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 150, height: 50, frame_rate: "30/1".to_string(), duration: Some(3.0), alternative_null_audio_duration: Some(3.0), start_offset: Some(5.5), ..Default::default() },
+        ];
+        let options = EncodeOptions { timeline: Some(TimelineSpec { offsets: vec![1.0, 5.5] }), ..Default::default() };
+        assert_eq!(get_avfilter_code(&input_files, &options), [
+            "[0:v:0]null[v0];[0:a:0]anull[a0];[1:v:0]scale=300:100[v1];anullsrc=d=3:sample_rate=48000:channel_layout=stereo[a1];",
+            "color=c=black:s=300x100:r=30/1:d=8.5[vbase0];",
+            "[v0]setpts=PTS-STARTPTS+1/TB[vt0];[vbase0][vt0]overlay=eof_action=pass[vbase1];",
+            "[v1]setpts=PTS-STARTPTS+5.5/TB[vt1];[vbase1][vt1]overlay=eof_action=pass[vout];",
+            "anullsrc=d=8.5:sample_rate=48000:channel_layout=stereo[abase];",
+            "[a0]asetpts=PTS-STARTPTS,adelay=1000:all=1[at0];[a1]asetpts=PTS-STARTPTS,adelay=5500:all=1[at1];",
+            "[abase][at0][at1]amix=inputs=3:duration=first:normalize=0[aout]",
+        ].concat());
+    }
+
+    #[test]
+    fn it_can_align_audio_to_video() {
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, duration: Some(1.5), ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, duration: Some(2.0), alternative_null_audio_duration: Some(2.0), ..Default::default() },
+        ];
+        let options = EncodeOptions { align_audio_to_video: true, ..Default::default() };
+        assert_eq!(get_avfilter_code(&input_files, &options), "[0:v:0]null[v0];[0:a:0]apad=whole_dur=1.5,atrim=duration=1.5[a0];[1:v:0]null[v1];anullsrc=d=2,atrim=duration=2[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]".to_string());
+    }
+
+    #[test]
+    fn it_can_fade_program() {
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, duration: Some(1.5), ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, duration: Some(2.5), ..Default::default() },
+        ];
+        let options = EncodeOptions { fade_in: Some(1.0), fade_out: Some(0.5), ..Default::default() };
+        assert_eq!(get_avfilter_code(&input_files, &options), "[0:v:0]null[v0];[0:a:0]anull[a0];[1:v:0]null[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vprogram][aprogram];[vprogram]fade=t=in:st=0:d=1,fade=t=out:st=3.5:d=0.5[vout];[aprogram]afade=t=in:st=0:d=1,afade=t=out:st=3.5:d=0.5[aout]".to_string());
+
+        // after the watermark, at the output range edges
+        let options = EncodeOptions { fade_out: Some(1.0), output_range: Some((1.0, 3.0)), watermark: Some(WatermarkSpec { path: PathBuf::from("w.png"), position: WatermarkPosition::TopLeft, opacity: 1.0 }), ..Default::default() };
+        assert_eq!(get_avfilter_code(&input_files, &options), "[0:v:0]null[v0];[0:a:0]anull[a0];[1:v:0]null[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vconcat][aprogram];movie=w.png,format=rgba,colorchannelmixer=aa=1[watermark];[vconcat][watermark]overlay=10:10[vprogram];[vprogram]fade=t=out:st=2:d=1[vout];[aprogram]afade=t=out:st=2:d=1[aout]".to_string());
+    }
+
+    #[test]
+    fn it_rounds_target_down_to_even() {
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 301, height: 401, ..Default::default() },
+        ];
+        assert_eq!(get_avfilter_code(&input_files, &EncodeOptions::default()), "[0:v:0]scale=300:400:force_original_aspect_ratio=decrease,pad=300:400:(ow-iw)/2:(oh-ih)/2[v0];[0:a:0]anull[a0];[v0][a0]concat=n=1:v=1:a=1[vout][aout]".to_string());
+
+        // the max of each dimension can come from different inputs
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 301, height: 200, ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 200, height: 301, ..Default::default() },
+        ];
+        assert_eq!(get_avfilter_code(&input_files, &EncodeOptions::default()), "[0:v:0]scale=300:300:force_original_aspect_ratio=decrease,pad=300:300:(ow-iw)/2:(oh-ih)/2[v0];[0:a:0]anull[a0];[1:v:0]scale=300:300:force_original_aspect_ratio=decrease,pad=300:300:(ow-iw)/2:(oh-ih)/2[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]".to_string());
+
+        // a same aspect ratio scale stays even too
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 301, height: 301, ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 100, height: 100, ..Default::default() },
+        ];
+        assert_eq!(get_avfilter_code(&input_files, &EncodeOptions::default()), "[0:v:0]scale=300:300[v0];[0:a:0]anull[a0];[1:v:0]scale=300:300[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]".to_string());
+    }
+
+    #[test]
+    fn it_can_fix_aspect_ratio() {
+        let options = EncodeOptions { aspect_ratio: Some((16, 9)), ..Default::default() };
+
+        // a vertical input is pillarboxed into the 16:9 box of its height
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 1080, height: 1920, ..Default::default() },
+        ];
+        assert_eq!(get_avfilter_code(&input_files, &options), "[0:v:0]scale=3414:1920:force_original_aspect_ratio=decrease,pad=3414:1920:(ow-iw)/2:(oh-ih)/2[v0];[0:a:0]anull[a0];[v0][a0]concat=n=1:v=1:a=1[vout][aout]".to_string());
+
+        // the max of 4:3 and vertical inputs grows to 16:9, both are padded
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 640, height: 480, ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 360, height: 640, ..Default::default() },
+        ];
+        assert_eq!(get_avfilter_code(&input_files, &options), "[0:v:0]scale=1138:640:force_original_aspect_ratio=decrease,pad=1138:640:(ow-iw)/2:(oh-ih)/2[v0];[0:a:0]anull[a0];[1:v:0]scale=1138:640:force_original_aspect_ratio=decrease,pad=1138:640:(ow-iw)/2:(oh-ih)/2[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]".to_string());
+
+        // already 16:9 inputs are untouched
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 1280, height: 720, ..Default::default() },
+        ];
+        assert_eq!(get_avfilter_code(&input_files, &options), "[0:v:0]null[v0];[0:a:0]anull[a0];[v0][a0]concat=n=1:v=1:a=1[vout][aout]".to_string());
+    }
+
+    #[test]
+    fn it_can_trim_edges() {
+        let mut input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, duration: Some(1.5), trim_range: Some((0.5, 2.0)), ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, duration: Some(2.0), alternative_null_audio_duration: Some(2.0), trim_range: Some((0.0, 2.0)), ..Default::default() },
+        ];
+        assert_eq!(get_avfilter_code(&input_files, &EncodeOptions::default()), "[0:v:0]trim=start=0.5:end=2,setpts=PTS-STARTPTS[v0];[0:a:0]atrim=start=0.5:end=2,asetpts=PTS-STARTPTS[a0];[1:v:0]trim=start=0:end=2,setpts=PTS-STARTPTS[v1];anullsrc=d=2[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]".to_string());
+
+        // the trimmed audio is aligned to the trimmed duration
+        input_files.truncate(1);
+        let options = EncodeOptions { align_audio_to_video: true, ..Default::default() };
+        assert_eq!(get_avfilter_code(&input_files, &options), "[0:v:0]trim=start=0.5:end=2,setpts=PTS-STARTPTS[v0];[0:a:0]atrim=start=0.5:end=2,asetpts=PTS-STARTPTS,apad=whole_dur=1.5,atrim=duration=1.5[a0];[v0][a0]concat=n=1:v=1:a=1[vout][aout]".to_string());
+    }
+
+    #[test]
+    fn it_can_concat_unsafe() {
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, ..Default::default() },
+        ];
+        let options = EncodeOptions { concat_unsafe: true, ..Default::default() };
+        assert_eq!(get_avfilter_code(&input_files, &options), "[0:v:0]null[v0];[0:a:0]anull[a0];[1:v:0]null[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1:unsafe=1[vout][aout]".to_string());
+    }
+
+    #[test]
+    fn it_can_limit_memory() {
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 3840, height: 2160, ..Default::default() },
+        ];
+
+        // 3840x2160 is about 8GB, 2048MB fits 1952x1098
+        let options = EncodeOptions { memory_limit_mb: Some(2048), ..Default::default() };
+        assert_eq!(get_avfilter_code(&input_files, &options), "[0:v:0]scale=1952:1098[v0];[0:a:0]anull[a0];[v0][a0]concat=n=1:v=1:a=1[vout][aout]".to_string());
+        let options = EncodeOptions { memory_limit_mb: Some(8192), ..Default::default() };
+        assert_eq!(get_avfilter_code(&input_files, &options), "[0:v:0]null[v0];[0:a:0]anull[a0];[v0][a0]concat=n=1:v=1:a=1[vout][aout]".to_string());
+    }
+
+    #[test]
+    fn it_can_rotate_to_majority() {
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 100, height: 300, rotation: 90, ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, ..Default::default() },
+            InputFile { path: PathBuf::from("2.mp4"), width: 100, height: 300, ..Default::default() },
+        ];
+
+        let test_cases = [
+            (OrientationPolicy::Pad, "[0:v:0]scale=300:300:force_original_aspect_ratio=decrease,pad=300:300:(ow-iw)/2:(oh-ih)/2[v0];[0:a:0]anull[a0];[1:v:0]scale=300:300:force_original_aspect_ratio=decrease,pad=300:300:(ow-iw)/2:(oh-ih)/2[v1];[1:a:0]anull[a1];[2:v:0]scale=300:300:force_original_aspect_ratio=decrease,pad=300:300:(ow-iw)/2:(oh-ih)/2[v2];[2:a:0]anull[a2];[v0][a0][v1][a1][v2][a2]concat=n=3:v=1:a=1[vout][aout]"),
+            (OrientationPolicy::RotateToMajority, "[0:v:0]null[v0];[0:a:0]anull[a0];[1:v:0]transpose=1[v1];[1:a:0]anull[a1];[2:v:0]null[v2];[2:a:0]anull[a2];[v0][a0][v1][a1][v2][a2]concat=n=3:v=1:a=1[vout][aout]"),
+        ];
+
+        for (orientation_policy, filter) in test_cases {
+            let options = EncodeOptions { orientation_policy, ..Default::default() };
+            assert_eq!(get_avfilter_code(&input_files, &options), filter.to_string());
+        }
+
+        // rotated input also needs scale after transpose
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 400, height: 200, ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 400, height: 200, ..Default::default() },
+            InputFile { path: PathBuf::from("2.mp4"), width: 100, height: 200, ..Default::default() },
+        ];
+        let options = EncodeOptions { orientation_policy: OrientationPolicy::RotateToMajority, ..Default::default() };
+        assert_eq!(get_avfilter_code(&input_files, &options), "[0:v:0]null[v0];[0:a:0]anull[a0];[1:v:0]null[v1];[1:a:0]anull[a1];[2:v:0]transpose=1,scale=400:200[v2];[2:a:0]anull[a2];[v0][a0][v1][a1][v2][a2]concat=n=3:v=1:a=1[vout][aout]".to_string());
+    }
+
+    #[test]
+    fn it_can_overlay_watermark() {
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, ..Default::default() },
+        ];
+
+        let test_cases = [
+            (WatermarkPosition::BottomRight, "overlay=W-w-10:H-h-10"),
+            (WatermarkPosition::TopLeft, "overlay=10:10"),
+            (WatermarkPosition::TopRight, "overlay=W-w-10:10"),
+            (WatermarkPosition::BottomLeft, "overlay=10:H-h-10"),
+        ];
+        for (position, overlay_filter) in test_cases {
+            let options = EncodeOptions { watermark: Some(WatermarkSpec { path: PathBuf::from("data/logo.png"), position, opacity: 0.5 }), ..Default::default() };
+            assert_eq!(get_avfilter_code(&input_files, &options), format!("[0:v:0]null[v0];[0:a:0]anull[a0];[1:v:0]null[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vconcat][aout];movie=data/logo.png,format=rgba,colorchannelmixer=aa=0.5[watermark];[vconcat][watermark]{:}[vout]", overlay_filter));
+        }
+    }
+}
+
+fn get_target_resolution(resolutions: &[(i64, i64)], policy: TargetResolutionPolicy) -> (i64, i64) {
+    assert!(!resolutions.is_empty());
+    let mut widths = resolutions.iter().map(|(width, _)| *width).collect::<Vec<_>>();
+    let mut heights = resolutions.iter().map(|(_, height)| *height).collect::<Vec<_>>();
+    widths.sort();
+    heights.sort();
+    match policy {
+        TargetResolutionPolicy::Max => (widths[widths.len() - 1], heights[heights.len() - 1]),
+        TargetResolutionPolicy::Min => (widths[0], heights[0]),
+        // lower median for an even count, so it's always an input's dimension
+        TargetResolutionPolicy::Median => (widths[(widths.len() - 1) / 2], heights[(heights.len() - 1) / 2]),
+        TargetResolutionPolicy::Fixed(width, height) => (width, height),
+    }
+}
+
+#[cfg(test)]
+mod test_get_target_resolution {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let resolutions = vec![(1920, 1080), (640, 480), (1280, 720), (300, 400)];
+        assert_eq!(get_target_resolution(&resolutions, TargetResolutionPolicy::Max), (1920, 1080));
+        assert_eq!(get_target_resolution(&resolutions, TargetResolutionPolicy::Min), (300, 400));
+        assert_eq!(get_target_resolution(&resolutions, TargetResolutionPolicy::Median), (640, 480));
+        assert_eq!(get_target_resolution(&resolutions, TargetResolutionPolicy::Fixed(854, 480)), (854, 480));
+        assert_eq!(get_target_resolution(&[(640, 480)], TargetResolutionPolicy::Median), (640, 480));
+    }
+}
+
+// the smallest resolution of the aspect ratio containing the given one, rounded up to even numbers
+fn fit_resolution_to_aspect_ratio(width: i64, height: i64, (aspect_width, aspect_height): (u32, u32)) -> (i64, i64) {
+    let (aspect_width, aspect_height) = (aspect_width as i64, aspect_height as i64);
+    let (width, height) = if height * aspect_width < width * aspect_height {
+        (width, (width * aspect_height + aspect_width - 1) / aspect_width)
+    } else {
+        ((height * aspect_width + aspect_height - 1) / aspect_height, height)
+    };
+    ((width + 1) / 2 * 2, (height + 1) / 2 * 2)
+}
+
+#[cfg(test)]
+mod test_fit_resolution_to_aspect_ratio {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let test_cases = [
+            (1920, 1080, (16, 9), (1920, 1080)),
+            (1080, 1920, (16, 9), (3414, 1920)),
+            (640, 480, (16, 9), (854, 480)),
+            (1920, 1080, (4, 3), (1920, 1440)),
+            (1920, 1080, (1, 1), (1920, 1920)),
+            (301, 101, (1, 1), (302, 302)),
+        ];
+
+        for (width, height, aspect_ratio, expected) in test_cases {
+            assert_eq!(fit_resolution_to_aspect_ratio(width, height, aspect_ratio), expected);
+        }
+    }
+}
+
+// keeps the aspect ratio, rounded down to even numbers
+fn fit_resolution_to_max_pixels(width: i64, height: i64, max_pixels: i64) -> (i64, i64) {
+    if width * height <= max_pixels {
+        return (width, height);
+    }
+    let scale = (max_pixels as f64 / (width * height) as f64).sqrt();
+    let fitted_width = ((width as f64 * scale) as i64 / 2 * 2).max(2);
+    let fitted_height = ((height as f64 * scale) as i64 / 2 * 2).max(2);
+    (fitted_width, fitted_height)
+}
+
+#[cfg(test)]
+mod test_fit_resolution_to_max_pixels {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let test_cases = [
+            (1920, 1080, 1920 * 1080, (1920, 1080)),
+            (1920, 1080, 1920 * 1080 - 1, (1918, 1078)),
+            (3840, 2160, 1920 * 1080, (1920, 1080)),
+            (1000, 1000, 250000, (500, 500)),
+            (1000, 1000, 1, (2, 2)),
+        ];
+
+        for (width, height, max_pixels, expected) in test_cases {
+            assert_eq!(fit_resolution_to_max_pixels(width, height, max_pixels), expected);
+        }
+    }
+}
+
+// ties and square inputs count as landscape
+fn get_majority_is_portrait(input_files: &[InputFile]) -> bool {
+    let resolutions = input_files.iter().map(get_display_resolution).collect::<Vec<_>>();
+    let portrait_count = resolutions.iter().filter(|(width, height)| width < height).count();
+    let landscape_count = resolutions.iter().filter(|(width, height)| width > height).count();
+    portrait_count > landscape_count
+}
+
+#[cfg(test)]
+mod test_get_majority_is_portrait {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let portrait = || InputFile { width: 100, height: 200, ..Default::default() };
+        let landscape = || InputFile { width: 200, height: 100, ..Default::default() };
+        let square = || InputFile { width: 100, height: 100, ..Default::default() };
+
+        assert!(get_majority_is_portrait(&[portrait()]));
+        assert!(!get_majority_is_portrait(&[landscape()]));
+        assert!(!get_majority_is_portrait(&[square()]));
+        assert!(!get_majority_is_portrait(&[portrait(), landscape()]));
+        assert!(get_majority_is_portrait(&[portrait(), portrait(), landscape()]));
+        assert!(get_majority_is_portrait(&[portrait(), square(), square()]));
+    }
+}
+
+// returns (width, height, needs_transpose), square inputs are never transposed
+fn get_oriented_resolution(input_file: &InputFile, majority_is_portrait: Option<bool>) -> (i64, i64, bool) {
+    let (width, height) = get_display_resolution(input_file);
+    let is_portrait = width < height;
+    let is_landscape = width > height;
+    match majority_is_portrait {
+        Some(true) if is_landscape => (height, width, true),
+        Some(false) if is_portrait => (height, width, true),
+        _ => (width, height, false),
+    }
+}
+
+#[cfg(test)]
+mod test_get_oriented_resolution {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let portrait = InputFile { width: 100, height: 200, ..Default::default() };
+        let landscape = InputFile { width: 200, height: 100, ..Default::default() };
+        let square = InputFile { width: 100, height: 100, ..Default::default() };
+
+        let test_cases = [
+            (&portrait, None, (100, 200, false)),
+            (&portrait, Some(true), (100, 200, false)),
+            (&portrait, Some(false), (200, 100, true)),
+            (&landscape, None, (200, 100, false)),
+            (&landscape, Some(true), (100, 200, true)),
+            (&landscape, Some(false), (200, 100, false)),
+            (&square, Some(true), (100, 100, false)),
+            (&square, Some(false), (100, 100, false)),
+        ];
+
+        for (input_file, majority_is_portrait, expected) in test_cases {
+            assert_eq!(get_oriented_resolution(input_file, majority_is_portrait), expected);
+        }
+    }
+}
+
+fn get_video_color(path: &Path, video_stream_position: usize) -> ColorProperties {
+    get_video_color_impl(FFPROBE_CMD_STR, path, video_stream_position)
+}
+
+// separate impl for test, the ffprobe crate doesn't parse the transfer and the primaries
+fn get_video_color_impl(cmd_str: &str, path: &Path, video_stream_position: usize) -> ColorProperties {
+    let mut ffprobe_cmd = Command::new(cmd_str);
+    ffprobe_cmd.args([
+        "-v", "error",
+        "-select_streams", &format!("v:{:}", video_stream_position),
+        "-show_entries", "stream=color_primaries,color_transfer,color_space",
+        "-of", "default=noprint_wrappers=1",
+    ]).arg(path);
+
+    let output = match run_command(&mut ffprobe_cmd) {
+        Ok(output) => output,
+        Err(err) => {
+            log::warn!("Couldn't get video color, assume unknown: {:} ({:})", path.display(), err);
+            return ColorProperties::default();
+        },
+    };
+
+    parse_color_properties(&String::from_utf8_lossy(&output.stdout))
+}
+
+#[cfg(test)]
+mod test_get_video_color {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn it_works() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+
+        // sdr
+        assert!(!get_video_color(&video_dir_path.join("va-300x400.mp4"), 0).transfer.as_deref().is_some_and(is_hdr_transfer));
+        assert_eq!(get_video_color_impl("__command_not_found__", &video_dir_path.join("va-300x400.mp4"), 0), ColorProperties::default());
+    }
+}
+
+fn parse_color_properties(stdout: &str) -> ColorProperties {
+    let mut color = ColorProperties::default();
+    for line in stdout.lines() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        let value = (!value.is_empty() && value != "unknown").then(|| value.to_string());
+        match key {
+            "color_primaries" => color.primaries = value,
+            "color_transfer" => color.transfer = value,
+            "color_space" => color.space = value,
+            _ => {},
+        }
+    }
+    color
+}
+
+#[cfg(test)]
+mod test_parse_color_properties {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(parse_color_properties(""), ColorProperties::default());
+        assert_eq!(parse_color_properties("color_space=bt2020nc\ncolor_transfer=smpte2084\ncolor_primaries=bt2020\n"), ColorProperties { primaries: Some("bt2020".to_string()), transfer: Some("smpte2084".to_string()), space: Some("bt2020nc".to_string()) });
+        assert_eq!(parse_color_properties("color_space=unknown\ncolor_transfer=unknown\ncolor_primaries=bt709\n"), ColorProperties { primaries: Some("bt709".to_string()), ..Default::default() });
+    }
+}
+
+// pq and hlg
+fn is_hdr_transfer(transfer: &str) -> bool {
+    matches!(transfer, "smpte2084" | "arib-std-b67")
+}
+
+// the color shared by the inputs when all of them are hdr, mixed ones are encoded as sdr
+fn get_hdr_color(input_files: &[InputFile]) -> Option<ColorProperties> {
+    let first_input_file = input_files.first()?;
+    let all_hdr_alike = input_files.iter().all(|input_file| {
+        input_file.color.transfer.as_deref().is_some_and(is_hdr_transfer) && input_file.color == first_input_file.color
+    });
+    all_hdr_alike.then(|| first_input_file.color.clone())
+}
+
+#[cfg(test)]
+mod test_get_hdr_color {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let pq = ColorProperties { primaries: Some("bt2020".to_string()), transfer: Some("smpte2084".to_string()), space: Some("bt2020nc".to_string()) };
+        let hlg = ColorProperties { transfer: Some("arib-std-b67".to_string()), ..pq.clone() };
+        let sdr = ColorProperties { primaries: Some("bt709".to_string()), transfer: Some("bt709".to_string()), space: Some("bt709".to_string()) };
+        let input_file = |color: &ColorProperties| InputFile { color: color.clone(), ..Default::default() };
+
+        assert_eq!(get_hdr_color(&[input_file(&pq), input_file(&pq)]), Some(pq.clone()));
+        assert_eq!(get_hdr_color(&[input_file(&hlg)]), Some(hlg.clone()));
+        assert_eq!(get_hdr_color(&[input_file(&pq), input_file(&hlg)]), None);
+        assert_eq!(get_hdr_color(&[input_file(&pq), input_file(&sdr)]), None);
+        assert_eq!(get_hdr_color(&[input_file(&pq), input_file(&ColorProperties::default())]), None);
+        assert_eq!(get_hdr_color(&[input_file(&sdr)]), None);
+        assert_eq!(get_hdr_color(&[]), None);
+    }
+}
+
+fn get_video_rotation(path: &Path, video_stream_position: usize) -> i64 {
+    get_video_rotation_impl(FFPROBE_CMD_STR, path, video_stream_position)
+}
+
+// separate impl for test
+fn get_video_rotation_impl(cmd_str: &str, path: &Path, video_stream_position: usize) -> i64 {
+    let mut ffprobe_cmd = Command::new(cmd_str);
+    ffprobe_cmd.args([
+        "-v", "error",
+        "-select_streams", &format!("v:{:}", video_stream_position),
+        "-show_entries", "stream_tags=rotate:stream_side_data=rotation",
+        "-of", "default=noprint_wrappers=1",
+    ]).arg(path);
+
+    let output = match run_command(&mut ffprobe_cmd) {
+        Ok(output) => output,
+        Err(err) => {
+            log::warn!("Couldn't get video rotation, assume not rotated: {:} ({:})", path.display(), err);
+            return 0;
+        },
+    };
+
+    parse_rotation(&String::from_utf8_lossy(&output.stdout))
+}
+
+#[cfg(test)]
+mod test_get_video_rotation {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn it_works() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+
+        assert_eq!(get_video_rotation(&video_dir_path.join("va-300x400.mp4"), 0), 0);
+        assert_eq!(get_video_rotation_impl("__command_not_found__", &video_dir_path.join("va-300x400.mp4"), 0), 0);
+    }
+}
+
+// normalized into 0..360 clockwise
+fn parse_rotation(stdout: &str) -> i64 {
+    let Some(caps) = FFPROBE_STDOUT_RETRIEVE_ROTATION_REGEX.captures(stdout) else {
+        return 0;
+    };
+    let Ok(rotation) = caps[1].parse::<i64>() else {
+        return 0;
+    };
+    // side data rotation is counterclockwise while the legacy rotate tag is clockwise
+    let rotation = if caps[0].starts_with("rotation") { -rotation } else { rotation };
+    rotation.rem_euclid(360)
+}
+
+#[cfg(test)]
+mod test_parse_rotation {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let test_cases = [
+            ("", 0),
+            ("TAG:rotate=90\n", 90),
+            ("TAG:rotate=270\n", 270),
+            ("side_data_type=Display Matrix\nrotation=-90\n", 90),
+            ("side_data_type=Display Matrix\nrotation=90\n", 270),
+            ("rotation=180\n", 180),
+            ("rotation=0\n", 0),
+            ("rotation=99999999999999999999\n", 0),
+        ];
+
+        for (stdout, expected) in test_cases {
+            assert_eq!(parse_rotation(stdout), expected);
+        }
+    }
+}
+
+// expected output duration, the sum of the input durations
+fn get_total_duration(input_files: &[InputFile]) -> f64 {
+    // on a timeline the end of the last clip, overlapping or not
+    if input_files.iter().any(|input_file| input_file.start_offset.is_some()) {
+        return input_files.iter().map(|input_file| input_file.start_offset.unwrap_or(0.0) + input_file.duration.unwrap_or(0.0)).fold(0.0, f64::max);
+    }
+    input_files.iter().filter_map(|input_file| input_file.duration).sum()
+}
+
+#[cfg(test)]
+mod test_get_total_duration {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, duration: Some(1.5), ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, duration: Some(2.0), ..Default::default() },
+        ];
+        assert_eq!(get_total_duration(&input_files), 3.5);
 
-        let filter_code_statement = if let Some(alternative_null_audio_duration) = input_file.alternative_null_audio_duration {
-            format!("anullsrc=d={:}[a{:}];", alternative_null_audio_duration, index)
-        } else {
-            format!("[{0:}:a:0]anull[a{0:}];", index)
-        };
-        filter_code.push_str(&filter_code_statement);
-        log::info!("Add filter: {:}", filter_code_statement);
+        // synthetic zero-duration inputs
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, duration: Some(0.0), ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, duration: None, ..Default::default() },
+        ];
+        assert_eq!(get_total_duration(&input_files), 0.0);
+        assert_eq!(get_total_duration(&[]), 0.0);
 
-        concat_input_part_filter_code.push_str(&format!("[v{0:}]", index));
-        concat_input_part_filter_code.push_str(&format!("[a{0:}]", index));
+        let input_files = vec![
+            InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, duration: Some(2.0), start_offset: Some(1.0), ..Default::default() },
+            InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, duration: Some(3.0), start_offset: Some(5.0), ..Default::default() },
+            InputFile { path: PathBuf::from("2.mp4"), width: 300, height: 100, duration: Some(1.0), start_offset: Some(0.0), ..Default::default() },
+        ];
+        assert_eq!(get_total_duration(&input_files), 8.0);
     }
+}
 
-    let filter_code_statement = format!("{:}concat=n={:}:v=1:a=1[vout][aout]", concat_input_part_filter_code, input_files.len());
-
-    log::info!("Add filter: {:}", filter_code_statement);
-    filter_code.push_str(&filter_code_statement);
-    filter_code
+fn get_output_duration(total_duration: f64, output_range: Option<(f64, f64)>) -> f64 {
+    let Some((start, end)) = output_range else {
+        return total_duration;
+    };
+    (end.min(total_duration) - start.min(total_duration)).max(0.0)
 }
 
 #[cfg(test)]
-mod test_get_avfilter_code {
+mod test_get_output_duration {
     use super::*;
 
     #[test]
     fn it_works() {
-        let test_cases = [
-            ("[0:v:0]null[v0];[0:a:0]anull[a0];[1:v:0]null[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]", vec![
-                InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, alternative_null_audio_duration: None },
-                InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, alternative_null_audio_duration: None },
-            ]),
-            ("[0:v:0]null[v0];[0:a:0]anull[a0];[1:v:0]scale=300:100[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]", vec![
-                InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, alternative_null_audio_duration: None },
-                InputFile { path: PathBuf::from("1.mp4"), width: 150, height: 50, alternative_null_audio_duration: None },
-            ]),
-            ("[0:v:0]scale=300:150:force_original_aspect_ratio=decrease,pad=300:150:(ow-iw)/2:(oh-ih)/2[v0];[0:a:0]anull[a0];[1:v:0]scale=300:150:force_original_aspect_ratio=decrease,pad=300:150:(ow-iw)/2:(oh-ih)/2[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]", vec![
-                InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, alternative_null_audio_duration: None },
-                InputFile { path: PathBuf::from("1.mp4"), width: 50, height: 150, alternative_null_audio_duration: None },
-            ]),
-            ("[0:v:0]null[v0];anullsrc=d=3.5[a0];[1:v:0]null[v1];[1:a:0]anull[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]", vec![
-                InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, alternative_null_audio_duration: Some(3.5) },
-                InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, alternative_null_audio_duration: None },
-            ]),
-            ("[0:v:0]null[v0];[0:a:0]anull[a0];[1:v:0]null[v1];anullsrc=d=10.5[a1];[v0][a0][v1][a1]concat=n=2:v=1:a=1[vout][aout]", vec![
-                InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 100, alternative_null_audio_duration: None },
-                InputFile { path: PathBuf::from("1.mp4"), width: 300, height: 100, alternative_null_audio_duration: Some(10.5) },
-            ]),
-        ];
+        assert_eq!(get_output_duration(60.0, None), 60.0);
+        assert_eq!(get_output_duration(60.0, Some((10.0, 12.0))), 2.0);
+        assert_eq!(get_output_duration(60.0, Some((50.0, 120.0))), 10.0);
+        assert_eq!(get_output_duration(60.0, Some((70.0, 120.0))), 0.0);
+    }
+}
 
-        for (filter, input_files) in test_cases {
-            assert_eq!(get_avfilter_code(&input_files), filter.to_string());
-        }
+// duration_ts in time_base units, none when either is missing or invalid
+fn get_exact_stream_duration(stream: &ffprobe::Stream) -> Option<f64> {
+    let duration_ts = stream.duration_ts?;
+    let (numerator, denominator) = stream.time_base.split_once('/')?;
+    let (numerator, denominator) = (numerator.parse::<i64>().ok()?, denominator.parse::<i64>().ok()?);
+    if duration_ts <= 0 || numerator <= 0 || denominator <= 0 {
+        return None;
+    }
+    Some((duration_ts as f64 * numerator as f64) / denominator as f64)
+}
+
+#[cfg(test)]
+mod test_get_exact_stream_duration {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let stream = |duration_ts: Option<i64>, time_base: &str| ffprobe::Stream { duration_ts, time_base: time_base.to_string(), ..Default::default() };
+        assert_eq!(get_exact_stream_duration(&stream(Some(15360), "1/15360")), Some(1.0));
+        assert_eq!(get_exact_stream_duration(&stream(Some(1001), "1/30000")), Some(1001.0 / 30000.0));
+        assert_eq!(get_exact_stream_duration(&stream(None, "1/15360")), None);
+        assert_eq!(get_exact_stream_duration(&stream(Some(15360), "")), None);
+        assert_eq!(get_exact_stream_duration(&stream(Some(15360), "0/0")), None);
+        assert_eq!(get_exact_stream_duration(&stream(Some(0), "1/15360")), None);
     }
 }
 
@@ -580,8 +6171,9 @@ mod test_get_first_stream_for_codec_type {
     }
 }
 
+// the main video stream, see select_video_stream()
 fn get_first_video_stream<'a>(streams: &'a Vec<ffprobe::Stream>) -> Option<&'a ffprobe::Stream> {
-    get_first_stream_for_codec_type("video", streams)
+    select_video_stream(streams).map(|(_, stream)| stream)
 }
 
 #[cfg(test)]
@@ -609,6 +6201,43 @@ mod test_get_first_video_stream {
     }
 }
 
+// skips cover art (attached pic) streams and prefers the largest, the first one on a tie
+// the position counts video streams only, as "v:n" in ffmpeg stream specifiers
+fn select_video_stream(streams: &[ffprobe::Stream]) -> Option<(usize, &ffprobe::Stream)> {
+    let pixels = |stream: &ffprobe::Stream| stream.width.unwrap_or(0) * stream.height.unwrap_or(0);
+    streams.iter()
+        .filter(|stream| stream.codec_type.as_deref() == Some("video"))
+        .enumerate()
+        .filter(|(_, stream)| stream.disposition.attached_pic == 0)
+        .max_by(|(a_position, a), (b_position, b)| pixels(a).cmp(&pixels(b)).then(b_position.cmp(a_position)))
+}
+
+#[cfg(test)]
+mod test_select_video_stream {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let video_stream = |index: i64, width: i64, height: i64, attached_pic: i64| ffprobe::Stream {
+            index, codec_type: Some("video".to_string()), width: Some(width), height: Some(height), disposition: ffprobe::Disposition { attached_pic, ..Default::default() }, ..Default::default()
+        };
+        let audio_stream = |index: i64| ffprobe::Stream { index, codec_type: Some("audio".to_string()), ..Default::default() };
+
+        // cover art sorted before the real video
+        let streams = vec![video_stream(0, 600, 600, 1), audio_stream(1), video_stream(2, 300, 400, 0)];
+        let (position, stream) = select_video_stream(&streams).unwrap();
+        assert_eq!((position, stream.index), (1, 2));
+        assert_eq!(get_first_video_stream(&streams).unwrap().index, 2);
+
+        let streams = vec![audio_stream(0), video_stream(1, 300, 400, 0), video_stream(2, 600, 800, 0), video_stream(3, 600, 800, 0)];
+        assert_eq!(select_video_stream(&streams).map(|(position, stream)| (position, stream.index)), Some((1, 2)));
+
+        let streams = vec![video_stream(0, 600, 600, 1), audio_stream(1)];
+        assert!(select_video_stream(&streams).is_none());
+        assert!(select_video_stream(&[audio_stream(0)]).is_none());
+    }
+}
+
 fn get_first_audio_stream<'a>(streams: &'a Vec<ffprobe::Stream>) -> Option<&'a ffprobe::Stream> {
     get_first_stream_for_codec_type("audio", streams)
 }
@@ -638,47 +6267,453 @@ mod test_get_first_audio_stream {
     }
 }
 
-fn get_best_crf(video_path: impl AsRef<Path>, enough_vmaf: u8, min_crf: u8) -> Result<(u8, Option<f64>), Error> {
-    get_best_crf_impl(AB_AV1_CMD_STR, video_path, enough_vmaf, min_crf)
-}
-
-// separate impl for test
-fn get_best_crf_impl(cmd_str: &str, video_path: impl AsRef<Path>, enough_vmaf: u8, min_crf: u8) -> Result<(u8, Option<f64>), Error> {
+// takes the command for test, encode_best_effort_impl passes it through
+fn get_best_crf_impl(cmd_str: &str, video_path: impl AsRef<Path>, enough_vmaf: f64, min_crf: u8, options: &EncodeOptions) -> Result<(u8, Option<f64>), Error> {
     let video_path = video_path.as_ref();
 
+    // ab-av1 doesn't reject it, but no encode reaches it
+    if !(0.0..=MAX_VMAF as f64).contains(&enough_vmaf) {
+        return Err(Error { kind: ErrorKind::InvalidVmaf(enough_vmaf) });
+    }
+
     let mut ab_av1_cmd = Command::new(cmd_str);
-    ab_av1_cmd.args([
-        "crf-search",
-        "--min-vmaf", &enough_vmaf.to_string(),
-        "--min-crf", &(min_crf + 1).to_string(),
-        "--max-crf", &MAX_CRF.to_string(),
-        "--max-encoded-percent", "100",
+    ab_av1_cmd.args(get_crf_search_args(enough_vmaf, min_crf, options)).arg(video_path);
+
+    let timeout = options.crf_search_timeout.map(Duration::from_secs_f64);
+    let mut attempt = 0;
+    loop {
+        let output = match run_command_with_timeout(&mut ab_av1_cmd, options.cancel.as_deref(), timeout) {
+            Ok(output) => output,
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => return Err(Error { kind: ErrorKind::CrfSearchTimedOut(video_path.into(), options.crf_search_timeout.unwrap_or_default()) }),
+            Err(err) => return Err(Error { kind: ErrorKind::AbAv1CommandProcessFailed(video_path.into(), err.to_string()) }),
+        };
+
+        if output.status.success() {
+            let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+            let Some((crf, vmaf)) = parse_ab_av1_crf_output(&stdout) else {
+                return Err(Error { kind: ErrorKind::InvalidAbAv1Output(video_path.into(), stdout) });
+            };
+            return Ok((crf, Some(vmaf)));
+        }
+
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        match classify_ab_av1_failure(&stderr) {
+            AbAv1Failure::GoodCrfNotFound => {
+                if options.fail_below_vmaf {
+                    return Err(Error { kind: ErrorKind::VmafTargetUnreachable(video_path.into(), enough_vmaf) });
+                }
+                // if failed with not found good crf, then max crf
+                return Ok((min_crf, None));
+            },
+            AbAv1Failure::Transient if attempt < options.ab_av1_retries => {
+                attempt += 1;
+                log::warn!("ab-av1 failed transiently, retry {:}/{:}: {:}", attempt, options.ab_av1_retries, stderr.trim_end());
+            },
+            _ => return Err(Error { kind: ErrorKind::UnknownAbAv1ErrorMessage(video_path.into(), stderr) }),
+        }
+    }
+}
+
+// a timed out search falls back to min crf as when no crf is good enough, the flag tells which
+fn get_best_crf_or_fallback(cmd_str: &str, video_path: impl AsRef<Path>, enough_vmaf: f64, min_crf: u8, options: &EncodeOptions) -> Result<(u8, Option<f64>, bool), Error> {
+    match get_best_crf_impl(cmd_str, video_path, enough_vmaf, min_crf, options) {
+        Ok((crf, vmaf)) => Ok((crf, vmaf, false)),
+        Err(Error { kind: ErrorKind::CrfSearchTimedOut(path, timeout) }) => {
+            log::warn!("Crf search timed out after {:}s, use min: {:} ({:})", timeout, min_crf, path.display());
+            Ok((min_crf, None, true))
+        },
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod test_get_best_crf_or_fallback {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn it_works() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+        let video_path = video_dir_path.join("va-300x400.mp4");
+
+        // never converges
+        let script_path = output_dir_path.join("test_get_best_crf_or_fallback.sh");
+        std::fs::write(&script_path, "#!/bin/sh\nexec sleep 10\n").unwrap();
+        std::fs::set_permissions(&script_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let script_str = script_path.to_str().unwrap();
+
+        let options = EncodeOptions { crf_search_timeout: Some(0.3), ..Default::default() };
+        let started_at = std::time::Instant::now();
+        assert!(match get_best_crf_impl(script_str, &video_path, 80.0, 40, &options) {
+            Err(Error { kind: ErrorKind::CrfSearchTimedOut(path, timeout) }) => path == video_path && timeout == 0.3, _ => false,
+        });
+        assert_eq!(get_best_crf_or_fallback(script_str, &video_path, 80.0, 40, &options), Ok((40, None, true)));
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+
+        // other errors are still errors
+        assert!(matches!(get_best_crf_or_fallback("false", &video_path, 80.0, 40, &options), Err(Error { kind: ErrorKind::UnknownAbAv1ErrorMessage(_, _) })));
+        assert!(matches!(get_best_crf_or_fallback("echo", &video_path, 80.0, 40, &options), Err(Error { kind: ErrorKind::InvalidAbAv1Output(_, _) })));
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum AbAv1Failure {
+    GoodCrfNotFound,
+    Transient,
+    Unknown,
+}
+
+fn classify_ab_av1_failure(stderr: &str) -> AbAv1Failure {
+    if AB_AV1_STDERR_CHECK_GOOD_CRF_NOT_FOUND_REGEX.is_match(stderr) {
+        AbAv1Failure::GoodCrfNotFound
+    } else if AB_AV1_STDERR_CHECK_TRANSIENT_ERROR_REGEX.is_match(stderr) {
+        AbAv1Failure::Transient
+    } else {
+        AbAv1Failure::Unknown
+    }
+}
+
+#[cfg(test)]
+mod test_classify_ab_av1_failure {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let test_cases = [
+            ("Error: Failed to find a suitable crf\n", AbAv1Failure::GoodCrfNotFound),
+            ("Error: ffmpeg encode exit code 1\n", AbAv1Failure::Transient),
+            ("Error: ffmpeg exit signal: 9 (SIGKILL)\n", AbAv1Failure::Transient),
+            ("Error: Broken pipe (os error 32)\n", AbAv1Failure::Transient),
+            ("Error: Resource temporarily unavailable (os error 11)\n", AbAv1Failure::Transient),
+            ("error: unexpected argument '--foo' found\n", AbAv1Failure::Unknown),
+            ("", AbAv1Failure::Unknown),
+        ];
+        for (stderr, expected) in test_cases {
+            assert_eq!(classify_ab_av1_failure(stderr), expected);
+        }
+    }
+}
+
+// ends with --input, the path follows
+fn get_crf_search_args(enough_vmaf: f64, min_crf: u8, options: &EncodeOptions) -> Vec<String> {
+    let mut args = vec![
+        "crf-search".to_string(),
+        "--min-vmaf".to_string(), enough_vmaf.to_string(),
+        "--min-crf".to_string(), (min_crf + 1).to_string(),
+        "--max-crf".to_string(), MAX_CRF.to_string(),
+        "--max-encoded-percent".to_string(), "100".to_string(),
+        "--pix-format".to_string(), options.search_pixel_format.unwrap_or(options.pixel_format).as_ffmpeg_value().to_string(),
+    ];
+    // the same params as the final encode, otherwise the searched crf doesn't match its quality
+    for param in get_search_svtav1_params(options) {
+        args.extend(["--svt".to_string(), param]);
+    }
+    args.extend([
         "--enc", "fps_mode=passthrough",
         "--enc", "dn",
         "--input",
-    ]).arg(&video_path);
+    ].map(String::from));
+    args
+}
+
+#[cfg(test)]
+mod test_get_crf_search_args {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_crf_search_args(80.0, 40, &EncodeOptions::default()).join(" "), "crf-search --min-vmaf 80 --min-crf 41 --max-crf 55 --max-encoded-percent 100 --pix-format yuv420p10le --enc fps_mode=passthrough --enc dn --input");
+        assert_eq!(get_crf_search_args(93.5, 40, &EncodeOptions::default()).join(" "), "crf-search --min-vmaf 93.5 --min-crf 41 --max-crf 55 --max-encoded-percent 100 --pix-format yuv420p10le --enc fps_mode=passthrough --enc dn --input");
+        assert_eq!(get_crf_search_args(80.0, 40, &EncodeOptions { pixel_format: OutputPixelFormat::Yuv444p10le, ..Default::default() }).join(" "), "crf-search --min-vmaf 80 --min-crf 41 --max-crf 55 --max-encoded-percent 100 --pix-format yuv444p10le --enc fps_mode=passthrough --enc dn --input");
+        assert_eq!(get_crf_search_args(80.0, 40, &EncodeOptions { tune: Some(1), ..Default::default() }).join(" "), "crf-search --min-vmaf 80 --min-crf 41 --max-crf 55 --max-encoded-percent 100 --pix-format yuv420p10le --svt tune=1 --enc fps_mode=passthrough --enc dn --input");
+
+        // search and encode pixel formats go to their own commands
+        let options = EncodeOptions { pixel_format: OutputPixelFormat::Yuv420p10le, search_pixel_format: Some(OutputPixelFormat::Yuv420p), ..Default::default() };
+        let crf_search_args = get_crf_search_args(80.0, 40, &options);
+        assert_eq!(crf_search_args[crf_search_args.iter().position(|arg| arg == "--pix-format").unwrap() + 1], "yuv420p");
+        let output_args = get_output_args(30, Path::new("a.mp4"), &options, None);
+        assert_eq!(output_args[output_args.iter().position(|arg| arg == "-pix_fmt").unwrap() + 1], "yuv420p10le");
+
+        // the encode gets the identical param
+        let options = EncodeOptions { tune: Some(1), ..Default::default() };
+        let output_args = get_output_args(30, Path::new("a.mp4"), &options, None);
+        let svtav1_params_position = output_args.iter().position(|arg| arg == "-svtav1-params").unwrap();
+        assert_eq!(output_args[svtav1_params_position + 1], "tune=1");
+        let crf_search_args = get_crf_search_args(80.0, 40, &options);
+        let svt_position = crf_search_args.iter().position(|arg| arg == "--svt").unwrap();
+        assert_eq!(crf_search_args[svt_position + 1], output_args[svtav1_params_position + 1]);
+
+        // the level and tier too, one --svt each
+        let options = EncodeOptions { av1_level: Some("5.1".to_string()), av1_tier: Some("main".to_string()), ..Default::default() };
+        let output_args = get_output_args(30, Path::new("a.mp4"), &options, None);
+        assert_eq!(output_args[output_args.iter().position(|arg| arg == "-svtav1-params").unwrap() + 1], "level=5.1:tier=0");
+        assert!(get_crf_search_args(80.0, 40, &options).join(" ").contains("--svt level=5.1 --svt tier=0 "));
+        assert!(get_sample_encode_args(40, &options).join(" ").contains("--svt level=5.1 --svt tier=0 "));
+    }
+}
+
+// what keeps auto-encode from producing the same output as the separate steps
+fn get_auto_encode_blocker(input_files: &[InputFile], options: &EncodeOptions) -> Option<&'static str> {
+    if input_files.len() != 1 {
+        Some("multiple inputs need the concat filter")
+    } else if options.fixed_crf.is_some() {
+        Some("fixed crf skips the search")
+    } else if !options.renditions.is_empty() || options.memory_limit_mb.is_some() || options.aspect_ratio.is_some() || options.watermark.is_some() || options.timeline.is_some() || options.align_audio_to_video || options.trim_black.is_some() || options.trim_silence.is_some() || options.fade_in.is_some() || options.fade_out.is_some() || options.denoise.is_some() || options.speeds.is_some() || options.audio_delays_ms.is_some() {
+        Some("the options need the filter graph")
+    } else if options.captions_path.is_some() || options.chapters || options.output_range.is_some() || options.emit_audio_sidecar || options.output_container.is_some() || options.deterministic || options.metadata_stamp != MetadataStamp::Keep || !options.source_ids.is_empty() || options.mp4_mode != Mp4Mode::Regular {
+        Some("the options need extra ffmpeg inputs or output args")
+    } else if get_drops_audio(input_files, options.audio_policy) && has_any_audio(input_files) {
+        Some("dropping the audio needs ffmpeg output args")
+    } else if options.measure_fallback_vmaf || options.hard_floor_vmaf.is_some() {
+        Some("fallback vmaf needs the separate search")
+    } else if options.prefer_smaller {
+        Some("prefer smaller needs the separate search")
+    } else if options.crf_search_timeout.is_some() {
+        Some("search timeout needs the separate search")
+    } else if options.verify_prediction {
+        Some("verify prediction needs the separate search")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test_get_auto_encode_blocker {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let input_file = || InputFile { path: PathBuf::from("0.mp4"), width: 300, height: 400, ..Default::default() };
+        assert_eq!(get_auto_encode_blocker(&[input_file()], &EncodeOptions::default()), None);
+        assert!(get_auto_encode_blocker(&[input_file(), input_file()], &EncodeOptions::default()).is_some());
+        assert!(get_auto_encode_blocker(&[input_file()], &EncodeOptions { fixed_crf: Some(30), ..Default::default() }).is_some());
+        assert!(get_auto_encode_blocker(&[input_file()], &EncodeOptions { memory_limit_mb: Some(1024), ..Default::default() }).is_some());
+        assert!(get_auto_encode_blocker(&[input_file()], &EncodeOptions { chapters: true, ..Default::default() }).is_some());
+        assert!(get_auto_encode_blocker(&[input_file()], &EncodeOptions { deterministic: true, ..Default::default() }).is_some());
+        assert!(get_auto_encode_blocker(&[input_file()], &EncodeOptions { metadata_stamp: MetadataStamp::Strip, ..Default::default() }).is_some());
+        assert!(get_auto_encode_blocker(&[input_file()], &EncodeOptions { source_ids: vec!["a.mp4".to_string()], ..Default::default() }).is_some());
+        assert!(get_auto_encode_blocker(&[input_file()], &EncodeOptions { mp4_mode: Mp4Mode::Faststart, ..Default::default() }).is_some());
+        assert!(get_auto_encode_blocker(&[input_file()], &EncodeOptions { hard_floor_vmaf: Some(80), ..Default::default() }).is_some());
+    }
+}
+
+// the crf and its vmaf, none when no crf reaches enough vmaf and nothing was encoded
+fn auto_encode_impl(cmd_str: &str, video_path: &Path, output_video_path: &Path, enough_vmaf: f64, min_crf: u8, options: &EncodeOptions) -> Result<Option<(u8, f64)>, Error> {
+    let mut ab_av1_cmd = Command::new(cmd_str);
+    ab_av1_cmd.args(get_auto_encode_args(enough_vmaf, min_crf, options)).arg(video_path).arg("--output").arg(output_video_path);
 
-    let output = match ab_av1_cmd.output() {
+    let output = match run_command_with_cancel(&mut ab_av1_cmd, options.cancel.as_deref()) {
         Ok(output) => output,
         Err(err) => return Err(Error { kind: ErrorKind::AbAv1CommandProcessFailed(video_path.into(), err.to_string()) }),
     };
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    if !output.status.success() {
+        if classify_ab_av1_failure(&stderr) != AbAv1Failure::GoodCrfNotFound {
+            return Err(Error { kind: ErrorKind::UnknownAbAv1ErrorMessage(video_path.into(), stderr) });
+        }
+        if options.fail_below_vmaf {
+            return Err(Error { kind: ErrorKind::VmafTargetUnreachable(video_path.into(), enough_vmaf) });
+        }
+        return Ok(None);
+    }
 
-    if output.status.success() {
-        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-        let Some(caps) = AB_AV1_STDOUT_RETRIEVE_CRF_REGEX.captures(&stdout) else {
-            return Err(Error { kind: ErrorKind::InvalidAbAv1Output(video_path.into(), stdout) });
-        };
-        assert!(caps.len() >= 2);
-        let crf = parse_number::<u8, _>(&caps[1], Error { kind: ErrorKind::InvalidAbAv1Output(video_path.into(), stdout.clone()) })?;
-        let vmaf = parse_number::<f64, _>(&caps[2], Error { kind: ErrorKind::InvalidAbAv1Output(video_path.into(), stdout.clone()) })?;
-        Ok((crf, Some(vmaf)))
-    } else {
+    // the search result goes to stderr along with the progress
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let Some((crf, vmaf)) = parse_ab_av1_crf_output(&format!("{:}\n{:}", stdout, stderr)) else {
+        return Err(Error { kind: ErrorKind::InvalidAbAv1Output(video_path.into(), stdout) });
+    };
+    Ok(Some((crf, vmaf)))
+}
+
+// the crf search args with the encode side of get_output_args, ends with --input
+fn get_auto_encode_args(enough_vmaf: f64, min_crf: u8, options: &EncodeOptions) -> Vec<String> {
+    let mut args = get_crf_search_args(enough_vmaf, min_crf, options);
+    args[0] = "auto-encode".to_string();
+    let input_position = args.len() - 1;
+    args.splice(input_position..input_position, [
+        "--preset".to_string(), "8".to_string(),
+        "--acodec".to_string(), "libopus".to_string(),
+        "--enc".to_string(), format!("application:a={:}", options.opus_application.as_ffmpeg_value()),
+    ]);
+    args
+}
+
+#[cfg(test)]
+mod test_get_auto_encode_args {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_auto_encode_args(80.0, 40, &EncodeOptions::default()).join(" "), "auto-encode --min-vmaf 80 --min-crf 41 --max-crf 55 --max-encoded-percent 100 --pix-format yuv420p10le --enc fps_mode=passthrough --enc dn --preset 8 --acodec libopus --enc application:a=audio --input");
+    }
+}
+
+// one sample encode at the given crf, for its vmaf
+fn get_sample_vmaf_impl(cmd_str: &str, video_path: impl AsRef<Path>, crf: u8, options: &EncodeOptions) -> Result<f64, Error> {
+    let video_path = video_path.as_ref();
+
+    let mut ab_av1_cmd = Command::new(cmd_str);
+    ab_av1_cmd.args(get_sample_encode_args(crf, options)).arg(video_path);
+
+    let output = match run_command_with_cancel(&mut ab_av1_cmd, options.cancel.as_deref()) {
+        Ok(output) => output,
+        Err(err) => return Err(Error { kind: ErrorKind::AbAv1CommandProcessFailed(video_path.into(), err.to_string()) }),
+    };
+    if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-        if !AB_AV1_STDERR_CHECK_GOOD_CRF_NOT_FOUND_REGEX.is_match(&stderr) {
-            return Err(Error { kind: ErrorKind::UnknownAbAv1ErrorMessage(video_path.into(), stderr) });
+        return Err(Error { kind: ErrorKind::UnknownAbAv1ErrorMessage(video_path.into(), stderr) });
+    }
+
+    // sample-encode prints the same crf and vmaf line as crf-search
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let Some((_, vmaf)) = parse_ab_av1_crf_output(&stdout) else {
+        return Err(Error { kind: ErrorKind::InvalidAbAv1Output(video_path.into(), stdout) });
+    };
+    Ok(vmaf)
+}
+
+#[cfg(test)]
+mod test_get_sample_vmaf {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn it_works() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+
+        assert!(matches!(get_sample_vmaf_impl("__command_not_found__", video_dir_path.join("va-300x400.mp4"), MAX_CRF, &EncodeOptions::default()), Err(Error { kind: ErrorKind::AbAv1CommandProcessFailed(_, _) })));
+        assert!(matches!(get_sample_vmaf_impl("echo", video_dir_path.join("va-300x400.mp4"), MAX_CRF, &EncodeOptions::default()), Err(Error { kind: ErrorKind::InvalidAbAv1Output(_, _) })));
+        assert!(matches!(get_sample_vmaf_impl("false", video_dir_path.join("va-300x400.mp4"), MAX_CRF, &EncodeOptions::default()), Err(Error { kind: ErrorKind::UnknownAbAv1ErrorMessage(_, _) })));
+        let vmaf = get_sample_vmaf_impl(AB_AV1_CMD_STR, video_dir_path.join("va-300x400.mp4"), MAX_CRF, &EncodeOptions::default()).unwrap();
+        assert!(0.0 <= vmaf && vmaf <= MAX_VMAF as f64);
+    }
+}
+
+// greedy, only one step up from the crf-search result
+fn get_smaller_crf_impl(cmd_str: &str, video_path: impl AsRef<Path>, enough_vmaf: f64, crf: u8, vmaf: f64, options: &EncodeOptions) -> Result<(u8, f64), Error> {
+    if MAX_CRF <= crf || vmaf < enough_vmaf + options.prefer_smaller_margin {
+        return Ok((crf, vmaf));
+    }
+    let higher_crf = crf + 1;
+    log::info!("Vmaf has headroom, try a higher crf: {:} (vmaf={:} enough={:})", higher_crf, vmaf, enough_vmaf);
+    let higher_vmaf = get_sample_vmaf_impl(cmd_str, video_path, higher_crf, options)?;
+    if higher_vmaf < enough_vmaf {
+        log::info!("Higher crf is below enough vmaf, keep: {:} (vmaf={:})", crf, higher_vmaf);
+        return Ok((crf, vmaf));
+    }
+    Ok((higher_crf, higher_vmaf))
+}
+
+#[cfg(test)]
+mod test_get_smaller_crf {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn it_works() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+        let video_path = video_dir_path.join("va-300x400.mp4");
+
+        // sample-encode --crf 34 loses a little vmaf
+        let script_path = output_dir_path.join("test_get_smaller_crf.sh");
+        std::fs::write(&script_path, r#"#!/bin/sh
+if [ "$1" = "sample-encode" ] && [ "$3" = "34" ]; then
+    echo "crf 34 VMAF 94.20 predicted video stream size 0.90 MiB (22%) taking 3 seconds"
+    exit 0
+fi
+exit 1
+"#).unwrap();
+        std::fs::set_permissions(&script_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let script_str = script_path.to_str().unwrap();
+
+        let options = EncodeOptions { prefer_smaller: true, prefer_smaller_margin: 1.0, ..Default::default() };
+        assert_eq!(get_smaller_crf_impl(script_str, &video_path, 90.0, 33, 95.0, &options), Ok((34, 94.2)));
+
+        // still probed, but the higher crf misses the target
+        assert_eq!(get_smaller_crf_impl(script_str, &video_path, 94.5, 33, 95.6, &options), Ok((33, 95.6)));
+
+        // no headroom or no higher crf, nothing runs
+        assert_eq!(get_smaller_crf_impl("false", &video_path, 94.5, 33, 95.0, &options), Ok((33, 95.0)));
+        assert_eq!(get_smaller_crf_impl("false", &video_path, 80.0, MAX_CRF, 95.0, &options), Ok((MAX_CRF, 95.0)));
+
+        assert!(matches!(get_smaller_crf_impl("false", &video_path, 80.0, 33, 95.0, &options), Err(Error { kind: ErrorKind::UnknownAbAv1ErrorMessage(_, _) })));
+    }
+}
+
+// ends with --input, the path follows
+fn get_sample_encode_args(crf: u8, options: &EncodeOptions) -> Vec<String> {
+    let mut args = vec![
+        "sample-encode".to_string(),
+        "--crf".to_string(), crf.to_string(),
+        "--pix-format".to_string(), options.search_pixel_format.unwrap_or(options.pixel_format).as_ffmpeg_value().to_string(),
+    ];
+    for param in get_search_svtav1_params(options) {
+        args.extend(["--svt".to_string(), param]);
+    }
+    args.extend([
+        "--enc", "fps_mode=passthrough",
+        "--enc", "dn",
+        "--input",
+    ].map(String::from));
+    args
+}
+
+#[cfg(test)]
+mod test_get_sample_encode_args {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(get_sample_encode_args(40, &EncodeOptions::default()).join(" "), "sample-encode --crf 40 --pix-format yuv420p10le --enc fps_mode=passthrough --enc dn --input");
+        assert_eq!(get_sample_encode_args(40, &EncodeOptions { tune: Some(2), ..Default::default() }).join(" "), "sample-encode --crf 40 --pix-format yuv420p10le --svt tune=2 --enc fps_mode=passthrough --enc dn --input");
+        assert_eq!(get_sample_encode_args(40, &EncodeOptions { search_pixel_format: Some(OutputPixelFormat::Yuv420p), ..Default::default() }).join(" "), "sample-encode --crf 40 --pix-format yuv420p --enc fps_mode=passthrough --enc dn --input");
+    }
+}
+
+// the last line matching any known format wins, colored output is accepted too
+fn parse_ab_av1_crf_output(stdout: &str) -> Option<(u8, f64)> {
+    let stdout = AB_AV1_STDOUT_ANSI_ESCAPE_REGEX.replace_all(stdout, "");
+    stdout.lines().rev().find_map(|line| {
+        let caps = AB_AV1_STDOUT_RETRIEVE_CRF_REGEXES.iter().find_map(|re| re.captures(line))?;
+        let crf = caps[1].parse::<u8>().ok()?;
+        let vmaf = caps[2].parse::<f64>().ok()?;
+        Some((crf, vmaf))
+    })
+}
+
+#[cfg(test)]
+mod test_parse_ab_av1_crf_output {
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let test_cases = [
+            // 0.7
+            ("crf 32 VMAF 95.12 predicted video stream size 1.21 MiB (25%) taking 3 seconds\n", Some((32, 95.12))),
+            // 0.8
+            ("- crf 40 VMAF 93.50 (30%)\n- crf 36 VMAF 94.80 (35%)\ncrf 36 VMAF 94.80 predicted full encode size 2.00 MiB (35%) taking 5 seconds\n", Some((36, 94.8))),
+            // 0.9
+            ("\x1b[1mcrf 28\x1b[0m predicted VMAF \x1b[32m96.01\x1b[0m predicted full encode size 3.1 MiB (40%) taking 8 seconds\n", Some((28, 96.01))),
+            ("crf 30 VMAF 95\n", Some((30, 95.0))),
+            ("", None),
+            ("Encoded 10 frames\n", None),
+            ("crf 300 VMAF 95.0\n", None),
+        ];
+        for (stdout, expected) in test_cases {
+            assert_eq!(parse_ab_av1_crf_output(stdout), expected);
         }
-        // if failed with not found good crf, then max crf
-        Ok((min_crf, None))
     }
 }
 
@@ -693,18 +6728,69 @@ mod test_get_best_crf {
         let root_path = Path::new(&root_path);
         let video_dir_path = root_path.join("tests/videos");
 
-        assert!(match get_best_crf_impl("__command_not_found__", video_dir_path.join("va-300x400.mp4"), 80, 40) {
-            Err(Error { kind: ErrorKind::AbAv1CommandProcessFailed(_, _) }) => true, _ => false,
-        });
-        assert!(match get_best_crf_impl("echo", video_dir_path.join("va-300x400.mp4"), 80, 40) {
-            Err(Error { kind: ErrorKind::InvalidAbAv1Output(_, _) }) => true, _ => false,
-        });
-        assert!(match get_best_crf_impl("false", video_dir_path.join("va-300x400.mp4"), 80, 40) {
-            Err(Error { kind: ErrorKind::UnknownAbAv1ErrorMessage(_, _) }) => true, _ => false,
+        assert!(matches!(get_best_crf_impl("__command_not_found__", video_dir_path.join("va-300x400.mp4"), 80.0, 40, &EncodeOptions::default()), Err(Error { kind: ErrorKind::AbAv1CommandProcessFailed(_, _) })));
+        assert!(matches!(get_best_crf_impl("echo", video_dir_path.join("va-300x400.mp4"), 80.0, 40, &EncodeOptions::default()), Err(Error { kind: ErrorKind::InvalidAbAv1Output(_, _) })));
+        assert!(matches!(get_best_crf_impl("false", video_dir_path.join("va-300x400.mp4"), 80.0, 40, &EncodeOptions::default()), Err(Error { kind: ErrorKind::UnknownAbAv1ErrorMessage(_, _) })));
+        assert_eq!(get_best_crf_impl(AB_AV1_CMD_STR, video_dir_path.join("va-300x400.mp4"), 100.0, MAX_CRF - 2, &EncodeOptions::default()), Ok((MAX_CRF - 2, None)));
+        assert!(matches!(get_best_crf_impl(AB_AV1_CMD_STR, video_dir_path.join("va-300x400.mp4"), 0.0, MAX_CRF - 2, &EncodeOptions::default()), Ok((MAX_CRF, Some(_)))));
+
+        let options = EncodeOptions { fail_below_vmaf: true, ..Default::default() };
+        assert!(match get_best_crf_impl(AB_AV1_CMD_STR, video_dir_path.join("va-300x400.mp4"), 100.0, MAX_CRF - 2, &options) {
+            Err(Error { kind: ErrorKind::VmafTargetUnreachable(_, enough_vmaf) }) => enough_vmaf == 100.0, _ => false,
         });
-        assert_eq!(get_best_crf(video_dir_path.join("va-300x400.mp4"), 100, MAX_CRF - 2), Ok((MAX_CRF - 2, None)));
-        assert!(match get_best_crf(video_dir_path.join("va-300x400.mp4"), 0, MAX_CRF - 2) {
-            Ok((MAX_CRF, Some(_))) => true, _ => false,
+        assert!(matches!(get_best_crf_impl(AB_AV1_CMD_STR, video_dir_path.join("va-300x400.mp4"), 0.0, MAX_CRF - 2, &options), Ok((MAX_CRF, Some(_)))));
+    }
+
+    #[test]
+    fn it_retries_transient_failure() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+        let output_dir_path = root_path.join("output");
+
+        // crashes on the first two runs, then finds a crf
+        let count_path = output_dir_path.join("it_retries_transient_failure.count");
+        let script_path = output_dir_path.join("it_retries_transient_failure.sh");
+        std::fs::write(&script_path, format!(r#"#!/bin/sh
+count=$(cat "{0:}" 2>/dev/null || echo 0)
+echo $((count + 1)) > "{0:}"
+if [ "$count" -lt 2 ]; then
+    echo "Error: ffmpeg encode exit code 1" >&2
+    exit 1
+fi
+echo "crf 33 VMAF 95.00 predicted video stream size 1.00 MiB (25%) taking 3 seconds"
+"#, count_path.display())).unwrap();
+        std::fs::set_permissions(&script_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let script_str = script_path.to_str().unwrap();
+
+        let _ = std::fs::remove_file(&count_path);
+        let options = EncodeOptions { ab_av1_retries: 2, ..Default::default() };
+        assert_eq!(get_best_crf_impl(script_str, video_dir_path.join("va-300x400.mp4"), 80.0, 40, &options), Ok((33, Some(95.0))));
+        assert_eq!(std::fs::read_to_string(&count_path).unwrap().trim(), "3");
+
+        let _ = std::fs::remove_file(&count_path);
+        let options = EncodeOptions { ab_av1_retries: 1, ..Default::default() };
+        assert!(matches!(get_best_crf_impl(script_str, video_dir_path.join("va-300x400.mp4"), 80.0, 40, &options), Err(Error { kind: ErrorKind::UnknownAbAv1ErrorMessage(_, _) })));
+        assert_eq!(std::fs::read_to_string(&count_path).unwrap().trim(), "2");
+    }
+
+    #[test]
+    fn it_validates_enough_vmaf() {
+        let root_path = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let root_path = Path::new(&root_path);
+        let video_dir_path = root_path.join("tests/videos");
+
+        // in range goes on to run the command
+        for enough_vmaf in [0.0, 93.5, MAX_VMAF as f64] {
+            assert!(matches!(get_best_crf_impl("echo", video_dir_path.join("va-300x400.mp4"), enough_vmaf, 40, &EncodeOptions::default()), Err(Error { kind: ErrorKind::InvalidAbAv1Output(_, _) })));
+        }
+        for enough_vmaf in [MAX_VMAF as f64 + 0.5, 200.0, -1.0] {
+            assert_eq!(get_best_crf_impl("echo", video_dir_path.join("va-300x400.mp4"), enough_vmaf, 40, &EncodeOptions::default()), Err(Error { kind: ErrorKind::InvalidVmaf(enough_vmaf) }));
+        }
+        assert!(match get_best_crf_impl("echo", video_dir_path.join("va-300x400.mp4"), f64::NAN, 40, &EncodeOptions::default()) {
+            Err(Error { kind: ErrorKind::InvalidVmaf(enough_vmaf) }) => enough_vmaf.is_nan(), _ => false,
         });
     }
 }